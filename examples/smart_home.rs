@@ -10,6 +10,7 @@ fn main() -> CustomResult<()> {
     let device1 = SmartDevice::Thermo(Thermometer {
         name: "Therm1".to_owned(),
         state: Temperature::Celsius(18.0),
+        ..Default::default()
     });
 
     //create power socket
@@ -18,6 +19,7 @@ fn main() -> CustomResult<()> {
         description: "Power Socket".to_owned(),
         state: PowerSocketState::NotPowered,
         power_consumption: 0,
+        ..Default::default()
     });
 
     //create rooms:
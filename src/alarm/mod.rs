@@ -0,0 +1,383 @@
+use crate::device_key::{device_key, DeviceKey};
+use crate::events::{DeviceEvent, EventBus};
+use crate::{SmartDevice, SmartDeviceList, Temperature};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::sync::mpsc::Receiver;
+use std::time::{Duration, SystemTime};
+
+/// Cleared alarms an `AlarmMonitor` keeps unless configured otherwise.
+pub const ALARM_HISTORY_LIMIT: usize = 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum AlarmKind {
+    Low,
+    High,
+    RateOfChange,
+    SensorStale,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum Severity {
+    Warning,
+    Critical,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Alarm {
+    pub room: String,
+    pub device: String,
+    pub kind: AlarmKind,
+    pub severity: Severity,
+    /// celsius reading (or rate in celsius per minute) that raised the alarm
+    pub value: Option<f32>,
+    pub raised_at: SystemTime,
+    pub cleared_at: Option<SystemTime>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum AlarmEvent {
+    Raised(Alarm),
+    Cleared(Alarm),
+}
+
+impl AlarmEvent {
+    pub fn alarm(&self) -> &Alarm {
+        match self {
+            AlarmEvent::Raised(alarm) | AlarmEvent::Cleared(alarm) => alarm,
+        }
+    }
+}
+
+/// Alarm thresholds of a single thermometer. All temperatures are in celsius.
+#[derive(Debug, Clone, Default)]
+pub struct AlarmConfig {
+    low: Option<(f32, Severity)>,
+    high: Option<(f32, Severity)>,
+    max_rate: Option<(f32, Severity)>,
+    stale_after: Option<(Duration, Severity)>,
+    hysteresis: f32,
+}
+
+impl AlarmConfig {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// raised when temperature drops below `celsius`
+    pub fn low(mut self, celsius: f32, severity: Severity) -> Self {
+        self.low = Some((celsius, severity));
+        self
+    }
+    /// raised when temperature rises above `celsius`
+    pub fn high(mut self, celsius: f32, severity: Severity) -> Self {
+        self.high = Some((celsius, severity));
+        self
+    }
+    /// raised when temperature changes faster than `celsius_per_minute` between two readings
+    pub fn max_rate(mut self, celsius_per_minute: f32, severity: Severity) -> Self {
+        self.max_rate = Some((celsius_per_minute, severity));
+        self
+    }
+    /// raised by `AlarmMonitor::check_stale` when no reading arrived for `timeout`
+    pub fn stale_after(mut self, timeout: Duration, severity: Severity) -> Self {
+        self.stale_after = Some((timeout, severity));
+        self
+    }
+    /// threshold alarms clear only after temperature returns `celsius` past the threshold,
+    /// so a reading hovering around the limit does not flap
+    pub fn hysteresis(mut self, celsius: f32) -> Self {
+        self.hysteresis = celsius.abs();
+        self
+    }
+}
+
+enum Check {
+    Fire(Severity, f32),
+    Clear,
+    Keep,
+}
+
+#[derive(Debug, Default)]
+struct SensorState {
+    config: AlarmConfig,
+    last_reading: Option<(f32, SystemTime)>,
+    watched_since: Option<SystemTime>,
+    active: HashMap<AlarmKind, Alarm>,
+}
+
+/// Evaluates thermometer readings against per-device `AlarmConfig`s.
+/// Alarm changes are published to the event bus, the last cleared alarms are kept in history.
+#[derive(Debug)]
+pub struct AlarmMonitor {
+    /// by lowercase room and device name
+    sensors: HashMap<DeviceKey, SensorState>,
+    history: VecDeque<Alarm>,
+    history_limit: usize,
+    events: EventBus,
+}
+
+impl Default for AlarmMonitor {
+    fn default() -> Self {
+        Self {
+            sensors: HashMap::new(),
+            history: VecDeque::new(),
+            history_limit: ALARM_HISTORY_LIMIT,
+            events: EventBus::default(),
+        }
+    }
+}
+
+impl AlarmMonitor {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// publish alarm events to an existing bus instead of a private one
+    pub fn with_event_bus(events: EventBus) -> Self {
+        Self {
+            events,
+            ..Self::default()
+        }
+    }
+    /// keep only the last `limit` cleared alarms, the oldest are dropped first
+    pub fn with_history_limit(mut self, limit: usize) -> Self {
+        self.history_limit = limit;
+        self
+    }
+    pub fn subscribe(&self) -> Receiver<DeviceEvent> {
+        self.events.subscribe()
+    }
+    /// Room and device names are case insensitive. Reconfiguring keeps the active alarms
+    /// until the next reading is evaluated.
+    pub fn configure(&mut self, room: &str, device: &str, config: AlarmConfig) {
+        self.sensors
+            .entry(device_key(room, device))
+            .or_default()
+            .config = config;
+    }
+    /// Stops watching the device; its active alarms are cleared at `at`.
+    pub fn remove(&mut self, room: &str, device: &str, at: SystemTime) -> Option<AlarmConfig> {
+        let sensor = self.sensors.remove(&device_key(room, device))?;
+        let mut alarms: Vec<Alarm> = sensor.active.into_values().collect();
+        alarms.sort_by_key(|a| a.raised_at);
+        let events: Vec<AlarmEvent> = alarms
+            .into_iter()
+            .map(|mut alarm| {
+                alarm.cleared_at = Some(at);
+                AlarmEvent::Cleared(alarm)
+            })
+            .collect();
+        self.emit(&events);
+        Some(sensor.config)
+    }
+    /// Stops watching the devices of `room`, see `remove`.
+    pub fn remove_room(&mut self, room: &str, at: SystemTime) {
+        for (room, device) in self.sensors_in(room) {
+            self.remove(&room, &device, at);
+        }
+    }
+    /// Keeps watching the device under its new name, active alarms included.
+    pub fn rename_device(&mut self, room: &str, device: &str, new_name: &str) {
+        self.rename(device_key(room, device), device_key(room, new_name));
+    }
+    /// Keeps watching the devices of `room` under its new name.
+    pub fn rename_room(&mut self, room: &str, new_name: &str) {
+        for old in self.sensors_in(room) {
+            let new = device_key(new_name, &old.1);
+            self.rename(old, new);
+        }
+    }
+
+    /// Evaluates a new reading taken at `at`. Readings of unconfigured devices are ignored.
+    /// Returns the alarm changes caused by this reading.
+    pub fn record(
+        &mut self,
+        room: &str,
+        device: &str,
+        temperature: Temperature,
+        at: SystemTime,
+    ) -> Vec<AlarmEvent> {
+        let (room, name) = device_key(room, device);
+        let sensor = match self.sensors.get_mut(&(room.clone(), name.clone())) {
+            Some(s) => s,
+            None => return Vec::new(),
+        };
+        let celsius = temperature.as_celsius_f32();
+        let config = &sensor.config;
+        let hysteresis = config.hysteresis;
+        let mut checks: Vec<(AlarmKind, Check)> = Vec::new();
+
+        if let Some((limit, severity)) = config.low {
+            let check = if celsius < limit {
+                Check::Fire(severity, celsius)
+            } else if celsius >= limit + hysteresis {
+                Check::Clear
+            } else {
+                Check::Keep
+            };
+            checks.push((AlarmKind::Low, check));
+        }
+        if let Some((limit, severity)) = config.high {
+            let check = if celsius > limit {
+                Check::Fire(severity, celsius)
+            } else if celsius <= limit - hysteresis {
+                Check::Clear
+            } else {
+                Check::Keep
+            };
+            checks.push((AlarmKind::High, check));
+        }
+        if let (Some((limit, severity)), Some((prev, prev_at))) =
+            (config.max_rate, sensor.last_reading)
+        {
+            let minutes = at.duration_since(prev_at).unwrap_or_default().as_secs_f32() / 60.;
+            if minutes > 0. {
+                let rate = (celsius - prev).abs() / minutes;
+                let check = if rate > limit {
+                    Check::Fire(severity, rate)
+                } else {
+                    Check::Clear
+                };
+                checks.push((AlarmKind::RateOfChange, check));
+            }
+        }
+        // any reading means the sensor is alive again
+        checks.push((AlarmKind::SensorStale, Check::Clear));
+        sensor.last_reading = Some((celsius, at));
+
+        let mut events = Vec::new();
+        for (kind, check) in checks {
+            match check {
+                Check::Fire(severity, value) if !sensor.active.contains_key(&kind) => {
+                    let alarm = Alarm {
+                        room: room.clone(),
+                        device: name.clone(),
+                        kind,
+                        severity,
+                        value: Some(value),
+                        raised_at: at,
+                        cleared_at: None,
+                    };
+                    sensor.active.insert(kind, alarm.clone());
+                    events.push(AlarmEvent::Raised(alarm));
+                }
+                Check::Clear => {
+                    if let Some(mut alarm) = sensor.active.remove(&kind) {
+                        alarm.cleared_at = Some(at);
+                        events.push(AlarmEvent::Cleared(alarm));
+                    }
+                }
+                _ => {}
+            }
+        }
+        self.emit(&events);
+        events
+    }
+
+    /// Raises stale alarms for configured sensors that did not report within their timeout.
+    /// A sensor that never reported is measured from the moment it was first checked.
+    pub fn check_stale(&mut self, now: SystemTime) -> Vec<AlarmEvent> {
+        let mut events = Vec::new();
+        for ((room, name), sensor) in self.sensors.iter_mut() {
+            let (timeout, severity) = match sensor.config.stale_after {
+                Some(stale) => stale,
+                None => continue,
+            };
+            let last_seen = match sensor
+                .last_reading
+                .map(|(_, at)| at)
+                .or(sensor.watched_since)
+            {
+                Some(at) => at,
+                None => {
+                    sensor.watched_since = Some(now);
+                    continue;
+                }
+            };
+            let silent = now.duration_since(last_seen).unwrap_or_default();
+            if silent > timeout && !sensor.active.contains_key(&AlarmKind::SensorStale) {
+                let alarm = Alarm {
+                    room: room.clone(),
+                    device: name.clone(),
+                    kind: AlarmKind::SensorStale,
+                    severity,
+                    value: None,
+                    raised_at: now,
+                    cleared_at: None,
+                };
+                sensor.active.insert(AlarmKind::SensorStale, alarm.clone());
+                events.push(AlarmEvent::Raised(alarm));
+            }
+        }
+        self.emit(&events);
+        events
+    }
+
+    /// Records current readings of every configured thermometer in the list.
+    pub fn scan(&mut self, devices: &SmartDeviceList, at: SystemTime) -> Vec<AlarmEvent> {
+        let readings = devices.map(|room, device| match device {
+            SmartDevice::Thermo(t) => Some((room.to_owned(), t.name.clone(), t.get_temperature())),
+            _ => None,
+        });
+        readings
+            .into_iter()
+            .flatten()
+            .flat_map(|(room, name, temperature)| self.record(&room, &name, temperature, at))
+            .collect()
+    }
+
+    /// Currently raised alarms, most severe first.
+    pub fn active_alarms(&self) -> Vec<Alarm> {
+        let mut alarms: Vec<Alarm> = self
+            .sensors
+            .values()
+            .flat_map(|s| s.active.values().cloned())
+            .collect();
+        alarms.sort_by(|a, b| {
+            b.severity
+                .cmp(&a.severity)
+                .then_with(|| a.raised_at.cmp(&b.raised_at))
+                .then_with(|| (&a.room, &a.device).cmp(&(&b.room, &b.device)))
+        });
+        alarms
+    }
+    pub fn active_alarms_for(&self, room: &str, device: &str) -> Vec<Alarm> {
+        let (room, device) = device_key(room, device);
+        self.active_alarms()
+            .into_iter()
+            .filter(|a| a.room == room && a.device == device)
+            .collect()
+    }
+    /// The last cleared alarms in the order they were cleared.
+    pub fn history(&self) -> &VecDeque<Alarm> {
+        &self.history
+    }
+
+    fn sensors_in(&self, room: &str) -> Vec<DeviceKey> {
+        let room = room.to_lowercase();
+        self.sensors
+            .keys()
+            .filter(|(r, _)| *r == room)
+            .cloned()
+            .collect()
+    }
+    fn rename(&mut self, old: DeviceKey, new: DeviceKey) {
+        if let Some(mut sensor) = self.sensors.remove(&old) {
+            for alarm in sensor.active.values_mut() {
+                alarm.room = new.0.clone();
+                alarm.device = new.1.clone();
+            }
+            self.sensors.insert(new, sensor);
+        }
+    }
+    fn emit(&mut self, events: &[AlarmEvent]) {
+        for event in events {
+            if let AlarmEvent::Cleared(alarm) = event {
+                self.history.push_back(alarm.clone());
+                while self.history.len() > self.history_limit {
+                    self.history.pop_front();
+                }
+            }
+            self.events.publish(DeviceEvent::Alarm(event.clone()));
+        }
+    }
+}
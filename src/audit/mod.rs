@@ -0,0 +1,241 @@
+//! Audit log of executed device commands.
+//!
+//! File logs are append-only JSON lines, one `AuditRecord` per line. With rotation
+//! enabled a full log is renamed to `<path>.1` (older ones shift to `<path>.2`, ...)
+//! and a new file is started; queries read the rotated files as well.
+
+use crate::{CustomError, CustomResult, DeviceCommand, ExecutionResult};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditRecord {
+    pub time: SystemTime,
+    /// `None` for commands issued in-process, e.g. by the local CLI
+    pub principal: Option<String>,
+    /// `None` when the device was not found
+    pub room: Option<String>,
+    pub device: String,
+    pub command: DeviceCommand,
+    pub result: ExecutionResult,
+}
+
+impl AuditRecord {
+    pub fn is_error(&self) -> bool {
+        matches!(self.result, ExecutionResult::Error(_))
+    }
+}
+
+/// Filters for `AuditLog::query`, combined with logical AND.
+#[derive(Debug, Clone, Default)]
+pub struct AuditQuery {
+    device: Option<String>,
+    principal: Option<String>,
+    since: Option<SystemTime>,
+    until: Option<SystemTime>,
+}
+
+impl AuditQuery {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// device names are case insensitive
+    pub fn device(mut self, device: &str) -> Self {
+        self.device = Some(device.to_lowercase());
+        self
+    }
+    pub fn principal(mut self, principal: &str) -> Self {
+        self.principal = Some(principal.to_owned());
+        self
+    }
+    /// records at or after `time`
+    pub fn since(mut self, time: SystemTime) -> Self {
+        self.since = Some(time);
+        self
+    }
+    /// records before `time`
+    pub fn until(mut self, time: SystemTime) -> Self {
+        self.until = Some(time);
+        self
+    }
+    pub fn matches(&self, record: &AuditRecord) -> bool {
+        self.device
+            .as_ref()
+            .is_none_or(|d| record.device.to_lowercase() == *d)
+            && self
+                .principal
+                .as_ref()
+                .is_none_or(|p| record.principal.as_ref() == Some(p))
+            && self.since.is_none_or(|t| record.time >= t)
+            && self.until.is_none_or(|t| record.time < t)
+    }
+}
+
+#[derive(Debug)]
+enum Store {
+    Memory(Vec<AuditRecord>),
+    File {
+        path: PathBuf,
+        file: File,
+        size: u64,
+        /// rotate once the file reaches `max_bytes`, keeping `keep` old files
+        rotation: Option<(u64, usize)>,
+    },
+}
+
+/// Shared handle to an audit log; clones write to the same log.
+#[derive(Debug, Clone)]
+pub struct AuditLog(Arc<Mutex<Store>>);
+
+fn storage_error(path: &Path, e: impl std::fmt::Display) -> CustomError {
+    CustomError::StorageError(format!("{}: {}", path.display(), e))
+}
+
+impl AuditLog {
+    /// Keeps records in memory only, mostly useful for tests.
+    pub fn in_memory() -> Self {
+        Self(Arc::new(Mutex::new(Store::Memory(Vec::new()))))
+    }
+    /// Appends to the file at `path`, creating it if needed.
+    pub fn open<P: AsRef<Path>>(path: P) -> CustomResult<Self> {
+        let path = path.as_ref().to_owned();
+        let (file, size) = open_append(&path)?;
+        Ok(Self(Arc::new(Mutex::new(Store::File {
+            path,
+            file,
+            size,
+            rotation: None,
+        }))))
+    }
+    /// Rotates the file before a record would grow it past `max_bytes`,
+    /// keeping at most `keep` rotated files. No effect on in-memory logs.
+    pub fn with_rotation(self, max_bytes: u64, keep: usize) -> Self {
+        if let Store::File { rotation, .. } = &mut *self.0.lock().unwrap() {
+            *rotation = Some((max_bytes, keep));
+        }
+        self
+    }
+
+    pub fn record(&self, record: &AuditRecord) -> CustomResult<()> {
+        let mut store = self.0.lock().unwrap();
+        match &mut *store {
+            Store::Memory(records) => {
+                records.push(record.clone());
+                Ok(())
+            }
+            Store::File {
+                path,
+                file,
+                size,
+                rotation,
+            } => {
+                let mut line = serde_json::to_string(record)
+                    .map_err(|e| CustomError::StorageError(e.to_string()))?;
+                line.push('\n');
+                if let Some((max_bytes, keep)) = *rotation {
+                    if *size > 0 && *size + line.len() as u64 > max_bytes {
+                        (*file, *size) = rotate(path, keep)?;
+                    }
+                }
+                file.write_all(line.as_bytes())
+                    .and_then(|_| file.flush())
+                    .map_err(|e| storage_error(path, e))?;
+                *size += line.len() as u64;
+                Ok(())
+            }
+        }
+    }
+
+    /// Starts a new file now, regardless of its size. Without rotation
+    /// settings one old file is kept.
+    pub fn rotate(&self) -> CustomResult<()> {
+        if let Store::File {
+            path,
+            file,
+            size,
+            rotation,
+        } = &mut *self.0.lock().unwrap()
+        {
+            let keep = rotation.map_or(1, |(_, keep)| keep);
+            (*file, *size) = rotate(path, keep)?;
+        }
+        Ok(())
+    }
+
+    /// Matching records, oldest first.
+    pub fn query(&self, query: &AuditQuery) -> CustomResult<Vec<AuditRecord>> {
+        let store = self.0.lock().unwrap();
+        match &*store {
+            Store::Memory(records) => Ok(records
+                .iter()
+                .filter(|r| query.matches(r))
+                .cloned()
+                .collect()),
+            Store::File { path, .. } => {
+                // rotated files may come from runs with other rotation settings
+                let rotated_files = (1..).take_while(|&n| rotated(path, n).exists()).count();
+                let mut records = Vec::new();
+                for path in (1..=rotated_files)
+                    .rev()
+                    .map(|n| rotated(path, n))
+                    .chain([path.clone()])
+                {
+                    read_records(&path, query, &mut records)?;
+                }
+                Ok(records)
+            }
+        }
+    }
+}
+
+fn open_append(path: &Path) -> CustomResult<(File, u64)> {
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .map_err(|e| storage_error(path, e))?;
+    let size = file.metadata().map_err(|e| storage_error(path, e))?.len();
+    Ok((file, size))
+}
+
+fn rotated(path: &Path, n: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{}", n));
+    PathBuf::from(name)
+}
+
+/// Shifts `path.{n}` to `path.{n+1}`, dropping the oldest, and reopens `path` empty.
+fn rotate(path: &Path, keep: usize) -> CustomResult<(File, u64)> {
+    if keep == 0 {
+        fs::remove_file(path).map_err(|e| storage_error(path, e))?;
+        return open_append(path);
+    }
+    fs::remove_file(rotated(path, keep)).ok();
+    for n in (1..keep).rev() {
+        fs::rename(rotated(path, n), rotated(path, n + 1)).ok();
+    }
+    fs::rename(path, rotated(path, 1)).map_err(|e| storage_error(path, e))?;
+    open_append(path)
+}
+
+fn read_records(path: &Path, query: &AuditQuery, out: &mut Vec<AuditRecord>) -> CustomResult<()> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(storage_error(path, e)),
+    };
+    for line in BufReader::new(file).lines() {
+        let line = line.map_err(|e| storage_error(path, e))?;
+        // a line cut short by a crash is skipped instead of hiding the rest of the log
+        if let Ok(record) = serde_json::from_str::<AuditRecord>(&line) {
+            if query.matches(&record) {
+                out.push(record);
+            }
+        }
+    }
+    Ok(())
+}
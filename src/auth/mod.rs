@@ -0,0 +1,320 @@
+//! Authentication of remote clients and per-room permissions.
+//!
+//! An `Authenticator` maps pre-shared tokens and user passwords to `Principal`s.
+//! Its JSON form is used for `--auth` files:
+//!
+//! ```json
+//! {
+//!   "tokens": [{"token": "s3cret", "name": "guest", "permissions": [{"room": "*", "actions": ["read"]}]}],
+//!   "users": [{"user": "alice", "password_hash": "pbkdf2-sha256$100000$...", "permissions": [{"room": "*", "actions": ["read", "control", "configure"]}]}]
+//! }
+//! ```
+//!
+//! Passwords are stored as salted PBKDF2 hashes made by `hash_password`
+//! (`smart-house hash-password` on the command line).
+
+use crate::{CustomError, CustomResult, DeviceCommand, PowerSocketCommand, ThermometerCommand};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use ring::rand::{SecureRandom, SystemRandom};
+use ring::{digest, pbkdf2};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs;
+use std::num::NonZeroU32;
+use std::path::Path;
+
+/// Room name granting a permission in every room.
+pub const ANY_ROOM: &str = "*";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    /// listing devices, reading states and watching changes
+    Read,
+    /// commands that change a device state
+    Control,
+    /// adding and removing rooms and devices
+    Configure,
+}
+
+impl Action {
+    pub fn of(command: &DeviceCommand) -> Self {
+        match command {
+            DeviceCommand::PowerSocket(PowerSocketCommand::GetState)
+            | DeviceCommand::Thermometer(ThermometerCommand::GetTemperature) => Action::Read,
+            // settings of the device itself
+            DeviceCommand::PowerSocket(
+                PowerSocketCommand::SetPowerLimit(_) | PowerSocketCommand::SetDescription(_),
+            )
+            | DeviceCommand::Thermometer(_) => Action::Configure,
+            DeviceCommand::PowerSocket(_) => Action::Control,
+        }
+    }
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Action::Read => "read",
+            Action::Control => "control",
+            Action::Configure => "configure",
+        };
+        f.write_str(name)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Permission {
+    /// room name or `ANY_ROOM`
+    pub room: String,
+    pub actions: Vec<Action>,
+}
+
+/// Authenticated identity with the permissions granted to it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Principal {
+    pub name: String,
+    #[serde(default)]
+    pub permissions: Vec<Permission>,
+}
+
+impl Principal {
+    /// A principal without any permission.
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_owned(),
+            permissions: Vec::new(),
+        }
+    }
+    /// A principal allowed to do anything, used when a server has no authenticator.
+    pub fn admin(name: &str) -> Self {
+        Self::new(name).allow(
+            ANY_ROOM,
+            &[Action::Read, Action::Control, Action::Configure],
+        )
+    }
+    /// Grants `actions` in `room` (case insensitive) or in every room for `ANY_ROOM`.
+    pub fn allow(mut self, room: &str, actions: &[Action]) -> Self {
+        self.permissions.push(Permission {
+            room: room.to_owned(),
+            actions: actions.to_vec(),
+        });
+        self
+    }
+    pub fn can(&self, action: Action, room: &str) -> bool {
+        self.permissions.iter().any(|p| {
+            (p.room == ANY_ROOM || p.room.eq_ignore_ascii_case(room)) && p.actions.contains(&action)
+        })
+    }
+    pub fn check(&self, action: Action, room: &str) -> CustomResult<()> {
+        match self.can(action, room) {
+            true => Ok(()),
+            false => Err(CustomError::PermissionDenied(format!(
+                "{} may not {} in room {}",
+                self.name, action, room
+            ))),
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Credentials {
+    Token(String),
+    Password { user: String, password: String },
+}
+
+impl fmt::Debug for Credentials {
+    /// Secrets are never printed.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Credentials::Token(_) => f.write_str("Token(***)"),
+            Credentials::Password { user, .. } => write!(f, "Password {{ user: {:?} }}", user),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct TokenEntry {
+    token: String,
+    #[serde(flatten)]
+    principal: Principal,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct UserEntry {
+    user: String,
+    /// see `hash_password`
+    password_hash: String,
+    permissions: Vec<Permission>,
+}
+
+const HASH_SCHEME: &str = "pbkdf2-sha256";
+const HASH_ITERATIONS: u32 = 100_000;
+const SALT_LEN: usize = 16;
+
+/// Salted hash of `password` for the `password_hash` of auth file users,
+/// formatted as `pbkdf2-sha256$<iterations>$<salt>$<hash>` in base64.
+pub fn hash_password(password: &str) -> String {
+    let mut salt = [0u8; SALT_LEN];
+    SystemRandom::new()
+        .fill(&mut salt)
+        .expect("system random source unavailable");
+    let iterations = NonZeroU32::new(HASH_ITERATIONS).unwrap();
+    let mut hash = [0u8; digest::SHA256_OUTPUT_LEN];
+    pbkdf2::derive(
+        pbkdf2::PBKDF2_HMAC_SHA256,
+        iterations,
+        &salt,
+        password.as_bytes(),
+        &mut hash,
+    );
+    format!(
+        "{}${}${}${}",
+        HASH_SCHEME,
+        iterations,
+        BASE64.encode(salt),
+        BASE64.encode(hash)
+    )
+}
+
+/// Checks `password` against a `hash_password` hash in constant time.
+/// Malformed hashes match no password.
+fn verify_password(hash: &str, password: &str) -> bool {
+    let parts: Vec<&str> = hash.split('$').collect();
+    let (iterations, salt, hash) = match parts.as_slice() {
+        [HASH_SCHEME, iterations, salt, hash] => (iterations, salt, hash),
+        _ => return false,
+    };
+    let iterations = match iterations.parse().ok().and_then(NonZeroU32::new) {
+        Some(iterations) => iterations,
+        None => return false,
+    };
+    match (BASE64.decode(salt), BASE64.decode(hash)) {
+        (Ok(salt), Ok(hash)) => pbkdf2::verify(
+            pbkdf2::PBKDF2_HMAC_SHA256,
+            iterations,
+            &salt,
+            password.as_bytes(),
+            &hash,
+        )
+        .is_ok(),
+        _ => false,
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Authenticator {
+    #[serde(default)]
+    tokens: Vec<TokenEntry>,
+    #[serde(default)]
+    users: Vec<UserEntry>,
+}
+
+impl Authenticator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn with_token(mut self, token: &str, principal: Principal) -> Self {
+        self.tokens.push(TokenEntry {
+            token: token.to_owned(),
+            principal,
+        });
+        self
+    }
+    /// The principal is named after the user. Only a hash of the password is kept.
+    pub fn with_user(mut self, user: &str, password: &str, permissions: Vec<Permission>) -> Self {
+        self.users.push(UserEntry {
+            user: user.to_owned(),
+            password_hash: hash_password(password),
+            permissions,
+        });
+        self
+    }
+    pub fn authenticate(&self, credentials: &Credentials) -> CustomResult<Principal> {
+        let principal = match credentials {
+            Credentials::Token(token) => self
+                .tokens
+                .iter()
+                .find(|e| constant_time_eq(&e.token, token))
+                .map(|e| e.principal.clone()),
+            Credentials::Password { user, password } => self
+                .users
+                .iter()
+                .find(|e| e.user == *user)
+                .filter(|e| verify_password(&e.password_hash, password))
+                .map(|e| Principal {
+                    name: e.user.clone(),
+                    permissions: e.permissions.clone(),
+                }),
+        };
+        principal.ok_or_else(|| CustomError::AuthenticationFailed("invalid credentials".into()))
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> CustomResult<Self> {
+        let data = fs::read_to_string(path.as_ref()).map_err(|e| {
+            CustomError::StorageError(format!("cannot read {}: {}", path.as_ref().display(), e))
+        })?;
+        serde_json::from_str(&data).map_err(|e| CustomError::StorageError(e.to_string()))
+    }
+}
+
+/// Compares secrets without leaking the position of the first difference.
+fn constant_time_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |acc, (x, y)| acc | (x ^ y))
+            == 0
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn auth_file_format() {
+        let auth: Authenticator = serde_json::from_str(&format!(
+            r#"{{
+                "tokens": [{{"token": "t1", "name": "guest", "permissions": [{{"room": "*", "actions": ["read"]}}]}}],
+                "users": [{{"user": "alice", "password_hash": "{}", "permissions": [{{"room": "Hall", "actions": ["control"]}}]}}]
+            }}"#,
+            hash_password("pw")
+        ))
+        .unwrap();
+        let guest = auth.authenticate(&Credentials::Token("t1".into())).unwrap();
+        assert_eq!(guest.name, "guest");
+        assert!(guest.can(Action::Read, "kitchen"));
+        assert!(!guest.can(Action::Control, "kitchen"));
+
+        let alice = auth
+            .authenticate(&Credentials::Password {
+                user: "alice".into(),
+                password: "pw".into(),
+            })
+            .unwrap();
+        assert!(alice.can(Action::Control, "hall"));
+        assert!(!alice.can(Action::Control, "kitchen"));
+        assert!(auth.authenticate(&Credentials::Token("t2".into())).is_err());
+    }
+
+    #[test]
+    fn passwords_are_stored_salted() {
+        let (first, second) = (hash_password("pw"), hash_password("pw"));
+        assert!(first.starts_with("pbkdf2-sha256$100000$"));
+        assert_ne!(first, second);
+        assert!(verify_password(&first, "pw") && verify_password(&second, "pw"));
+        assert!(!verify_password(&first, "pW"));
+        assert!(!verify_password("pw", "pw"));
+        let auth = Authenticator::new().with_user("alice", "pw", Vec::new());
+        assert!(!serde_json::to_string(&auth).unwrap().contains("\"pw\""));
+    }
+
+    #[test]
+    fn credentials_debug_hides_secrets() {
+        let credentials = Credentials::Password {
+            user: "alice".into(),
+            password: "pw".into(),
+        };
+        assert!(!format!("{:?}", credentials).contains("pw"));
+    }
+}
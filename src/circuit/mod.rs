@@ -0,0 +1,163 @@
+use serde::{Deserialize, Serialize};
+
+/// Socket attached to a circuit. Sockets with lower priority are shed first.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CircuitMember {
+    pub room: String,
+    pub device: String,
+    pub priority: u8,
+}
+
+/// Group of power sockets sharing one breaker.
+/// Limits are enforced for commands executed through `SmartDeviceList`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Circuit {
+    name: String,
+    max_watts: u32,
+    members: Vec<CircuitMember>,
+    shed_on_overload: bool,
+}
+
+impl Circuit {
+    pub fn new(name: &str, max_watts: u32) -> Self {
+        Self {
+            name: name.to_owned(),
+            max_watts,
+            members: Vec::new(),
+            shed_on_overload: false,
+        }
+    }
+    /// room and device names are case insensitive
+    pub fn with_socket(mut self, room: &str, device: &str, priority: u8) -> Self {
+        self.members.push(CircuitMember {
+            room: room.to_lowercase(),
+            device: device.to_lowercase(),
+            priority,
+        });
+        self
+    }
+    /// Instead of refusing to turn a socket on, turn off lower priority sockets
+    /// until the new load fits under the limit.
+    pub fn shed_on_overload(mut self) -> Self {
+        self.shed_on_overload = true;
+        self
+    }
+    pub fn get_name(&self) -> &str {
+        &self.name
+    }
+    pub fn get_max_watts(&self) -> u32 {
+        self.max_watts
+    }
+    pub fn get_members(&self) -> &[CircuitMember] {
+        &self.members
+    }
+    pub fn sheds_on_overload(&self) -> bool {
+        self.shed_on_overload
+    }
+    pub fn contains(&self, room: &str, device: &str) -> bool {
+        self.member(room, device).is_some()
+    }
+    pub fn member(&self, room: &str, device: &str) -> Option<&CircuitMember> {
+        self.members.iter().find(|m| m.is(room, device))
+    }
+    /// Keeps the members of a renamed room on the circuit.
+    pub(crate) fn rename_room(&mut self, room: &str, new_name: &str) {
+        for member in self.members.iter_mut() {
+            if member.room == room.to_lowercase() {
+                member.room = new_name.to_lowercase();
+            }
+        }
+    }
+
+    /// Drops a removed socket, a new one of the same name does not join in its place.
+    pub(crate) fn remove_member(&mut self, room: &str, device: &str) {
+        self.members.retain(|m| !m.is(room, device));
+    }
+    /// Drops the sockets of a removed room, see `remove_member`.
+    pub(crate) fn remove_room(&mut self, room: &str) {
+        self.members.retain(|m| m.room != room.to_lowercase());
+    }
+
+    /// Decides which sockets to turn off so that `member` can be powered with `load` watts.
+    /// `powered` holds the members that are currently on with their consumption.
+    /// Returns `None` if the load cannot fit.
+    pub(crate) fn plan_turn_on(
+        &self,
+        member: &CircuitMember,
+        load: u32,
+        powered: &[(CircuitMember, u32)],
+    ) -> Option<Vec<CircuitMember>> {
+        let current: u32 = powered
+            .iter()
+            .filter(|(m, _)| m != member)
+            .map(|(_, w)| w)
+            .sum();
+        let mut total = current + load;
+        if total <= self.max_watts {
+            return Some(Vec::new());
+        }
+        if !self.shed_on_overload {
+            return None;
+        }
+        let mut candidates: Vec<&(CircuitMember, u32)> = powered
+            .iter()
+            .filter(|(m, _)| m != member && m.priority < member.priority)
+            .collect();
+        candidates.sort_by_key(|(m, _)| m.priority);
+        let mut shed = Vec::new();
+        for (member, watts) in candidates {
+            if total <= self.max_watts {
+                break;
+            }
+            total -= watts;
+            shed.push(member.clone());
+        }
+        (total <= self.max_watts).then_some(shed)
+    }
+}
+
+impl CircuitMember {
+    /// room and device names are case insensitive
+    pub fn is(&self, room: &str, device: &str) -> bool {
+        self.room == room.to_lowercase() && self.device == device.to_lowercase()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn powered(circuit: &Circuit, devices: &[(&str, u32)]) -> Vec<(CircuitMember, u32)> {
+        devices
+            .iter()
+            .map(|(d, w)| (member(circuit, d).clone(), *w))
+            .collect()
+    }
+
+    fn member<'a>(circuit: &'a Circuit, device: &str) -> &'a CircuitMember {
+        circuit.member("kitchen", device).unwrap()
+    }
+
+    #[test]
+    fn plan_sheds_lowest_priority_first() {
+        let circuit = Circuit::new("kitchen", 3000)
+            .with_socket("kitchen", "kettle", 5)
+            .with_socket("kitchen", "heater", 1)
+            .with_socket("kitchen", "lamp", 2)
+            .with_socket("kitchen", "oven", 9)
+            .shed_on_overload();
+        let on = powered(
+            &circuit,
+            &[("heater", 1500), ("lamp", 100), ("kettle", 1000)],
+        );
+        let oven = member(&circuit, "oven");
+        assert_eq!(
+            circuit.plan_turn_on(oven, 1900, &on),
+            Some(vec![member(&circuit, "heater").clone()])
+        );
+        assert_eq!(circuit.plan_turn_on(oven, 400, &on), Some(vec![]));
+        // cannot shed higher priority sockets
+        let lamp = member(&circuit, "lamp");
+        assert_eq!(circuit.plan_turn_on(lamp, 2500, &on[2..]), None);
+    }
+}
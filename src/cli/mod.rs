@@ -0,0 +1,828 @@
+use crate::control::{ConnectOptions, ControlClient, ControlServer};
+use crate::http::{HttpServer, RestApi};
+use crate::mqtt::MqttBridge;
+use crate::repl::Shell;
+use crate::storage::HouseFile;
+use crate::{
+    hash_password, AuditLog, AuditQuery, AuditRecord, Authenticator, Availability, Command,
+    CommandData, ConfigChange, ConfigHistory, Credentials, CustomError, CustomResult,
+    DeviceCommand, ExecutionResult, PowerSocket, PowerSocketCommand, ReportEntry, SmartDevice,
+    SmartDeviceList, SmartHouse, Temperature, TemperatureUnit, Thermometer, TlsClientConfig,
+    TlsServerConfig,
+};
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+pub const DEFAULT_HOUSE_FILE: &str = "house.json";
+/// environment variable holding the password of `--user` and `hash-password`,
+/// so that it does not show up in the process list
+pub const PASSWORD_VAR: &str = "SMART_HOUSE_PASSWORD";
+/// how often servers check for due socket timers
+const TIMER_INTERVAL: Duration = Duration::from_secs(1);
+
+pub const USAGE: &str = "\
+usage: smart-house [--file <path>] [--remote <addr>] [--token <token> | --user <name>]
+                   [--tls-ca <pem>] [--tls-cert <pem> --tls-key <pem>] <command>
+
+commands:
+  init                                        create an empty house file
+  room add <name>                             add a room
+  room remove <name>                          remove a room with its devices
+  room rename <name> <new name>               rename a room
+  room list                                   list rooms
+  device add <room> socket <name> [--power <watts>] [--description <text>]
+  device add <room> thermometer <name> [--temperature <value, e.g. 21.5C>]
+  device remove <room> <name>                 remove a device
+  device rename <room> <name> <new name>      rename a device
+  device describe <room>/<device> [--format table|json]
+                                              show the commands and readings of a device
+  undo                                        revert the last room or device change
+  redo                                        apply the last undone change again
+  report [--format table|json] [--unit c|f|k] print the house report
+  socket on|off|state|cancel <room>/<device>  control a power socket
+  socket on_for|off_in|on_in <room>/<device> <duration, e.g. 45m>
+                                              switch a socket by timer
+  serve <addr> [--auth <file>] [--audit <file>]
+                                              run a control server for the house file
+  http <addr> [--auth <file>] [--audit <file>]
+                                              serve the house file over a REST API
+  audit <file> [--device <name>] [--principal <name>] [--since <unix time>] [--until <unix time>]
+                                              show commands recorded in an audit log
+  mqtt <broker addr> [--prefix <topic>] [--auth <file>]
+                                              bridge the house file to an MQTT broker
+  hash-password                               print the password hash for an auth file
+  shell                                       interactive shell, requires --remote
+
+with --remote, socket and describe commands are sent to the control server at <addr>,
+authenticated with --token or --user when given. The password of --user and
+hash-password is read from the SMART_HOUSE_PASSWORD environment variable.
+Servers started with --auth only accept clients listed in the auth file, --audit
+appends every executed command to the given file. mqtt connects to the broker
+with --user and, given --auth, runs commands with that user's permissions;
+the user may not control or configure every room.
+
+with --tls-ca, remote clients connect over TLS and trust servers signed by that CA,
+presenting --tls-cert/--tls-key to servers requiring client certificates.
+serve encrypts with --tls-cert/--tls-key and, given --tls-ca, only accepts clients
+with a certificate signed by it";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReportFormat {
+    Table,
+    Json,
+}
+
+#[derive(Debug)]
+pub enum CliCommand {
+    Help,
+    Init,
+    AddRoom(String),
+    RemoveRoom(String),
+    RenameRoom {
+        room: String,
+        to: String,
+    },
+    ListRooms,
+    AddDevice {
+        room: String,
+        device: SmartDevice,
+    },
+    RemoveDevice {
+        room: String,
+        device: String,
+    },
+    RenameDevice {
+        room: String,
+        device: String,
+        to: String,
+    },
+    DescribeDevice {
+        room: String,
+        device: String,
+        format: ReportFormat,
+    },
+    Undo,
+    Redo,
+    Report {
+        format: ReportFormat,
+        unit: Option<TemperatureUnit>,
+    },
+    Socket {
+        room: String,
+        device: String,
+        command: PowerSocketCommand,
+    },
+    Serve {
+        addr: String,
+        auth: Option<PathBuf>,
+        audit: Option<PathBuf>,
+    },
+    Http {
+        addr: String,
+        auth: Option<PathBuf>,
+        audit: Option<PathBuf>,
+    },
+    Audit {
+        log: PathBuf,
+        query: AuditQuery,
+    },
+    Mqtt {
+        broker: String,
+        prefix: Option<String>,
+        auth: Option<PathBuf>,
+    },
+    HashPassword,
+    Shell,
+}
+
+/// Parsed command line of the `smart-house` tool.
+#[derive(Debug)]
+pub struct Cli {
+    pub file: PathBuf,
+    pub remote: Option<String>,
+    pub credentials: Option<Credentials>,
+    pub tls_ca: Option<PathBuf>,
+    pub tls_cert: Option<PathBuf>,
+    pub tls_key: Option<PathBuf>,
+    pub command: CliCommand,
+}
+
+fn usage_error(msg: &str) -> CustomError {
+    CustomError::ParseError(format!("{}\n\n{}", msg, USAGE))
+}
+
+fn password_from_env() -> CustomResult<String> {
+    std::env::var(PASSWORD_VAR)
+        .map_err(|_| usage_error(&format!("set the password in {}", PASSWORD_VAR)))
+}
+
+impl Cli {
+    /// `args` excludes the program name.
+    pub fn parse<I, S>(args: I) -> CustomResult<Self>
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        let mut file = PathBuf::from(DEFAULT_HOUSE_FILE);
+        let mut remote = None;
+        let mut credentials = None;
+        let (mut tls_ca, mut tls_cert, mut tls_key) = (None, None, None);
+        let mut words = Vec::new();
+        let mut args = args.into_iter().map(Into::into);
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "-f" | "--file" => file = PathBuf::from(next_value(&mut args, &arg)?),
+                "-r" | "--remote" => remote = Some(next_value(&mut args, &arg)?),
+                "--token" => credentials = Some(Credentials::Token(next_value(&mut args, &arg)?)),
+                "--user" => {
+                    credentials = Some(Credentials::Password {
+                        user: next_value(&mut args, &arg)?,
+                        password: password_from_env()?,
+                    });
+                }
+                "--tls-ca" => tls_ca = Some(PathBuf::from(next_value(&mut args, &arg)?)),
+                "--tls-cert" => tls_cert = Some(PathBuf::from(next_value(&mut args, &arg)?)),
+                "--tls-key" => tls_key = Some(PathBuf::from(next_value(&mut args, &arg)?)),
+                "-h" | "--help" => words.push("help".to_owned()),
+                _ => words.push(arg),
+            }
+        }
+        if tls_cert.is_some() != tls_key.is_some() {
+            return Err(usage_error("--tls-cert and --tls-key go together"));
+        }
+        let command = parse_command(&words)?;
+        Ok(Self {
+            file,
+            remote,
+            credentials,
+            tls_ca,
+            tls_cert,
+            tls_key,
+            command,
+        })
+    }
+
+    /// Credentials and TLS settings for connecting to `--remote`.
+    fn connect_options(&self) -> CustomResult<ConnectOptions> {
+        let mut options = ConnectOptions::new();
+        if let Some(credentials) = &self.credentials {
+            options = options.with_credentials(credentials.clone());
+        }
+        if let Some(ca) = &self.tls_ca {
+            let identity = self.tls_cert.as_ref().zip(self.tls_key.as_ref());
+            options = options.with_tls(TlsClientConfig::load(ca, identity)?);
+        }
+        Ok(options)
+    }
+
+    /// TLS settings of `serve`, none without a server certificate.
+    fn server_tls(&self) -> CustomResult<Option<TlsServerConfig>> {
+        match (&self.tls_cert, &self.tls_key) {
+            (Some(cert), Some(key)) => {
+                TlsServerConfig::load(cert, key, self.tls_ca.as_ref()).map(Some)
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Executes the command and returns the text to print. Servers block
+    /// until they stop, use [`Cli::start`] to print their address first.
+    pub fn run(self) -> CustomResult<String> {
+        // errors only matter for the commands using them
+        let connect_options = self.connect_options();
+        match self.command {
+            CliCommand::Help => Ok(USAGE.to_owned()),
+            CliCommand::Init => {
+                if self.file.exists() {
+                    return Err(CustomError::StorageError(format!(
+                        "{} already exists",
+                        self.file.display()
+                    )));
+                }
+                HouseFile::default().save(&self.file)?;
+                Ok(format!("created {}", self.file.display()))
+            }
+            CliCommand::AddRoom(name) => {
+                apply_change(&self.file, ConfigChange::add_room(&name))?;
+                Ok(format!("added room {}", name))
+            }
+            CliCommand::RemoveRoom(name) => {
+                apply_change(&self.file, ConfigChange::RemoveRoom { room: name.clone() })?;
+                Ok(format!("removed room {}", name))
+            }
+            CliCommand::RenameRoom { room, to } => {
+                let change = ConfigChange::RenameRoom {
+                    room: room.clone(),
+                    to: to.clone(),
+                };
+                apply_change(&self.file, change)?;
+                Ok(format!("renamed room {} to {}", room, to))
+            }
+            CliCommand::ListRooms => {
+                let (house, _) = HouseFile::load(&self.file)?.into_house()?;
+                Ok(house.get_rooms().join("\n"))
+            }
+            CliCommand::AddDevice { room, device } => {
+                let name = device.get_name();
+                apply_change(
+                    &self.file,
+                    ConfigChange::AddDevice {
+                        room: room.clone(),
+                        device,
+                    },
+                )?;
+                Ok(format!("added device {} to {}", name, room))
+            }
+            CliCommand::RemoveDevice { room, device } => {
+                let change = ConfigChange::RemoveDevice {
+                    room: room.clone(),
+                    device: device.clone(),
+                };
+                apply_change(&self.file, change)?;
+                Ok(format!("removed device {} from {}", device, room))
+            }
+            CliCommand::RenameDevice { room, device, to } => {
+                let change = ConfigChange::RenameDevice {
+                    room: room.clone(),
+                    device: device.clone(),
+                    to: to.clone(),
+                };
+                apply_change(&self.file, change)?;
+                Ok(format!("renamed device {}/{} to {}", room, device, to))
+            }
+            CliCommand::DescribeDevice {
+                room,
+                device,
+                format,
+            } => {
+                let capabilities = match &self.remote {
+                    Some(addr) => {
+                        let mut client = ControlClient::connect_with(addr, &connect_options?)?;
+                        client.capabilities(Some(&room), &device)?
+                    }
+                    None => {
+                        let (_, devices) = HouseFile::load(&self.file)?.into_house()?;
+                        devices
+                            .snapshot()
+                            .get(&room, &device)
+                            .map(|d| d.capabilities())
+                            .ok_or(CustomError::DeviceNotFound)?
+                    }
+                };
+                match format {
+                    ReportFormat::Table => Ok(capabilities.to_string()),
+                    ReportFormat::Json => serde_json::to_string_pretty(&capabilities)
+                        .map_err(|e| CustomError::ParseError(e.to_string())),
+                }
+            }
+            CliCommand::Undo => {
+                let change = edit_house(&self.file, |house, devices, history| {
+                    history.undo(house, devices)
+                })?;
+                Ok(format!("undo: {}", change))
+            }
+            CliCommand::Redo => {
+                let change = edit_house(&self.file, |house, devices, history| {
+                    history.redo(house, devices)
+                })?;
+                Ok(format!("redo: {}", change))
+            }
+            CliCommand::Report { format, unit } => {
+                let (mut house, devices) = HouseFile::load(&self.file)?.into_house()?;
+                if let Some(unit) = unit {
+                    house.set_temperature_unit(unit);
+                }
+                let entries = house.get_report_entries(&devices);
+                match format {
+                    ReportFormat::Table => Ok(format_table(&entries)),
+                    ReportFormat::Json => serde_json::to_string_pretty(&entries)
+                        .map_err(|e| CustomError::ParseError(e.to_string())),
+                }
+            }
+            CliCommand::Socket {
+                room,
+                device,
+                command,
+            } => {
+                let data = DeviceCommand::PowerSocket(command);
+                let result = match &self.remote {
+                    Some(addr) => {
+                        let mut client = ControlClient::connect_with(addr, &connect_options?)?;
+                        client.send(&Command::ExecuteIn {
+                            room,
+                            command: CommandData {
+                                device_name: device,
+                                data,
+                            },
+                            version: None,
+                        })?
+                    }
+                    None => edit_house(&self.file, |_, devices, _| {
+                        let entry = devices.resolve(Some(&room), &device)?;
+                        // errors leave the file untouched
+                        match devices.execute_command_in(
+                            &entry.room,
+                            CommandData {
+                                device_name: entry.device.get_name(),
+                                data,
+                            },
+                        ) {
+                            ExecutionResult::Error(err) => Err(err),
+                            result => Ok(result),
+                        }
+                    })?,
+                };
+                format_result(result)
+            }
+            command @ (CliCommand::Serve { .. }
+            | CliCommand::Http { .. }
+            | CliCommand::Mqtt { .. }) => match (Cli { command, ..self }).start()? {
+                CliOutput::Text(text) => Ok(text),
+                CliOutput::Server(server) => {
+                    server.run()?;
+                    Ok(String::new())
+                }
+            },
+            CliCommand::Audit { log, query } => {
+                if !log.exists() {
+                    return Err(CustomError::StorageError(format!(
+                        "{} does not exist",
+                        log.display()
+                    )));
+                }
+                let records = AuditLog::open(log)?.query(&query)?;
+                Ok(records
+                    .iter()
+                    .map(format_audit_record)
+                    .collect::<Vec<_>>()
+                    .join("\n"))
+            }
+            CliCommand::HashPassword => Ok(hash_password(&password_from_env()?)),
+            CliCommand::Shell => {
+                let addr = self
+                    .remote
+                    .as_deref()
+                    .ok_or_else(|| usage_error("shell requires --remote <addr>"))?;
+                let history = std::env::var_os("HOME")
+                    .map(|home| PathBuf::from(home).join(".smart_house_history"));
+                Shell::connect_with(addr, connect_options?)?.run(history)?;
+                Ok(String::new())
+            }
+        }
+    }
+
+    /// Starts `serve`, `http` and `mqtt` without blocking, so the caller can
+    /// print where the server listens before running it. Other commands run
+    /// right away and return their text.
+    pub fn start(self) -> CustomResult<CliOutput> {
+        let server_tls = self.server_tls();
+        match self.command {
+            CliCommand::Serve { addr, auth, audit } => {
+                let (_, devices) = HouseFile::load(&self.file)?.into_house()?;
+                if let Some(audit) = audit {
+                    devices.set_audit_log(AuditLog::open(audit)?);
+                }
+                let timers = devices.spawn_timers(TIMER_INTERVAL);
+                let mut server = ControlServer::bind(addr.as_str(), devices)?;
+                if let Some(auth) = auth {
+                    server = server.with_auth(Authenticator::load(auth)?);
+                }
+                if let Some(tls) = server_tls? {
+                    server = server.with_tls(tls);
+                }
+                let banner = format!("listening on {}", server.local_addr()?);
+                Ok(CliServer::output(banner, move || {
+                    let _timers = timers;
+                    server.run();
+                    Ok(())
+                }))
+            }
+            CliCommand::Http { addr, auth, audit } => {
+                let (house, devices) = HouseFile::load(&self.file)?.into_house()?;
+                if let Some(audit) = audit {
+                    devices.set_audit_log(AuditLog::open(audit)?);
+                }
+                let timers = devices.spawn_timers(TIMER_INTERVAL);
+                let mut api = RestApi::new(Arc::new(Mutex::new(house)), devices);
+                if let Some(auth) = auth {
+                    api = api.with_auth(Authenticator::load(auth)?);
+                }
+                let server = HttpServer::bind(addr.as_str(), api)?;
+                let banner = format!("listening on http://{}", server.local_addr()?);
+                Ok(CliServer::output(banner, move || {
+                    let _timers = timers;
+                    server.run();
+                    Ok(())
+                }))
+            }
+            CliCommand::Mqtt {
+                broker,
+                prefix,
+                auth,
+            } => {
+                let (_, devices) = HouseFile::load(&self.file)?.into_house()?;
+                let mut bridge = MqttBridge::new(devices.clone());
+                if let Some(prefix) = prefix {
+                    bridge = bridge.with_prefix(&prefix);
+                }
+                match self.credentials {
+                    Some(Credentials::Password { user, password }) => {
+                        bridge = bridge.with_credentials(&user, &password);
+                    }
+                    Some(Credentials::Token(_)) => {
+                        return Err(usage_error("mqtt takes --user, not --token"));
+                    }
+                    None => {}
+                }
+                if let Some(auth) = auth {
+                    bridge = bridge.with_auth(Authenticator::load(auth)?);
+                }
+                let timers = devices.spawn_timers(TIMER_INTERVAL);
+                let banner = format!("bridging to mqtt://{}", broker);
+                Ok(CliServer::output(banner, move || {
+                    let _timers = timers;
+                    bridge.run(broker.as_str())
+                }))
+            }
+            command => (Cli { command, ..self }).run().map(CliOutput::Text),
+        }
+    }
+}
+
+/// What [`Cli::start`] produced: text to print or a server to run.
+pub enum CliOutput {
+    Text(String),
+    Server(CliServer),
+}
+
+/// A server started from the command line, see [`Cli::start`].
+pub struct CliServer {
+    banner: String,
+    serve: Box<dyn FnOnce() -> CustomResult<()>>,
+}
+
+impl CliServer {
+    fn output(banner: String, serve: impl FnOnce() -> CustomResult<()> + 'static) -> CliOutput {
+        CliOutput::Server(CliServer {
+            banner,
+            serve: Box::new(serve),
+        })
+    }
+
+    /// Where the server listens, e.g. `listening on 127.0.0.1:7878`.
+    pub fn banner(&self) -> &str {
+        &self.banner
+    }
+
+    /// Serves until the server stops.
+    pub fn run(self) -> CustomResult<()> {
+        (self.serve)()
+    }
+}
+
+/// Loads the house file, runs `edit` and saves the result together with the
+/// undo history. The file is left untouched when `edit` fails.
+fn edit_house<T>(
+    file: &Path,
+    edit: impl FnOnce(&mut SmartHouse, &mut SmartDeviceList, &mut ConfigHistory) -> CustomResult<T>,
+) -> CustomResult<T> {
+    let mut house_file = HouseFile::load(file)?;
+    let mut history = std::mem::take(&mut house_file.history);
+    let (mut house, mut devices) = house_file.into_house()?;
+    let result = edit(&mut house, &mut devices, &mut history)?;
+    HouseFile::from_house(&house, &devices)
+        .with_history(history)
+        .save(file)?;
+    Ok(result)
+}
+
+fn apply_change(file: &Path, change: ConfigChange) -> CustomResult<()> {
+    edit_house(file, |house, devices, history| {
+        history.apply(change, house, devices)
+    })
+}
+
+fn next_value(args: &mut impl Iterator<Item = String>, flag: &str) -> CustomResult<String> {
+    args.next()
+        .ok_or_else(|| usage_error(&format!("missing value for {}", flag)))
+}
+
+fn parse_command(words: &[String]) -> CustomResult<CliCommand> {
+    let words: Vec<&str> = words.iter().map(String::as_str).collect();
+    let command = match words.as_slice() {
+        [] | ["help"] => CliCommand::Help,
+        ["init"] => CliCommand::Init,
+        ["room", "add", name] => CliCommand::AddRoom(name.to_string()),
+        ["room", "remove", name] => CliCommand::RemoveRoom(name.to_string()),
+        ["room", "list"] => CliCommand::ListRooms,
+        ["room", "rename", room, to] => CliCommand::RenameRoom {
+            room: room.to_string(),
+            to: to.to_string(),
+        },
+        ["device", "add", room, kind, name, options @ ..] => CliCommand::AddDevice {
+            room: room.to_string(),
+            device: parse_device(kind, name, options)?,
+        },
+        ["device", "remove", room, name] => CliCommand::RemoveDevice {
+            room: room.to_string(),
+            device: name.to_string(),
+        },
+        ["device", "rename", room, name, to] => CliCommand::RenameDevice {
+            room: room.to_string(),
+            device: name.to_string(),
+            to: to.to_string(),
+        },
+        ["device", "describe", target, options @ ..] => {
+            let (room, device) = target
+                .split_once('/')
+                .ok_or_else(|| usage_error("device target must be <room>/<device>"))?;
+            let mut format = ReportFormat::Table;
+            for (key, value) in parse_options(options)? {
+                match (key, value.to_lowercase().as_str()) {
+                    ("--format", "table") => format = ReportFormat::Table,
+                    ("--format", "json") => format = ReportFormat::Json,
+                    _ => return Err(usage_error(&format!("invalid option {} {}", key, value))),
+                }
+            }
+            CliCommand::DescribeDevice {
+                room: room.to_owned(),
+                device: device.to_owned(),
+                format,
+            }
+        }
+        ["undo"] => CliCommand::Undo,
+        ["redo"] => CliCommand::Redo,
+        ["report", options @ ..] => {
+            let mut format = ReportFormat::Table;
+            let mut unit = None;
+            for (key, value) in parse_options(options)? {
+                match (key, value.to_lowercase().as_str()) {
+                    ("--format", "table") => format = ReportFormat::Table,
+                    ("--format", "json") => format = ReportFormat::Json,
+                    ("--unit", "c") => unit = Some(TemperatureUnit::Celsius),
+                    ("--unit", "f") => unit = Some(TemperatureUnit::Fahrenheit),
+                    ("--unit", "k") => unit = Some(TemperatureUnit::Kelvin),
+                    _ => return Err(usage_error(&format!("invalid option {} {}", key, value))),
+                }
+            }
+            CliCommand::Report { format, unit }
+        }
+        ["socket", action, target, duration @ ..] => {
+            let command = match (*action, duration) {
+                ("on", []) => PowerSocketCommand::TurnOn,
+                ("off", []) => PowerSocketCommand::TurnOff,
+                ("state", []) => PowerSocketCommand::GetState,
+                ("cancel", []) => PowerSocketCommand::CancelTimer,
+                ("on_for" | "off_in" | "on_in", [duration]) => {
+                    match format!("socket.{} {}", action, duration).parse()? {
+                        DeviceCommand::PowerSocket(command) => command,
+                        _ => unreachable!("socket commands parse as socket commands"),
+                    }
+                }
+                _ => return Err(usage_error(&format!("unknown socket action {}", action))),
+            };
+            let (room, device) = target
+                .split_once('/')
+                .ok_or_else(|| usage_error("socket target must be <room>/<device>"))?;
+            CliCommand::Socket {
+                room: room.to_owned(),
+                device: device.to_owned(),
+                command,
+            }
+        }
+        ["serve", addr, options @ ..] => {
+            let (auth, audit) = parse_server_options(options)?;
+            CliCommand::Serve {
+                addr: addr.to_string(),
+                auth,
+                audit,
+            }
+        }
+        ["http", addr, options @ ..] => {
+            let (auth, audit) = parse_server_options(options)?;
+            CliCommand::Http {
+                addr: addr.to_string(),
+                auth,
+                audit,
+            }
+        }
+        ["audit", log, options @ ..] => {
+            let mut query = AuditQuery::new();
+            for (key, value) in parse_options(options)? {
+                query = match key {
+                    "--device" => query.device(value),
+                    "--principal" => query.principal(value),
+                    "--since" => query.since(parse_unix_time(value)?),
+                    "--until" => query.until(parse_unix_time(value)?),
+                    _ => return Err(usage_error(&format!("invalid option {} {}", key, value))),
+                };
+            }
+            CliCommand::Audit {
+                log: PathBuf::from(log),
+                query,
+            }
+        }
+        ["mqtt", broker, options @ ..] => {
+            let (mut prefix, mut auth) = (None, None);
+            for (key, value) in parse_options(options)? {
+                match key {
+                    "--prefix" => prefix = Some(value.to_string()),
+                    "--auth" => auth = Some(PathBuf::from(value)),
+                    _ => return Err(usage_error(&format!("invalid option {} {}", key, value))),
+                }
+            }
+            CliCommand::Mqtt {
+                broker: broker.to_string(),
+                prefix,
+                auth,
+            }
+        }
+        ["hash-password"] => CliCommand::HashPassword,
+        ["shell"] => CliCommand::Shell,
+        _ => {
+            return Err(usage_error(&format!(
+                "unknown command: {}",
+                words.join(" ")
+            )))
+        }
+    };
+    Ok(command)
+}
+
+/// `--auth` and `--audit` files of `serve` and `http`
+fn parse_server_options(options: &[&str]) -> CustomResult<(Option<PathBuf>, Option<PathBuf>)> {
+    let (mut auth, mut audit) = (None, None);
+    for (key, value) in parse_options(options)? {
+        match key {
+            "--auth" => auth = Some(PathBuf::from(value)),
+            "--audit" => audit = Some(PathBuf::from(value)),
+            _ => return Err(usage_error(&format!("invalid option {} {}", key, value))),
+        }
+    }
+    Ok((auth, audit))
+}
+
+fn parse_unix_time(value: &str) -> CustomResult<SystemTime> {
+    let secs = value
+        .parse()
+        .map_err(|_| usage_error(&format!("invalid unix time {}", value)))?;
+    Ok(UNIX_EPOCH + Duration::from_secs(secs))
+}
+
+fn parse_options<'a>(options: &[&'a str]) -> CustomResult<Vec<(&'a str, &'a str)>> {
+    if !options.len().is_multiple_of(2) || options.iter().step_by(2).any(|o| !o.starts_with("--")) {
+        return Err(usage_error(&format!(
+            "invalid options: {}",
+            options.join(" ")
+        )));
+    }
+    Ok(options.chunks(2).map(|kv| (kv[0], kv[1])).collect())
+}
+
+fn parse_device(kind: &str, name: &str, options: &[&str]) -> CustomResult<SmartDevice> {
+    let options = parse_options(options)?;
+    match kind {
+        "socket" => {
+            let mut socket = PowerSocket::new(name, 0);
+            for (key, value) in options {
+                match key {
+                    "--power" => {
+                        let watts = value
+                            .parse()
+                            .map_err(|_| usage_error(&format!("invalid power {}", value)))?;
+                        socket.rated_power = Some(watts);
+                    }
+                    "--description" => socket.description = value.to_owned(),
+                    _ => return Err(usage_error(&format!("unknown option {}", key))),
+                }
+            }
+            Ok(SmartDevice::Socket(socket))
+        }
+        "thermometer" => {
+            let mut thermometer = Thermometer::new(name, Temperature::Celsius(0.));
+            for (key, value) in options {
+                match key {
+                    "--temperature" => thermometer.state = value.parse()?,
+                    _ => return Err(usage_error(&format!("unknown option {}", key))),
+                }
+            }
+            Ok(SmartDevice::Thermo(thermometer))
+        }
+        _ => Err(usage_error(&format!("unknown device kind {}", kind))),
+    }
+}
+
+fn format_result(result: ExecutionResult) -> CustomResult<String> {
+    match result {
+        ExecutionResult::Error(err) => Err(err),
+        result => Ok(result.to_string()),
+    }
+}
+
+fn format_audit_record(record: &AuditRecord) -> String {
+    let time = record
+        .time
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    let target = match &record.room {
+        Some(room) => format!("{}/{}", room, record.device),
+        None => record.device.clone(),
+    };
+    let result = match &record.result {
+        ExecutionResult::Error(err) => format!("error: {}", err),
+        result => result.to_string(),
+    };
+    format!(
+        "{} {} {} {:?} -> {}",
+        time,
+        record.principal.as_deref().unwrap_or("-"),
+        target,
+        record.command,
+        result
+    )
+}
+
+fn format_table(entries: &[ReportEntry]) -> String {
+    let rows: Vec<[String; 4]> = entries
+        .iter()
+        .map(|e| match &e.info {
+            Ok(info) => {
+                let state = match info.countdown {
+                    Some(countdown) => format!("{}, {}", info.state, countdown),
+                    None => info.state.clone(),
+                };
+                [
+                    e.room.clone(),
+                    info.name.clone(),
+                    info.kind.clone(),
+                    match info.availability {
+                        Availability::Online => state,
+                        other => format!("{} ({})", state, other),
+                    },
+                ]
+            }
+            Err(err) => [e.room.clone(), e.device.clone(), "-".into(), err.clone()],
+        })
+        .collect();
+    let header = ["ROOM", "DEVICE", "KIND", "STATE"].map(String::from);
+    let mut widths = header.clone().map(|h| h.chars().count());
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+    let mut table = String::new();
+    for row in std::iter::once(&header).chain(&rows) {
+        let line = row
+            .iter()
+            .zip(widths)
+            .map(|(cell, width)| format!("{:width$}", cell, width = width))
+            .collect::<Vec<_>>()
+            .join("  ");
+        writeln!(table, "{}", line.trim_end()).unwrap();
+    }
+    table.pop();
+    table
+}
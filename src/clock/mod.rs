@@ -0,0 +1,51 @@
+//! Sources of the current time, so time dependent behavior can be tested without waiting.
+
+use std::fmt::Debug;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+pub trait Clock: Debug + Send + Sync {
+    fn now(&self) -> SystemTime;
+}
+
+/// The real time.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+/// Time source advanced by hand. Clones share the same time.
+#[derive(Debug, Clone)]
+pub struct VirtualClock {
+    start: SystemTime,
+    elapsed: Arc<Mutex<Duration>>,
+}
+
+impl VirtualClock {
+    pub fn new(start: SystemTime) -> Self {
+        Self {
+            start,
+            elapsed: Arc::default(),
+        }
+    }
+    pub fn now(&self) -> SystemTime {
+        self.start + self.elapsed()
+    }
+    /// time passed since the clock was created
+    pub fn elapsed(&self) -> Duration {
+        *self.elapsed.lock().unwrap()
+    }
+    pub fn advance(&self, duration: Duration) {
+        *self.elapsed.lock().unwrap() += duration;
+    }
+}
+
+impl Clock for VirtualClock {
+    fn now(&self) -> SystemTime {
+        VirtualClock::now(self)
+    }
+}
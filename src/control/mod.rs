@@ -0,0 +1,443 @@
+//! TCP control channel. Every frame is a single line of JSON:
+//! the client sends a `Command`, the server answers with an `ExecutionResult`.
+//! Servers with an `Authenticator` answer every command with an error until the
+//! client sent `Command::Authenticate`, and only show rooms the principal may read.
+//! The channel can be encrypted with TLS, see `ControlServer::with_tls`.
+//! Overlong lines close the connection, as does not authenticating in time.
+
+use crate::deadline::Deadline;
+use crate::{
+    Action, Authenticator, Batch, BatchResult, Capabilities, Command, CommandData, Credentials,
+    CustomError, CustomResult, DeviceEntry, DeviceEvent, DeviceQuery, ExecutionResult, Principal,
+    SmartDeviceList, TlsClientConfig, TlsServerConfig,
+};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// Longest line a client may send, in bytes.
+const MAX_LINE: usize = 64 * 1024;
+/// How long a client may take to authenticate on servers that require it.
+const AUTH_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub struct ControlServer {
+    listener: TcpListener,
+    devices: SmartDeviceList,
+    auth: Option<Arc<Authenticator>>,
+    tls: Option<TlsServerConfig>,
+}
+
+impl ControlServer {
+    pub fn bind<A: ToSocketAddrs>(addr: A, devices: SmartDeviceList) -> CustomResult<Self> {
+        let listener =
+            TcpListener::bind(addr).map_err(|e| CustomError::ConnectionError(e.to_string()))?;
+        Ok(Self {
+            listener,
+            devices,
+            auth: None,
+            tls: None,
+        })
+    }
+    /// Only accepts TLS connections.
+    pub fn with_tls(mut self, tls: TlsServerConfig) -> Self {
+        self.tls = Some(tls);
+        self
+    }
+    /// Requires clients to authenticate and enforces their permissions.
+    pub fn with_auth(mut self, auth: Authenticator) -> Self {
+        self.auth = Some(Arc::new(auth));
+        self
+    }
+    pub fn local_addr(&self) -> CustomResult<SocketAddr> {
+        self.listener
+            .local_addr()
+            .map_err(|e| CustomError::ConnectionError(e.to_string()))
+    }
+    /// Accepts clients forever, serving each one on its own thread.
+    pub fn run(self) {
+        for stream in self.listener.incoming().flatten() {
+            let devices = self.devices.clone();
+            let auth = self.auth.clone();
+            let tls = self.tls.clone();
+            // read timeouts are set on the socket, underneath TLS
+            let socket = match stream.try_clone() {
+                Ok(socket) => socket,
+                Err(_) => continue,
+            };
+            thread::spawn(move || match tls {
+                // the handshake runs on the first read, failures close the connection
+                Some(tls) => {
+                    if let Ok(stream) = tls.accept(stream) {
+                        handle_client(stream, socket, devices, auth);
+                    }
+                }
+                None => handle_client(stream, socket, devices, auth),
+            });
+        }
+    }
+    pub fn spawn(self) -> JoinHandle<()> {
+        thread::spawn(move || self.run())
+    }
+}
+
+fn handle_client<S: Read + Write>(
+    stream: S,
+    socket: TcpStream,
+    devices: SmartDeviceList,
+    auth: Option<Arc<Authenticator>>,
+) {
+    let mut stream = BufReader::new(stream);
+    // without an authenticator everybody may do everything
+    let mut principal = match auth {
+        Some(_) => None,
+        None => Some(Principal::admin("anonymous")),
+    };
+    let auth_deadline = Instant::now() + AUTH_TIMEOUT;
+    let mut line = String::new();
+    loop {
+        line.clear();
+        let read = match principal {
+            Some(_) => read_line(&mut stream, &mut line),
+            None => read_line(
+                &mut Deadline::new(&mut stream, &socket, auth_deadline),
+                &mut line,
+            ),
+        };
+        match read {
+            Ok(0) | Err(_) => return,
+            Ok(n) if n > MAX_LINE => {
+                let err = CustomError::ParseError(format!("line longer than {} bytes", MAX_LINE));
+                write_frame(stream.get_mut(), &ExecutionResult::Error(err)).ok();
+                return;
+            }
+            Ok(_) => {}
+        }
+        let result = match (serde_json::from_str::<Command>(&line), &principal) {
+            (Err(e), _) => ExecutionResult::Error(CustomError::ParseError(e.to_string())),
+            (Ok(Command::Authenticate(credentials)), _) => {
+                match authenticate(auth.as_deref(), &credentials) {
+                    Ok(authenticated) if socket.set_read_timeout(None).is_ok() => {
+                        let name = authenticated.name.clone();
+                        principal = Some(authenticated);
+                        ExecutionResult::Authenticated(name)
+                    }
+                    Ok(_) => return,
+                    Err(err) => ExecutionResult::Error(err),
+                }
+            }
+            (Ok(_), None) => ExecutionResult::Error(CustomError::AuthenticationFailed(
+                "authentication required".into(),
+            )),
+            (Ok(Command::Execute(data)), Some(principal)) => {
+                execute(&devices, principal, None, data, None)
+            }
+            (Ok(Command::ExecuteIf { command, version }), Some(principal)) => {
+                execute(&devices, principal, None, command, Some(version))
+            }
+            (
+                Ok(Command::ExecuteIn {
+                    room,
+                    command,
+                    version,
+                }),
+                Some(principal),
+            ) => execute(&devices, principal, Some(&room), command, version),
+            (Ok(Command::ListDevices), Some(principal)) => {
+                ExecutionResult::Devices(readable(&devices, principal))
+            }
+            (Ok(Command::Describe { device, room }), Some(principal)) => {
+                describe(&devices, principal, room.as_deref(), &device)
+            }
+            (Ok(Command::Batch(batch)), Some(principal)) => {
+                execute_batch(&devices, principal, batch)
+            }
+            (Ok(Command::Watch), Some(principal)) => {
+                return watch(stream.get_mut(), &devices, principal);
+            }
+            (Ok(Command::Unknown), Some(_)) => ExecutionResult::Error(
+                CustomError::CommandExecutionFailure("Unknown command".into()),
+            ),
+        };
+        if write_frame(stream.get_mut(), &result).is_err() {
+            return;
+        }
+    }
+}
+
+/// Reads at most `MAX_LINE + 1` bytes, more than `MAX_LINE` means the line is too long.
+fn read_line(reader: &mut impl BufRead, line: &mut String) -> std::io::Result<usize> {
+    reader.take(MAX_LINE as u64 + 1).read_line(line)
+}
+
+fn watch(writer: &mut impl Write, devices: &SmartDeviceList, principal: &Principal) {
+    let events = devices.subscribe();
+    let current = ExecutionResult::Devices(readable(devices, principal));
+    if write_frame(writer, &current).is_err() {
+        return;
+    }
+    for event in events.iter().filter(|e| may_see(principal, e)) {
+        if write_frame(writer, &ExecutionResult::Event(event)).is_err() {
+            return;
+        }
+    }
+}
+
+fn authenticate(
+    auth: Option<&Authenticator>,
+    credentials: &Credentials,
+) -> CustomResult<Principal> {
+    match auth {
+        Some(auth) => auth.authenticate(credentials),
+        None => Ok(Principal::admin("anonymous")),
+    }
+}
+
+/// The device is resolved once, so the room checked is the room the command runs in.
+fn execute(
+    devices: &SmartDeviceList,
+    principal: &Principal,
+    room: Option<&str>,
+    data: CommandData,
+    version: Option<u64>,
+) -> ExecutionResult {
+    let entry = match devices.resolve(room, &data.device_name) {
+        Ok(entry) => entry,
+        Err(err) => return ExecutionResult::Error(err),
+    };
+    if let Err(err) = principal.check(Action::of(&data.data), &entry.room) {
+        return ExecutionResult::Error(err);
+    }
+    let cmd = CommandData {
+        device_name: entry.device.get_name(),
+        data: data.data,
+    };
+    devices.execute_command_as(cmd, Some(&entry.room), Some(&principal.name), version)
+}
+
+/// The whole batch is refused when the principal may not run one of its commands.
+fn execute_batch(
+    devices: &SmartDeviceList,
+    principal: &Principal,
+    batch: Batch,
+) -> ExecutionResult {
+    let result = devices.execute_batch_checked(batch, &principal.name, |room, command| {
+        principal.check(Action::of(command), room)
+    });
+    match result {
+        Ok(result) => ExecutionResult::Batch(result),
+        Err(err) => ExecutionResult::Error(err),
+    }
+}
+
+fn describe(
+    devices: &SmartDeviceList,
+    principal: &Principal,
+    room: Option<&str>,
+    device: &str,
+) -> ExecutionResult {
+    match devices.resolve(room, device) {
+        Ok(entry) if principal.can(Action::Read, &entry.room) => {
+            ExecutionResult::Capabilities(entry.device.capabilities())
+        }
+        // devices in rooms the principal may not read stay hidden
+        _ => ExecutionResult::Error(CustomError::DeviceNotFound),
+    }
+}
+
+fn readable(devices: &SmartDeviceList, principal: &Principal) -> Vec<DeviceEntry> {
+    devices
+        .query(&DeviceQuery::new())
+        .into_iter()
+        .filter(|e| principal.can(Action::Read, &e.room))
+        .collect()
+}
+
+fn may_see(principal: &Principal, event: &DeviceEvent) -> bool {
+    let room = match event {
+        DeviceEvent::StateChanged { room, .. } => Some(room.clone()),
+        DeviceEvent::Alarm(alarm) => Some(alarm.alarm().room.clone()),
+    };
+    room.is_some_and(|room| principal.can(Action::Read, &room))
+}
+
+fn write_frame<T: serde::Serialize>(writer: &mut impl Write, frame: &T) -> CustomResult<()> {
+    let mut data =
+        serde_json::to_string(frame).map_err(|e| CustomError::ParseError(e.to_string()))?;
+    data.push('\n');
+    writer
+        .write_all(data.as_bytes())
+        .and_then(|_| writer.flush())
+        .map_err(|e| CustomError::ConnectionError(e.to_string()))
+}
+
+trait Transport: Read + Write + Send {}
+impl<T: Read + Write + Send> Transport for T {}
+
+/// How a client reaches a control server.
+#[derive(Clone, Default)]
+pub struct ConnectOptions {
+    credentials: Option<Credentials>,
+    tls: Option<TlsClientConfig>,
+}
+
+impl ConnectOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Authenticates right after connecting.
+    pub fn with_credentials(mut self, credentials: Credentials) -> Self {
+        self.credentials = Some(credentials);
+        self
+    }
+    pub fn with_tls(mut self, tls: TlsClientConfig) -> Self {
+        self.tls = Some(tls);
+        self
+    }
+}
+
+pub struct ControlClient {
+    stream: BufReader<Box<dyn Transport>>,
+    /// the underlying socket, also when `stream` is encrypted
+    socket: TcpStream,
+}
+
+impl ControlClient {
+    pub fn connect<A: ToSocketAddrs>(addr: A) -> CustomResult<Self> {
+        let socket =
+            TcpStream::connect(addr).map_err(|e| CustomError::ConnectionError(e.to_string()))?;
+        let stream = socket
+            .try_clone()
+            .map_err(|e| CustomError::ConnectionError(e.to_string()))?;
+        Ok(Self {
+            stream: BufReader::new(Box::new(stream)),
+            socket,
+        })
+    }
+    /// Connects with TLS and credentials as configured in `options`.
+    /// The server certificate must be valid for the host part of `addr`.
+    pub fn connect_with(addr: &str, options: &ConnectOptions) -> CustomResult<Self> {
+        let mut client = match &options.tls {
+            Some(tls) => {
+                let socket = TcpStream::connect(addr)
+                    .map_err(|e| CustomError::ConnectionError(e.to_string()))?;
+                let stream = socket
+                    .try_clone()
+                    .map_err(|e| CustomError::ConnectionError(e.to_string()))?;
+                Self {
+                    stream: BufReader::new(Box::new(tls.connect(host(addr), stream)?)),
+                    socket,
+                }
+            }
+            None => Self::connect(addr)?,
+        };
+        if let Some(credentials) = &options.credentials {
+            client.authenticate(credentials)?;
+        }
+        Ok(client)
+    }
+    pub fn send(&mut self, command: &Command) -> CustomResult<ExecutionResult> {
+        write_frame(self.stream.get_mut(), command)?;
+        self.receive()
+    }
+    /// Has to come first on servers that require authentication.
+    /// Returns the name of the authenticated principal.
+    pub fn authenticate(&mut self, credentials: &Credentials) -> CustomResult<String> {
+        match self.send(&Command::Authenticate(credentials.clone()))? {
+            ExecutionResult::Authenticated(name) => Ok(name),
+            ExecutionResult::Error(err) => Err(err),
+            other => Err(CustomError::ConnectionError(format!(
+                "unexpected response {:?}",
+                other
+            ))),
+        }
+    }
+    /// Devices of the rooms the client may read, ordered by room and name.
+    pub fn list_devices(&mut self) -> CustomResult<Vec<DeviceEntry>> {
+        match self.send(&Command::ListDevices)? {
+            ExecutionResult::Devices(devices) => Ok(devices),
+            ExecutionResult::Error(err) => Err(err),
+            other => Err(CustomError::ConnectionError(format!(
+                "unexpected response {:?}",
+                other
+            ))),
+        }
+    }
+    pub fn execute_batch(&mut self, batch: Batch) -> CustomResult<BatchResult> {
+        match self.send(&Command::Batch(batch))? {
+            ExecutionResult::Batch(result) => Ok(result),
+            ExecutionResult::Error(err) => Err(err),
+            other => Err(CustomError::ConnectionError(format!(
+                "unexpected response {:?}",
+                other
+            ))),
+        }
+    }
+    /// Capabilities of the device in `room`, or of the only device of that name
+    /// when `room` is `None`.
+    pub fn capabilities(&mut self, room: Option<&str>, device: &str) -> CustomResult<Capabilities> {
+        let command = Command::Describe {
+            device: device.to_owned(),
+            room: room.map(str::to_owned),
+        };
+        match self.send(&command)? {
+            ExecutionResult::Capabilities(capabilities) => Ok(capabilities),
+            ExecutionResult::Error(err) => Err(err),
+            other => Err(CustomError::ConnectionError(format!(
+                "unexpected response {:?}",
+                other
+            ))),
+        }
+    }
+    /// Shutting down the returned stream closes this connection,
+    /// which also ends a running `watch`.
+    pub fn shutdown_handle(&self) -> CustomResult<TcpStream> {
+        self.socket
+            .try_clone()
+            .map_err(|e| CustomError::ConnectionError(e.to_string()))
+    }
+    /// Switches the connection to watch mode: the returned iterator yields
+    /// events until the server closes the connection.
+    /// Returns once the server is subscribed, so no later change is missed.
+    pub fn watch(mut self) -> CustomResult<impl Iterator<Item = CustomResult<DeviceEvent>>> {
+        write_frame(self.stream.get_mut(), &Command::Watch)?;
+        match self.receive()? {
+            ExecutionResult::Devices(_) => {}
+            ExecutionResult::Error(err) => return Err(err),
+            other => {
+                return Err(CustomError::ConnectionError(format!(
+                    "unexpected response {:?}",
+                    other
+                )))
+            }
+        }
+        Ok(std::iter::from_fn(move || match self.receive() {
+            Ok(ExecutionResult::Event(event)) => Some(Ok(event)),
+            Ok(other) => Some(Err(CustomError::ConnectionError(format!(
+                "unexpected response {:?}",
+                other
+            )))),
+            Err(CustomError::ConnectionError(_)) => None,
+            Err(err) => Some(Err(err)),
+        }))
+    }
+    fn receive(&mut self) -> CustomResult<ExecutionResult> {
+        let mut line = String::new();
+        match self.stream.read_line(&mut line) {
+            Ok(0) => Err(CustomError::ConnectionError("connection closed".into())),
+            Ok(_) => {
+                serde_json::from_str(&line).map_err(|e| CustomError::ParseError(e.to_string()))
+            }
+            Err(e) => Err(CustomError::ConnectionError(e.to_string())),
+        }
+    }
+}
+
+/// `host:port` or `[ipv6]:port` -> host
+fn host(addr: &str) -> &str {
+    match addr.rsplit_once(':') {
+        Some((host, _)) => host.trim_start_matches('[').trim_end_matches(']'),
+        None => addr,
+    }
+}
@@ -0,0 +1,83 @@
+//! Reads that have to finish by a deadline, however slowly the peer sends.
+
+use std::io::{self, BufRead, ErrorKind, Read};
+use std::net::TcpStream;
+use std::time::Instant;
+
+/// Buffered reader whose reads fail with `ErrorKind::TimedOut` once `until` passed.
+/// Sets the read timeout of `socket`, the stream the reader reads from, before
+/// every read; callers reset it when the deadline no longer applies.
+pub(crate) struct Deadline<'a, R> {
+    reader: &'a mut R,
+    socket: &'a TcpStream,
+    until: Instant,
+}
+
+impl<'a, R: BufRead> Deadline<'a, R> {
+    pub(crate) fn new(reader: &'a mut R, socket: &'a TcpStream, until: Instant) -> Self {
+        Self {
+            reader,
+            socket,
+            until,
+        }
+    }
+}
+
+impl<R: BufRead> BufRead for Deadline<'_, R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        let remaining = self
+            .until
+            .checked_duration_since(Instant::now())
+            .filter(|d| !d.is_zero())
+            .ok_or_else(|| io::Error::from(ErrorKind::TimedOut))?;
+        self.socket.set_read_timeout(Some(remaining))?;
+        self.reader.fill_buf()
+    }
+    fn consume(&mut self, amount: usize) {
+        self.reader.consume(amount)
+    }
+}
+
+impl<R: BufRead> Read for Deadline<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let available = self.fill_buf()?;
+        let n = available.len().min(buf.len());
+        buf[..n].copy_from_slice(&available[..n]);
+        self.consume(n);
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::{BufReader, Write};
+    use std::net::TcpListener;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn trickling_peers_time_out() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut peer = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (socket, _) = listener.accept().unwrap();
+        thread::spawn(move || {
+            // a byte every 10 ms, each well within any per-read timeout
+            while peer.write_all(b"a").is_ok() {
+                thread::sleep(Duration::from_millis(10));
+            }
+        });
+        let mut reader = BufReader::new(socket.try_clone().unwrap());
+        let started = Instant::now();
+        let until = started + Duration::from_millis(100);
+        let mut line = String::new();
+        let err = Deadline::new(&mut reader, &socket, until)
+            .read_line(&mut line)
+            .unwrap_err();
+        assert!(matches!(
+            err.kind(),
+            ErrorKind::TimedOut | ErrorKind::WouldBlock
+        ));
+        assert!(started.elapsed() < Duration::from_secs(1));
+    }
+}
@@ -0,0 +1,432 @@
+use super::SmartDeviceList;
+use crate::device_key::device_key;
+use crate::{
+    CommandData, CustomError, CustomResult, DeviceCommand, ExecutionResult, PowerSocketCommand,
+    SmartDevice, ThermometerCommand, TimerAction,
+};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum BatchMode {
+    /// one command after the other, in the order given
+    #[default]
+    Sequential,
+    /// all commands at once, each on its own thread
+    Parallel,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum OnError {
+    /// run the remaining commands anyway
+    #[default]
+    Continue,
+    /// skip the commands after the first failure
+    Stop,
+    /// stop and restore the devices the batch touched, and sockets shed from
+    /// their circuit on the way, to their state before it
+    Rollback,
+}
+
+/// Commands submitted together, see `SmartDeviceList::execute_batch`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Batch {
+    commands: Vec<CommandData>,
+    #[serde(default)]
+    mode: BatchMode,
+    #[serde(default)]
+    on_error: OnError,
+}
+
+impl Batch {
+    /// Sequential, running every command regardless of failures.
+    pub fn new(commands: Vec<CommandData>) -> Self {
+        Self {
+            commands,
+            ..Self::default()
+        }
+    }
+    pub fn command(mut self, command: CommandData) -> Self {
+        self.commands.push(command);
+        self
+    }
+    /// Commands run at once, so a failure cannot skip the others;
+    /// a rollback still restores the devices after all of them finished.
+    pub fn parallel(mut self) -> Self {
+        self.mode = BatchMode::Parallel;
+        self
+    }
+    pub fn stop_on_error(mut self) -> Self {
+        self.on_error = OnError::Stop;
+        self
+    }
+    /// Undo the batch with compensating commands when any command fails.
+    pub fn transactional(mut self) -> Self {
+        self.on_error = OnError::Rollback;
+        self
+    }
+    pub fn get_commands(&self) -> &[CommandData] {
+        &self.commands
+    }
+    pub fn get_mode(&self) -> BatchMode {
+        self.mode
+    }
+    pub fn get_on_error(&self) -> OnError {
+        self.on_error
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum BatchOutcome {
+    Done(ExecutionResult),
+    Failed(CustomError),
+    /// not run because an earlier command failed
+    Skipped,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BatchItem {
+    pub device_name: String,
+    pub command: DeviceCommand,
+    pub outcome: BatchOutcome,
+}
+
+/// A command sent to restore a device during a rollback.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Compensation {
+    pub room: String,
+    pub device_name: String,
+    pub command: DeviceCommand,
+    pub result: ExecutionResult,
+}
+
+/// Outcome of every command of a batch, in the order they were submitted.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BatchResult {
+    pub items: Vec<BatchItem>,
+    /// whether a failure made the batch roll back
+    pub rolled_back: bool,
+    /// commands sent during the rollback, in the order they ran
+    pub compensations: Vec<Compensation>,
+}
+
+impl BatchResult {
+    pub fn is_success(&self) -> bool {
+        self.items
+            .iter()
+            .all(|item| matches!(item.outcome, BatchOutcome::Done(_)))
+    }
+    pub fn failures(&self) -> impl Iterator<Item = &BatchItem> {
+        self.items
+            .iter()
+            .filter(|item| matches!(item.outcome, BatchOutcome::Failed(_)))
+    }
+    /// False when a compensating command failed and a device may be left changed.
+    pub fn is_restored(&self) -> bool {
+        self.compensations
+            .iter()
+            .all(|c| !matches!(c.result, ExecutionResult::Error(_)))
+    }
+}
+
+/// Formats as `2 done, 1 failed, 1 skipped, rolled back`.
+impl fmt::Display for BatchResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let count = |f: fn(&BatchOutcome) -> bool| {
+            self.items.iter().filter(|item| f(&item.outcome)).count()
+        };
+        write!(f, "{} done", count(|o| matches!(o, BatchOutcome::Done(_))))?;
+        let failed = count(|o| matches!(o, BatchOutcome::Failed(_)));
+        if failed > 0 {
+            write!(f, ", {} failed", failed)?;
+        }
+        let skipped = count(|o| matches!(o, BatchOutcome::Skipped));
+        if skipped > 0 {
+            write!(f, ", {} skipped", skipped)?;
+        }
+        match (self.rolled_back, self.is_restored()) {
+            (false, _) => Ok(()),
+            (true, true) => write!(f, ", rolled back"),
+            (true, false) => write!(f, ", rollback incomplete"),
+        }
+    }
+}
+
+/// Room and exact name of the device a batch command runs on.
+type Target = CustomResult<(String, String)>;
+
+impl SmartDeviceList {
+    /// Runs the commands of the batch as its mode and error handling say.
+    /// Every command goes through `execute_command`, with policies and the audit log.
+    /// Device names are resolved once before the batch starts, see `resolve`.
+    pub fn execute_batch(&self, batch: Batch) -> BatchResult {
+        let targets = self.batch_targets(&batch);
+        self.run_batch(batch, targets, None)
+    }
+    /// Same as `execute_batch`, recording `principal` as the issuer in the audit log.
+    pub fn execute_batch_by(&self, batch: Batch, principal: &str) -> BatchResult {
+        let targets = self.batch_targets(&batch);
+        self.run_batch(batch, targets, Some(principal))
+    }
+    /// Same as `execute_batch_by`, refusing the whole batch when `check` fails
+    /// for the room of any command's device.
+    pub(crate) fn execute_batch_checked<F>(
+        &self,
+        batch: Batch,
+        principal: &str,
+        check: F,
+    ) -> CustomResult<BatchResult>
+    where
+        F: Fn(&str, &DeviceCommand) -> CustomResult<()>,
+    {
+        let targets = self.batch_targets(&batch);
+        for (cmd, target) in batch.commands.iter().zip(&targets) {
+            // unknown devices fail inside the batch
+            if let Ok((room, _)) = target {
+                check(room, &cmd.data)?;
+            }
+        }
+        Ok(self.run_batch(batch, targets, Some(principal)))
+    }
+    fn batch_targets(&self, batch: &Batch) -> Vec<Target> {
+        batch
+            .commands
+            .iter()
+            .map(|cmd| {
+                let entry = self.resolve(None, &cmd.device_name)?;
+                Ok((entry.room, entry.device.get_name()))
+            })
+            .collect()
+    }
+    fn run_target(
+        &self,
+        cmd: &CommandData,
+        target: &Target,
+        principal: Option<&str>,
+    ) -> ExecutionResult {
+        match target {
+            Ok((room, name)) => {
+                let cmd = CommandData {
+                    device_name: name.clone(),
+                    data: cmd.data.clone(),
+                };
+                self.execute_command_as(cmd, Some(room), principal, None)
+            }
+            Err(err) => {
+                let result = ExecutionResult::Error(err.clone());
+                let (device, command) = (cmd.device_name.clone(), cmd.data.clone());
+                self.audit(principal, None, device, command, &result);
+                result
+            }
+        }
+    }
+    fn run_batch(
+        &self,
+        batch: Batch,
+        targets: Vec<Target>,
+        principal: Option<&str>,
+    ) -> BatchResult {
+        let Batch {
+            commands,
+            mode,
+            on_error,
+        } = batch;
+        let before = match on_error {
+            OnError::Rollback => self.batch_snapshot(&targets),
+            _ => Vec::new(),
+        };
+        let items = match mode {
+            BatchMode::Sequential => {
+                let stop = on_error != OnError::Continue;
+                let mut failed = false;
+                commands
+                    .into_iter()
+                    .zip(&targets)
+                    .map(|(cmd, target)| match failed && stop {
+                        true => item(cmd, None),
+                        false => {
+                            let result = self.run_target(&cmd, target, principal);
+                            failed |= matches!(result, ExecutionResult::Error(_));
+                            item(cmd, Some(result))
+                        }
+                    })
+                    .collect()
+            }
+            BatchMode::Parallel => thread::scope(|scope| {
+                let running: Vec<_> = commands
+                    .into_iter()
+                    .zip(&targets)
+                    .map(|(cmd, target)| {
+                        let run = cmd.clone();
+                        (
+                            cmd,
+                            scope.spawn(move || self.run_target(&run, target, principal)),
+                        )
+                    })
+                    .collect();
+                running
+                    .into_iter()
+                    .map(|(cmd, handle)| {
+                        let result = handle.join().unwrap_or_else(|_| {
+                            ExecutionResult::Error(CustomError::CommandExecutionFailure(
+                                "command panicked".into(),
+                            ))
+                        });
+                        item(cmd, Some(result))
+                    })
+                    .collect()
+            }),
+        };
+        let mut result = BatchResult {
+            items,
+            rolled_back: false,
+            compensations: Vec::new(),
+        };
+        if on_error == OnError::Rollback && !result.is_success() {
+            result.rolled_back = true;
+            result.compensations = self.roll_back(&before, principal);
+        }
+        result
+    }
+    /// Devices as they were before the batch, in the order the batch touches them.
+    /// Powered sockets sharing a circuit with a target come before it, turning
+    /// the target on may shed them.
+    fn batch_snapshot(&self, targets: &[Target]) -> Vec<(String, SmartDevice)> {
+        let circuits = self.get_circuits();
+        let mut before: Vec<(String, SmartDevice)> = Vec::new();
+        let mut push = |room: &str, name: &str| {
+            let key = device_key(room, name);
+            let known = before
+                .iter()
+                .any(|(r, d)| device_key(r, &d.get_name()) == key);
+            if known {
+                return;
+            }
+            if let Ok(entry) = self.resolve(Some(room), name) {
+                before.push((entry.room, entry.device));
+            }
+        };
+        for (room, name) in targets.iter().flatten() {
+            if let Some(circuit) = circuits.iter().find(|c| c.contains(room, name)) {
+                for (member, _) in self.powered_members(circuit) {
+                    push(&member.room, &member.device);
+                }
+            }
+            push(room, name);
+        }
+        before
+    }
+    /// Undoes the changes to `before` in reverse order of the batch.
+    fn roll_back(
+        &self,
+        before: &[(String, SmartDevice)],
+        principal: Option<&str>,
+    ) -> Vec<Compensation> {
+        let mut compensations = Vec::new();
+        for (room, device) in before.iter().rev() {
+            let name = device.get_name();
+            let current = match self.resolve(Some(room), &name) {
+                Ok(entry) => entry.device,
+                Err(_) => continue,
+            };
+            for command in compensation(device, &current, self.now()) {
+                let result = self.execute_command_as(
+                    CommandData {
+                        device_name: name.clone(),
+                        data: command.clone(),
+                    },
+                    Some(room),
+                    principal,
+                    None,
+                );
+                compensations.push(Compensation {
+                    room: room.clone(),
+                    device_name: name.clone(),
+                    command,
+                    result,
+                });
+            }
+        }
+        compensations
+    }
+}
+
+fn item(cmd: CommandData, result: Option<ExecutionResult>) -> BatchItem {
+    BatchItem {
+        device_name: cmd.device_name,
+        command: cmd.data,
+        outcome: match result {
+            None => BatchOutcome::Skipped,
+            Some(ExecutionResult::Error(err)) => BatchOutcome::Failed(err),
+            Some(result) => BatchOutcome::Done(result),
+        },
+    }
+}
+
+/// Commands taking `current` back to `before`. A power limit that was not set
+/// before cannot be removed by a command and stays.
+fn compensation(
+    before: &SmartDevice,
+    current: &SmartDevice,
+    now: SystemTime,
+) -> Vec<DeviceCommand> {
+    let mut commands = Vec::new();
+    match (before, current) {
+        (SmartDevice::Socket(before), SmartDevice::Socket(current)) => {
+            let mut socket = Vec::new();
+            if before.description != current.description {
+                socket.push(PowerSocketCommand::SetDescription(
+                    before.description.clone(),
+                ));
+            }
+            if let Some(limit) = before
+                .get_power_limit()
+                .filter(|_| before.get_power_limit() != current.get_power_limit())
+            {
+                socket.push(PowerSocketCommand::SetPowerLimit(limit));
+            }
+            // switching clears the timer, so it is restored afterwards
+            let switched = before.is_turned_on() != current.is_turned_on();
+            if switched {
+                socket.push(match before.is_turned_on() {
+                    true => PowerSocketCommand::TurnOn,
+                    false => PowerSocketCommand::TurnOff,
+                });
+            }
+            match before.get_timer() {
+                Some(timer) if switched || before.get_timer() != current.get_timer() => {
+                    // whole seconds, and a due timer fires right away
+                    let remaining = timer.at.duration_since(now).unwrap_or_default();
+                    let delay = Duration::from_secs(remaining.as_secs().max(1));
+                    socket.push(match timer.action {
+                        TimerAction::TurnOn => PowerSocketCommand::TurnOnIn(delay),
+                        TimerAction::TurnOff => PowerSocketCommand::TurnOffIn(delay),
+                    });
+                }
+                None if !switched && current.get_timer().is_some() => {
+                    socket.push(PowerSocketCommand::CancelTimer)
+                }
+                _ => {}
+            }
+            commands.extend(socket.into_iter().map(DeviceCommand::PowerSocket));
+            commands
+        }
+        (SmartDevice::Thermo(before), SmartDevice::Thermo(current)) => {
+            if before.get_calibration() != current.get_calibration() {
+                commands.push(DeviceCommand::Thermometer(
+                    ThermometerCommand::SetCalibration(before.get_calibration()),
+                ));
+            }
+            if before.state != current.state {
+                commands.push(DeviceCommand::Thermometer(ThermometerCommand::SetReading(
+                    before.state,
+                )));
+            }
+            commands
+        }
+        // replaced by a device of another kind, nothing to restore
+        _ => commands,
+    }
+}
@@ -1,20 +1,105 @@
-use crate::{CommandData, CustomError, CustomResult, ExecutionResult, SmartDevice};
+mod batch;
+mod query;
+mod snapshot;
+
+use crate::device_key::{device_key, DeviceKey};
+use crate::events::{DeviceEvent, EventBus};
+use crate::health::HealthMonitor;
+use crate::policy::{self, Admission, Policies};
+use crate::{
+    AuditLog, AuditRecord, Availability, Circuit, CircuitMember, Clock, CommandData, CommandPolicy,
+    Countdown, CustomError, CustomResult, DeviceCommand, DeviceFault, DeviceHealth,
+    ExecutionResult, FaultInjector, FaultKind, HealthStatus, PowerSocketCommand, SmartDevice,
+    SystemClock, Temperature, ThermometerCommand, TimerAction,
+};
 use dashmap::DashMap;
-use std::sync::Arc;
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+pub use batch::{Batch, BatchItem, BatchMode, BatchOutcome, BatchResult, Compensation, OnError};
+pub use query::{DeviceEntry, DeviceKind, DeviceQuery, SortKey};
+pub use snapshot::DeviceSnapshot;
 
 pub trait DeviceInfoProvider {
     fn get_device_info(&self, room: &str, device: &str) -> CustomResult<DeviceInfo>;
 }
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
 pub struct DeviceInfo {
     pub kind: String,
     pub name: String,
     pub state: String,
+    /// current reading for thermometers, lets reports convert it to the preferred unit
+    pub temperature: Option<Temperature>,
+    #[serde(default)]
+    pub availability: Availability,
+    #[serde(default)]
+    pub health: HealthStatus,
+    #[serde(default)]
+    pub last_seen: Option<SystemTime>,
+    /// pending socket timer
+    #[serde(default)]
+    pub countdown: Option<Countdown>,
+    /// see `SmartDeviceList::version`
+    #[serde(default)]
+    pub version: u64,
+}
+
+impl From<&SmartDevice> for DeviceInfo {
+    fn from(device: &SmartDevice) -> Self {
+        DeviceInfo {
+            kind: device.get_type(),
+            name: device.get_name(),
+            state: device.get_state(),
+            temperature: match device {
+                SmartDevice::Thermo(t) => Some(t.get_temperature()),
+                _ => None,
+            },
+            availability: Availability::Online,
+            health: HealthStatus::Unknown,
+            last_seen: None,
+            countdown: match device {
+                SmartDevice::Socket(s) => s.countdown(SystemTime::now()),
+                _ => None,
+            },
+            version: 0,
+        }
+    }
+}
+
+/// Stops the timer thread when dropped.
+pub struct TimerHandle {
+    stop: Sender<()>,
 }
 
+impl TimerHandle {
+    pub fn stop(self) {
+        self.stop.send(()).ok();
+    }
+}
+
+/// Room of the device and result of one attempt at a command.
+type Attempt = (Option<String>, ExecutionResult);
+
 #[derive(Debug, Clone)]
-pub struct SmartDeviceList(Arc<DashMap<String, Vec<SmartDevice>>>);
+pub struct SmartDeviceList {
+    devices: Arc<DashMap<String, Vec<SmartDevice>>>,
+    circuits: Arc<Mutex<Vec<Circuit>>>,
+    events: EventBus,
+    audit: Arc<Mutex<Option<AuditLog>>>,
+    audit_failures: Arc<AtomicU64>,
+    faults: FaultInjector,
+    policies: Policies,
+    health: HealthMonitor,
+    clock: Arc<Mutex<Arc<dyn Clock>>>,
+    /// by lowercase room and device name, kept when a device is removed
+    /// so versions never repeat
+    versions: Arc<DashMap<DeviceKey, u64>>,
+}
 impl Default for SmartDeviceList {
     fn default() -> Self {
         Self::new()
@@ -23,10 +108,21 @@ impl Default for SmartDeviceList {
 
 impl SmartDeviceList {
     pub fn new() -> Self {
-        Self(Arc::new(DashMap::new()))
+        Self {
+            devices: Arc::new(DashMap::new()),
+            circuits: Arc::new(Mutex::new(Vec::new())),
+            events: EventBus::new(),
+            audit: Arc::new(Mutex::new(None)),
+            audit_failures: Arc::new(AtomicU64::new(0)),
+            faults: FaultInjector::new(),
+            policies: Policies::default(),
+            health: HealthMonitor::default(),
+            clock: Arc::new(Mutex::new(Arc::new(SystemClock))),
+            versions: Arc::new(DashMap::new()),
+        }
     }
     pub fn add_device(&mut self, room: &str, device: SmartDevice) -> CustomResult<()> {
-        let mut mut_vec = self.0.entry(room.to_lowercase()).or_insert(Vec::new());
+        let mut mut_vec = self.devices.entry(room.to_lowercase()).or_default();
 
         match mut_vec
             .iter()
@@ -39,26 +135,908 @@ impl SmartDeviceList {
             true => Err(CustomError::AddDeviceError),
         }
     }
+    /// Room and device names are case insensitive. The health, faults,
+    /// policy and circuit membership of the device are dropped with it.
+    pub fn remove_device(&self, room: &str, device: &str) -> CustomResult<SmartDevice> {
+        let mut circuits = self.circuits.lock().unwrap();
+        let mut room_devices = self
+            .devices
+            .get_mut(&room.to_lowercase())
+            .ok_or(CustomError::RoomNotFound)?;
+        let pos = room_devices
+            .iter()
+            .position(|d| d.get_name().to_lowercase() == device.to_lowercase())
+            .ok_or(CustomError::DeviceNotFound)?;
+        let removed = room_devices.remove(pos);
+        for circuit in circuits.iter_mut() {
+            circuit.remove_member(room, device);
+        }
+        self.health.remove_device(room, device);
+        self.faults.remove_device(room, device);
+        self.policies.remove_device(room, device);
+        Ok(removed)
+    }
+    /// Removes the room with all its devices and returns them.
+    pub fn remove_room(&self, room: &str) -> CustomResult<Vec<SmartDevice>> {
+        let mut circuits = self.circuits.lock().unwrap();
+        let (_, devices) = self
+            .devices
+            .remove(&room.to_lowercase())
+            .ok_or(CustomError::RoomNotFound)?;
+        for circuit in circuits.iter_mut() {
+            circuit.remove_room(room);
+        }
+        self.health.remove_room(room);
+        self.faults.remove_room(room);
+        self.policies.remove_room(room);
+        Ok(devices)
+    }
+    /// Moves the devices of `room` to `new_name`; fails if that room already has devices.
+    pub fn rename_room(&self, room: &str, new_name: &str) -> CustomResult<()> {
+        let (room, new_name) = (room.to_lowercase(), new_name.to_lowercase());
+        if room == new_name {
+            return Ok(());
+        }
+        if self.devices.get(&new_name).is_some_and(|d| !d.is_empty()) {
+            return Err(CustomError::AddRoomError);
+        }
+        let mut circuits = self.circuits.lock().unwrap();
+        let (_, devices) = self
+            .devices
+            .remove(&room)
+            .ok_or(CustomError::RoomNotFound)?;
+        self.devices.insert(new_name.clone(), devices);
+        // the old entries stay, a device added to the old room later does not repeat them
+        let moved: Vec<_> = self
+            .versions
+            .iter()
+            .filter(|v| v.key().0 == room)
+            .map(|v| (v.key().1.clone(), *v.value()))
+            .collect();
+        for (device, version) in moved {
+            let mut entry = self.versions.entry((new_name.clone(), device)).or_default();
+            *entry = (*entry).max(version);
+        }
+        for circuit in circuits.iter_mut() {
+            circuit.rename_room(&room, &new_name);
+        }
+        self.health.rename_room(&room, &new_name);
+        self.faults.rename_room(&room, &new_name);
+        self.policies.rename_room(&room, &new_name);
+        Ok(())
+    }
+    /// Sockets on a circuit cannot be renamed, circuits refer to them by name.
+    /// The health, faults and policy of the device keep applying to it.
+    pub fn rename_device(&self, room: &str, device: &str, new_name: &str) -> CustomResult<()> {
+        if self
+            .circuits
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|c| c.contains(room, device))
+        {
+            return Err(CustomError::InvalidCircuit(format!(
+                "socket {} is on a circuit",
+                device
+            )));
+        }
+        let mut room_devices = self
+            .devices
+            .get_mut(&room.to_lowercase())
+            .ok_or(CustomError::RoomNotFound)?;
+        if device.to_lowercase() != new_name.to_lowercase()
+            && room_devices
+                .iter()
+                .any(|d| d.get_name().to_lowercase() == new_name.to_lowercase())
+        {
+            return Err(CustomError::AddDeviceError);
+        }
+        room_devices
+            .iter_mut()
+            .find(|d| d.get_name().to_lowercase() == device.to_lowercase())
+            .ok_or(CustomError::DeviceNotFound)?
+            .set_name(new_name);
+        let version = self
+            .current_version(room, device)
+            .max(self.current_version(room, new_name));
+        self.versions
+            .insert(device_key(room, new_name), version + 1);
+        self.health.rename_device(room, device, new_name);
+        self.faults.rename_device(room, device, new_name);
+        self.policies.rename_device(room, device, new_name);
+        Ok(())
+    }
+    /// A socket can belong to one circuit only.
+    pub fn add_circuit(&self, circuit: Circuit) -> CustomResult<()> {
+        let mut circuits = self.circuits.lock().unwrap();
+        if circuits
+            .iter()
+            .any(|c| c.get_name().to_lowercase() == circuit.get_name().to_lowercase())
+        {
+            return Err(CustomError::InvalidCircuit(format!(
+                "circuit {} already exists",
+                circuit.get_name()
+            )));
+        }
+        if let Some(member) = circuit
+            .get_members()
+            .iter()
+            .find(|m| circuits.iter().any(|c| c.contains(&m.room, &m.device)))
+        {
+            return Err(CustomError::InvalidCircuit(format!(
+                "socket {} in {} is already on another circuit",
+                member.device, member.room
+            )));
+        }
+        circuits.push(circuit);
+        Ok(())
+    }
+    pub fn remove_circuit(&self, name: &str) -> CustomResult<Circuit> {
+        let mut circuits = self.circuits.lock().unwrap();
+        let pos = circuits
+            .iter()
+            .position(|c| c.get_name().to_lowercase() == name.to_lowercase())
+            .ok_or_else(|| CustomError::InvalidCircuit(format!("no circuit {}", name)))?;
+        Ok(circuits.remove(pos))
+    }
+    pub fn get_circuits(&self) -> Vec<Circuit> {
+        self.circuits.lock().unwrap().clone()
+    }
+    /// Current consumption of powered sockets on the circuit, in watts.
+    pub fn circuit_load(&self, name: &str) -> CustomResult<u32> {
+        let circuits = self.circuits.lock().unwrap();
+        let circuit = circuits
+            .iter()
+            .find(|c| c.get_name().to_lowercase() == name.to_lowercase())
+            .ok_or_else(|| CustomError::InvalidCircuit(format!("no circuit {}", name)))?;
+        Ok(self.powered_members(circuit).iter().map(|(_, w)| w).sum())
+    }
+
+    /// Records every command executed through this list, including failed ones.
+    /// Clones of the list share the log.
+    pub fn set_audit_log(&self, log: AuditLog) {
+        *self.audit.lock().unwrap() = Some(log);
+    }
+    pub fn audit_log(&self) -> Option<AuditLog> {
+        self.audit.lock().unwrap().clone()
+    }
+    /// Commands that ran but could not be written to the audit log.
+    pub fn audit_failures(&self) -> u64 {
+        self.audit_failures.load(Ordering::SeqCst)
+    }
+
+    /// Faults injected into commands executed through this list, for testing
+    /// how callers cope with failing devices. Clones of the list share them.
+    pub fn faults(&self) -> &FaultInjector {
+        &self.faults
+    }
+
+    /// Policy applied to commands to devices without a policy of their own.
+    pub fn set_default_policy(&self, policy: CommandPolicy) {
+        self.policies.set_default(policy);
+    }
+    /// Policy of the device in `room`. Room and device names are case insensitive.
+    /// Clones of the list share the policies.
+    pub fn set_policy(&self, room: &str, device: &str, policy: CommandPolicy) {
+        self.policies.set(room, device, policy);
+    }
+    pub fn policy(&self, room: &str, device: &str) -> CommandPolicy {
+        self.policies.get(room, device)
+    }
+    /// Availability of the device in `room` judged by the commands it was sent.
+    pub fn availability(&self, room: &str, device: &str) -> Availability {
+        self.policies.availability(room, device)
+    }
+    /// Reads the state of every offline device that is due for a probe and
+    /// returns the rooms and names of the devices that are back online.
+    /// Meant to be called periodically.
+    pub fn probe_offline_devices(&self) -> Vec<(String, String)> {
+        let mut recovered = Vec::new();
+        for (room, device) in self.policies.due_for_probe() {
+            let entry = match self.resolve(Some(&room), &device) {
+                Ok(entry) => entry,
+                Err(_) => continue,
+            };
+            let name = entry.device.get_name();
+            let data = match entry.device {
+                SmartDevice::Socket(_) => DeviceCommand::PowerSocket(PowerSocketCommand::GetState),
+                SmartDevice::Thermo(_) => {
+                    DeviceCommand::Thermometer(ThermometerCommand::GetTemperature)
+                }
+            };
+            let cmd = CommandData {
+                device_name: name.clone(),
+                data,
+            };
+            self.execute_command_in(&entry.room, cmd);
+            if self.availability(&entry.room, &name) != Availability::Offline {
+                recovered.push((entry.room, name));
+            }
+        }
+        recovered
+    }
+
+    /// Marks the device in `room` as alive, e.g. when it reports a reading on
+    /// its own. Answered commands count as heartbeats as well.
+    pub fn heartbeat(&self, room: &str, device: &str) {
+        self.health.heartbeat(room, device, SystemTime::now());
+    }
+    /// Interval of devices without one of their own, `DEFAULT_HEARTBEAT_INTERVAL` by default.
+    pub fn set_default_heartbeat_interval(&self, interval: Duration) {
+        self.health.set_default_interval(interval);
+    }
+    pub fn set_heartbeat_interval(&self, room: &str, device: &str, interval: Duration) {
+        self.health.set_interval(room, device, interval);
+    }
+    /// Liveness of the device in `room` as of `now`.
+    pub fn device_health(
+        &self,
+        room: &str,
+        device: &str,
+        now: SystemTime,
+    ) -> CustomResult<DeviceHealth> {
+        let entry = self.resolve(Some(room), device)?;
+        let name = entry.device.get_name();
+        Ok(self.health.health(
+            &entry.room,
+            &name,
+            self.availability(&entry.room, &name),
+            now,
+        ))
+    }
+    /// Liveness of every device as of `now`, ordered by room and device name.
+    pub fn health_report(&self, now: SystemTime) -> Vec<DeviceHealth> {
+        let mut report: Vec<DeviceHealth> = self
+            .map(|room, device| (room.to_owned(), device.get_name()))
+            .into_iter()
+            .map(|(room, name)| {
+                self.health
+                    .health(&room, &name, self.availability(&room, &name), now)
+            })
+            .collect();
+        report.sort_by(|a, b| (&a.room, &a.device).cmp(&(&b.room, &b.device)));
+        report
+    }
+    /// Time source of socket timers, the system clock by default.
+    /// Clones of the list share it.
+    pub fn set_clock(&self, clock: impl Clock + 'static) {
+        *self.clock.lock().unwrap() = Arc::new(clock);
+    }
+    pub fn now(&self) -> SystemTime {
+        self.clock.lock().unwrap().now()
+    }
+    /// Fires socket timers that are due and returns the sockets that switched.
+    /// Meant to be called periodically, see `spawn_timers`.
+    pub fn run_timers(&self) -> Vec<String> {
+        let now = self.now();
+        let circuits = self.circuits.lock().unwrap();
+        let due = self.map(|room, device| match device {
+            SmartDevice::Socket(s) => s
+                .get_timer()
+                .filter(|timer| timer.at <= now)
+                .map(|timer| (room.to_owned(), s.name.clone(), timer.action)),
+            _ => None,
+        });
+        let mut switched = Vec::new();
+        for (room, name, action) in due.into_iter().flatten() {
+            // a timed turn on respects the circuit like a manual one
+            let blocked = action == TimerAction::TurnOn
+                && self.prepare_circuit(&circuits, &room, &name).is_err();
+            let mut devices = match self.devices.get_mut(&room) {
+                Some(devices) => devices,
+                None => continue,
+            };
+            let device = match devices.iter_mut().find(|d| d.get_name() == name) {
+                Some(device) => device,
+                None => continue,
+            };
+            let before = device.get_state();
+            // the timer may have been cancelled or moved since `due` was taken
+            let changed = match device {
+                SmartDevice::Socket(socket) => {
+                    let timer = socket.get_timer();
+                    if blocked {
+                        socket.cancel_timer();
+                    } else if socket.run_timer(now).is_some() {
+                        switched.push(name.clone());
+                    }
+                    socket.get_timer() != timer
+                }
+                _ => false,
+            };
+            if changed {
+                self.bump_version(&room, &name);
+            }
+            self.notify_change(&room, device, before);
+        }
+        switched
+    }
+    /// Runs `run_timers` every `interval` on a background thread.
+    pub fn spawn_timers(&self, interval: Duration) -> TimerHandle {
+        let (stop, stopped) = mpsc::channel();
+        let list = self.clone();
+        thread::spawn(move || loop {
+            list.run_timers();
+            match stopped.recv_timeout(interval) {
+                Err(RecvTimeoutError::Timeout) => continue,
+                _ => break,
+            }
+        });
+        TimerHandle { stop }
+    }
+
+    /// `DeviceInfo` including availability, liveness and pending timers.
+    pub(crate) fn device_info(&self, room: &str, device: &SmartDevice) -> DeviceInfo {
+        let name = device.get_name();
+        let availability = self.availability(room, &name);
+        let health = self
+            .health
+            .health(room, &name, availability, SystemTime::now());
+        DeviceInfo {
+            availability,
+            health: health.status,
+            last_seen: health.last_seen,
+            countdown: match device {
+                SmartDevice::Socket(s) => s.countdown(self.now()),
+                _ => None,
+            },
+            version: self.current_version(room, &name),
+            ..DeviceInfo::from(device)
+        }
+    }
+
+    /// Version of the state of the device in `room`. It starts at 0 and goes up
+    /// with every change made through this list: commands other than reads,
+    /// timers, circuits shedding the socket, `with_device_mut` and renames.
+    pub fn version(&self, room: &str, device: &str) -> CustomResult<u64> {
+        Ok(self.resolve(Some(room), device)?.version)
+    }
+    fn current_version(&self, room: &str, device: &str) -> u64 {
+        self.versions
+            .get(&device_key(room, device))
+            .map_or(0, |version| *version)
+    }
+    fn check_version(&self, room: &str, device: &str, expected: Option<u64>) -> CustomResult<()> {
+        let actual = self.current_version(room, device);
+        match expected {
+            Some(expected) if expected != actual => Err(CustomError::VersionConflict {
+                device: device.to_owned(),
+                expected,
+                actual,
+            }),
+            _ => Ok(()),
+        }
+    }
+    fn bump_version(&self, room: &str, device: &str) {
+        *self.versions.entry(device_key(room, device)).or_default() += 1;
+    }
+
+    /// Commands address devices by their exact name. A name used in more than one room
+    /// fails with `CustomError::AmbiguousDevice`, see `execute_command_in`.
     pub fn execute_command(&self, cmd: CommandData) -> ExecutionResult {
+        self.execute_command_as(cmd, None, None, None)
+    }
+    /// Runs the command on the device of that name in `room`.
+    pub fn execute_command_in(&self, room: &str, cmd: CommandData) -> ExecutionResult {
+        self.execute_command_as(cmd, Some(room), None, None)
+    }
+    /// Same as `execute_command`, recording `principal` as the issuer in the audit log.
+    pub fn execute_command_by(&self, cmd: CommandData, principal: &str) -> ExecutionResult {
+        self.execute_command_as(cmd, None, Some(principal), None)
+    }
+    /// Runs the command only if the device is still at `version`,
+    /// failing with `CustomError::VersionConflict` otherwise.
+    pub fn execute_command_if(&self, cmd: CommandData, version: u64) -> ExecutionResult {
+        self.execute_command_as(cmd, None, None, Some(version))
+    }
+    /// Same as `execute_command_if`, recording `principal` as the issuer in the audit log.
+    pub fn execute_command_if_by(
+        &self,
+        cmd: CommandData,
+        version: u64,
+        principal: &str,
+    ) -> ExecutionResult {
+        self.execute_command_as(cmd, None, Some(principal), Some(version))
+    }
+    /// Runs the command on the device in `room`, or on the only device of that
+    /// name when `room` is `None`.
+    pub(crate) fn execute_command_as(
+        &self,
+        cmd: CommandData,
+        room: Option<&str>,
+        principal: Option<&str>,
+        expected: Option<u64>,
+    ) -> ExecutionResult {
+        let device = cmd.device_name.clone();
+        let command = cmd.data.clone();
+        let (room, result) = self.execute_with_policy(cmd, room, expected);
+        self.audit(principal, room, device, command, &result);
+        result
+    }
+    /// Records the command in the audit log, if there is one.
+    pub(crate) fn audit(
+        &self,
+        principal: Option<&str>,
+        room: Option<String>,
+        device: String,
+        command: DeviceCommand,
+        result: &ExecutionResult,
+    ) {
+        if let Some(log) = self.audit_log() {
+            let record = AuditRecord {
+                time: SystemTime::now(),
+                principal: principal.map(str::to_owned),
+                room,
+                device,
+                command,
+                result: result.clone(),
+            };
+            // the command already ran, a broken log must not turn it into a failure
+            if log.record(&record).is_err() {
+                self.audit_failures.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+    }
+    /// Applies the device's `CommandPolicy` around `execute`.
+    fn execute_with_policy(
+        &self,
+        cmd: CommandData,
+        room: Option<&str>,
+        expected: Option<u64>,
+    ) -> (Option<String>, ExecutionResult) {
         let CommandData { device_name, data } = cmd;
-        for mut room in self.0.iter_mut() {
-            for device in room.iter_mut() {
-                if device.get_name() == device_name {
-                    return device
-                        .execute_command(data)
+        // unknown or ambiguous devices get the default policy and fail in `execute`
+        let located = self.locate(room, &device_name).ok();
+        let policy = match &located {
+            Some(room) => self.policies.get(room, &device_name),
+            None => self.policies.get_default(),
+        };
+        let probe = match located.as_deref() {
+            Some(found) => match self.policies.admit(found, &device_name, &policy) {
+                Admission::Send => false,
+                Admission::Probe => true,
+                Admission::Refuse(fault) => {
+                    return (located, ExecutionResult::Error(fault.into()));
                 }
+            },
+            None => false,
+        };
+        let mut retry = 0;
+        loop {
+            let (found, result, running) = self.execute_with_timeout(
+                &device_name,
+                data.clone(),
+                room,
+                expected,
+                policy.get_timeout(),
+            );
+            if let Some(found) = &found {
+                // every attempt counts for the liveness of the device
+                self.health
+                    .record(found, &device_name, &result, SystemTime::now());
+            }
+            // a failing probe keeps the device offline without further attempts
+            // a compare-and-set that reached the device moved its version,
+            // sending the same one again can only conflict
+            let idempotent = policy.is_idempotent() && expected.is_none();
+            if !probe && retry < policy.get_retries() && policy::is_retryable(&result, idempotent) {
+                thread::sleep(self.policies.backoff(&policy, retry));
+                // attempts never overlap: a timed out one still running by now
+                // ends the retries
+                let finished = running.is_none_or(|running| {
+                    !matches!(running.try_recv(), Err(mpsc::TryRecvError::Empty))
+                });
+                if finished {
+                    retry += 1;
+                    continue;
+                }
+            }
+            if let Some(found) = &found {
+                let failed = policy::is_device_failure(&result);
+                self.policies
+                    .record(found, &device_name, &policy, failed, retry > 0);
+            }
+            return (found, result);
+        }
+    }
+    /// On a timeout, also returns a receiver for the answer of the command,
+    /// which keeps running.
+    fn execute_with_timeout(
+        &self,
+        device_name: &str,
+        data: DeviceCommand,
+        room: Option<&str>,
+        expected: Option<u64>,
+        timeout: Option<Duration>,
+    ) -> (Option<String>, ExecutionResult, Option<Receiver<Attempt>>) {
+        let cmd = CommandData {
+            device_name: device_name.to_owned(),
+            data,
+        };
+        let timeout = match timeout {
+            Some(timeout) => timeout,
+            None => {
+                let (room, result) = self.execute(cmd, room, expected);
+                return (room, result, None);
+            }
+        };
+        let (tx, rx) = mpsc::channel();
+        let list = self.clone();
+        let target = room.map(str::to_owned);
+        // a late answer is dropped, but the command still takes effect
+        thread::spawn(move || tx.send(list.execute(cmd, target.as_deref(), expected)).ok());
+        match rx.recv_timeout(timeout) {
+            Ok((room, result)) => (room, result, None),
+            Err(_) => {
+                let fault = DeviceFault::new(device_name, FaultKind::Timeout)
+                    .with_detail(&format!("no answer within {:?}", timeout));
+                let room = self.locate(room, device_name).ok();
+                (room, ExecutionResult::Error(fault.into()), Some(rx))
+            }
+        }
+    }
+    /// Returns the room of the device next to the result.
+    fn execute(
+        &self,
+        cmd: CommandData,
+        room: Option<&str>,
+        expected: Option<u64>,
+    ) -> (Option<String>, ExecutionResult) {
+        let CommandData { device_name, data } = cmd;
+        let found = self.locate(room, &device_name).ok();
+        let latency = found.as_deref().map_or(Duration::ZERO, |room| {
+            self.faults.latency(room, &device_name)
+        });
+        if !latency.is_zero() {
+            thread::sleep(latency);
+        }
+        let fault = found.and_then(|room| {
+            let fault = self.faults.trigger(&room, &device_name)?;
+            Some((room, fault))
+        });
+        let fault = match fault {
+            Some((room, fault)) if !fault.kind.reaches_device() => {
+                return (Some(room), ExecutionResult::Error(fault.into()));
+            }
+            Some((_, fault)) => Some(fault),
+            None => None,
+        };
+        let (room, result) = self.execute_on_device(device_name, data, room, expected);
+        match fault {
+            // the command ran, but the caller never hears about it
+            Some(fault) if room.is_some() => (room, ExecutionResult::Error(fault.into())),
+            _ => (room, result),
+        }
+    }
+    fn execute_on_device(
+        &self,
+        device_name: String,
+        data: DeviceCommand,
+        room: Option<&str>,
+        expected: Option<u64>,
+    ) -> (Option<String>, ExecutionResult) {
+        let now = self.now();
+        let room = match self.locate(room, &device_name) {
+            Ok(room) => room,
+            Err(err) => return (None, ExecutionResult::Error(err)),
+        };
+        // a socket turned on within a circuit keeps it locked until it is on,
+        // so concurrent commands cannot both fit under the same limit
+        let circuits = match data {
+            DeviceCommand::PowerSocket(
+                PowerSocketCommand::TurnOn | PowerSocketCommand::TurnOnFor(_),
+            ) => {
+                let circuits = self.circuits.lock().unwrap();
+                circuits
+                    .iter()
+                    .any(|c| c.member(&room, &device_name).is_some())
+                    .then_some(circuits)
+            }
+            _ => None,
+        };
+        if let Some(circuits) = &circuits {
+            // a stale version must not shed other sockets
+            let prepared = self
+                .check_version(&room, &device_name, expected)
+                .and_then(|_| self.prepare_circuit(circuits, &room, &device_name));
+            if let Err(err) = prepared {
+                return (Some(room), ExecutionResult::Error(err));
             }
         }
-        ExecutionResult::Error(CustomError::DeviceNotFound)
+        let mut devices = match self.devices.get_mut(&room) {
+            Some(devices) => devices,
+            None => return (None, ExecutionResult::Error(CustomError::DeviceNotFound)),
+        };
+        // versions of a room's devices change only while it is locked,
+        // so checking and bumping them is atomic
+        if let Err(err) = self.check_version(&room, &device_name, expected) {
+            return (Some(room), ExecutionResult::Error(err));
+        }
+        let device = match devices.iter_mut().find(|d| d.get_name() == device_name) {
+            Some(device) => device,
+            None => return (None, ExecutionResult::Error(CustomError::DeviceNotFound)),
+        };
+        let before = device.get_state();
+        let result = device.execute_command_at(data.clone(), now);
+        drop(circuits);
+        let read = matches!(
+            data,
+            DeviceCommand::PowerSocket(PowerSocketCommand::GetState)
+                | DeviceCommand::Thermometer(ThermometerCommand::GetTemperature)
+        );
+        if !read && !matches!(result, ExecutionResult::Error(_)) {
+            self.bump_version(&room, &device_name);
+        }
+        self.notify_change(&room, device, before);
+        (Some(room), result)
+    }
+    /// Room of the device named exactly `device_name`, in `room` if given.
+    fn locate(&self, room: Option<&str>, device_name: &str) -> CustomResult<String> {
+        if let Some(room) = room {
+            let devices = self
+                .devices
+                .get(&room.to_lowercase())
+                .ok_or(CustomError::RoomNotFound)?;
+            return match devices.iter().any(|d| d.get_name() == device_name) {
+                true => Ok(devices.key().to_owned()),
+                false => Err(CustomError::DeviceNotFound),
+            };
+        }
+        let rooms: Vec<String> = self
+            .devices
+            .iter()
+            .filter(|devices| devices.iter().any(|d| d.get_name() == device_name))
+            .map(|devices| devices.key().to_owned())
+            .collect();
+        match rooms.as_slice() {
+            [] => Err(CustomError::DeviceNotFound),
+            [room] => Ok(room.clone()),
+            _ => Err(CustomError::AmbiguousDevice(device_name.to_owned())),
+        }
+    }
+    /// Bus receiving `DeviceEvent::StateChanged` for every device state change
+    /// made through this list. Clones of the list share the bus.
+    pub fn events(&self) -> &EventBus {
+        &self.events
+    }
+    pub fn subscribe(&self) -> Receiver<DeviceEvent> {
+        self.events.subscribe()
+    }
+    fn notify_change(&self, room: &str, device: &SmartDevice, before: String) {
+        let state = device.get_state();
+        if state != before {
+            self.events.publish(DeviceEvent::StateChanged {
+                room: room.to_owned(),
+                device: device.get_name(),
+                state,
+            });
+        }
     }
+    /// Makes room on the socket's circuit (if any) before it is turned on.
+    /// Returns the sockets that were shed, none when the socket itself
+    /// refuses the load.
+    fn prepare_circuit(
+        &self,
+        circuits: &[Circuit],
+        room: &str,
+        device_name: &str,
+    ) -> CustomResult<Vec<CircuitMember>> {
+        let (circuit, member) = match circuits
+            .iter()
+            .find_map(|c| Some((c, c.member(room, device_name)?)))
+        {
+            Some(found) => found,
+            None => return Ok(Vec::new()),
+        };
+        let load = self.devices.get(&room.to_lowercase()).and_then(|devices| {
+            devices.iter().find_map(|d| match d {
+                SmartDevice::Socket(s) if s.name == device_name && !s.is_turned_on() => {
+                    Some(s.check_power_limit().map(|_| s.get_rated_power() as u32))
+                }
+                _ => None,
+            })
+        });
+        let load = match load {
+            Some(load) => load.map_err(CustomError::DeviceFault)?,
+            // unknown device or already on: nothing to check
+            None => return Ok(Vec::new()),
+        };
+        let powered = self.powered_members(circuit);
+        let shed = circuit
+            .plan_turn_on(member, load, &powered)
+            .ok_or_else(|| overload(circuit, load, &powered))?;
+        self.shed(&shed);
+        Ok(shed)
+    }
+    /// Turns off sockets shed from their circuit.
+    fn shed(&self, members: &[CircuitMember]) {
+        for member in members {
+            let mut devices = match self.devices.get_mut(&member.room) {
+                Some(devices) => devices,
+                None => continue,
+            };
+            for device in devices.iter_mut() {
+                if let SmartDevice::Socket(s) = device {
+                    if s.name.to_lowercase() == member.device {
+                        s.turn_off();
+                        self.bump_version(&member.room, &s.name);
+                        self.events.publish(DeviceEvent::StateChanged {
+                            room: member.room.clone(),
+                            device: s.name.clone(),
+                            state: format!("{:?}", s.get_state()),
+                        });
+                    }
+                }
+            }
+        }
+    }
+    fn powered_members(&self, circuit: &Circuit) -> Vec<(CircuitMember, u32)> {
+        self.map(|room, d| match d {
+            SmartDevice::Socket(s) if s.is_turned_on() => circuit
+                .member(room, &s.name)
+                .map(|m| (m.clone(), s.get_power_consumption() as u32)),
+            _ => None,
+        })
+        .into_iter()
+        .flatten()
+        .collect()
+    }
+    /// Returns copies of the devices matching the query.
+    /// Devices are cloned, so no map locks are held after the call returns.
+    pub fn query(&self, query: &DeviceQuery) -> Vec<DeviceEntry> {
+        let mut entries = Vec::new();
+        for room in self.devices.iter() {
+            for device in room.iter() {
+                if query.matches(room.key(), device) {
+                    entries.push(DeviceEntry {
+                        room: room.key().to_owned(),
+                        device: device.clone(),
+                        version: self.current_version(room.key(), &device.get_name()),
+                    });
+                }
+            }
+        }
+        query.paginate(entries)
+    }
+    /// Number of devices matching the query filters (pagination is ignored).
+    pub fn count(&self, query: &DeviceQuery) -> usize {
+        self.devices
+            .iter()
+            .map(|room| room.iter().filter(|d| query.matches(room.key(), d)).count())
+            .sum()
+    }
+    /// The device named `device` in `room`, or in any room when `room` is `None`.
+    /// Names are case insensitive. Without a room, a name used in more than one
+    /// room fails with `CustomError::AmbiguousDevice`.
+    pub fn resolve(&self, room: Option<&str>, device: &str) -> CustomResult<DeviceEntry> {
+        let name = device.to_lowercase();
+        let entry = |room: &str, d: &SmartDevice| DeviceEntry {
+            room: room.to_owned(),
+            device: d.clone(),
+            version: self.current_version(room, &d.get_name()),
+        };
+        if let Some(room) = room {
+            let devices = self
+                .devices
+                .get(&room.to_lowercase())
+                .ok_or(CustomError::RoomNotFound)?;
+            return devices
+                .iter()
+                .find(|d| d.get_name().to_lowercase() == name)
+                .map(|d| entry(devices.key(), d))
+                .ok_or(CustomError::DeviceNotFound);
+        }
+        let found =
+            self.map(|room, d| (d.get_name().to_lowercase() == name).then(|| entry(room, d)));
+        let mut found = found.into_iter().flatten();
+        match (found.next(), found.next()) {
+            (None, _) => Err(CustomError::DeviceNotFound),
+            (Some(entry), None) => Ok(entry),
+            (Some(_), Some(_)) => Err(CustomError::AmbiguousDevice(device.to_owned())),
+        }
+    }
+    /// Looks a device up by name in all rooms; names are case insensitive.
+    /// When several rooms have a device of that name, any of them is returned,
+    /// see `resolve`.
+    pub fn find(&self, device: &str) -> Option<DeviceEntry> {
+        let device = device.to_lowercase();
+        self.devices.iter().find_map(|room| {
+            room.iter()
+                .find(|d| d.get_name().to_lowercase() == device)
+                .map(|d| DeviceEntry {
+                    room: room.key().to_owned(),
+                    device: d.clone(),
+                    version: self.current_version(room.key(), &d.get_name()),
+                })
+        })
+    }
+    /// Point-in-time copy of every device in the list.
+    /// Each room is copied under its own lock, so the snapshot is consistent per room.
+    pub fn snapshot(&self) -> DeviceSnapshot {
+        DeviceSnapshot::new(self.query(&DeviceQuery::new()))
+    }
+    /// Calls `f` for every device. Map locks are held while `f` runs,
+    /// so `f` must not access this list (use `snapshot` for long operations).
+    pub fn for_each<F: FnMut(&str, &SmartDevice)>(&self, mut f: F) {
+        for room in self.devices.iter() {
+            for device in room.iter() {
+                f(room.key(), device);
+            }
+        }
+    }
+    /// Same locking rules as `for_each`.
+    pub fn map<T, F: FnMut(&str, &SmartDevice) -> T>(&self, mut f: F) -> Vec<T> {
+        let mut result = Vec::new();
+        self.for_each(|room, device| result.push(f(room, device)));
+        result
+    }
+    /// Gives `f` mutable access to a single device; the room lock is released when `f` returns.
+    /// Room and device names are case insensitive.
+    ///
+    /// A socket that `f` turns on is held to its circuit like one turned on by a command:
+    /// lower priority sockets are shed, or the socket is turned off again and
+    /// `CustomError::CircuitOverload` returned.
+    ///
+    /// `f` runs while the room is locked, it must not call back into the list
+    /// for the same room. Circuits are not locked while `f` runs.
+    pub fn with_device_mut<T, F: FnOnce(&mut SmartDevice) -> T>(
+        &self,
+        room: &str,
+        device: &str,
+        f: F,
+    ) -> CustomResult<T> {
+        let circuit = self.circuits.lock().unwrap().iter().find_map(|c| {
+            let member = c.member(room, device)?.clone();
+            Some((c.clone(), member))
+        });
+        // taken before the room is locked, `f` cannot change other rooms
+        let powered = circuit
+            .as_ref()
+            .map_or_else(Vec::new, |(c, _)| self.powered_members(c));
+        let mut room_devices = self
+            .devices
+            .get_mut(&room.to_lowercase())
+            .ok_or(CustomError::RoomNotFound)?;
+        let room_name = room_devices.key().to_owned();
+        let device = room_devices
+            .iter_mut()
+            .find(|d| d.get_name().to_lowercase() == device.to_lowercase())
+            .ok_or(CustomError::DeviceNotFound)?;
+        let unchanged = device.clone();
+        let before = device.get_state();
+        let was_on = matches!(device, SmartDevice::Socket(s) if s.is_turned_on());
+        let result = f(device);
+        let mut shed = Vec::new();
+        if let (Some((circuit, member)), SmartDevice::Socket(socket)) = (&circuit, &mut *device) {
+            if socket.is_turned_on() && !was_on {
+                let load = socket.get_power_consumption() as u32;
+                match circuit.plan_turn_on(member, load, &powered) {
+                    Some(members) => shed = members,
+                    None => {
+                        socket.turn_off();
+                        self.notify_change(&room_name, device, before);
+                        return Err(overload(circuit, load, &powered));
+                    }
+                }
+            }
+        }
+        if *device != unchanged {
+            self.bump_version(&room_name, &device.get_name());
+        }
+        self.notify_change(&room_name, device, before);
+        drop(room_devices);
+        self.shed(&shed);
+        Ok(result)
+    }
+    #[deprecated(note = "use `snapshot`, `for_each`, `map` or `with_device_mut` instead")]
     pub fn get_inner_list(&self) -> Arc<DashMap<String, Vec<SmartDevice>>> {
-        Arc::clone(&self.0)
+        Arc::clone(&self.devices)
     }
 }
 impl DeviceInfoProvider for SmartDeviceList {
     fn get_device_info(&self, room: &str, device: &str) -> CustomResult<DeviceInfo> {
         let room_devices = self
-            .0
+            .devices
             .get(&room.to_lowercase())
             .ok_or(CustomError::RoomNotFound)?;
         let device = room_devices
@@ -66,10 +1044,14 @@ impl DeviceInfoProvider for SmartDeviceList {
             .find(|&d| d.get_name().to_lowercase() == device)
             .ok_or(CustomError::DeviceNotFound)?;
 
-        Ok(DeviceInfo {
-            kind: device.get_type(),
-            name: device.get_name(),
-            state: device.get_state(),
-        })
+        Ok(self.device_info(room_devices.key(), device))
+    }
+}
+
+fn overload(circuit: &Circuit, load: u32, powered: &[(CircuitMember, u32)]) -> CustomError {
+    CustomError::CircuitOverload {
+        circuit: circuit.get_name().to_owned(),
+        load: load + powered.iter().map(|(_, w)| w).sum::<u32>(),
+        limit: circuit.get_max_watts(),
     }
 }
@@ -0,0 +1,231 @@
+use crate::{PowerSocketState, SmartDevice};
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::collections::HashSet;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceKind {
+    Socket,
+    Thermometer,
+}
+impl DeviceKind {
+    pub fn of(device: &SmartDevice) -> Self {
+        match device {
+            SmartDevice::Socket(_) => DeviceKind::Socket,
+            SmartDevice::Thermo(_) => DeviceKind::Thermometer,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SortKey {
+    Room,
+    Name,
+    Kind,
+    /// thermometers are compared by temperature, sockets by power consumption
+    Reading,
+}
+
+/// Device found by a query, together with the room it is stored in.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeviceEntry {
+    pub room: String,
+    pub device: SmartDevice,
+    /// see `SmartDeviceList::version`
+    #[serde(default)]
+    pub version: u64,
+}
+
+/// Filter, sort and pagination options for `SmartDeviceList::query`.
+/// All filters are combined with logical AND; an empty query matches every device.
+#[derive(Debug, Clone, Default)]
+pub struct DeviceQuery {
+    rooms: Option<HashSet<String>>,
+    name_pattern: Option<String>,
+    kind: Option<DeviceKind>,
+    powered: Option<bool>,
+    temperature_above: Option<f32>,
+    temperature_below: Option<f32>,
+    sort: Option<(SortKey, bool)>,
+    offset: usize,
+    limit: Option<usize>,
+}
+
+impl DeviceQuery {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// room names are case insensitive
+    pub fn in_rooms<S: AsRef<str>>(mut self, rooms: &[S]) -> Self {
+        self.rooms = Some(rooms.iter().map(|r| r.as_ref().to_lowercase()).collect());
+        self
+    }
+    /// case insensitive glob pattern: `*` matches any sequence, `?` matches one character
+    pub fn name_matches(mut self, pattern: &str) -> Self {
+        self.name_pattern = Some(pattern.to_lowercase());
+        self
+    }
+    pub fn kind(mut self, kind: DeviceKind) -> Self {
+        self.kind = Some(kind);
+        self
+    }
+    /// only sockets that are turned on
+    pub fn powered(mut self) -> Self {
+        self.powered = Some(true);
+        self
+    }
+    /// only sockets that are turned off
+    pub fn not_powered(mut self) -> Self {
+        self.powered = Some(false);
+        self
+    }
+    /// only thermometers strictly above the threshold (in celsius)
+    pub fn temperature_above(mut self, celsius: f32) -> Self {
+        self.temperature_above = Some(celsius);
+        self
+    }
+    /// only thermometers strictly below the threshold (in celsius)
+    pub fn temperature_below(mut self, celsius: f32) -> Self {
+        self.temperature_below = Some(celsius);
+        self
+    }
+    pub fn sort_by(mut self, key: SortKey) -> Self {
+        self.sort = Some((key, false));
+        self
+    }
+    pub fn sort_by_desc(mut self, key: SortKey) -> Self {
+        self.sort = Some((key, true));
+        self
+    }
+    /// skips `offset` matches and returns at most `limit` of the rest
+    pub fn page(mut self, offset: usize, limit: usize) -> Self {
+        self.offset = offset;
+        self.limit = Some(limit);
+        self
+    }
+
+    pub fn matches(&self, room: &str, device: &SmartDevice) -> bool {
+        if let Some(rooms) = &self.rooms {
+            if !rooms.contains(&room.to_lowercase()) {
+                return false;
+            }
+        }
+        if let Some(pattern) = &self.name_pattern {
+            if !glob_match(pattern, &device.get_name().to_lowercase()) {
+                return false;
+            }
+        }
+        if let Some(kind) = self.kind {
+            if DeviceKind::of(device) != kind {
+                return false;
+            }
+        }
+        if let Some(powered) = self.powered {
+            match device {
+                SmartDevice::Socket(s) if s.is_turned_on() == powered => {}
+                _ => return false,
+            }
+        }
+        if self.temperature_above.is_some() || self.temperature_below.is_some() {
+            let celsius = match device {
+                SmartDevice::Thermo(t) => t.get_temperature().as_celsius_f32(),
+                _ => return false,
+            };
+            if self.temperature_above.is_some_and(|min| celsius <= min)
+                || self.temperature_below.is_some_and(|max| celsius >= max)
+            {
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Sorts and paginates entries that already passed `matches`.
+    pub(crate) fn paginate(&self, mut entries: Vec<DeviceEntry>) -> Vec<DeviceEntry> {
+        // DashMap iteration order is unspecified, so pagination needs a stable order
+        // even when no explicit sort was requested.
+        let (key, desc) = self.sort.unwrap_or((SortKey::Room, false));
+        entries.sort_by(|a, b| {
+            let ord = compare(key, a, b)
+                .then_with(|| a.room.cmp(&b.room))
+                .then_with(|| a.device.get_name().cmp(&b.device.get_name()));
+            if desc {
+                ord.reverse()
+            } else {
+                ord
+            }
+        });
+        entries
+            .into_iter()
+            .skip(self.offset)
+            .take(self.limit.unwrap_or(usize::MAX))
+            .collect()
+    }
+}
+
+fn compare(key: SortKey, a: &DeviceEntry, b: &DeviceEntry) -> Ordering {
+    match key {
+        SortKey::Room => a.room.cmp(&b.room),
+        SortKey::Name => a
+            .device
+            .get_name()
+            .to_lowercase()
+            .cmp(&b.device.get_name().to_lowercase()),
+        SortKey::Kind => a.device.get_type().cmp(&b.device.get_type()),
+        SortKey::Reading => reading(&a.device)
+            .partial_cmp(&reading(&b.device))
+            .unwrap_or(Ordering::Equal),
+    }
+}
+
+fn reading(device: &SmartDevice) -> f32 {
+    match device {
+        SmartDevice::Thermo(t) => t.get_temperature().as_celsius_f32(),
+        SmartDevice::Socket(s) => match s.get_state() {
+            PowerSocketState::Powered(p) => p as f32,
+            PowerSocketState::NotPowered => 0.,
+        },
+    }
+}
+
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    let mut backtrack: Option<(usize, usize)> = None;
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, t));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match backtrack {
+                Some((bp, bt)) => {
+                    p = bp + 1;
+                    t = bt + 1;
+                    backtrack = Some((bp, bt + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+#[cfg(test)]
+mod test {
+    use super::glob_match;
+    #[test]
+    fn glob() {
+        assert!(glob_match("socket*", "socket1"));
+        assert!(glob_match("*1", "socket1"));
+        assert!(glob_match("s?ck*t?", "socket1"));
+        assert!(glob_match("*", ""));
+        assert!(!glob_match("therm*", "socket1"));
+        assert!(!glob_match("socket", "socket1"));
+    }
+}
@@ -0,0 +1,61 @@
+use super::DeviceEntry;
+use crate::SmartDevice;
+use std::time::SystemTime;
+
+/// Immutable copy of the device list taken at `taken_at`.
+/// Holds no locks on the original list, so it can be kept around as long as needed.
+#[derive(Debug, Clone)]
+pub struct DeviceSnapshot {
+    taken_at: SystemTime,
+    entries: Vec<DeviceEntry>,
+}
+
+impl DeviceSnapshot {
+    pub(crate) fn new(mut entries: Vec<DeviceEntry>) -> Self {
+        entries.sort_by(|a, b| {
+            a.room
+                .cmp(&b.room)
+                .then_with(|| a.device.get_name().cmp(&b.device.get_name()))
+        });
+        Self {
+            taken_at: SystemTime::now(),
+            entries,
+        }
+    }
+    pub fn taken_at(&self) -> SystemTime {
+        self.taken_at
+    }
+    /// entries ordered by room, then by device name
+    pub fn iter(&self) -> impl Iterator<Item = &DeviceEntry> {
+        self.entries.iter()
+    }
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+    pub fn rooms(&self) -> Vec<&str> {
+        let mut rooms: Vec<&str> = self.entries.iter().map(|e| e.room.as_str()).collect();
+        rooms.dedup();
+        rooms
+    }
+    /// room and device names are case insensitive
+    pub fn get(&self, room: &str, device: &str) -> Option<&SmartDevice> {
+        self.entries
+            .iter()
+            .find(|e| {
+                e.room == room.to_lowercase()
+                    && e.device.get_name().to_lowercase() == device.to_lowercase()
+            })
+            .map(|e| &e.device)
+    }
+}
+
+impl IntoIterator for DeviceSnapshot {
+    type Item = DeviceEntry;
+    type IntoIter = std::vec::IntoIter<DeviceEntry>;
+    fn into_iter(self) -> Self::IntoIter {
+        self.entries.into_iter()
+    }
+}
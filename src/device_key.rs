@@ -0,0 +1,38 @@
+//! Keys of the per-device state kept beside the device list: lowercase room
+//! and device names, so lookups are case insensitive like the list itself.
+
+use std::collections::HashMap;
+
+pub(crate) type DeviceKey = (String, String);
+
+pub(crate) fn device_key(room: &str, device: &str) -> DeviceKey {
+    (room.to_lowercase(), device.to_lowercase())
+}
+
+/// Moves the entries of the devices in `room` to `new_name`.
+pub(crate) fn rename_room_keys<T>(map: &mut HashMap<DeviceKey, T>, room: &str, new_name: &str) {
+    let room = room.to_lowercase();
+    let moved: Vec<_> = map.keys().filter(|(r, _)| *r == room).cloned().collect();
+    for old in moved {
+        let value = map.remove(&old).unwrap();
+        map.insert(device_key(new_name, &old.1), value);
+    }
+}
+
+/// Moves the entry of `device` in `room` to `new_name`.
+pub(crate) fn rename_device_key<T>(
+    map: &mut HashMap<DeviceKey, T>,
+    room: &str,
+    device: &str,
+    new_name: &str,
+) {
+    if let Some(value) = map.remove(&device_key(room, device)) {
+        map.insert(device_key(room, new_name), value);
+    }
+}
+
+/// Drops the entries of the devices in `room`.
+pub(crate) fn remove_room_keys<T>(map: &mut HashMap<DeviceKey, T>, room: &str) {
+    let room = room.to_lowercase();
+    map.retain(|(r, _), _| *r != room);
+}
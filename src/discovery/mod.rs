@@ -0,0 +1,325 @@
+//! Discovery of devices on the local network.
+//!
+//! Devices run a `DeviceAnnouncer` that sends an `Announcement` as a JSON datagram
+//! every few seconds, usually to the broadcast address or the multicast group on
+//! `DISCOVERY_PORT`. The controller listens with a `Discovery` and keeps the latest
+//! announcement of every device, so installers can adopt new devices into a room
+//! instead of entering them by hand.
+
+use crate::{
+    Capabilities, ConfigChange, CustomError, CustomResult, PowerSocket, SmartDevice,
+    SmartDeviceList, SmartHouse, Temperature, Thermometer,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::net::{Ipv4Addr, SocketAddr, ToSocketAddrs, UdpSocket};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+/// Port announcements are sent to by default.
+pub const DISCOVERY_PORT: u16 = 47810;
+/// Multicast group announcements are sent to by default.
+pub const DISCOVERY_GROUP: Ipv4Addr = Ipv4Addr::new(239, 255, 71, 10);
+/// Time between two announcements of a `DeviceAnnouncer`.
+pub const DEFAULT_ANNOUNCE_INTERVAL: Duration = Duration::from_secs(5);
+
+const MAX_DATAGRAM: usize = 8 * 1024;
+
+/// What a device tells about itself.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Announcement {
+    /// same as `SmartDevice::get_type`
+    pub kind: String,
+    pub name: String,
+    /// where the device can be reached
+    pub address: SocketAddr,
+    /// missing for devices that do not describe themselves
+    #[serde(default)]
+    pub capabilities: Option<Capabilities>,
+}
+
+impl Announcement {
+    pub fn new(device: &SmartDevice, address: SocketAddr) -> Self {
+        Self {
+            kind: device.get_type(),
+            name: device.get_name(),
+            address,
+            capabilities: Some(device.capabilities()),
+        }
+    }
+    /// A device in its initial state, ready to be added to a room.
+    pub fn to_device(&self) -> CustomResult<SmartDevice> {
+        let device = match self.kind.as_str() {
+            "SmartSocket" => SmartDevice::Socket(
+                PowerSocket::new(&self.name, 0)
+                    .with_description(&format!("discovered at {}", self.address)),
+            ),
+            "SmartThermometer" => {
+                SmartDevice::Thermo(Thermometer::new(&self.name, Temperature::Celsius(0.)))
+            }
+            kind => {
+                return Err(CustomError::ParseError(format!(
+                    "unknown device kind {}",
+                    kind
+                )))
+            }
+        };
+        Ok(device)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+enum Message {
+    Announce(Announcement),
+}
+
+/// Sends the announcement of a device.
+pub struct DeviceAnnouncer {
+    socket: UdpSocket,
+    target: SocketAddr,
+    announcement: Announcement,
+    interval: Duration,
+}
+
+impl DeviceAnnouncer {
+    /// `target` is a broadcast, multicast or unicast address.
+    pub fn new<A: ToSocketAddrs>(announcement: Announcement, target: A) -> CustomResult<Self> {
+        let io_err = |e: std::io::Error| CustomError::ConnectionError(e.to_string());
+        let target = target
+            .to_socket_addrs()
+            .map_err(io_err)?
+            .next()
+            .ok_or_else(|| CustomError::ConnectionError("no target address".into()))?;
+        let local: SocketAddr = match target {
+            SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+            SocketAddr::V6(_) => "[::]:0".parse().unwrap(),
+        };
+        let socket = UdpSocket::bind(local).map_err(io_err)?;
+        socket.set_broadcast(true).map_err(io_err)?;
+        Ok(Self {
+            socket,
+            target,
+            announcement,
+            interval: DEFAULT_ANNOUNCE_INTERVAL,
+        })
+    }
+    pub fn with_interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+    /// Sends the announcement once.
+    pub fn announce(&self) -> CustomResult<()> {
+        let message = serde_json::to_vec(&Message::Announce(self.announcement.clone()))
+            .map_err(|e| CustomError::ParseError(e.to_string()))?;
+        self.socket
+            .send_to(&message, self.target)
+            .map_err(|e| CustomError::ConnectionError(e.to_string()))?;
+        Ok(())
+    }
+    /// Announces every interval on a background thread until the handle is stopped or dropped.
+    pub fn spawn(self) -> AnnouncerHandle {
+        let (stop, stopped) = mpsc::channel();
+        thread::spawn(move || loop {
+            // a lost datagram is repeated with the next announcement
+            self.announce().ok();
+            match stopped.recv_timeout(self.interval) {
+                Err(RecvTimeoutError::Timeout) => continue,
+                _ => break,
+            }
+        });
+        AnnouncerHandle { stop }
+    }
+}
+
+/// Stops the announcer when dropped.
+pub struct AnnouncerHandle {
+    stop: Sender<()>,
+}
+
+impl AnnouncerHandle {
+    pub fn stop(self) {
+        self.stop.send(()).ok();
+    }
+}
+
+/// A device heard on the network.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Discovered {
+    /// the latest announcement
+    pub announcement: Announcement,
+    pub first_seen: SystemTime,
+    pub last_seen: SystemTime,
+}
+
+/// Listens for announcements.
+pub struct Discovery {
+    socket: UdpSocket,
+    handle: DiscoveryHandle,
+    devices: Option<SmartDeviceList>,
+}
+
+impl Discovery {
+    /// Use `("0.0.0.0", DISCOVERY_PORT)` to hear broadcasts on the local network.
+    pub fn bind<A: ToSocketAddrs>(addr: A) -> CustomResult<Self> {
+        let socket =
+            UdpSocket::bind(addr).map_err(|e| CustomError::ConnectionError(e.to_string()))?;
+        let addr = socket
+            .local_addr()
+            .map_err(|e| CustomError::ConnectionError(e.to_string()))?;
+        Ok(Self {
+            socket,
+            handle: DiscoveryHandle {
+                addr,
+                found: Arc::default(),
+            },
+            devices: None,
+        })
+    }
+    /// Also hears announcements sent to the multicast `group`.
+    pub fn join_multicast(self, group: Ipv4Addr) -> CustomResult<Self> {
+        self.socket
+            .join_multicast_v4(&group, &Ipv4Addr::UNSPECIFIED)
+            .map_err(|e| CustomError::ConnectionError(e.to_string()))?;
+        Ok(self)
+    }
+    /// Announcements of devices already in the list count as their heartbeats.
+    pub fn with_heartbeats(mut self, devices: SmartDeviceList) -> Self {
+        self.devices = Some(devices);
+        self
+    }
+    pub fn local_addr(&self) -> SocketAddr {
+        self.handle.addr
+    }
+    pub fn handle(&self) -> DiscoveryHandle {
+        self.handle.clone()
+    }
+
+    /// Waits up to `timeout` for a datagram and records the announcement in it.
+    /// Returns `None` on timeout and for datagrams that are not announcements.
+    pub fn receive(&self, timeout: Duration) -> CustomResult<Option<Announcement>> {
+        let io_err = |e: std::io::Error| CustomError::ConnectionError(e.to_string());
+        self.socket
+            .set_read_timeout(Some(timeout.max(Duration::from_millis(1))))
+            .map_err(io_err)?;
+        let mut buf = [0; MAX_DATAGRAM];
+        let len = match self.socket.recv_from(&mut buf) {
+            Ok((len, _)) => len,
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+                ) =>
+            {
+                return Ok(None)
+            }
+            Err(e) => return Err(io_err(e)),
+        };
+        let Ok(Message::Announce(announcement)) = serde_json::from_slice(&buf[..len]) else {
+            return Ok(None);
+        };
+        self.handle.record(&announcement);
+        if let Some(devices) = &self.devices {
+            // announcements do not name a room, names used in several rooms are skipped
+            if let Ok(entry) = devices.resolve(None, &announcement.name) {
+                devices.heartbeat(&entry.room, &entry.device.get_name());
+            }
+        }
+        Ok(Some(announcement))
+    }
+    /// Listens forever.
+    pub fn run(self) {
+        loop {
+            if self.receive(Duration::from_secs(60)).is_err() {
+                // e.g. ICMP errors reported on some platforms, keep listening
+                thread::sleep(Duration::from_millis(100));
+            }
+        }
+    }
+    /// Listens on a background thread.
+    pub fn spawn(self) -> DiscoveryHandle {
+        let handle = self.handle();
+        thread::spawn(move || self.run());
+        handle
+    }
+}
+
+/// Devices heard by a `Discovery`, shared with it.
+#[derive(Debug, Clone)]
+pub struct DiscoveryHandle {
+    addr: SocketAddr,
+    /// by lowercase device name
+    found: Arc<Mutex<BTreeMap<String, Discovered>>>,
+}
+
+impl DiscoveryHandle {
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+    /// Every device heard so far, ordered by name.
+    pub fn discovered(&self) -> Vec<Discovered> {
+        self.found.lock().unwrap().values().cloned().collect()
+    }
+    /// Device names are case insensitive.
+    pub fn get(&self, name: &str) -> Option<Discovered> {
+        self.found
+            .lock()
+            .unwrap()
+            .get(&name.to_lowercase())
+            .cloned()
+    }
+    /// Discovered devices that are not in the list yet.
+    pub fn new_devices(&self, devices: &SmartDeviceList) -> Vec<Discovered> {
+        self.discovered()
+            .into_iter()
+            .filter(|d| devices.find(&d.announcement.name).is_none())
+            .collect()
+    }
+    /// Waits until the device is heard, up to `timeout`.
+    pub fn wait_for(&self, name: &str, timeout: Duration) -> Option<Discovered> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            if let Some(found) = self.get(name) {
+                return Some(found);
+            }
+            if Instant::now() >= deadline {
+                return None;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+    /// Adds the discovered device to `room` of both the house and the device list.
+    pub fn adopt(
+        &self,
+        name: &str,
+        room: &str,
+        house: &mut SmartHouse,
+        devices: &mut SmartDeviceList,
+    ) -> CustomResult<SmartDevice> {
+        let found = self.get(name).ok_or(CustomError::DeviceNotFound)?;
+        let device = found.announcement.to_device()?;
+        let change = ConfigChange::AddDevice {
+            room: room.to_owned(),
+            device: device.clone(),
+        };
+        change.apply(house, devices)?;
+        Ok(device)
+    }
+
+    fn record(&self, announcement: &Announcement) {
+        let now = SystemTime::now();
+        let mut found = self.found.lock().unwrap();
+        found
+            .entry(announcement.name.to_lowercase())
+            .and_modify(|d| {
+                d.announcement = announcement.clone();
+                d.last_seen = now;
+            })
+            .or_insert_with(|| Discovered {
+                announcement: announcement.clone(),
+                first_seen: now,
+                last_seen: now,
+            });
+    }
+}
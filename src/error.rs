@@ -1,11 +1,13 @@
+use crate::DeviceFault;
 use serde::{Deserialize, Serialize};
 use std::fmt::Debug;
 use thiserror::Error;
 
-#[derive(Debug, Serialize, Deserialize, Error)]
+#[derive(Debug, Clone, Serialize, Deserialize, Error)]
 pub enum CustomError {
+    /// a command failed on the device or on the way to it
     #[error("Error in device: {0}")]
-    DeviceFailure(String),
+    DeviceFault(DeviceFault),
     #[error("Cannot add room")]
     AddRoomError,
     #[error("Cannot add device")]
@@ -14,8 +16,53 @@ pub enum CustomError {
     DeviceNotFound,
     #[error("Room not found")]
     RoomNotFound,
+    /// a device name used in several rooms, given without a room
+    #[error("Device {0} is in more than one room")]
+    AmbiguousDevice(String),
     #[error("Unknown error")]
     Unknown,
     #[error("Failed to execute command. Message: {0}")]
     CommandExecutionFailure(String),
+    #[error("Invalid command code: {0}")]
+    InvalidCommandCode(String),
+    #[error("Invalid {parameter} for {command}: {reason}")]
+    InvalidParameter {
+        command: String,
+        parameter: String,
+        reason: String,
+    },
+    #[error("Failed to parse: {0}")]
+    ParseError(String),
+    #[error("Version conflict on {device}: expected {expected}, found {actual}")]
+    VersionConflict {
+        device: String,
+        expected: u64,
+        actual: u64,
+    },
+    #[error("Circuit {circuit} overloaded: {load} W requested, limit is {limit} W")]
+    CircuitOverload {
+        circuit: String,
+        load: u32,
+        limit: u32,
+    },
+    #[error("Invalid circuit: {0}")]
+    InvalidCircuit(String),
+    #[error("Connection error: {0}")]
+    ConnectionError(String),
+    #[error("Storage error: {0}")]
+    StorageError(String),
+    #[error("Authentication failed: {0}")]
+    AuthenticationFailed(String),
+    #[error("Permission denied: {0}")]
+    PermissionDenied(String),
+    #[error("TLS error: {0}")]
+    TlsError(String),
+    #[error("History error: {0}")]
+    HistoryError(String),
+}
+
+impl From<DeviceFault> for CustomError {
+    fn from(fault: DeviceFault) -> Self {
+        CustomError::DeviceFault(fault)
+    }
 }
@@ -0,0 +1,40 @@
+use crate::alarm::AlarmEvent;
+use serde::{Deserialize, Serialize};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum DeviceEvent {
+    /// `state` is formatted the same way as `DeviceInfo::state`
+    StateChanged {
+        room: String,
+        device: String,
+        state: String,
+    },
+    Alarm(AlarmEvent),
+}
+
+/// Broadcasts events to every subscriber. Cloned buses share subscribers.
+#[derive(Debug, Clone, Default)]
+pub struct EventBus(Arc<Mutex<Vec<Sender<DeviceEvent>>>>);
+
+impl EventBus {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Subscribers that dropped their receiver are removed on the next publish.
+    pub fn subscribe(&self) -> Receiver<DeviceEvent> {
+        let (tx, rx) = channel();
+        self.0.lock().unwrap().push(tx);
+        rx
+    }
+    pub fn publish(&self, event: DeviceEvent) {
+        self.0
+            .lock()
+            .unwrap()
+            .retain(|tx| tx.send(event.clone()).is_ok());
+    }
+    pub fn subscriber_count(&self) -> usize {
+        self.0.lock().unwrap().len()
+    }
+}
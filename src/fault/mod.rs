@@ -0,0 +1,280 @@
+//! Device failures and a harness injecting them.
+//!
+//! A failed command carries a `DeviceFault` in `CustomError::DeviceFault`, telling
+//! callers what went wrong and whether trying again may help. Real devices only
+//! reject commands they do not understand; the other kinds come from the network
+//! or the hardware and can be injected per device through a `FaultInjector`.
+
+use crate::device_key::{
+    device_key, remove_room_keys, rename_device_key, rename_room_keys, DeviceKey,
+};
+use crate::SimRng;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum FaultKind {
+    /// the command never reached the device
+    Unreachable,
+    /// the device executed the command but its answer was lost,
+    /// so the caller cannot tell whether it took effect
+    Timeout,
+    /// the device is broken and did not execute the command
+    HardwareFault,
+    /// the device refused the command, e.g. because it does not support it
+    CommandRejected,
+}
+
+impl FaultKind {
+    /// Faults that may go away when the command is sent again.
+    pub fn is_transient(&self) -> bool {
+        matches!(self, FaultKind::Unreachable | FaultKind::Timeout)
+    }
+    /// Whether the command was executed despite the failure.
+    pub fn reaches_device(&self) -> bool {
+        matches!(self, FaultKind::Timeout)
+    }
+}
+
+impl fmt::Display for FaultKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            FaultKind::Unreachable => "unreachable",
+            FaultKind::Timeout => "timed out",
+            FaultKind::HardwareFault => "hardware fault",
+            FaultKind::CommandRejected => "command rejected",
+        })
+    }
+}
+
+/// Why a command failed on a device.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DeviceFault {
+    pub device: String,
+    pub kind: FaultKind,
+    /// free form explanation, may be empty
+    #[serde(default)]
+    pub detail: String,
+}
+
+impl DeviceFault {
+    pub fn new(device: &str, kind: FaultKind) -> Self {
+        Self {
+            device: device.to_owned(),
+            kind,
+            detail: String::new(),
+        }
+    }
+    pub fn with_detail(mut self, detail: &str) -> Self {
+        self.detail = detail.to_owned();
+        self
+    }
+}
+
+impl fmt::Display for DeviceFault {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.device, self.kind)?;
+        if !self.detail.is_empty() {
+            write!(f, ": {}", self.detail)?;
+        }
+        Ok(())
+    }
+}
+
+impl Error for DeviceFault {}
+
+/// A fault to inject; by default every command to the device fails.
+#[derive(Debug, Clone)]
+pub struct Fault {
+    kind: FaultKind,
+    detail: String,
+    /// commands left to fail, unlimited when `None`
+    remaining: Option<u32>,
+    probability: f64,
+}
+
+impl Fault {
+    pub fn new(kind: FaultKind) -> Self {
+        Self {
+            kind,
+            detail: String::new(),
+            remaining: None,
+            probability: 1.0,
+        }
+    }
+    /// Only the next `count` failing commands fail, then the device recovers.
+    pub fn times(mut self, count: u32) -> Self {
+        self.remaining = Some(count);
+        self
+    }
+    /// Each command fails with probability `p`, drawn from the injector's seeded generator.
+    pub fn probability(mut self, p: f64) -> Self {
+        self.probability = p.clamp(0.0, 1.0);
+        self
+    }
+    pub fn detail(mut self, detail: &str) -> Self {
+        self.detail = detail.to_owned();
+        self
+    }
+}
+
+#[derive(Debug)]
+struct Injector {
+    /// by lowercase room and device name
+    faults: HashMap<DeviceKey, Fault>,
+    /// number of injected failures by lowercase room and device name
+    triggered: HashMap<DeviceKey, u32>,
+    /// by lowercase room and device name
+    latency: HashMap<DeviceKey, Duration>,
+    rng: SimRng,
+}
+
+/// Faults injected into commands executed through a `SmartDeviceList`.
+/// Clones share the same faults.
+#[derive(Debug, Clone)]
+pub struct FaultInjector(Arc<Mutex<Injector>>);
+
+impl Default for FaultInjector {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FaultInjector {
+    pub fn new() -> Self {
+        Self(Arc::new(Mutex::new(Injector {
+            faults: HashMap::new(),
+            triggered: HashMap::new(),
+            latency: HashMap::new(),
+            rng: SimRng::new(0),
+        })))
+    }
+    /// Restarts the generator used for probabilistic faults.
+    pub fn seed(&self, seed: u64) {
+        self.0.lock().unwrap().rng = SimRng::new(seed);
+    }
+    /// Replaces any fault injected into the device before.
+    pub fn inject(&self, room: &str, device: &str, fault: Fault) {
+        let mut injector = self.0.lock().unwrap();
+        if fault.remaining == Some(0) {
+            injector.faults.remove(&device_key(room, device));
+        } else {
+            injector.faults.insert(device_key(room, device), fault);
+        }
+    }
+    /// Every command to the device takes `latency` before it reaches the device.
+    pub fn set_latency(&self, room: &str, device: &str, latency: Duration) {
+        let mut injector = self.0.lock().unwrap();
+        injector.latency.insert(device_key(room, device), latency);
+    }
+    /// Lets the device work again.
+    pub fn clear(&self, room: &str, device: &str) {
+        let mut injector = self.0.lock().unwrap();
+        injector.faults.remove(&device_key(room, device));
+        injector.latency.remove(&device_key(room, device));
+    }
+    pub fn clear_all(&self) {
+        let mut injector = self.0.lock().unwrap();
+        injector.faults.clear();
+        injector.latency.clear();
+    }
+    pub fn is_faulty(&self, room: &str, device: &str) -> bool {
+        self.0
+            .lock()
+            .unwrap()
+            .faults
+            .contains_key(&device_key(room, device))
+    }
+    /// How many commands to the device failed because of injected faults.
+    pub fn triggered(&self, room: &str, device: &str) -> u32 {
+        let injector = self.0.lock().unwrap();
+        injector
+            .triggered
+            .get(&device_key(room, device))
+            .copied()
+            .unwrap_or_default()
+    }
+
+    pub(crate) fn latency(&self, room: &str, device: &str) -> Duration {
+        let injector = self.0.lock().unwrap();
+        injector
+            .latency
+            .get(&device_key(room, device))
+            .copied()
+            .unwrap_or_default()
+    }
+    /// Decides whether the next command to the device fails.
+    pub(crate) fn trigger(&self, room: &str, device: &str) -> Option<DeviceFault> {
+        let key = device_key(room, device);
+        let mut injector = self.0.lock().unwrap();
+        let Injector {
+            faults,
+            triggered,
+            rng,
+            ..
+        } = &mut *injector;
+        let fault = faults.get_mut(&key)?;
+        if fault.probability < 1.0 && !rng.chance(fault.probability) {
+            return None;
+        }
+        let result = DeviceFault::new(device, fault.kind).with_detail(&fault.detail);
+        if let Some(remaining) = &mut fault.remaining {
+            *remaining = remaining.saturating_sub(1);
+            if *remaining == 0 {
+                faults.remove(&key);
+            }
+        }
+        *triggered.entry(key).or_default() += 1;
+        Some(result)
+    }
+    /// Keeps the faults of the devices in `room` under its new name.
+    pub(crate) fn rename_room(&self, room: &str, new_name: &str) {
+        let mut injector = self.0.lock().unwrap();
+        let Injector {
+            faults,
+            triggered,
+            latency,
+            ..
+        } = &mut *injector;
+        rename_room_keys(faults, room, new_name);
+        rename_room_keys(triggered, room, new_name);
+        rename_room_keys(latency, room, new_name);
+    }
+    pub(crate) fn rename_device(&self, room: &str, device: &str, new_name: &str) {
+        let mut injector = self.0.lock().unwrap();
+        let Injector {
+            faults,
+            triggered,
+            latency,
+            ..
+        } = &mut *injector;
+        rename_device_key(faults, room, device, new_name);
+        rename_device_key(triggered, room, device, new_name);
+        rename_device_key(latency, room, device, new_name);
+    }
+    /// Forgets the faults of a removed device, so a device added under
+    /// its name later starts without them.
+    pub(crate) fn remove_device(&self, room: &str, device: &str) {
+        let key = device_key(room, device);
+        let mut injector = self.0.lock().unwrap();
+        injector.faults.remove(&key);
+        injector.triggered.remove(&key);
+        injector.latency.remove(&key);
+    }
+    pub(crate) fn remove_room(&self, room: &str) {
+        let mut injector = self.0.lock().unwrap();
+        let Injector {
+            faults,
+            triggered,
+            latency,
+            ..
+        } = &mut *injector;
+        remove_room_keys(faults, room);
+        remove_room_keys(triggered, room);
+        remove_room_keys(latency, room);
+    }
+}
@@ -0,0 +1,208 @@
+//! Liveness of devices.
+//!
+//! A device is seen whenever it answers a command or sends a heartbeat on its own.
+//! It is expected to be seen at least once per heartbeat interval and counts as
+//! unresponsive after missing `MISSED_HEARTBEATS` of them in a row.
+
+use crate::device_key::{
+    device_key, remove_room_keys, rename_device_key, rename_room_keys, DeviceKey,
+};
+use crate::policy::is_device_failure;
+use crate::{Availability, ExecutionResult};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+/// Heartbeat interval of devices without one of their own.
+pub const DEFAULT_HEARTBEAT_INTERVAL: Duration = Duration::from_secs(60);
+/// Heartbeats a device may miss before it is unresponsive.
+pub const MISSED_HEARTBEATS: u32 = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum HealthStatus {
+    /// never seen
+    #[default]
+    Unknown,
+    Healthy,
+    /// seen recently, but its last command failed or needed retries
+    Degraded,
+    /// missed its heartbeats or is offline
+    Unresponsive,
+}
+
+impl fmt::Display for HealthStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            HealthStatus::Unknown => "unknown",
+            HealthStatus::Healthy => "healthy",
+            HealthStatus::Degraded => "degraded",
+            HealthStatus::Unresponsive => "unresponsive",
+        })
+    }
+}
+
+/// Liveness of a device at a point in time.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DeviceHealth {
+    pub room: String,
+    pub device: String,
+    pub status: HealthStatus,
+    pub last_seen: Option<SystemTime>,
+    pub heartbeat_interval: Duration,
+    /// time the device has been responsive without interruption
+    pub uptime: Option<Duration>,
+    /// commands sent to the device
+    pub commands: u64,
+    /// commands that failed on the device
+    pub errors: u64,
+    pub consecutive_errors: u32,
+    pub last_error: Option<String>,
+}
+
+#[derive(Debug, Default)]
+struct Liveness {
+    last_seen: Option<SystemTime>,
+    up_since: Option<SystemTime>,
+    interval: Option<Duration>,
+    commands: u64,
+    errors: u64,
+    consecutive_errors: u32,
+    last_error: Option<String>,
+}
+
+#[derive(Debug)]
+struct State {
+    default_interval: Duration,
+    /// by lowercase room and device name
+    devices: HashMap<DeviceKey, Liveness>,
+}
+
+/// Liveness of the devices of a `SmartDeviceList`, shared by its clones.
+#[derive(Debug, Clone)]
+pub(crate) struct HealthMonitor(Arc<Mutex<State>>);
+
+impl Default for HealthMonitor {
+    fn default() -> Self {
+        Self(Arc::new(Mutex::new(State {
+            default_interval: DEFAULT_HEARTBEAT_INTERVAL,
+            devices: HashMap::new(),
+        })))
+    }
+}
+
+impl HealthMonitor {
+    pub(crate) fn set_default_interval(&self, interval: Duration) {
+        self.0.lock().unwrap().default_interval = interval;
+    }
+    pub(crate) fn set_interval(&self, room: &str, device: &str, interval: Duration) {
+        let mut state = self.0.lock().unwrap();
+        state
+            .devices
+            .entry(device_key(room, device))
+            .or_default()
+            .interval = Some(interval);
+    }
+    pub(crate) fn heartbeat(&self, room: &str, device: &str, time: SystemTime) {
+        let mut state = self.0.lock().unwrap();
+        let default_interval = state.default_interval;
+        let liveness = state.devices.entry(device_key(room, device)).or_default();
+        let interval = liveness.interval.unwrap_or(default_interval);
+        // coming back after missed heartbeats restarts the uptime
+        if liveness.up_since.is_none() || is_stale(liveness.last_seen, interval, time) {
+            liveness.up_since = Some(time);
+        }
+        liveness.last_seen = Some(time);
+        liveness.consecutive_errors = 0;
+    }
+    /// Counts a command that reached the device.
+    pub(crate) fn record(
+        &self,
+        room: &str,
+        device: &str,
+        result: &ExecutionResult,
+        time: SystemTime,
+    ) {
+        if !is_device_failure(result) {
+            self.heartbeat(room, device, time);
+        }
+        let mut state = self.0.lock().unwrap();
+        let liveness = state.devices.entry(device_key(room, device)).or_default();
+        liveness.commands += 1;
+        if let ExecutionResult::Error(err) = result {
+            if is_device_failure(result) {
+                liveness.errors += 1;
+                liveness.consecutive_errors += 1;
+                liveness.last_error = Some(err.to_string());
+                liveness.up_since = None;
+            }
+        }
+    }
+    pub(crate) fn health(
+        &self,
+        room: &str,
+        device: &str,
+        availability: Availability,
+        now: SystemTime,
+    ) -> DeviceHealth {
+        let state = self.0.lock().unwrap();
+        let empty = Liveness::default();
+        let liveness = state
+            .devices
+            .get(&device_key(room, device))
+            .unwrap_or(&empty);
+        let interval = liveness.interval.unwrap_or(state.default_interval);
+        let status = if availability == Availability::Offline {
+            HealthStatus::Unresponsive
+        } else if liveness.last_seen.is_none() && liveness.errors == 0 {
+            HealthStatus::Unknown
+        } else if is_stale(liveness.last_seen, interval, now) {
+            HealthStatus::Unresponsive
+        } else if liveness.consecutive_errors > 0 || availability == Availability::Degraded {
+            HealthStatus::Degraded
+        } else {
+            HealthStatus::Healthy
+        };
+        let uptime = match status {
+            HealthStatus::Healthy | HealthStatus::Degraded => liveness
+                .up_since
+                .map(|t| now.duration_since(t).unwrap_or_default()),
+            _ => None,
+        };
+        DeviceHealth {
+            room: room.to_owned(),
+            device: device.to_owned(),
+            status,
+            last_seen: liveness.last_seen,
+            heartbeat_interval: interval,
+            uptime,
+            commands: liveness.commands,
+            errors: liveness.errors,
+            consecutive_errors: liveness.consecutive_errors,
+            last_error: liveness.last_error.clone(),
+        }
+    }
+    /// Keeps the liveness of the devices in `room` under its new name.
+    pub(crate) fn rename_room(&self, room: &str, new_name: &str) {
+        rename_room_keys(&mut self.0.lock().unwrap().devices, room, new_name);
+    }
+    pub(crate) fn rename_device(&self, room: &str, device: &str, new_name: &str) {
+        rename_device_key(&mut self.0.lock().unwrap().devices, room, device, new_name);
+    }
+    /// Forgets the liveness of a removed device.
+    pub(crate) fn remove_device(&self, room: &str, device: &str) {
+        let mut state = self.0.lock().unwrap();
+        state.devices.remove(&device_key(room, device));
+    }
+    pub(crate) fn remove_room(&self, room: &str) {
+        remove_room_keys(&mut self.0.lock().unwrap().devices, room);
+    }
+}
+
+fn is_stale(last_seen: Option<SystemTime>, interval: Duration, now: SystemTime) -> bool {
+    match last_seen {
+        Some(t) => now.duration_since(t).unwrap_or_default() > interval * MISSED_HEARTBEATS,
+        None => true,
+    }
+}
@@ -0,0 +1,271 @@
+//! Reversible configuration changes of a house and its devices.
+//!
+//! Applying a `ConfigChange` returns the change that reverts it, capturing whatever
+//! it removed (a room's devices, its position, a device's state). `ConfigHistory`
+//! keeps those reverts on a bounded undo stack; undoing one pushes its own revert
+//! onto the redo stack.
+
+use crate::{CustomError, CustomResult, Room, SmartDevice, SmartDeviceList, SmartHouse};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::fmt;
+
+/// Number of changes a `ConfigHistory::default()` can undo.
+pub const DEFAULT_HISTORY_LIMIT: usize = 100;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum ConfigChange {
+    AddRoom {
+        room: String,
+        /// index in `SmartHouse::get_rooms`, the end when `None`
+        #[serde(default)]
+        position: Option<usize>,
+        /// devices added together with the room
+        #[serde(default)]
+        devices: Vec<SmartDevice>,
+    },
+    /// removes the room with its devices
+    RemoveRoom {
+        room: String,
+    },
+    AddDevice {
+        room: String,
+        device: SmartDevice,
+    },
+    RemoveDevice {
+        room: String,
+        device: String,
+    },
+    RenameRoom {
+        room: String,
+        to: String,
+    },
+    RenameDevice {
+        room: String,
+        device: String,
+        to: String,
+    },
+}
+
+impl ConfigChange {
+    /// An empty room at the end of the room list.
+    pub fn add_room(room: &str) -> Self {
+        ConfigChange::AddRoom {
+            room: room.to_owned(),
+            position: None,
+            devices: Vec::new(),
+        }
+    }
+
+    /// Applies the change to both the house and the device list and returns
+    /// the change reverting it. Nothing is changed when an error is returned.
+    pub fn apply(
+        &self,
+        house: &mut SmartHouse,
+        devices: &mut SmartDeviceList,
+    ) -> CustomResult<ConfigChange> {
+        match self {
+            ConfigChange::AddRoom {
+                room,
+                position,
+                devices: room_devices,
+            } => {
+                let position = position.unwrap_or(house.get_rooms().len());
+                let mut new_room = Room::with_name(room);
+                for device in room_devices {
+                    new_room.try_add_device(&device.get_name())?;
+                }
+                house.try_insert_room(position, new_room)?;
+                for device in room_devices {
+                    if let Err(err) = devices.add_device(room, device.clone()) {
+                        house.try_remove_room(room).ok();
+                        for device in room_devices {
+                            devices.remove_device(room, &device.get_name()).ok();
+                        }
+                        return Err(err);
+                    }
+                }
+                Ok(ConfigChange::RemoveRoom { room: room.clone() })
+            }
+            ConfigChange::RemoveRoom { room } => {
+                let position = house.room_position(room).ok_or(CustomError::RoomNotFound)?;
+                house.try_remove_room(room)?;
+                // rooms without devices may be missing from the device list
+                let mut removed = devices.remove_room(room).unwrap_or_default();
+                removed.sort_by_key(|d| d.get_name().to_lowercase());
+                Ok(ConfigChange::AddRoom {
+                    room: room.clone(),
+                    position: Some(position),
+                    devices: removed,
+                })
+            }
+            ConfigChange::AddDevice { room, device } => {
+                let name = device.get_name();
+                house.try_add_device(room, &name)?;
+                if let Err(err) = devices.add_device(room, device.clone()) {
+                    house.try_remove_device(room, &name.to_lowercase()).ok();
+                    return Err(err);
+                }
+                Ok(ConfigChange::RemoveDevice {
+                    room: room.clone(),
+                    device: name,
+                })
+            }
+            ConfigChange::RemoveDevice { room, device } => {
+                let removed = devices.remove_device(room, device)?;
+                if let Err(err) = house.try_remove_device(room, &device.to_lowercase()) {
+                    devices.add_device(room, removed).ok();
+                    return Err(err);
+                }
+                Ok(ConfigChange::AddDevice {
+                    room: room.clone(),
+                    device: removed,
+                })
+            }
+            ConfigChange::RenameRoom { room, to } => {
+                house.try_rename_room(room, to)?;
+                if let Err(err) = devices.rename_room(room, to) {
+                    house.try_rename_room(to, room).ok();
+                    return Err(err);
+                }
+                Ok(ConfigChange::RenameRoom {
+                    room: to.clone(),
+                    to: room.clone(),
+                })
+            }
+            ConfigChange::RenameDevice { room, device, to } => {
+                devices.rename_device(room, device, to)?;
+                if let Err(err) = house.try_rename_device(room, device, to) {
+                    devices.rename_device(room, to, device).ok();
+                    return Err(err);
+                }
+                Ok(ConfigChange::RenameDevice {
+                    room: room.clone(),
+                    device: to.clone(),
+                    to: device.clone(),
+                })
+            }
+        }
+    }
+}
+
+impl fmt::Display for ConfigChange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigChange::AddRoom { room, devices, .. } if devices.is_empty() => {
+                write!(f, "add room {}", room)
+            }
+            ConfigChange::AddRoom { room, devices, .. } => {
+                write!(f, "add room {} with {} devices", room, devices.len())
+            }
+            ConfigChange::RemoveRoom { room } => write!(f, "remove room {}", room),
+            ConfigChange::AddDevice { room, device } => {
+                write!(f, "add device {} to {}", device.get_name(), room)
+            }
+            ConfigChange::RemoveDevice { room, device } => {
+                write!(f, "remove device {} from {}", device, room)
+            }
+            ConfigChange::RenameRoom { room, to } => write!(f, "rename room {} to {}", room, to),
+            ConfigChange::RenameDevice { room, device, to } => {
+                write!(f, "rename device {}/{} to {}", room, device, to)
+            }
+        }
+    }
+}
+
+/// Undo and redo stacks of configuration changes. Only the last `limit`
+/// changes can be undone; applying a new change clears the redo stack.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ConfigHistory {
+    limit: usize,
+    /// reverts of the applied changes, most recent last
+    undo: VecDeque<ConfigChange>,
+    /// reverts of the undone changes, most recent last
+    redo: Vec<ConfigChange>,
+}
+
+impl Default for ConfigHistory {
+    fn default() -> Self {
+        Self::new(DEFAULT_HISTORY_LIMIT)
+    }
+}
+
+impl ConfigHistory {
+    pub fn new(limit: usize) -> Self {
+        Self {
+            limit,
+            undo: VecDeque::new(),
+            redo: Vec::new(),
+        }
+    }
+    pub fn is_empty(&self) -> bool {
+        self.undo.is_empty() && self.redo.is_empty()
+    }
+    pub fn can_undo(&self) -> bool {
+        !self.undo.is_empty()
+    }
+    pub fn can_redo(&self) -> bool {
+        !self.redo.is_empty()
+    }
+
+    pub fn apply(
+        &mut self,
+        change: ConfigChange,
+        house: &mut SmartHouse,
+        devices: &mut SmartDeviceList,
+    ) -> CustomResult<()> {
+        let revert = change.apply(house, devices)?;
+        self.undo.push_back(revert);
+        while self.undo.len() > self.limit {
+            self.undo.pop_front();
+        }
+        self.redo.clear();
+        Ok(())
+    }
+
+    /// Reverts the last applied change and returns the revert that was applied.
+    /// The change stays on the stack when it cannot be reverted, e.g. because
+    /// the house was modified without going through the history.
+    pub fn undo(
+        &mut self,
+        house: &mut SmartHouse,
+        devices: &mut SmartDeviceList,
+    ) -> CustomResult<ConfigChange> {
+        let revert = self
+            .undo
+            .pop_back()
+            .ok_or_else(|| CustomError::HistoryError("nothing to undo".into()))?;
+        match revert.apply(house, devices) {
+            Ok(redo) => {
+                self.redo.push(redo);
+                Ok(revert)
+            }
+            Err(err) => {
+                self.undo.push_back(revert);
+                Err(err)
+            }
+        }
+    }
+
+    /// Applies the last undone change again and returns it.
+    pub fn redo(
+        &mut self,
+        house: &mut SmartHouse,
+        devices: &mut SmartDeviceList,
+    ) -> CustomResult<ConfigChange> {
+        let change = self
+            .redo
+            .pop()
+            .ok_or_else(|| CustomError::HistoryError("nothing to redo".into()))?;
+        match change.apply(house, devices) {
+            Ok(revert) => {
+                self.undo.push_back(revert);
+                Ok(change)
+            }
+            Err(err) => {
+                self.redo.push(change);
+                Err(err)
+            }
+        }
+    }
+}
@@ -1,6 +1,11 @@
-use crate::{device_info_provider::DeviceInfoProvider, CustomError};
+use crate::{
+    device_info_provider::DeviceInfoProvider, CustomError, DeviceInfo, SmartDeviceList,
+    TemperatureUnit,
+};
+use serde::{Deserialize, Serialize};
 use std::collections::HashSet;
 use std::fmt::Write;
+use std::time::{Duration, SystemTime};
 
 pub type CustomResult<T> = Result<T, CustomError>;
 
@@ -17,7 +22,7 @@ impl Room {
         }
     }
     pub fn try_add_device(&mut self, name: &str) -> CustomResult<()> {
-        if let false = self.devices.insert(name.to_lowercase()) {
+        if !self.devices.insert(name.to_lowercase()) {
             return Err(CustomError::AddDeviceError);
         }
         Ok(())
@@ -25,7 +30,7 @@ impl Room {
     pub fn try_remove_device(&mut self, name: &str) -> CustomResult<()> {
         self.devices
             .remove(name)
-            .then(|| ())
+            .then_some(())
             .ok_or(CustomError::DeviceNotFound)
     }
     pub fn get_name(&self) -> &str {
@@ -33,14 +38,33 @@ impl Room {
     }
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ReportEntry {
+    pub room: String,
+    pub device: String,
+    pub info: Result<DeviceInfo, String>,
+}
+
 #[derive(Default, Debug)]
 pub struct SmartHouse {
     rooms: Vec<Room>,
+    temperature_unit: Option<TemperatureUnit>,
 }
 
 impl SmartHouse {
     pub fn new() -> Self {
-        Self { rooms: Vec::new() }
+        Self {
+            rooms: Vec::new(),
+            temperature_unit: None,
+        }
+    }
+    /// Unit used for thermometer readings in reports.
+    /// When not set, readings are reported in the unit the device provides.
+    pub fn set_temperature_unit(&mut self, unit: TemperatureUnit) {
+        self.temperature_unit = Some(unit);
+    }
+    pub fn temperature_unit(&self) -> Option<TemperatureUnit> {
+        self.temperature_unit
     }
     pub fn get_rooms(&self) -> Vec<&str> {
         self.rooms.iter().map(|r| r.name.as_str()).collect()
@@ -71,24 +95,74 @@ impl SmartHouse {
         Ok(room.unwrap().devices.iter().map(|d| d.as_str()).collect())
     }
 
+    /// Info of every device in the house. Thermometer readings are converted to the
+    /// preferred temperature unit, if any, and their state is shown as e.g. `21.5°C`.
+    pub fn get_report_entries<T: DeviceInfoProvider>(&self, provider: &T) -> Vec<ReportEntry> {
+        let mut entries = Vec::new();
+        for &room in self.get_rooms().iter() {
+            let mut devices = self.get_devices(room).unwrap();
+            devices.sort_unstable();
+            for device in devices {
+                let info = provider.get_device_info(room, device).map(|mut i| {
+                    if let Some(t) = i.temperature {
+                        let t = self.temperature_unit.map_or(t, |unit| t.convert(unit));
+                        i.temperature = Some(t);
+                        i.state = format!("{:.1}", t);
+                    }
+                    i
+                });
+                entries.push(ReportEntry {
+                    room: room.to_owned(),
+                    device: device.to_owned(),
+                    info: info.map_err(|err| err.to_string()),
+                });
+            }
+        }
+        entries
+    }
+
     pub fn get_report<T: DeviceInfoProvider>(&self, provider: &T) -> String {
+        let mut report = String::new();
+        for entry in self.get_report_entries(provider) {
+            let device_info = match entry.info {
+                Ok(info) => format!("{:?}", info),
+                Err(err) => err,
+            };
+            writeln!(&mut report, "room: {}, device: {}", entry.room, device_info).unwrap();
+        }
+        report
+    }
+    /// Liveness of every device in the house as of `now`, one line per device.
+    pub fn get_health_report(&self, devices: &SmartDeviceList, now: SystemTime) -> String {
         let mut report = String::new();
         for &room in self.get_rooms().iter() {
-            for device in self.get_devices(room).unwrap() {
-                let device_info: String = provider
-                    .get_device_info(room, device)
-                    .map(|i| format!("{:?}", i))
-                    .unwrap_or_else(|err| err.to_string());
-                writeln!(&mut report, "room: {}, device: {}", room, device_info).unwrap();
+            let mut names = self.get_devices(room).unwrap();
+            names.sort_unstable();
+            for name in names {
+                let health = match devices.device_health(room, name, now) {
+                    Ok(health) => health,
+                    Err(err) => {
+                        writeln!(&mut report, "room: {}, device: {}, {}", room, name, err).unwrap();
+                        continue;
+                    }
+                };
+                let seconds = |d: Duration| format!("{}s", d.as_secs());
+                let last_seen = health
+                    .last_seen
+                    .map(|t| format!("{} ago", seconds(now.duration_since(t).unwrap_or_default())))
+                    .unwrap_or_else(|| "never".into());
+                let uptime = health.uptime.map(seconds).unwrap_or_else(|| "-".into());
+                writeln!(
+                    &mut report,
+                    "room: {}, device: {}, status: {}, last seen: {}, uptime: {}, errors: {}/{}",
+                    room, name, health.status, last_seen, uptime, health.errors, health.commands
+                )
+                .unwrap();
             }
         }
         report
     }
-    pub fn try_add_device<'a, 'b>(
-        &'b mut self,
-        room: &'a str,
-        device: &'a str,
-    ) -> CustomResult<()> {
+    pub fn try_add_device(&mut self, room: &str, device: &str) -> CustomResult<()> {
         if let Some(room) = self.get_room_mut(room) {
             return room.try_add_device(device);
         }
@@ -100,13 +174,49 @@ impl SmartHouse {
                 room.try_remove_device(device)
             })
     }
+    /// Keeps the order of the remaining rooms.
     pub fn try_remove_room(&mut self, name: &str) -> CustomResult<()> {
-        let pos = self
+        let pos = self.room_position(name).ok_or(CustomError::RoomNotFound)?;
+        self.rooms.remove(pos);
+        Ok(())
+    }
+    /// Index of the room in `get_rooms`.
+    pub fn room_position(&self, name: &str) -> Option<usize> {
+        self.rooms.iter().position(|r| r.get_name() == name)
+    }
+    /// Like `try_add_room`, at `position` instead of the end.
+    pub fn try_insert_room(&mut self, position: usize, room: Room) -> CustomResult<()> {
+        self.try_add_room(room)?;
+        let room = self.rooms.pop().unwrap();
+        self.rooms.insert(position.min(self.rooms.len()), room);
+        Ok(())
+    }
+    pub fn try_rename_room(&mut self, name: &str, new_name: &str) -> CustomResult<()> {
+        let pos = self.room_position(name).ok_or(CustomError::RoomNotFound)?;
+        if self
             .rooms
             .iter()
-            .position(|r| r.get_name() == name)
-            .ok_or(CustomError::RoomNotFound)?;
-        self.rooms.swap_remove(pos);
+            .enumerate()
+            .any(|(i, r)| i != pos && r.name.to_lowercase() == new_name.to_lowercase())
+        {
+            return Err(CustomError::AddRoomError);
+        }
+        self.rooms[pos].name = new_name.to_owned();
         Ok(())
     }
+    pub fn try_rename_device(
+        &mut self,
+        room: &str,
+        device: &str,
+        new_name: &str,
+    ) -> CustomResult<()> {
+        let room = self.get_room_mut(room).ok_or(CustomError::RoomNotFound)?;
+        if device.to_lowercase() != new_name.to_lowercase()
+            && room.devices.contains(&new_name.to_lowercase())
+        {
+            return Err(CustomError::AddDeviceError);
+        }
+        room.try_remove_device(&device.to_lowercase())?;
+        room.try_add_device(new_name)
+    }
 }
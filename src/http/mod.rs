@@ -0,0 +1,639 @@
+//! Minimal HTTP/1.1 server exposing the house as a JSON REST API:
+//!
+//! | method   | path                                          | body            | response            |
+//! |----------|-----------------------------------------------|-----------------|---------------------|
+//! | `GET`    | `/rooms`                                      |                 | room names          |
+//! | `POST`   | `/rooms`                                      | `{"name": ..}`  | `201`               |
+//! | `DELETE` | `/rooms/{room}`                               |                 | `204`               |
+//! | `GET`    | `/rooms/{room}/devices`                       |                 | `[DeviceInfo]`      |
+//! | `POST`   | `/rooms/{room}/devices`                       | `SmartDevice`   | `201`, `DeviceInfo` |
+//! | `DELETE` | `/rooms/{room}/devices/{device}`              |                 | `204`               |
+//! | `POST`   | `/rooms/{room}/devices/{device}/commands`     | `DeviceCommand` | `ExecutionResult`   |
+//! | `GET`    | `/rooms/{room}/devices/{device}/capabilities` |                 | `Capabilities`      |
+//! | `GET`    | `/devices/{device}`                           |                 | `DeviceInfo`        |
+//! | `GET`    | `/devices/{device}/capabilities`              |                 | `Capabilities`      |
+//! | `POST`   | `/devices/{device}/commands`                  | `DeviceCommand` | `ExecutionResult`   |
+//! | `POST`   | `/batch`                                      | `Batch`         | `BatchResult`       |
+//! | `GET`    | `/health`                                     |                 | `[DeviceHealth]`    |
+//! | `GET`    | `/ws`                                         |                 | WebSocket upgrade   |
+//!
+//! Paths without a room answer `409` for a device name used in several rooms.
+//! Commands may also be posted in their text form, e.g. `socket.on`.
+//! With `If-Match: <version>` a command only runs if the device is still at the
+//! `version` of its `DeviceInfo`, otherwise it is answered with `409`.
+//! Errors are returned as `{"error": message}` with a matching status code.
+//! Requests with overlong lines, too many headers or a large body are refused,
+//! and clients that stall while sending one get `408`.
+//! APIs with an `Authenticator` expect `Authorization: Bearer <token>` or
+//! `Basic` credentials on every request, including the WebSocket upgrade.
+//! Every connection serves one request, except `/ws` which stays open and
+//! pushes live updates (see `WebSocketClient`).
+
+use crate::deadline::Deadline;
+use crate::{
+    Action, Authenticator, Batch, CommandData, Credentials, CustomError, CustomResult,
+    DeviceCommand, DeviceEntry, DeviceInfo, DeviceInfoProvider, ExecutionResult, FaultKind,
+    Principal, Room, SmartDevice, SmartDeviceList, SmartHouse,
+};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use std::io::{BufRead, BufReader, ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime};
+
+mod websocket;
+pub use websocket::{Topic, WebSocketClient, WsClientMessage, WsServerMessage};
+
+/// Largest request body the server accepts.
+const MAX_BODY: usize = 64 * 1024;
+/// Longest request line or header line, in bytes.
+const MAX_LINE: usize = 8 * 1024;
+const MAX_HEADERS: usize = 100;
+/// How long a client may take to send its whole request, however slowly it trickles in.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HttpRequest {
+    pub method: String,
+    pub path: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl HttpRequest {
+    pub fn new(method: &str, path: &str, body: &[u8]) -> Self {
+        Self {
+            method: method.to_owned(),
+            path: path.to_owned(),
+            headers: Vec::new(),
+            body: body.to_vec(),
+        }
+    }
+    /// header names are case insensitive
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HttpResponse {
+    pub status: u16,
+    pub body: String,
+}
+
+impl HttpResponse {
+    pub fn json<T: Serialize>(status: u16, body: &T) -> Self {
+        match serde_json::to_string(body) {
+            Ok(body) => Self { status, body },
+            Err(e) => Self::error(500, &e.to_string()),
+        }
+    }
+    pub fn empty(status: u16) -> Self {
+        Self {
+            status,
+            body: String::new(),
+        }
+    }
+    pub fn error(status: u16, message: &str) -> Self {
+        Self {
+            status,
+            body: serde_json::json!({ "error": message }).to_string(),
+        }
+    }
+    pub fn from_error(err: &CustomError) -> Self {
+        let status = match err {
+            CustomError::DeviceNotFound | CustomError::RoomNotFound => 404,
+            CustomError::AddRoomError
+            | CustomError::AddDeviceError
+            | CustomError::AmbiguousDevice(_)
+            | CustomError::CircuitOverload { .. }
+            | CustomError::VersionConflict { .. } => 409,
+            CustomError::ParseError(_) | CustomError::InvalidCommandCode(_) => 400,
+            CustomError::InvalidParameter { .. } => 422,
+            CustomError::AuthenticationFailed(_) => 401,
+            CustomError::PermissionDenied(_) => 403,
+            CustomError::DeviceFault(fault) => match fault.kind {
+                FaultKind::CommandRejected => 422,
+                FaultKind::HardwareFault => 502,
+                FaultKind::Unreachable => 503,
+                FaultKind::Timeout => 504,
+            },
+            _ => 500,
+        };
+        Self::error(status, &err.to_string())
+    }
+    fn reason(&self) -> &'static str {
+        match self.status {
+            200 => "OK",
+            201 => "Created",
+            204 => "No Content",
+            400 => "Bad Request",
+            401 => "Unauthorized",
+            403 => "Forbidden",
+            404 => "Not Found",
+            405 => "Method Not Allowed",
+            408 => "Request Timeout",
+            409 => "Conflict",
+            413 => "Payload Too Large",
+            414 => "URI Too Long",
+            422 => "Unprocessable Entity",
+            431 => "Request Header Fields Too Large",
+            502 => "Bad Gateway",
+            503 => "Service Unavailable",
+            504 => "Gateway Timeout",
+            _ => "Internal Server Error",
+        }
+    }
+    fn write_to(&self, writer: &mut impl Write) -> std::io::Result<()> {
+        write!(
+            writer,
+            "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            self.status,
+            self.reason(),
+            self.body.len(),
+            self.body
+        )?;
+        writer.flush()
+    }
+}
+
+#[derive(Deserialize)]
+struct NewRoom {
+    name: String,
+}
+
+/// Routes REST requests to a house and its device list.
+#[derive(Clone)]
+pub struct RestApi {
+    house: Arc<Mutex<SmartHouse>>,
+    devices: SmartDeviceList,
+    auth: Option<Arc<Authenticator>>,
+}
+
+impl RestApi {
+    pub fn new(house: Arc<Mutex<SmartHouse>>, devices: SmartDeviceList) -> Self {
+        Self {
+            house,
+            devices,
+            auth: None,
+        }
+    }
+    /// Requires credentials on every request and enforces their permissions.
+    pub fn with_auth(mut self, auth: Authenticator) -> Self {
+        self.auth = Some(Arc::new(auth));
+        self
+    }
+
+    /// Principal of the request's `Authorization` header.
+    pub(crate) fn principal(&self, request: &HttpRequest) -> CustomResult<Principal> {
+        let auth = match &self.auth {
+            Some(auth) => auth,
+            None => return Ok(Principal::admin("anonymous")),
+        };
+        let header = request
+            .header("authorization")
+            .ok_or_else(|| CustomError::AuthenticationFailed("authentication required".into()))?;
+        let invalid = || CustomError::AuthenticationFailed("invalid authorization header".into());
+        let credentials = match header.split_once(' ') {
+            Some((scheme, token)) if scheme.eq_ignore_ascii_case("bearer") => {
+                Credentials::Token(token.trim().to_owned())
+            }
+            Some((scheme, encoded)) if scheme.eq_ignore_ascii_case("basic") => {
+                let decoded = BASE64.decode(encoded.trim()).map_err(|_| invalid())?;
+                let decoded = String::from_utf8(decoded).map_err(|_| invalid())?;
+                let (user, password) = decoded.split_once(':').ok_or_else(invalid)?;
+                Credentials::Password {
+                    user: user.to_owned(),
+                    password: password.to_owned(),
+                }
+            }
+            _ => return Err(invalid()),
+        };
+        auth.authenticate(&credentials)
+    }
+
+    pub fn handle(&self, request: &HttpRequest) -> HttpResponse {
+        let principal = match self.principal(request) {
+            Ok(principal) => principal,
+            Err(err) => return HttpResponse::from_error(&err),
+        };
+        let principal = &principal;
+        let path = request.path.split('?').next().unwrap_or_default();
+        let segments: Vec<String> = path
+            .split('/')
+            .filter(|s| !s.is_empty())
+            .map(percent_decode)
+            .collect();
+        let segments: Vec<&str> = segments.iter().map(String::as_str).collect();
+        let result = match (request.method.as_str(), segments.as_slice()) {
+            ("GET", ["rooms"]) => Ok(self.rooms(principal)),
+            ("POST", ["rooms"]) => self.add_room(principal, &request.body),
+            ("DELETE", ["rooms", room]) => self.remove_room(principal, room),
+            ("GET", ["rooms", room, "devices"]) => self.room_devices(principal, room),
+            ("POST", ["rooms", room, "devices"]) => self.add_device(principal, room, &request.body),
+            ("DELETE", ["rooms", room, "devices", device]) => {
+                self.remove_device(principal, room, device)
+            }
+            ("GET", ["devices", device]) => self.device(principal, device),
+            ("GET", ["devices", device, "capabilities"]) => {
+                self.capabilities(principal, None, device)
+            }
+            ("GET", ["rooms", room, "devices", device, "capabilities"]) => {
+                self.capabilities(principal, Some(room), device)
+            }
+            ("POST", ["devices", device, "commands"]) => {
+                self.command(principal, None, device, request)
+            }
+            ("POST", ["rooms", room, "devices", device, "commands"]) => {
+                self.command(principal, Some(room), device, request)
+            }
+            ("POST", ["batch"]) => self.batch(principal, &request.body),
+            ("GET", ["health"]) => Ok(self.health(principal)),
+            (_, ["rooms"])
+            | (_, ["rooms", _])
+            | (_, ["rooms", _, "devices"])
+            | (_, ["rooms", _, "devices", _])
+            | (_, ["rooms", _, "devices", _, "commands"])
+            | (_, ["rooms", _, "devices", _, "capabilities"])
+            | (_, ["devices", _])
+            | (_, ["devices", _, "capabilities"])
+            | (_, ["devices", _, "commands"])
+            | (_, ["batch"])
+            | (_, ["health"]) => Ok(HttpResponse::error(405, "method not allowed")),
+            _ => Ok(HttpResponse::error(404, "no such endpoint")),
+        };
+        result.unwrap_or_else(|err| HttpResponse::from_error(&err))
+    }
+
+    fn room_name(&self, room: &str) -> CustomResult<String> {
+        let house = self.house.lock().unwrap();
+        house
+            .get_rooms()
+            .into_iter()
+            .find(|r| r.to_lowercase() == room.to_lowercase())
+            .map(str::to_owned)
+            .ok_or(CustomError::RoomNotFound)
+    }
+
+    fn rooms(&self, principal: &Principal) -> HttpResponse {
+        let house = self.house.lock().unwrap();
+        let mut rooms = house.get_rooms();
+        rooms.retain(|room| principal.can(Action::Read, room));
+        HttpResponse::json(200, &rooms)
+    }
+
+    fn add_room(&self, principal: &Principal, body: &[u8]) -> CustomResult<HttpResponse> {
+        let room: NewRoom = parse_body(body)?;
+        principal.check(Action::Configure, &room.name)?;
+        self.house
+            .lock()
+            .unwrap()
+            .try_add_room(Room::with_name(&room.name))?;
+        Ok(HttpResponse::json(201, &room.name))
+    }
+
+    fn remove_room(&self, principal: &Principal, room: &str) -> CustomResult<HttpResponse> {
+        let room = self.room_name(room)?;
+        principal.check(Action::Configure, &room)?;
+        self.house.lock().unwrap().try_remove_room(&room)?;
+        self.devices.remove_room(&room).ok();
+        Ok(HttpResponse::empty(204))
+    }
+
+    fn room_devices(&self, principal: &Principal, room: &str) -> CustomResult<HttpResponse> {
+        let room = self.room_name(room)?;
+        principal.check(Action::Read, &room)?;
+        let house = self.house.lock().unwrap();
+        let mut names = house.get_devices(&room)?;
+        names.sort_unstable();
+        let infos: Vec<DeviceInfo> = names
+            .into_iter()
+            .filter_map(|name| self.devices.get_device_info(&room, name).ok())
+            .collect();
+        Ok(HttpResponse::json(200, &infos))
+    }
+
+    fn add_device(
+        &self,
+        principal: &Principal,
+        room: &str,
+        body: &[u8],
+    ) -> CustomResult<HttpResponse> {
+        let room = self.room_name(room)?;
+        principal.check(Action::Configure, &room)?;
+        let device: SmartDevice = parse_body(body)?;
+        let info = DeviceInfo::from(&device);
+        let mut house = self.house.lock().unwrap();
+        house.try_add_device(&room, &device.get_name())?;
+        if let Err(err) = self.devices.clone().add_device(&room, device) {
+            house
+                .try_remove_device(&room, &info.name.to_lowercase())
+                .ok();
+            return Err(err);
+        }
+        Ok(HttpResponse::json(201, &info))
+    }
+
+    fn remove_device(
+        &self,
+        principal: &Principal,
+        room: &str,
+        device: &str,
+    ) -> CustomResult<HttpResponse> {
+        let room = self.room_name(room)?;
+        principal.check(Action::Configure, &room)?;
+        self.house
+            .lock()
+            .unwrap()
+            .try_remove_device(&room, &device.to_lowercase())?;
+        self.devices.remove_device(&room, device).ok();
+        Ok(HttpResponse::empty(204))
+    }
+
+    /// Devices in rooms the principal may not read are not found, like missing ones,
+    /// so their existence is not revealed.
+    fn resolve(
+        &self,
+        principal: &Principal,
+        room: Option<&str>,
+        device: &str,
+    ) -> CustomResult<DeviceEntry> {
+        let entry = self.devices.resolve(room, device)?;
+        match principal.can(Action::Read, &entry.room) {
+            true => Ok(entry),
+            false => Err(CustomError::DeviceNotFound),
+        }
+    }
+
+    fn device(&self, principal: &Principal, device: &str) -> CustomResult<HttpResponse> {
+        let entry = self.resolve(principal, None, device)?;
+        let info = self.devices.device_info(&entry.room, &entry.device);
+        Ok(HttpResponse::json(200, &info))
+    }
+
+    fn capabilities(
+        &self,
+        principal: &Principal,
+        room: Option<&str>,
+        device: &str,
+    ) -> CustomResult<HttpResponse> {
+        let entry = self.resolve(principal, room, device)?;
+        Ok(HttpResponse::json(200, &entry.device.capabilities()))
+    }
+
+    fn health(&self, principal: &Principal) -> HttpResponse {
+        let mut report = self.devices.health_report(SystemTime::now());
+        report.retain(|health| principal.can(Action::Read, &health.room));
+        HttpResponse::json(200, &report)
+    }
+
+    /// With an `If-Match` header holding the device version,
+    /// the command only runs if the device is still at that version.
+    /// Without a room, the device name must be unique in the house.
+    fn command(
+        &self,
+        principal: &Principal,
+        room: Option<&str>,
+        device: &str,
+        request: &HttpRequest,
+    ) -> CustomResult<HttpResponse> {
+        let entry = self.resolve(principal, room, device)?;
+        let body = &request.body;
+        let data: DeviceCommand = match std::str::from_utf8(body).map(str::trim) {
+            Ok(text) if !text.starts_with('{') => text.parse()?,
+            _ => parse_body(body)?,
+        };
+        let version = match request.header("If-Match") {
+            Some(value) => Some(value.trim().trim_matches('"').parse().map_err(|_| {
+                CustomError::ParseError(format!("invalid If-Match version '{}'", value))
+            })?),
+            None => None,
+        };
+        principal.check(Action::of(&data), &entry.room)?;
+        let cmd = CommandData {
+            device_name: entry.device.get_name(),
+            data,
+        };
+        let result =
+            self.devices
+                .execute_command_as(cmd, Some(&entry.room), Some(&principal.name), version);
+        match result {
+            ExecutionResult::Error(err) => Err(err),
+            result => Ok(HttpResponse::json(200, &result)),
+        }
+    }
+
+    /// Answers 200 even when commands failed, the result tells which.
+    fn batch(&self, principal: &Principal, body: &[u8]) -> CustomResult<HttpResponse> {
+        let batch: Batch = parse_body(body)?;
+        // refuses the whole batch before anything runs
+        let result =
+            self.devices
+                .execute_batch_checked(batch, &principal.name, |room, command| {
+                    principal.check(Action::of(command), room)
+                })?;
+        Ok(HttpResponse::json(200, &result))
+    }
+}
+
+/// Value of the `Authorization` header carrying `credentials`.
+pub fn authorization(credentials: &Credentials) -> String {
+    match credentials {
+        Credentials::Token(token) => format!("Bearer {}", token),
+        Credentials::Password { user, password } => {
+            format!("Basic {}", BASE64.encode(format!("{}:{}", user, password)))
+        }
+    }
+}
+
+fn parse_body<T: for<'de> Deserialize<'de>>(body: &[u8]) -> CustomResult<T> {
+    serde_json::from_slice(body).map_err(|e| CustomError::ParseError(e.to_string()))
+}
+
+fn percent_decode(segment: &str) -> String {
+    let bytes = segment.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes
+            .get(i + 1..i + 3)
+            .and_then(|h| std::str::from_utf8(h).ok())
+            .and_then(|h| u8::from_str_radix(h, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            (byte, _) => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// Reads one line of at most `MAX_LINE` bytes, `None` if it is longer.
+fn read_line(stream: &mut impl BufRead, line: &mut String) -> Result<Option<()>, HttpResponse> {
+    line.clear();
+    (&mut *stream)
+        .take(MAX_LINE as u64 + 1)
+        .read_line(line)
+        .map_err(read_error)?;
+    Ok((line.len() <= MAX_LINE || line.ends_with('\n')).then_some(()))
+}
+
+/// Slow clients are answered with `408`, anything else that cannot be read with `400`.
+fn read_error(err: std::io::Error) -> HttpResponse {
+    match err.kind() {
+        ErrorKind::WouldBlock | ErrorKind::TimedOut => {
+            HttpResponse::error(408, "timed out reading the request")
+        }
+        _ => HttpResponse::error(400, &err.to_string()),
+    }
+}
+
+/// Reads one request from the stream. Requests that cannot be served are
+/// answered with the returned error response.
+pub fn read_request(stream: &mut impl BufRead) -> Result<HttpRequest, HttpResponse> {
+    let mut line = String::new();
+    if read_line(stream, &mut line)?.is_none() {
+        return Err(HttpResponse::error(414, "request line too long"));
+    }
+    let mut parts = line.split_whitespace();
+    let (method, path) = match (parts.next(), parts.next()) {
+        (Some(method), Some(path)) => (method.to_owned(), path.to_owned()),
+        _ => return Err(HttpResponse::error(400, "invalid request line")),
+    };
+    let mut headers = Vec::new();
+    loop {
+        if read_line(stream, &mut line)?.is_none() {
+            return Err(HttpResponse::error(431, "header line too long"));
+        }
+        let header = line.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if headers.len() == MAX_HEADERS {
+            return Err(HttpResponse::error(431, "too many headers"));
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            headers.push((name.trim().to_owned(), value.trim().to_owned()));
+        }
+    }
+    let mut request = HttpRequest {
+        method,
+        path,
+        headers,
+        body: Vec::new(),
+    };
+    let length: usize = request
+        .header("content-length")
+        .and_then(|l| l.parse().ok())
+        .unwrap_or(0);
+    if length > MAX_BODY {
+        return Err(HttpResponse::error(413, "request body too large"));
+    }
+    request.body = vec![0; length];
+    stream.read_exact(&mut request.body).map_err(read_error)?;
+    Ok(request)
+}
+
+pub struct HttpServer {
+    listener: TcpListener,
+    api: RestApi,
+}
+
+impl HttpServer {
+    pub fn bind<A: ToSocketAddrs>(addr: A, api: RestApi) -> CustomResult<Self> {
+        let listener =
+            TcpListener::bind(addr).map_err(|e| CustomError::ConnectionError(e.to_string()))?;
+        Ok(Self { listener, api })
+    }
+    pub fn local_addr(&self) -> CustomResult<SocketAddr> {
+        self.listener
+            .local_addr()
+            .map_err(|e| CustomError::ConnectionError(e.to_string()))
+    }
+    pub fn run(self) {
+        for stream in self.listener.incoming().flatten() {
+            let api = self.api.clone();
+            thread::spawn(move || handle_connection(stream, api));
+        }
+    }
+    pub fn spawn(self) -> JoinHandle<()> {
+        thread::spawn(move || self.run())
+    }
+}
+
+fn handle_connection(mut stream: TcpStream, api: RestApi) {
+    let mut reader = match stream.try_clone() {
+        Ok(reader) => BufReader::new(reader),
+        Err(_) => return,
+    };
+    let deadline = Instant::now() + REQUEST_TIMEOUT;
+    let response = match read_request(&mut Deadline::new(&mut reader, &stream, deadline)) {
+        Ok(request) if request.path == "/ws" && websocket::is_upgrade(&request) => {
+            match api.principal(&request) {
+                // WebSocket clients may stay quiet for as long as they like
+                Ok(principal) if stream.set_read_timeout(None).is_ok() => {
+                    return websocket::serve(reader, stream, &request, api.devices, principal)
+                }
+                Ok(_) => return,
+                Err(err) => HttpResponse::from_error(&err),
+            }
+        }
+        Ok(request) => api.handle(&request),
+        Err(response) => response,
+    };
+    response.write_to(&mut stream).ok();
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn decode_path_segments() {
+        assert_eq!(percent_decode("living%20room"), "living room");
+        assert_eq!(percent_decode("100%"), "100%");
+        assert_eq!(percent_decode("%zz"), "%zz");
+    }
+
+    #[test]
+    fn parse_request() {
+        let raw = b"POST /rooms HTTP/1.1\r\nHost: x\r\nContent-Length: 4\r\n\r\nbody";
+        let request = read_request(&mut &raw[..]).unwrap();
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.path, "/rooms");
+        assert_eq!(request.header("HOST"), Some("x"));
+        assert_eq!(request.body, b"body");
+    }
+
+    #[test]
+    fn oversized_requests_are_refused() {
+        let status = |raw: &[u8]| read_request(&mut &raw[..]).unwrap_err().status;
+        let long_path = format!("GET /{} HTTP/1.1\r\n\r\n", "a".repeat(MAX_LINE));
+        assert_eq!(status(long_path.as_bytes()), 414);
+        let long_header = format!("GET / HTTP/1.1\r\nX: {}\r\n\r\n", "a".repeat(MAX_LINE));
+        assert_eq!(status(long_header.as_bytes()), 431);
+        let many_headers = format!(
+            "GET / HTTP/1.1\r\n{}\r\n",
+            "X: y\r\n".repeat(MAX_HEADERS + 1)
+        );
+        assert_eq!(status(many_headers.as_bytes()), 431);
+        assert_eq!(
+            status(b"POST / HTTP/1.1\r\nContent-Length: 999999\r\n\r\n"),
+            413
+        );
+        assert_eq!(status(b"nonsense\r\n\r\n"), 400);
+        let stalled = std::io::Error::from(ErrorKind::WouldBlock);
+        assert_eq!(read_error(stalled).status, 408);
+    }
+}
@@ -0,0 +1,549 @@
+//! WebSocket push channel (RFC 6455) served at `GET /ws`.
+//!
+//! Clients send `WsClientMessage`s as text frames to pick what they receive;
+//! the server answers every request and pushes matching `DeviceEvent`s,
+//! all as JSON encoded `WsServerMessage`s.
+
+use super::HttpRequest;
+use crate::{
+    Action, Credentials, CustomError, CustomResult, DeviceEvent, Principal, SmartDeviceList,
+};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
+use std::collections::HashSet;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{Shutdown, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::RecvTimeoutError;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+/// the only protocol version there is
+const VERSION: &str = "13";
+const MAX_PAYLOAD: u64 = 64 * 1024;
+/// how often the push loop checks whether the client went away
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+const OP_CONTINUATION: u8 = 0x0;
+const OP_TEXT: u8 = 0x1;
+const OP_CLOSE: u8 = 0x8;
+const OP_PING: u8 = 0x9;
+const OP_PONG: u8 = 0xA;
+
+/// Selects events by room, device name or both (case insensitive).
+/// A topic with neither set selects every event.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Topic {
+    #[serde(default)]
+    pub room: Option<String>,
+    #[serde(default)]
+    pub device: Option<String>,
+}
+
+impl Topic {
+    pub fn all() -> Self {
+        Self::default()
+    }
+    pub fn room(room: &str) -> Self {
+        Self {
+            room: Some(room.to_owned()),
+            device: None,
+        }
+    }
+    pub fn device(device: &str) -> Self {
+        Self {
+            room: None,
+            device: Some(device.to_owned()),
+        }
+    }
+}
+
+/// `{"action": "subscribe", "room": "hall"}`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "action", rename_all = "lowercase")]
+pub enum WsClientMessage {
+    Subscribe(Topic),
+    Unsubscribe(Topic),
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum WsServerMessage {
+    Subscribed(Topic),
+    Unsubscribed(Topic),
+    Event(DeviceEvent),
+    Error(String),
+}
+
+#[derive(Debug, Default)]
+struct Subscriptions {
+    /// lowercase (room, device) pairs, `None` matches any
+    topics: HashSet<(Option<String>, Option<String>)>,
+}
+
+impl Subscriptions {
+    fn update(&mut self, topic: &Topic, subscribe: bool) {
+        let lower = |name: &Option<String>| name.as_ref().map(|n| n.to_lowercase());
+        let key = (lower(&topic.room), lower(&topic.device));
+        match subscribe {
+            true => self.topics.insert(key),
+            false => self.topics.remove(&key),
+        };
+    }
+    /// Events of rooms the principal may not read are never sent.
+    fn matches(&self, event: &DeviceEvent, principal: &Principal) -> bool {
+        let (room, device) = match event {
+            DeviceEvent::StateChanged { room, device, .. } => (room, device),
+            DeviceEvent::Alarm(alarm) => (&alarm.alarm().room, &alarm.alarm().device),
+        };
+        if !principal.can(Action::Read, room) {
+            return false;
+        }
+        let (room, device) = (room.to_lowercase(), device.to_lowercase());
+        self.topics.iter().any(|(r, d)| {
+            r.as_ref().is_none_or(|r| *r == room) && d.as_ref().is_none_or(|d| *d == device)
+        })
+    }
+}
+
+pub(crate) fn is_upgrade(request: &HttpRequest) -> bool {
+    request
+        .header("upgrade")
+        .is_some_and(|u| u.eq_ignore_ascii_case("websocket"))
+}
+
+fn accept_key(key: &str) -> String {
+    let mut sha = Sha1::new();
+    sha.update(key.as_bytes());
+    sha.update(GUID.as_bytes());
+    BASE64.encode(sha.finalize())
+}
+
+/// Completes the handshake and pushes events until the client disconnects.
+pub(crate) fn serve(
+    mut reader: BufReader<TcpStream>,
+    mut stream: TcpStream,
+    request: &HttpRequest,
+    devices: SmartDeviceList,
+    principal: Principal,
+) {
+    let key = match request.header("sec-websocket-key") {
+        Some(key) => key,
+        None => {
+            super::HttpResponse::error(400, "missing Sec-WebSocket-Key")
+                .write_to(&mut stream)
+                .ok();
+            return;
+        }
+    };
+    if request.header("sec-websocket-version") != Some(VERSION) {
+        let refusal = format!(
+            "HTTP/1.1 426 Upgrade Required\r\nSec-WebSocket-Version: {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+            VERSION
+        );
+        stream.write_all(refusal.as_bytes()).ok();
+        return;
+    }
+    // subscribe before the handshake completes, so no event is missed
+    let events = devices.subscribe();
+    let handshake = format!(
+        "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+        accept_key(key)
+    );
+    if stream.write_all(handshake.as_bytes()).is_err() {
+        return;
+    }
+
+    let writer = Arc::new(Mutex::new(stream));
+    let closed = Arc::new(AtomicBool::new(false));
+    let subscriptions = Arc::new(Mutex::new(Subscriptions::default()));
+    {
+        let writer = Arc::clone(&writer);
+        let closed = Arc::clone(&closed);
+        let subscriptions = Arc::clone(&subscriptions);
+        thread::spawn(move || {
+            read_client(&mut reader, &writer, &subscriptions);
+            closed.store(true, Ordering::SeqCst);
+            writer.lock().unwrap().shutdown(Shutdown::Both).ok();
+        });
+    }
+    while !closed.load(Ordering::SeqCst) {
+        let event = match events.recv_timeout(POLL_INTERVAL) {
+            Ok(event) => event,
+            Err(RecvTimeoutError::Timeout) => continue,
+            Err(RecvTimeoutError::Disconnected) => break,
+        };
+        if !subscriptions.lock().unwrap().matches(&event, &principal) {
+            continue;
+        }
+        if send_message(&writer, &WsServerMessage::Event(event)).is_err() {
+            break;
+        }
+    }
+}
+
+fn read_client(
+    reader: &mut impl BufRead,
+    writer: &Mutex<TcpStream>,
+    subscriptions: &Mutex<Subscriptions>,
+) {
+    // frames from clients are masked
+    let mut messages = MessageReader::new(true);
+    loop {
+        let (opcode, payload) = match messages.read(reader) {
+            Ok(frame) => frame,
+            Err(_) => return,
+        };
+        let reply = match opcode {
+            OP_TEXT => match serde_json::from_slice::<WsClientMessage>(&payload) {
+                Ok(WsClientMessage::Subscribe(topic)) => {
+                    subscriptions.lock().unwrap().update(&topic, true);
+                    WsServerMessage::Subscribed(topic)
+                }
+                Ok(WsClientMessage::Unsubscribe(topic)) => {
+                    subscriptions.lock().unwrap().update(&topic, false);
+                    WsServerMessage::Unsubscribed(topic)
+                }
+                Err(e) => WsServerMessage::Error(e.to_string()),
+            },
+            OP_PING => {
+                let mut writer = writer.lock().unwrap();
+                if write_frame(&mut *writer, OP_PONG, &payload, None).is_err() {
+                    return;
+                }
+                continue;
+            }
+            OP_CLOSE => {
+                let mut writer = writer.lock().unwrap();
+                write_frame(&mut *writer, OP_CLOSE, &payload, None).ok();
+                return;
+            }
+            _ => continue,
+        };
+        if send_message(writer, &reply).is_err() {
+            return;
+        }
+    }
+}
+
+fn send_message(writer: &Mutex<TcpStream>, message: &WsServerMessage) -> io::Result<()> {
+    let data = serde_json::to_vec(message)?;
+    let mut writer = writer.lock().unwrap();
+    write_frame(&mut *writer, OP_TEXT, &data, None)
+}
+
+fn protocol_error(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_owned())
+}
+
+/// Reads messages, joining fragmented frames. Control frames may arrive
+/// between the fragments of a message (RFC 6455 section 5.4), the fragments
+/// read so far are kept for the next call then.
+struct MessageReader {
+    /// whether frames must be masked, as those of clients; server frames must not be
+    masked: bool,
+    opcode: Option<u8>,
+    message: Vec<u8>,
+}
+
+impl MessageReader {
+    fn new(masked: bool) -> Self {
+        Self {
+            masked,
+            opcode: None,
+            message: Vec::new(),
+        }
+    }
+    /// Returns the opcode and payload of the next message or control frame.
+    fn read(&mut self, reader: &mut impl Read) -> io::Result<(u8, Vec<u8>)> {
+        loop {
+            let mut head = [0u8; 2];
+            reader.read_exact(&mut head)?;
+            let fin = head[0] & 0x80 != 0;
+            let opcode = head[0] & 0x0F;
+            if (head[1] & 0x80 != 0) != self.masked {
+                return Err(protocol_error(match self.masked {
+                    true => "unmasked client frame",
+                    false => "masked server frame",
+                }));
+            }
+            let len = match head[1] & 0x7F {
+                126 => {
+                    let mut len = [0u8; 2];
+                    reader.read_exact(&mut len)?;
+                    u16::from_be_bytes(len) as u64
+                }
+                127 => {
+                    let mut len = [0u8; 8];
+                    reader.read_exact(&mut len)?;
+                    u64::from_be_bytes(len)
+                }
+                len => len as u64,
+            };
+            let control = opcode >= OP_CLOSE;
+            if control && (!fin || len > 125) {
+                return Err(protocol_error("invalid control frame"));
+            }
+            if len > MAX_PAYLOAD || !control && self.message.len() as u64 + len > MAX_PAYLOAD {
+                return Err(protocol_error("frame too large"));
+            }
+            let mut mask = [0u8; 4];
+            if self.masked {
+                reader.read_exact(&mut mask)?;
+            }
+            let mut payload = vec![0u8; len as usize];
+            reader.read_exact(&mut payload)?;
+            if self.masked {
+                payload
+                    .iter_mut()
+                    .enumerate()
+                    .for_each(|(i, b)| *b ^= mask[i % 4]);
+            }
+            if control {
+                return Ok((opcode, payload));
+            }
+            match (opcode, self.opcode) {
+                (OP_CONTINUATION, None) => return Err(protocol_error("unexpected continuation")),
+                (OP_CONTINUATION, Some(_)) => {}
+                (_, None) => self.opcode = Some(opcode),
+                (_, Some(_)) => return Err(protocol_error("unfinished message")),
+            }
+            self.message.extend(payload);
+            if fin {
+                let opcode = self.opcode.take().unwrap_or(OP_TEXT);
+                return Ok((opcode, std::mem::take(&mut self.message)));
+            }
+        }
+    }
+}
+
+fn write_frame(
+    writer: &mut impl Write,
+    opcode: u8,
+    payload: &[u8],
+    mask: Option<[u8; 4]>,
+) -> io::Result<()> {
+    let mut frame = vec![0x80 | opcode];
+    let mask_bit = if mask.is_some() { 0x80 } else { 0 };
+    match payload.len() {
+        len @ 0..=125 => frame.push(mask_bit | len as u8),
+        len @ 126..=0xFFFF => {
+            frame.push(mask_bit | 126);
+            frame.extend((len as u16).to_be_bytes());
+        }
+        len => {
+            frame.push(mask_bit | 127);
+            frame.extend((len as u64).to_be_bytes());
+        }
+    }
+    match mask {
+        Some(mask) => {
+            frame.extend(mask);
+            frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+        }
+        None => frame.extend(payload),
+    }
+    writer.write_all(&frame)?;
+    writer.flush()
+}
+
+/// Minimal client for the push channel.
+pub struct WebSocketClient {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+    messages: MessageReader,
+}
+
+impl WebSocketClient {
+    pub fn connect<A: ToSocketAddrs>(addr: A, path: &str) -> CustomResult<Self> {
+        Self::open(addr, path, None)
+    }
+    /// Connects to a server that requires authentication.
+    pub fn connect_with<A: ToSocketAddrs>(
+        addr: A,
+        path: &str,
+        credentials: &Credentials,
+    ) -> CustomResult<Self> {
+        Self::open(addr, path, Some(credentials))
+    }
+
+    fn open<A: ToSocketAddrs>(
+        addr: A,
+        path: &str,
+        credentials: Option<&Credentials>,
+    ) -> CustomResult<Self> {
+        let io_err = |e: io::Error| CustomError::ConnectionError(e.to_string());
+        let mut writer = TcpStream::connect(addr).map_err(io_err)?;
+        let key = BASE64.encode(mask_key().repeat(4));
+        let authorization = credentials
+            .map(|c| format!("Authorization: {}\r\n", super::authorization(c)))
+            .unwrap_or_default();
+        let request = format!(
+            "GET {} HTTP/1.1\r\nHost: smart-house\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: {}\r\nSec-WebSocket-Version: 13\r\n{}\r\n",
+            path, key, authorization
+        );
+        writer.write_all(request.as_bytes()).map_err(io_err)?;
+        let mut reader = BufReader::new(writer.try_clone().map_err(io_err)?);
+        let mut accepted = false;
+        let mut line = String::new();
+        reader.read_line(&mut line).map_err(io_err)?;
+        if !line.starts_with("HTTP/1.1 101") {
+            return Err(CustomError::ConnectionError(format!(
+                "upgrade refused: {}",
+                line.trim()
+            )));
+        }
+        loop {
+            line.clear();
+            reader.read_line(&mut line).map_err(io_err)?;
+            match line.trim().split_once(':') {
+                Some((name, value)) if name.eq_ignore_ascii_case("sec-websocket-accept") => {
+                    accepted = value.trim() == accept_key(&key);
+                }
+                None => break,
+                _ => {}
+            }
+        }
+        if !accepted {
+            return Err(CustomError::ConnectionError(
+                "invalid Sec-WebSocket-Accept".into(),
+            ));
+        }
+        Ok(Self {
+            reader,
+            writer,
+            messages: MessageReader::new(false),
+        })
+    }
+
+    pub fn set_timeout(&self, timeout: Option<Duration>) -> CustomResult<()> {
+        self.writer
+            .set_read_timeout(timeout)
+            .map_err(|e| CustomError::ConnectionError(e.to_string()))
+    }
+
+    pub fn send(&mut self, message: &WsClientMessage) -> CustomResult<()> {
+        let data =
+            serde_json::to_string(message).map_err(|e| CustomError::ParseError(e.to_string()))?;
+        self.send_text(&data)
+    }
+
+    /// Sends a raw text frame.
+    pub fn send_text(&mut self, text: &str) -> CustomResult<()> {
+        write_frame(&mut self.writer, OP_TEXT, text.as_bytes(), Some(mask_key()))
+            .map_err(|e| CustomError::ConnectionError(e.to_string()))
+    }
+
+    /// Waits for the next server message, answering pings on the way.
+    pub fn receive(&mut self) -> CustomResult<WsServerMessage> {
+        loop {
+            let (opcode, payload) = self
+                .messages
+                .read(&mut self.reader)
+                .map_err(|e| CustomError::ConnectionError(e.to_string()))?;
+            match opcode {
+                OP_TEXT => {
+                    return serde_json::from_slice(&payload)
+                        .map_err(|e| CustomError::ParseError(e.to_string()))
+                }
+                OP_PING => write_frame(&mut self.writer, OP_PONG, &payload, Some(mask_key()))
+                    .map_err(|e| CustomError::ConnectionError(e.to_string()))?,
+                OP_CLOSE => return Err(CustomError::ConnectionError("connection closed".into())),
+                _ => {}
+            }
+        }
+    }
+
+    pub fn close(mut self) -> CustomResult<()> {
+        write_frame(&mut self.writer, OP_CLOSE, &[], Some(mask_key()))
+            .map_err(|e| CustomError::ConnectionError(e.to_string()))
+    }
+}
+
+/// Masking only needs to be unpredictable for proxies, not cryptographically strong.
+fn mask_key() -> [u8; 4] {
+    let nanos = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .subsec_nanos();
+    nanos.to_le_bytes()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn handshake_key() {
+        // example from RFC 6455
+        assert_eq!(
+            accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+    }
+
+    #[test]
+    fn frame_round_trip() {
+        for len in [0, 125, 126, 70_000 - 10_000] {
+            let payload = vec![7u8; len];
+            let mut data = Vec::new();
+            write_frame(&mut data, OP_TEXT, &payload, Some([1, 2, 3, 4])).unwrap();
+            let mut messages = MessageReader::new(true);
+            assert_eq!(messages.read(&mut &data[..]).unwrap(), (OP_TEXT, payload));
+        }
+    }
+
+    /// A masked frame with its FIN bit and opcode.
+    fn frame(first: u8, payload: &[u8]) -> Vec<u8> {
+        let mut data = Vec::new();
+        write_frame(&mut data, 0, payload, Some([9, 8, 7, 6])).unwrap();
+        data[0] = first;
+        data
+    }
+
+    #[test]
+    fn fragments_survive_control_frames() {
+        let data = [
+            frame(OP_TEXT, b"hel"),
+            frame(0x80 | OP_PING, b"?"),
+            frame(OP_CONTINUATION, b"lo "),
+            frame(0x80 | OP_CONTINUATION, b"world"),
+        ]
+        .concat();
+        let mut reader = &data[..];
+        let mut messages = MessageReader::new(true);
+        assert_eq!(
+            messages.read(&mut reader).unwrap(),
+            (OP_PING, b"?".to_vec())
+        );
+        assert_eq!(
+            messages.read(&mut reader).unwrap(),
+            (OP_TEXT, b"hello world".to_vec())
+        );
+    }
+
+    #[test]
+    fn unmasked_client_frames_are_rejected() {
+        let mut data = Vec::new();
+        write_frame(&mut data, OP_TEXT, b"hi", None).unwrap();
+        assert!(MessageReader::new(true).read(&mut &data[..]).is_err());
+        assert!(MessageReader::new(false).read(&mut &data[..]).is_ok());
+        let data = frame(0x80 | OP_TEXT, b"hi");
+        assert!(MessageReader::new(false).read(&mut &data[..]).is_err());
+        // a continuation without a message
+        let data = frame(0x80 | OP_CONTINUATION, b"hi");
+        assert!(MessageReader::new(true).read(&mut &data[..]).is_err());
+    }
+
+    #[test]
+    fn client_message_format() {
+        let message: WsClientMessage =
+            serde_json::from_str(r#"{"action":"subscribe","room":"hall"}"#).unwrap();
+        assert_eq!(message, WsClientMessage::Subscribe(Topic::room("hall")));
+        let message: WsClientMessage = serde_json::from_str(r#"{"action":"unsubscribe"}"#).unwrap();
+        assert_eq!(message, WsClientMessage::Unsubscribe(Topic::all()));
+    }
+}
@@ -1,14 +1,72 @@
+mod alarm;
+mod audit;
+mod auth;
+mod circuit;
+mod cli;
+mod clock;
+mod control;
+mod deadline;
 mod device_info_provider;
+mod device_key;
+mod discovery;
 mod error;
+mod events;
+mod fault;
+mod health;
+mod history;
 mod house;
+mod http;
+mod mqtt;
+mod policy;
+mod repl;
+mod simulation;
 mod smart_device;
+mod storage;
+mod tls;
 
-pub use device_info_provider::{DeviceInfoProvider, SmartDeviceList, DeviceInfo};
-pub use house::{Room, SmartHouse};
+pub use alarm::{
+    Alarm, AlarmConfig, AlarmEvent, AlarmKind, AlarmMonitor, Severity, ALARM_HISTORY_LIMIT,
+};
+pub use audit::{AuditLog, AuditQuery, AuditRecord};
+pub use auth::{
+    hash_password, Action, Authenticator, Credentials, Permission, Principal, ANY_ROOM,
+};
+pub use circuit::{Circuit, CircuitMember};
+pub use cli::{Cli, CliCommand, CliOutput, CliServer, ReportFormat};
+pub use clock::{Clock, SystemClock, VirtualClock};
+pub use control::{ConnectOptions, ControlClient, ControlServer};
+pub use device_info_provider::{
+    Batch, BatchItem, BatchMode, BatchOutcome, BatchResult, Compensation, DeviceEntry, DeviceInfo,
+    DeviceInfoProvider, DeviceKind, DeviceQuery, DeviceSnapshot, OnError, SmartDeviceList, SortKey,
+    TimerHandle,
+};
+pub use discovery::{
+    Announcement, AnnouncerHandle, DeviceAnnouncer, Discovered, Discovery, DiscoveryHandle,
+    DEFAULT_ANNOUNCE_INTERVAL, DISCOVERY_GROUP, DISCOVERY_PORT,
+};
+pub use events::{DeviceEvent, EventBus};
+pub use fault::{DeviceFault, Fault, FaultInjector, FaultKind};
+pub use health::{DeviceHealth, HealthStatus, DEFAULT_HEARTBEAT_INTERVAL, MISSED_HEARTBEATS};
+pub use history::{ConfigChange, ConfigHistory, DEFAULT_HISTORY_LIMIT};
+pub use house::{ReportEntry, Room, SmartHouse};
+pub use http::{
+    HttpRequest, HttpResponse, HttpServer, RestApi, Topic, WebSocketClient, WsClientMessage,
+    WsServerMessage,
+};
+pub use mqtt::{MqttBridge, MqttBroker, MqttBrokerHandle, MqttClient, MqttMessage};
+pub use policy::{Availability, CommandPolicy};
+pub use repl::{Shell, ShellCommand, ShellHelper};
+pub use simulation::{SimRng, SimulatedSocket, Simulation, ThermalModel};
 pub use smart_device::{
-    Command, CommandData, Device, DeviceCommand, Executable, ExecutionResult, PowerSocket,
-    PowerSocketCommand, PowerSocketState, SmartDevice, Temperature, Thermometer,
+    Capabilities, Command, CommandData, CommandSpec, Countdown, Device, DeviceCommand, Executable,
+    ExecutionResult, ParameterKind, ParameterSpec, PowerSocket, PowerSocketCommand,
+    PowerSocketResult, PowerSocketState, ReadingSpec, SmartDevice, SocketTimer, Temperature,
+    TemperatureDelta, TemperatureUnit, Thermometer, ThermometerCommand, TimerAction,
+    DEFAULT_SOCKET_POWER, MAX_CALIBRATION, MAX_DESCRIPTION_LEN, MAX_SOCKET_POWER, MAX_TIMER,
 };
 
+pub use storage::{HouseFile, RoomFile};
+pub use tls::{TlsClientConfig, TlsServerConfig};
+
 pub use error::CustomError;
 pub use house::CustomResult;
@@ -0,0 +1,23 @@
+use smart_house::{Cli, CliOutput};
+use std::process::exit;
+
+fn main() {
+    let result = Cli::parse(std::env::args().skip(1))
+        .and_then(|cli| cli.start())
+        .and_then(|output| match output {
+            CliOutput::Text(output) => {
+                if !output.is_empty() {
+                    println!("{}", output)
+                }
+                Ok(())
+            }
+            CliOutput::Server(server) => {
+                println!("{}", server.banner());
+                server.run()
+            }
+        });
+    if let Err(err) = result {
+        eprintln!("error: {}", err);
+        exit(1);
+    }
+}
@@ -0,0 +1,233 @@
+use super::packet::{read_packet, topic_matches, write_packet, Packet};
+use super::set_action;
+use crate::{Authenticator, Credentials, CustomError, CustomResult, Principal};
+use std::collections::HashMap;
+use std::io::BufReader;
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+struct Subscriber {
+    id: usize,
+    stream: Arc<Mutex<TcpStream>>,
+    filters: Vec<String>,
+}
+
+#[derive(Default)]
+struct BrokerState {
+    next_id: usize,
+    subscribers: Vec<Subscriber>,
+    retained: HashMap<String, Vec<u8>>,
+}
+
+/// ConnAck return codes
+const BAD_CREDENTIALS: u8 = 4;
+const NOT_AUTHORIZED: u8 = 5;
+
+/// Minimal in-process MQTT broker: QoS 0 delivery, wildcard subscriptions and
+/// retained messages. Meant for tests and local setups without a real broker.
+pub struct MqttBroker {
+    listener: TcpListener,
+    state: Arc<Mutex<BrokerState>>,
+    auth: Option<Arc<Authenticator>>,
+}
+
+impl MqttBroker {
+    pub fn bind<A: ToSocketAddrs>(addr: A) -> CustomResult<Self> {
+        let listener =
+            TcpListener::bind(addr).map_err(|e| CustomError::ConnectionError(e.to_string()))?;
+        Ok(Self {
+            listener,
+            state: Arc::default(),
+            auth: None,
+        })
+    }
+    /// Only accepts clients connecting with a user name and password valid in `auth`,
+    /// and only forwards the `/set` commands their user is permitted.
+    pub fn with_auth(mut self, auth: Authenticator) -> Self {
+        self.auth = Some(Arc::new(auth));
+        self
+    }
+    pub fn local_addr(&self) -> CustomResult<SocketAddr> {
+        self.listener
+            .local_addr()
+            .map_err(|e| CustomError::ConnectionError(e.to_string()))
+    }
+    pub fn run(self) {
+        for stream in self.listener.incoming().flatten() {
+            let state = Arc::clone(&self.state);
+            let auth = self.auth.clone();
+            thread::spawn(move || handle_client(stream, state, auth));
+        }
+    }
+    /// Runs the broker in the background. The returned handle can still read retained messages.
+    pub fn spawn(self) -> CustomResult<MqttBrokerHandle> {
+        let handle = MqttBrokerHandle {
+            addr: self.local_addr()?,
+            state: Arc::clone(&self.state),
+        };
+        thread::spawn(move || self.run());
+        Ok(handle)
+    }
+}
+
+/// Handle to a broker running on a background thread.
+#[derive(Clone)]
+pub struct MqttBrokerHandle {
+    addr: SocketAddr,
+    state: Arc<Mutex<BrokerState>>,
+}
+
+impl MqttBrokerHandle {
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+    /// Last retained message of `topic`.
+    pub fn retained(&self, topic: &str) -> Option<Vec<u8>> {
+        self.state.lock().unwrap().retained.get(topic).cloned()
+    }
+}
+
+fn handle_client(
+    stream: TcpStream,
+    state: Arc<Mutex<BrokerState>>,
+    auth: Option<Arc<Authenticator>>,
+) {
+    let writer = match stream.try_clone() {
+        Ok(writer) => Arc::new(Mutex::new(writer)),
+        Err(_) => return,
+    };
+    let mut reader = BufReader::new(stream);
+    let credentials = match read_packet(&mut reader) {
+        Ok(Packet::Connect { credentials, .. }) => credentials,
+        _ => return,
+    };
+    // without an authenticator every client may publish anything
+    let (code, principal) = match (auth, credentials) {
+        (None, _) => (0, None),
+        (Some(_), None) => (NOT_AUTHORIZED, None),
+        (Some(auth), Some((user, password))) => {
+            match auth.authenticate(&Credentials::Password { user, password }) {
+                Ok(principal) => (0, Some(principal)),
+                Err(_) => (BAD_CREDENTIALS, None),
+            }
+        }
+    };
+    if write_packet(&mut *writer.lock().unwrap(), &Packet::ConnAck { code }).is_err() || code != 0 {
+        return;
+    }
+    let id = {
+        let mut state = state.lock().unwrap();
+        state.next_id += 1;
+        let id = state.next_id;
+        state.subscribers.push(Subscriber {
+            id,
+            stream: Arc::clone(&writer),
+            filters: Vec::new(),
+        });
+        id
+    };
+    while let Ok(packet) = read_packet(&mut reader) {
+        let sent = match packet {
+            Packet::Subscribe { packet_id, filters } => {
+                // holding the writer keeps new messages behind the retained ones,
+                // the broker state is only locked to look them up
+                let mut writer = writer.lock().unwrap();
+                let ack = Packet::SubAck {
+                    packet_id,
+                    codes: vec![0; filters.len()],
+                };
+                let mut retained: Vec<_> = {
+                    let mut state = state.lock().unwrap();
+                    let retained = state
+                        .retained
+                        .iter()
+                        .filter(|(topic, _)| filters.iter().any(|f| topic_matches(f, topic)))
+                        .map(|(topic, payload)| (topic.clone(), payload.clone()))
+                        .collect();
+                    if let Some(subscriber) = state.subscribers.iter_mut().find(|s| s.id == id) {
+                        subscriber.filters.extend(filters);
+                    }
+                    retained
+                };
+                retained.sort();
+                let mut sent = write_packet(&mut *writer, &ack);
+                for (topic, payload) in retained {
+                    let publish = Packet::Publish {
+                        topic,
+                        payload,
+                        retain: true,
+                        packet_id: None,
+                    };
+                    sent = sent.and_then(|_| write_packet(&mut *writer, &publish));
+                }
+                sent
+            }
+            Packet::Publish {
+                topic,
+                payload,
+                retain,
+                packet_id,
+            } => {
+                if let Some(packet_id) = packet_id {
+                    write_packet(&mut *writer.lock().unwrap(), &Packet::PubAck(packet_id)).ok();
+                }
+                // unauthorized commands are dropped, MQTT 3.1.1 has no way to refuse them
+                if principal
+                    .as_ref()
+                    .is_none_or(|p| may_publish(p, &topic, &payload))
+                {
+                    publish(&state, topic, payload, retain);
+                }
+                Ok(())
+            }
+            Packet::PingReq => write_packet(&mut *writer.lock().unwrap(), &Packet::PingResp),
+            Packet::Disconnect => break,
+            _ => Ok(()),
+        };
+        if sent.is_err() {
+            break;
+        }
+    }
+    state.lock().unwrap().subscribers.retain(|s| s.id != id);
+}
+
+/// Publishes to `.../<room>/<device>/set` are commands for a bridge, which cannot
+/// tell who sent them; the publisher needs the permission the command needs in the room.
+fn may_publish(principal: &Principal, topic: &str, payload: &[u8]) -> bool {
+    let mut levels = topic.rsplit('/');
+    match (levels.next(), levels.next(), levels.next()) {
+        (Some("set"), Some(_), Some(room)) => {
+            principal.can(set_action(&String::from_utf8_lossy(payload)), room)
+        }
+        _ => true,
+    }
+}
+
+/// Stores retained messages (an empty payload clears them) and forwards to matching subscribers.
+/// The state is unlocked before writing, so a slow subscriber only holds up its own stream.
+fn publish(state: &Mutex<BrokerState>, topic: String, payload: Vec<u8>, retain: bool) {
+    let receivers: Vec<_> = {
+        let mut state = state.lock().unwrap();
+        if retain && payload.is_empty() {
+            state.retained.remove(&topic);
+        } else if retain {
+            state.retained.insert(topic.clone(), payload.clone());
+        }
+        state
+            .subscribers
+            .iter()
+            .filter(|s| s.filters.iter().any(|f| topic_matches(f, &topic)))
+            .map(|s| Arc::clone(&s.stream))
+            .collect()
+    };
+    let packet = Packet::Publish {
+        topic,
+        payload,
+        retain: false,
+        packet_id: None,
+    };
+    for receiver in receivers {
+        write_packet(&mut *receiver.lock().unwrap(), &packet).ok();
+    }
+}
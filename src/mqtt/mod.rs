@@ -0,0 +1,502 @@
+//! MQTT bridge. Device states are published as retained messages to
+//! `{prefix}/{room}/{device}/state`, and messages on `{prefix}/{room}/{device}/set`
+//! are turned into commands:
+//!
+//! | device      | `set` payload                                                      | `state` payload |
+//! |-------------|--------------------------------------------------------------------|-----------------|
+//! | socket      | `on`, `off`, `state` (republishes), `socket.limit 2000` or JSON    | `on`, `off`     |
+//! | thermometer | reading with a unit, e.g. `21.5C`                                  | e.g. `21.5°C`   |
+//!
+//! Commands that fail are reported on `{prefix}/{room}/{device}/error`.
+//! Rooms appear in topics lowercased, devices with their own name.
+//!
+//! The bridge connects with the user name and password given to
+//! `MqttBridge::with_credentials`. With `MqttBridge::with_auth` these must be
+//! valid in the auth file, and commands are checked against and audited under
+//! that user's permissions.
+
+mod broker;
+mod packet;
+
+pub use broker::{MqttBroker, MqttBrokerHandle};
+
+use crate::{
+    Action, Authenticator, CommandData, Credentials, CustomError, CustomResult, DeviceCommand,
+    DeviceEvent, ExecutionResult, PowerSocketCommand, Principal, SmartDevice, SmartDeviceList,
+    ThermometerCommand, ANY_ROOM,
+};
+use packet::{read_packet, write_packet, Packet};
+use std::collections::VecDeque;
+use std::io::{self, BufReader};
+use std::net::{Shutdown, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// keep alive announced to the broker
+const KEEP_ALIVE: Duration = Duration::from_secs(60);
+/// how often the bridge checks whether the connection went away
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+fn io_error(e: io::Error) -> CustomError {
+    CustomError::ConnectionError(e.to_string())
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MqttMessage {
+    pub topic: String,
+    pub payload: Vec<u8>,
+}
+
+impl MqttMessage {
+    pub fn payload_str(&self) -> String {
+        String::from_utf8_lossy(&self.payload).into_owned()
+    }
+}
+
+/// Write half of a connection, shared between threads.
+#[derive(Clone)]
+pub(crate) struct Publisher(Arc<Mutex<TcpStream>>);
+
+impl Publisher {
+    fn send(&self, packet: &Packet) -> CustomResult<()> {
+        write_packet(&mut *self.0.lock().unwrap(), packet).map_err(io_error)
+    }
+    fn publish(&self, topic: &str, payload: &[u8], retain: bool) -> CustomResult<()> {
+        self.send(&Packet::Publish {
+            topic: topic.to_owned(),
+            payload: payload.to_vec(),
+            retain,
+            packet_id: None,
+        })
+    }
+    fn shutdown(&self) {
+        self.0.lock().unwrap().shutdown(Shutdown::Both).ok();
+    }
+}
+
+/// Blocking MQTT 3.1.1 client publishing and subscribing at QoS 0.
+/// Brokers may drop clients that stay idle for over a minute without a `ping`.
+pub struct MqttClient {
+    reader: BufReader<TcpStream>,
+    writer: Publisher,
+    next_packet_id: u16,
+    /// messages that arrived while waiting for an acknowledgement
+    pending: VecDeque<MqttMessage>,
+}
+
+impl MqttClient {
+    pub fn connect<A: ToSocketAddrs>(addr: A, client_id: &str) -> CustomResult<Self> {
+        Self::open(addr, client_id, None)
+    }
+    /// Connects to a broker that requires a user name and password.
+    pub fn connect_as<A: ToSocketAddrs>(
+        addr: A,
+        client_id: &str,
+        user: &str,
+        password: &str,
+    ) -> CustomResult<Self> {
+        Self::open(
+            addr,
+            client_id,
+            Some((user.to_owned(), password.to_owned())),
+        )
+    }
+    fn open<A: ToSocketAddrs>(
+        addr: A,
+        client_id: &str,
+        credentials: Option<(String, String)>,
+    ) -> CustomResult<Self> {
+        let stream = TcpStream::connect(addr).map_err(io_error)?;
+        let writer = Publisher(Arc::new(Mutex::new(stream.try_clone().map_err(io_error)?)));
+        writer.send(&Packet::Connect {
+            client_id: client_id.to_owned(),
+            keep_alive: KEEP_ALIVE.as_secs() as u16,
+            credentials,
+        })?;
+        let mut client = Self {
+            reader: BufReader::new(stream),
+            writer,
+            next_packet_id: 0,
+            pending: VecDeque::new(),
+        };
+        match client.next_packet()? {
+            Packet::ConnAck { code: 0 } => Ok(client),
+            Packet::ConnAck { code } => Err(CustomError::ConnectionError(format!(
+                "broker refused connection with code {}",
+                code
+            ))),
+            other => Err(CustomError::ConnectionError(format!(
+                "unexpected packet {:?}",
+                other
+            ))),
+        }
+    }
+    pub fn set_timeout(&self, timeout: Option<Duration>) -> CustomResult<()> {
+        self.reader
+            .get_ref()
+            .set_read_timeout(timeout)
+            .map_err(io_error)
+    }
+    /// Returns once the broker acknowledged the subscription.
+    pub fn subscribe(&mut self, filter: &str) -> CustomResult<()> {
+        self.next_packet_id = self.next_packet_id.wrapping_add(1).max(1);
+        let id = self.next_packet_id;
+        self.writer.send(&Packet::Subscribe {
+            packet_id: id,
+            filters: vec![filter.to_owned()],
+        })?;
+        loop {
+            match self.next_packet()? {
+                Packet::SubAck { packet_id, codes } if packet_id == id => {
+                    return match codes.first() {
+                        Some(0..=2) => Ok(()),
+                        _ => Err(CustomError::ConnectionError(format!(
+                            "subscription to '{}' rejected",
+                            filter
+                        ))),
+                    };
+                }
+                packet => {
+                    if let Some(message) = self.accept(packet)? {
+                        self.pending.push_back(message);
+                    }
+                }
+            }
+        }
+    }
+    pub fn publish(&self, topic: &str, payload: &[u8], retain: bool) -> CustomResult<()> {
+        self.writer.publish(topic, payload, retain)
+    }
+    /// Round trip to the broker. Brokers handle a connection's packets in order,
+    /// so everything sent before was processed once this returns.
+    pub fn ping(&mut self) -> CustomResult<()> {
+        self.writer.send(&Packet::PingReq)?;
+        loop {
+            match self.next_packet()? {
+                Packet::PingResp => return Ok(()),
+                packet => {
+                    if let Some(message) = self.accept(packet)? {
+                        self.pending.push_back(message);
+                    }
+                }
+            }
+        }
+    }
+    /// Waits for the next message on a subscribed topic.
+    pub fn receive(&mut self) -> CustomResult<MqttMessage> {
+        if let Some(message) = self.pending.pop_front() {
+            return Ok(message);
+        }
+        loop {
+            let packet = self.next_packet()?;
+            if let Some(message) = self.accept(packet)? {
+                return Ok(message);
+            }
+        }
+    }
+    pub fn disconnect(self) -> CustomResult<()> {
+        self.writer.send(&Packet::Disconnect)
+    }
+    pub(crate) fn publisher(&self) -> Publisher {
+        self.writer.clone()
+    }
+    fn next_packet(&mut self) -> CustomResult<Packet> {
+        read_packet(&mut self.reader).map_err(io_error)
+    }
+    /// Acknowledges QoS 1 messages; other packets carry no message.
+    fn accept(&self, packet: Packet) -> CustomResult<Option<MqttMessage>> {
+        match packet {
+            Packet::Publish {
+                topic,
+                payload,
+                packet_id,
+                ..
+            } => {
+                if let Some(id) = packet_id {
+                    self.writer.send(&Packet::PubAck(id))?;
+                }
+                Ok(Some(MqttMessage { topic, payload }))
+            }
+            _ => Ok(None),
+        }
+    }
+}
+
+/// Connects a `SmartDeviceList` to an MQTT broker, see the module documentation
+/// for topics and payloads.
+pub struct MqttBridge {
+    devices: SmartDeviceList,
+    prefix: String,
+    client_id: String,
+    /// user name and password sent on connect
+    credentials: Option<(String, String)>,
+    auth: Option<Authenticator>,
+}
+
+impl MqttBridge {
+    pub fn new(devices: SmartDeviceList) -> Self {
+        Self {
+            devices,
+            prefix: "house".to_owned(),
+            client_id: "smart-house".to_owned(),
+            credentials: None,
+            auth: None,
+        }
+    }
+    pub fn with_prefix(mut self, prefix: &str) -> Self {
+        self.prefix = prefix.trim_end_matches('/').to_owned();
+        self
+    }
+    pub fn with_client_id(mut self, client_id: &str) -> Self {
+        self.client_id = client_id.to_owned();
+        self
+    }
+    /// User name and password for the broker.
+    pub fn with_credentials(mut self, user: &str, password: &str) -> Self {
+        self.credentials = Some((user.to_owned(), password.to_owned()));
+        self
+    }
+    /// The bridge only starts with credentials valid in `auth`, and only runs
+    /// the commands their user is permitted. Commands of every publisher run as
+    /// that user, so it may not control or configure every room; the broker
+    /// limits what each publisher may send, see `MqttBroker::with_auth`.
+    pub fn with_auth(mut self, auth: Authenticator) -> Self {
+        self.auth = Some(auth);
+        self
+    }
+    /// Bridges until the broker connection is lost.
+    pub fn run<A: ToSocketAddrs>(self, broker: A) -> CustomResult<()> {
+        let client = self.start(broker)?;
+        self.serve(client)
+    }
+    /// Returns once the bridge is subscribed and has published every device state;
+    /// bridging continues on a background thread.
+    pub fn spawn<A: ToSocketAddrs>(self, broker: A) -> CustomResult<JoinHandle<CustomResult<()>>> {
+        let client = self.start(broker)?;
+        Ok(thread::spawn(move || self.serve(client)))
+    }
+
+    /// Whom commands from the broker run as: the user of the credentials,
+    /// or the client id without them.
+    fn principal(&self) -> CustomResult<Principal> {
+        match (&self.auth, &self.credentials) {
+            (Some(auth), Some((user, password))) => {
+                let principal = auth.authenticate(&Credentials::Password {
+                    user: user.clone(),
+                    password: password.clone(),
+                })?;
+                match [Action::Control, Action::Configure]
+                    .into_iter()
+                    .any(|action| principal.can(action, ANY_ROOM))
+                {
+                    true => Err(CustomError::PermissionDenied(format!(
+                        "{} may act in every room, the bridge user may only have the rooms it serves",
+                        principal.name
+                    ))),
+                    false => Ok(principal),
+                }
+            }
+            (Some(_), None) => Err(CustomError::AuthenticationFailed(
+                "the bridge needs credentials".into(),
+            )),
+            (None, Some((user, _))) => Ok(Principal::admin(user)),
+            (None, None) => Ok(Principal::admin(&self.client_id)),
+        }
+    }
+
+    fn start<A: ToSocketAddrs>(&self, broker: A) -> CustomResult<Connection> {
+        let principal = self.principal()?;
+        let mut client = MqttClient::open(broker, &self.client_id, self.credentials.clone())?;
+        client.subscribe(&format!("{}/+/+/set", self.prefix))?;
+        // subscribe before publishing, so changes made meanwhile are not lost
+        let events = self.devices.subscribe();
+        let states = self.devices.map(|room, device| {
+            (
+                self.topic(room, &device.get_name(), "state"),
+                state_payload(device),
+            )
+        });
+        for (topic, payload) in states {
+            client.publish(&topic, payload.as_bytes(), true)?;
+        }
+        client.ping()?;
+        Ok((client, events, principal))
+    }
+
+    fn serve(&self, (mut client, events, principal): Connection) -> CustomResult<()> {
+        let publisher = client.publisher();
+        let closed = Arc::new(AtomicBool::new(false));
+        let forwarder = {
+            let publisher = publisher.clone();
+            let devices = self.devices.clone();
+            let prefix = self.prefix.clone();
+            let closed = Arc::clone(&closed);
+            thread::spawn(move || forward_events(events, publisher, devices, prefix, closed))
+        };
+        let result = loop {
+            let message = match client.receive() {
+                Ok(message) => message,
+                Err(err) => break Err(err),
+            };
+            let (room, device) = match self.parse_set_topic(&message.topic) {
+                Some(target) => target,
+                None => continue,
+            };
+            let payload = message.payload_str();
+            if let Err(err) = self.apply(&publisher, &principal, room, device, &payload) {
+                let topic = self.topic(room, device, "error");
+                if let Err(err) = publisher.publish(&topic, err.to_string().as_bytes(), false) {
+                    break Err(err);
+                }
+            }
+        };
+        closed.store(true, Ordering::SeqCst);
+        publisher.shutdown();
+        forwarder.join().ok();
+        result
+    }
+
+    fn topic(&self, room: &str, device: &str, leaf: &str) -> String {
+        format!("{}/{}/{}/{}", self.prefix, room, device, leaf)
+    }
+
+    /// `{prefix}/{room}/{device}/set` -> `(room, device)`
+    fn parse_set_topic<'a>(&self, topic: &'a str) -> Option<(&'a str, &'a str)> {
+        topic
+            .strip_prefix(self.prefix.as_str())?
+            .strip_prefix('/')?
+            .strip_suffix("/set")?
+            .split_once('/')
+    }
+
+    fn apply(
+        &self,
+        publisher: &Publisher,
+        principal: &Principal,
+        room: &str,
+        device: &str,
+        payload: &str,
+    ) -> CustomResult<()> {
+        let entry = self.devices.resolve(Some(room), device)?;
+        let command = match entry.device {
+            SmartDevice::Socket(_) => DeviceCommand::PowerSocket(parse_socket_payload(payload)?),
+            SmartDevice::Thermo(_) => {
+                DeviceCommand::Thermometer(ThermometerCommand::SetReading(payload.parse()?))
+            }
+        };
+        let get_state = command == DeviceCommand::PowerSocket(PowerSocketCommand::GetState);
+        principal.check(Action::of(&command), &entry.room)?;
+        // the broker does not tell who published a command, so it runs as the
+        // bridge's own principal; an authenticating broker checked the publisher
+        let result = self.devices.execute_command_as(
+            CommandData {
+                device_name: entry.device.get_name(),
+                data: command,
+            },
+            Some(&entry.room),
+            Some(&principal.name),
+            None,
+        );
+        match result {
+            ExecutionResult::Error(err) => Err(err),
+            _ if get_state => publish_state(
+                publisher,
+                &self.devices,
+                &self.prefix,
+                &entry.room,
+                &entry.device.get_name(),
+            ),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Client, device events and the principal commands run as.
+type Connection = (MqttClient, Receiver<DeviceEvent>, Principal);
+
+fn forward_events(
+    events: Receiver<DeviceEvent>,
+    publisher: Publisher,
+    devices: SmartDeviceList,
+    prefix: String,
+    closed: Arc<AtomicBool>,
+) {
+    let mut last_sent = Instant::now();
+    while !closed.load(Ordering::SeqCst) {
+        let sent = match events.recv_timeout(POLL_INTERVAL) {
+            Ok(DeviceEvent::StateChanged { room, device, .. }) => {
+                publish_state(&publisher, &devices, &prefix, &room, &device)
+            }
+            Ok(DeviceEvent::Alarm(_)) => continue,
+            Err(RecvTimeoutError::Timeout) if last_sent.elapsed() >= KEEP_ALIVE / 2 => {
+                publisher.send(&Packet::PingReq)
+            }
+            Err(RecvTimeoutError::Timeout) => continue,
+            Err(RecvTimeoutError::Disconnected) => return,
+        };
+        if sent.is_err() {
+            return;
+        }
+        last_sent = Instant::now();
+    }
+}
+
+fn publish_state(
+    publisher: &Publisher,
+    devices: &SmartDeviceList,
+    prefix: &str,
+    room: &str,
+    device: &str,
+) -> CustomResult<()> {
+    let entry = match devices.resolve(Some(room), device) {
+        Ok(entry) => entry,
+        // removed meanwhile
+        Err(_) => return Ok(()),
+    };
+    let topic = format!(
+        "{}/{}/{}/state",
+        prefix,
+        entry.room,
+        entry.device.get_name()
+    );
+    publisher.publish(&topic, state_payload(&entry.device).as_bytes(), true)
+}
+
+fn state_payload(device: &SmartDevice) -> String {
+    match device {
+        SmartDevice::Socket(socket) if socket.is_turned_on() => "on".to_owned(),
+        SmartDevice::Socket(_) => "off".to_owned(),
+        SmartDevice::Thermo(thermometer) => thermometer.get_temperature().to_string(),
+    }
+}
+
+/// Permission a `/set` payload needs: that of the socket command, or
+/// `Action::Configure` for anything else, such as thermometer readings.
+pub(crate) fn set_action(payload: &str) -> Action {
+    match parse_socket_payload(payload) {
+        Ok(command) => Action::of(&DeviceCommand::PowerSocket(command)),
+        Err(_) => Action::Configure,
+    }
+}
+
+fn parse_socket_payload(payload: &str) -> CustomResult<PowerSocketCommand> {
+    match payload.trim().to_lowercase().as_str() {
+        "on" | "1" | "true" => Ok(PowerSocketCommand::TurnOn),
+        "off" | "0" | "false" => Ok(PowerSocketCommand::TurnOff),
+        "state" | "get" => Ok(PowerSocketCommand::GetState),
+        _ => match payload
+            .parse::<DeviceCommand>()
+            .ok()
+            .or_else(|| serde_json::from_str(payload).ok())
+        {
+            Some(DeviceCommand::PowerSocket(command)) => Ok(command),
+            _ => Err(CustomError::ParseError(format!(
+                "unknown socket command '{}'",
+                payload
+            ))),
+        },
+    }
+}
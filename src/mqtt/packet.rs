@@ -0,0 +1,317 @@
+//! The subset of MQTT 3.1.1 packets used by the bridge and the embedded broker.
+//! Messages are exchanged at QoS 0; QoS 1 publishes are accepted and acknowledged.
+
+use std::io::{self, Read, Write};
+
+const PROTOCOL_LEVEL: u8 = 4;
+/// Largest packet either side accepts.
+const MAX_PACKET: usize = 256 * 1024;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Packet {
+    Connect {
+        client_id: String,
+        keep_alive: u16,
+        /// user name and password
+        credentials: Option<(String, String)>,
+    },
+    ConnAck {
+        code: u8,
+    },
+    Publish {
+        topic: String,
+        payload: Vec<u8>,
+        retain: bool,
+        /// set for QoS 1 messages, which must be acknowledged
+        packet_id: Option<u16>,
+    },
+    PubAck(u16),
+    Subscribe {
+        packet_id: u16,
+        filters: Vec<String>,
+    },
+    SubAck {
+        packet_id: u16,
+        codes: Vec<u8>,
+    },
+    PingReq,
+    PingResp,
+    Disconnect,
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_owned())
+}
+
+pub(crate) fn read_packet(reader: &mut impl Read) -> io::Result<Packet> {
+    let mut header = [0u8; 1];
+    reader.read_exact(&mut header)?;
+    let mut len = 0usize;
+    for shift in 0..4 {
+        let mut byte = [0u8; 1];
+        reader.read_exact(&mut byte)?;
+        len |= ((byte[0] & 0x7F) as usize) << (7 * shift);
+        if byte[0] & 0x80 == 0 {
+            break;
+        }
+        if shift == 3 {
+            return Err(invalid("malformed remaining length"));
+        }
+    }
+    if len > MAX_PACKET {
+        return Err(invalid("packet too large"));
+    }
+    let mut body = vec![0u8; len];
+    reader.read_exact(&mut body)?;
+    let mut body = Body(&body);
+    let flags = header[0] & 0x0F;
+    let packet = match header[0] >> 4 {
+        1 => {
+            if body.string()? != "MQTT" || body.u8()? != PROTOCOL_LEVEL {
+                return Err(invalid("unsupported protocol"));
+            }
+            let connect_flags = body.u8()?;
+            let keep_alive = body.u16()?;
+            let client_id = body.string()?;
+            if connect_flags & 0x04 != 0 {
+                // will topic and message, not supported and dropped
+                body.string()?;
+                let len = body.u16()? as usize;
+                body.take(len)?;
+            }
+            let user = match connect_flags & 0x80 {
+                0 => None,
+                _ => Some(body.string()?),
+            };
+            let password = match connect_flags & 0x40 {
+                0 => None,
+                _ => Some(body.string()?),
+            };
+            Packet::Connect {
+                client_id,
+                keep_alive,
+                credentials: user.map(|user| (user, password.unwrap_or_default())),
+            }
+        }
+        2 => {
+            let _session_present = body.u8()?;
+            Packet::ConnAck { code: body.u8()? }
+        }
+        3 => {
+            let topic = body.string()?;
+            let packet_id = match (flags >> 1) & 0x03 {
+                0 => None,
+                _ => Some(body.u16()?),
+            };
+            Packet::Publish {
+                topic,
+                payload: body.0.to_vec(),
+                retain: flags & 0x01 != 0,
+                packet_id,
+            }
+        }
+        4 => Packet::PubAck(body.u16()?),
+        8 => {
+            let packet_id = body.u16()?;
+            let mut filters = Vec::new();
+            while !body.0.is_empty() {
+                filters.push(body.string()?);
+                let _qos = body.u8()?;
+            }
+            Packet::Subscribe { packet_id, filters }
+        }
+        9 => Packet::SubAck {
+            packet_id: body.u16()?,
+            codes: body.0.to_vec(),
+        },
+        12 => Packet::PingReq,
+        13 => Packet::PingResp,
+        14 => Packet::Disconnect,
+        other => return Err(invalid(&format!("unsupported packet type {}", other))),
+    };
+    Ok(packet)
+}
+
+pub(crate) fn write_packet(writer: &mut impl Write, packet: &Packet) -> io::Result<()> {
+    let mut body = Vec::new();
+    let header = match packet {
+        Packet::Connect {
+            client_id,
+            keep_alive,
+            credentials,
+        } => {
+            put_string(&mut body, "MQTT");
+            body.push(PROTOCOL_LEVEL);
+            // clean session, with user name and password when given
+            body.push(match credentials {
+                Some(_) => 0xC2,
+                None => 0x02,
+            });
+            body.extend(keep_alive.to_be_bytes());
+            put_string(&mut body, client_id);
+            if let Some((user, password)) = credentials {
+                put_string(&mut body, user);
+                put_string(&mut body, password);
+            }
+            0x10
+        }
+        Packet::ConnAck { code } => {
+            body.extend([0, *code]);
+            0x20
+        }
+        Packet::Publish {
+            topic,
+            payload,
+            retain,
+            packet_id,
+        } => {
+            put_string(&mut body, topic);
+            let mut header = 0x30 | *retain as u8;
+            if let Some(id) = packet_id {
+                body.extend(id.to_be_bytes());
+                header |= 0x02;
+            }
+            body.extend(payload);
+            header
+        }
+        Packet::PubAck(id) => {
+            body.extend(id.to_be_bytes());
+            0x40
+        }
+        Packet::Subscribe { packet_id, filters } => {
+            body.extend(packet_id.to_be_bytes());
+            for filter in filters {
+                put_string(&mut body, filter);
+                body.push(0);
+            }
+            0x82
+        }
+        Packet::SubAck { packet_id, codes } => {
+            body.extend(packet_id.to_be_bytes());
+            body.extend(codes);
+            0x90
+        }
+        Packet::PingReq => 0xC0,
+        Packet::PingResp => 0xD0,
+        Packet::Disconnect => 0xE0,
+    };
+    if body.len() > MAX_PACKET {
+        return Err(invalid("packet too large"));
+    }
+    let mut frame = vec![header];
+    let mut len = body.len();
+    loop {
+        let byte = (len % 128) as u8;
+        len /= 128;
+        if len == 0 {
+            frame.push(byte);
+            break;
+        }
+        frame.push(byte | 0x80);
+    }
+    frame.extend(body);
+    writer.write_all(&frame)?;
+    writer.flush()
+}
+
+fn put_string(buf: &mut Vec<u8>, s: &str) {
+    buf.extend((s.len() as u16).to_be_bytes());
+    buf.extend(s.as_bytes());
+}
+
+struct Body<'a>(&'a [u8]);
+
+impl Body<'_> {
+    fn take(&mut self, n: usize) -> io::Result<&[u8]> {
+        if self.0.len() < n {
+            return Err(invalid("truncated packet"));
+        }
+        let (head, tail) = self.0.split_at(n);
+        self.0 = tail;
+        Ok(head)
+    }
+    fn u8(&mut self) -> io::Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+    fn u16(&mut self) -> io::Result<u16> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+    fn string(&mut self) -> io::Result<String> {
+        let len = self.u16()? as usize;
+        String::from_utf8(self.take(len)?.to_vec()).map_err(|_| invalid("invalid utf-8 string"))
+    }
+}
+
+/// Matches a topic against a subscription filter with `+` and `#` wildcards.
+pub(crate) fn topic_matches(filter: &str, topic: &str) -> bool {
+    let mut topic = topic.split('/');
+    for level in filter.split('/') {
+        match (level, topic.next()) {
+            ("#", _) => return true,
+            ("+", Some(_)) => {}
+            (level, Some(name)) if level == name => {}
+            _ => return false,
+        }
+    }
+    topic.next().is_none()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn packets_round_trip() {
+        let packets = [
+            Packet::Connect {
+                client_id: "bridge".into(),
+                keep_alive: 60,
+                credentials: None,
+            },
+            Packet::Connect {
+                client_id: "bridge".into(),
+                keep_alive: 60,
+                credentials: Some(("alice".into(), "pw".into())),
+            },
+            Packet::ConnAck { code: 0 },
+            Packet::Publish {
+                topic: "house/hall/lamp/state".into(),
+                payload: vec![b'x'; 300],
+                retain: true,
+                packet_id: None,
+            },
+            Packet::Publish {
+                topic: "a".into(),
+                payload: Vec::new(),
+                retain: false,
+                packet_id: Some(7),
+            },
+            Packet::Subscribe {
+                packet_id: 1,
+                filters: vec!["house/+/+/set".into(), "#".into()],
+            },
+            Packet::SubAck {
+                packet_id: 1,
+                codes: vec![0, 0],
+            },
+            Packet::PingReq,
+            Packet::Disconnect,
+        ];
+        for packet in packets {
+            let mut data = Vec::new();
+            write_packet(&mut data, &packet).unwrap();
+            assert_eq!(read_packet(&mut &data[..]).unwrap(), packet);
+        }
+    }
+
+    #[test]
+    fn wildcards() {
+        assert!(topic_matches("house/+/+/set", "house/hall/lamp/set"));
+        assert!(!topic_matches("house/+/+/set", "house/hall/lamp/state"));
+        assert!(!topic_matches("house/+/set", "house/hall/lamp/set"));
+        assert!(topic_matches("house/#", "house/hall/lamp/state"));
+        assert!(topic_matches("#", "house"));
+        assert!(!topic_matches("house/hall", "house"));
+    }
+}
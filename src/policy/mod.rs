@@ -0,0 +1,327 @@
+//! Retry, timeout and circuit breaker policies for device commands.
+//!
+//! `SmartDeviceList` applies the policy of the addressed device to every command:
+//! transient failures are retried with exponential backoff, and a device failing
+//! too many commands in a row is taken offline. Commands to an offline device fail
+//! at once until its probe interval has passed; the next command is then sent as a
+//! probe and brings the device back online when it succeeds.
+
+use crate::device_key::{
+    device_key, remove_room_keys, rename_device_key, rename_room_keys, DeviceKey,
+};
+use crate::{CustomError, DeviceFault, ExecutionResult, FaultKind, SimRng};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+/// How a device answered its recent commands.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum Availability {
+    #[default]
+    Online,
+    /// the last command failed or needed retries
+    Degraded,
+    /// failed too many commands in a row, commands are not sent until the next probe
+    Offline,
+}
+
+impl fmt::Display for Availability {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Availability::Online => "online",
+            Availability::Degraded => "degraded",
+            Availability::Offline => "offline",
+        })
+    }
+}
+
+/// The default policy sends every command once and waits for it as long as it takes.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CommandPolicy {
+    timeout: Option<Duration>,
+    retries: u32,
+    backoff: Duration,
+    max_backoff: Duration,
+    jitter: f64,
+    /// consecutive failures taking the device offline and the time between probes
+    breaker: Option<(u32, Duration)>,
+    idempotent: bool,
+}
+
+impl Default for CommandPolicy {
+    fn default() -> Self {
+        Self {
+            timeout: None,
+            retries: 0,
+            backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(10),
+            jitter: 0.0,
+            breaker: None,
+            idempotent: false,
+        }
+    }
+}
+
+impl CommandPolicy {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// A command not answered within `timeout` fails with `FaultKind::Timeout`.
+    /// The device still executes it once it arrives.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+    /// Transient failures are retried up to `retries` times. Timeouts reach
+    /// the device and are only retried for `idempotent` devices, once the
+    /// timed out command has finished within the backoff.
+    pub fn retries(mut self, retries: u32) -> Self {
+        self.retries = retries;
+        self
+    }
+    /// The first retry waits `initial`, every further one twice as long, up to `max`.
+    pub fn backoff(mut self, initial: Duration, max: Duration) -> Self {
+        self.backoff = initial;
+        self.max_backoff = max.max(initial);
+        self
+    }
+    /// Shortens every wait by a random part of up to `fraction` of it,
+    /// so that devices failing together are not retried in lockstep.
+    pub fn jitter(mut self, fraction: f64) -> Self {
+        self.jitter = fraction.clamp(0.0, 1.0);
+        self
+    }
+    /// Takes the device offline after `failures` failed commands in a row
+    /// and probes it again every `probe_interval`.
+    pub fn breaker(mut self, failures: u32, probe_interval: Duration) -> Self {
+        self.breaker = Some((failures.max(1), probe_interval));
+        self
+    }
+
+    /// Commands may be applied twice without harm, so timed out ones are retried too.
+    /// Commands conditional on a version never are, the first attempt moved it.
+    pub fn idempotent(mut self) -> Self {
+        self.idempotent = true;
+        self
+    }
+
+    pub fn get_timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
+    pub fn get_retries(&self) -> u32 {
+        self.retries
+    }
+    pub fn is_idempotent(&self) -> bool {
+        self.idempotent
+    }
+
+    /// Wait before retry number `retry` (starting at 0), without jitter.
+    pub fn backoff_delay(&self, retry: u32) -> Duration {
+        let factor = 2u32.saturating_pow(retry.min(31));
+        self.backoff.saturating_mul(factor).min(self.max_backoff)
+    }
+    fn delay(&self, retry: u32, rng: &mut SimRng) -> Duration {
+        self.backoff_delay(retry)
+            .mul_f64(1.0 - self.jitter * rng.next_f64())
+    }
+}
+
+/// Failures that say something about the health of the device.
+/// A rejected command was answered, so the device is reachable.
+pub(crate) fn is_device_failure(result: &ExecutionResult) -> bool {
+    matches!(
+        result,
+        ExecutionResult::Error(CustomError::DeviceFault(fault))
+            if fault.kind != FaultKind::CommandRejected
+    )
+}
+/// Transient failures worth sending the command again for; ones that reached
+/// the device would apply it twice unless that is harmless.
+pub(crate) fn is_retryable(result: &ExecutionResult, idempotent: bool) -> bool {
+    matches!(
+        result,
+        ExecutionResult::Error(CustomError::DeviceFault(fault))
+            if fault.kind.is_transient() && (idempotent || !fault.kind.reaches_device())
+    )
+}
+
+#[derive(Debug, Default)]
+struct Health {
+    consecutive_failures: u32,
+    degraded: bool,
+    offline: bool,
+    /// when the device may be probed next
+    next_probe: Option<Instant>,
+}
+
+#[derive(Debug)]
+struct State {
+    default: CommandPolicy,
+    /// by lowercase room and device name
+    policies: HashMap<DeviceKey, CommandPolicy>,
+    health: HashMap<DeviceKey, Health>,
+    rng: SimRng,
+}
+
+pub(crate) enum Admission {
+    Send,
+    /// the only command let through to an offline device
+    Probe,
+    Refuse(DeviceFault),
+}
+
+/// Policies and health of the devices of a `SmartDeviceList`, shared by its clones.
+#[derive(Debug, Clone)]
+pub(crate) struct Policies(Arc<Mutex<State>>);
+
+impl Default for Policies {
+    fn default() -> Self {
+        let seed = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map(|d| d.as_nanos() as u64)
+            .unwrap_or_default();
+        Self(Arc::new(Mutex::new(State {
+            default: CommandPolicy::default(),
+            policies: HashMap::new(),
+            health: HashMap::new(),
+            rng: SimRng::new(seed),
+        })))
+    }
+}
+
+impl Policies {
+    pub(crate) fn set_default(&self, policy: CommandPolicy) {
+        self.0.lock().unwrap().default = policy;
+    }
+    pub(crate) fn get_default(&self) -> CommandPolicy {
+        self.0.lock().unwrap().default.clone()
+    }
+    pub(crate) fn set(&self, room: &str, device: &str, policy: CommandPolicy) {
+        let mut state = self.0.lock().unwrap();
+        state.policies.insert(device_key(room, device), policy);
+    }
+    pub(crate) fn get(&self, room: &str, device: &str) -> CommandPolicy {
+        let state = self.0.lock().unwrap();
+        state
+            .policies
+            .get(&device_key(room, device))
+            .unwrap_or(&state.default)
+            .clone()
+    }
+    pub(crate) fn availability(&self, room: &str, device: &str) -> Availability {
+        let state = self.0.lock().unwrap();
+        match state.health.get(&device_key(room, device)) {
+            Some(h) if h.offline => Availability::Offline,
+            Some(h) if h.degraded => Availability::Degraded,
+            _ => Availability::Online,
+        }
+    }
+    /// Rooms and names of the offline devices whose probe is due.
+    pub(crate) fn due_for_probe(&self) -> Vec<(String, String)> {
+        let now = Instant::now();
+        let state = self.0.lock().unwrap();
+        state
+            .health
+            .iter()
+            .filter(|(_, h)| h.offline && h.next_probe.is_none_or(|t| t <= now))
+            .map(|(key, _)| key.clone())
+            .collect()
+    }
+
+    /// Decides whether a command may be sent to the device.
+    pub(crate) fn admit(&self, room: &str, device: &str, policy: &CommandPolicy) -> Admission {
+        let mut state = self.0.lock().unwrap();
+        let health = match state.health.get_mut(&device_key(room, device)) {
+            Some(h) if h.offline => h,
+            _ => return Admission::Send,
+        };
+        let interval = match policy.breaker {
+            Some((_, interval)) => interval,
+            // the breaker was removed from the policy
+            None => {
+                *health = Health::default();
+                return Admission::Send;
+            }
+        };
+        let now = Instant::now();
+        match health.next_probe {
+            Some(at) if at > now => Admission::Refuse(
+                DeviceFault::new(device, FaultKind::Unreachable).with_detail(&format!(
+                    "offline after {} failures, next probe in {:?}",
+                    health.consecutive_failures,
+                    at - now
+                )),
+            ),
+            _ => {
+                // concurrent commands wait for the outcome of this probe
+                health.next_probe = Some(now + interval);
+                Admission::Probe
+            }
+        }
+    }
+    pub(crate) fn backoff(&self, policy: &CommandPolicy, retry: u32) -> Duration {
+        policy.delay(retry, &mut self.0.lock().unwrap().rng)
+    }
+    /// Updates the health of the device after a command and its retries.
+    pub(crate) fn record(
+        &self,
+        room: &str,
+        device: &str,
+        policy: &CommandPolicy,
+        failed: bool,
+        retried: bool,
+    ) {
+        let mut state = self.0.lock().unwrap();
+        let health = state.health.entry(device_key(room, device)).or_default();
+        if !failed {
+            *health = Health {
+                degraded: retried,
+                ..Health::default()
+            };
+            return;
+        }
+        health.consecutive_failures += 1;
+        health.degraded = true;
+        if let Some((failures, interval)) = policy.breaker {
+            let now = Instant::now();
+            if health.offline || health.consecutive_failures >= failures {
+                health.offline = true;
+                health.next_probe = Some(now + interval);
+            }
+        }
+    }
+    /// Keeps the policies and health of the devices in `room` under its new name.
+    pub(crate) fn rename_room(&self, room: &str, new_name: &str) {
+        let mut state = self.0.lock().unwrap();
+        let State {
+            policies, health, ..
+        } = &mut *state;
+        rename_room_keys(policies, room, new_name);
+        rename_room_keys(health, room, new_name);
+    }
+    pub(crate) fn rename_device(&self, room: &str, device: &str, new_name: &str) {
+        let mut state = self.0.lock().unwrap();
+        let State {
+            policies, health, ..
+        } = &mut *state;
+        rename_device_key(policies, room, device, new_name);
+        rename_device_key(health, room, device, new_name);
+    }
+    /// Forgets the policy and health of a removed device.
+    pub(crate) fn remove_device(&self, room: &str, device: &str) {
+        let key = device_key(room, device);
+        let mut state = self.0.lock().unwrap();
+        state.policies.remove(&key);
+        state.health.remove(&key);
+    }
+    pub(crate) fn remove_room(&self, room: &str) {
+        let mut state = self.0.lock().unwrap();
+        let State {
+            policies, health, ..
+        } = &mut *state;
+        remove_room_keys(policies, room);
+        remove_room_keys(health, room);
+    }
+}
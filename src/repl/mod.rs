@@ -0,0 +1,460 @@
+use crate::control::{ConnectOptions, ControlClient};
+use crate::{
+    Command, CommandData, CustomError, CustomResult, DeviceCommand, DeviceEntry, DeviceEvent,
+    ExecutionResult, PowerSocketCommand,
+};
+use rustyline::completion::{Completer, Pair};
+use rustyline::error::ReadlineError;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::history::DefaultHistory;
+use rustyline::validate::Validator;
+use rustyline::{Context, Editor, Helper};
+use std::net::{Shutdown, TcpStream};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+pub const SHELL_HELP: &str = "\
+commands:
+  devices [room]                  list devices with their state
+  rooms                           list rooms
+  on|off|state <room>/<device>    control a power socket
+  describe <room>/<device>        show the commands and readings of a device
+  watch [room[/device]]           print state changes as they happen
+  unwatch                         stop watching
+  help                            show this help
+  quit                            leave the shell";
+
+const COMMANDS: [&str; 10] = [
+    "devices", "rooms", "on", "off", "state", "describe", "watch", "unwatch", "help", "quit",
+];
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ShellCommand {
+    Devices(Option<String>),
+    Rooms,
+    Socket {
+        room: String,
+        device: String,
+        command: PowerSocketCommand,
+    },
+    Describe {
+        room: String,
+        device: String,
+    },
+    /// room and optional device to watch, everything if `None`
+    Watch(Option<(String, Option<String>)>),
+    Unwatch,
+    Help,
+    Quit,
+}
+
+impl ShellCommand {
+    pub fn parse(line: &str) -> CustomResult<Self> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let command = match words.as_slice() {
+            ["devices"] => ShellCommand::Devices(None),
+            ["devices", room] => ShellCommand::Devices(Some(room.to_lowercase())),
+            ["rooms"] => ShellCommand::Rooms,
+            [action @ ("on" | "off" | "state"), target] => {
+                let (room, device) = target.split_once('/').ok_or_else(|| {
+                    CustomError::ParseError("target must be <room>/<device>".into())
+                })?;
+                let command = match *action {
+                    "on" => PowerSocketCommand::TurnOn,
+                    "off" => PowerSocketCommand::TurnOff,
+                    _ => PowerSocketCommand::GetState,
+                };
+                ShellCommand::Socket {
+                    room: room.to_lowercase(),
+                    device: device.to_owned(),
+                    command,
+                }
+            }
+            ["describe", target] => {
+                let (room, device) = target.split_once('/').ok_or_else(|| {
+                    CustomError::ParseError("target must be <room>/<device>".into())
+                })?;
+                ShellCommand::Describe {
+                    room: room.to_lowercase(),
+                    device: device.to_owned(),
+                }
+            }
+            ["watch"] => ShellCommand::Watch(None),
+            ["watch", target] => {
+                let filter = match target.split_once('/') {
+                    Some((room, device)) => (room.to_lowercase(), Some(device.to_lowercase())),
+                    None => (target.to_lowercase(), None),
+                };
+                ShellCommand::Watch(Some(filter))
+            }
+            ["unwatch"] => ShellCommand::Unwatch,
+            ["help"] => ShellCommand::Help,
+            ["quit"] | ["exit"] => ShellCommand::Quit,
+            _ => {
+                return Err(CustomError::ParseError(format!(
+                    "unknown command '{}', type 'help'",
+                    line.trim()
+                )))
+            }
+        };
+        Ok(command)
+    }
+}
+
+/// Completes command names, then room and `room/device` targets
+/// known from the last device listing.
+#[derive(Default)]
+pub struct ShellHelper {
+    devices: Arc<Mutex<Vec<DeviceEntry>>>,
+}
+
+impl ShellHelper {
+    pub fn candidates(&self, line: &str) -> (usize, Vec<String>) {
+        let start = line.rfind(' ').map_or(0, |i| i + 1);
+        let word = &line[start..];
+        let preceding: Vec<&str> = line[..start].split_whitespace().collect();
+        let options: Vec<String> = match preceding.as_slice() {
+            [] => COMMANDS.iter().map(|c| c.to_string()).collect(),
+            ["on" | "off" | "state" | "describe"] => self.targets(),
+            ["watch"] => {
+                let mut options = self.rooms();
+                options.extend(self.targets());
+                options
+            }
+            ["devices"] => self.rooms(),
+            _ => Vec::new(),
+        };
+        let word = word.to_lowercase();
+        let matches = options
+            .into_iter()
+            .filter(|o| o.to_lowercase().starts_with(&word))
+            .collect();
+        (start, matches)
+    }
+    fn rooms(&self) -> Vec<String> {
+        let mut rooms: Vec<String> = self
+            .devices
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|e| e.room.clone())
+            .collect();
+        rooms.dedup();
+        rooms
+    }
+    fn targets(&self) -> Vec<String> {
+        self.devices
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|e| format!("{}/{}", e.room, e.device.get_name()))
+            .collect()
+    }
+}
+
+impl Completer for ShellHelper {
+    type Candidate = Pair;
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _ctx: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<Pair>)> {
+        let (start, matches) = self.candidates(&line[..pos]);
+        let pairs = matches
+            .into_iter()
+            .map(|m| Pair {
+                display: m.clone(),
+                replacement: m,
+            })
+            .collect();
+        Ok((start, pairs))
+    }
+}
+
+impl Hinter for ShellHelper {
+    type Hint = String;
+}
+impl Highlighter for ShellHelper {}
+impl Validator for ShellHelper {}
+impl Helper for ShellHelper {}
+
+type Output = Arc<dyn Fn(String) + Send + Sync>;
+
+/// Interactive session with a control server.
+pub struct Shell {
+    addr: String,
+    options: ConnectOptions,
+    client: ControlClient,
+    devices: Arc<Mutex<Vec<DeviceEntry>>>,
+    watch: Option<TcpStream>,
+    output: Output,
+}
+
+impl Shell {
+    pub fn connect(addr: &str) -> CustomResult<Self> {
+        Self::connect_with(addr, ConnectOptions::new())
+    }
+    /// Every connection to the server, including watches, uses `options`.
+    pub fn connect_with(addr: &str, options: ConnectOptions) -> CustomResult<Self> {
+        let mut shell = Self {
+            addr: addr.to_owned(),
+            client: ControlClient::connect_with(addr, &options)?,
+            options,
+            devices: Arc::default(),
+            watch: None,
+            output: Arc::new(|line| println!("{}", line)),
+        };
+        shell.refresh()?;
+        Ok(shell)
+    }
+    /// Where watch events are written, stdout by default.
+    pub fn set_output<F: Fn(String) + Send + Sync + 'static>(&mut self, output: F) {
+        self.output = Arc::new(output);
+    }
+    pub fn helper(&self) -> ShellHelper {
+        ShellHelper {
+            devices: Arc::clone(&self.devices),
+        }
+    }
+
+    /// Runs one shell command and returns the text to print.
+    /// `Ok(None)` means the user asked to quit.
+    pub fn execute(&mut self, line: &str) -> CustomResult<Option<String>> {
+        if line.trim().is_empty() {
+            return Ok(Some(String::new()));
+        }
+        let output = match ShellCommand::parse(line)? {
+            ShellCommand::Quit => {
+                self.stop_watch();
+                return Ok(None);
+            }
+            ShellCommand::Help => SHELL_HELP.to_owned(),
+            ShellCommand::Rooms => self.helper().rooms().join("\n"),
+            ShellCommand::Devices(room) => {
+                self.refresh()?;
+                let devices: Vec<DeviceEntry> = self
+                    .devices
+                    .lock()
+                    .unwrap()
+                    .iter()
+                    .filter(|e| room.as_ref().is_none_or(|r| &e.room == r))
+                    .cloned()
+                    .collect();
+                pretty(&ExecutionResult::Devices(devices))
+            }
+            ShellCommand::Socket {
+                room,
+                device,
+                command,
+            } => {
+                let name = self.resolve(&room, &device)?;
+                let result = self.client.send(&Command::Execute(CommandData {
+                    device_name: name.clone(),
+                    data: DeviceCommand::PowerSocket(command),
+                }))?;
+                format!("{}/{}: {}", room, name, pretty(&result))
+            }
+            ShellCommand::Describe { room, device } => {
+                let name = self.resolve(&room, &device)?;
+                let result = self.client.send(&Command::Describe {
+                    device: name.clone(),
+                    room: Some(room.clone()),
+                })?;
+                format!("{}/{}: {}", room, name, pretty(&result))
+            }
+            ShellCommand::Watch(filter) => {
+                self.start_watch(filter)?;
+                "watching, type 'unwatch' to stop".to_owned()
+            }
+            ShellCommand::Unwatch => match self.stop_watch() {
+                true => "stopped watching".to_owned(),
+                false => "not watching".to_owned(),
+            },
+        };
+        Ok(Some(output))
+    }
+
+    /// Reads commands from the terminal until `quit` or end of input.
+    /// History is loaded from and saved to `history` when given.
+    pub fn run(mut self, history: Option<PathBuf>) -> CustomResult<()> {
+        let mut editor: Editor<ShellHelper, DefaultHistory> = Editor::new()
+            .map_err(|e| CustomError::CommandExecutionFailure(format!("terminal error: {}", e)))?;
+        editor.set_helper(Some(self.helper()));
+        if let Some(path) = &history {
+            editor.load_history(path).ok();
+        }
+        println!("connected to {}, type 'help' for commands", self.addr);
+        loop {
+            match editor.readline("smart-house> ") {
+                Ok(line) => {
+                    editor.add_history_entry(line.as_str()).ok();
+                    match self.execute(&line) {
+                        Ok(Some(output)) if output.is_empty() => {}
+                        Ok(Some(output)) => println!("{}", output),
+                        Ok(None) => break,
+                        Err(err) => println!("error: {}", err),
+                    }
+                }
+                Err(ReadlineError::Interrupted) => continue,
+                Err(ReadlineError::Eof) => break,
+                Err(e) => {
+                    return Err(CustomError::CommandExecutionFailure(format!(
+                        "terminal error: {}",
+                        e
+                    )))
+                }
+            }
+        }
+        self.stop_watch();
+        if let Some(path) = &history {
+            editor.save_history(path).ok();
+        }
+        Ok(())
+    }
+
+    fn refresh(&mut self) -> CustomResult<()> {
+        let devices = self.client.list_devices()?;
+        *self.devices.lock().unwrap() = devices;
+        Ok(())
+    }
+
+    fn resolve(&mut self, room: &str, device: &str) -> CustomResult<String> {
+        let find = |devices: &[DeviceEntry]| {
+            devices
+                .iter()
+                .find(|e| {
+                    e.room == room && e.device.get_name().to_lowercase() == device.to_lowercase()
+                })
+                .map(|e| e.device.get_name())
+        };
+        if let Some(name) = find(&self.devices.lock().unwrap()) {
+            return Ok(name);
+        }
+        self.refresh()?;
+        let devices = self.devices.lock().unwrap();
+        find(&devices).ok_or(CustomError::DeviceNotFound)
+    }
+
+    fn start_watch(&mut self, filter: Option<(String, Option<String>)>) -> CustomResult<()> {
+        self.stop_watch();
+        let client = ControlClient::connect_with(&self.addr, &self.options)?;
+        self.watch = Some(client.shutdown_handle()?);
+        let events = client.watch()?;
+        let output = Arc::clone(&self.output);
+        thread::spawn(move || {
+            for event in events {
+                let event = match event {
+                    Ok(event) => event,
+                    Err(err) => {
+                        output(format!("watch error: {}", err));
+                        continue;
+                    }
+                };
+                if let (
+                    Some((room, device)),
+                    DeviceEvent::StateChanged {
+                        room: r, device: d, ..
+                    },
+                ) = (&filter, &event)
+                {
+                    if room != r
+                        || device
+                            .as_ref()
+                            .is_some_and(|device| device != &d.to_lowercase())
+                    {
+                        continue;
+                    }
+                }
+                output(pretty(&ExecutionResult::Event(event)));
+            }
+        });
+        Ok(())
+    }
+
+    fn stop_watch(&mut self) -> bool {
+        match self.watch.take() {
+            Some(stream) => {
+                stream.shutdown(Shutdown::Both).ok();
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+impl Drop for Shell {
+    fn drop(&mut self) {
+        self.stop_watch();
+    }
+}
+
+/// Human readable form of a server response.
+pub fn pretty(result: &ExecutionResult) -> String {
+    match result {
+        ExecutionResult::Error(_) => format!("✘ {}", result),
+        ExecutionResult::Devices(devices) if devices.is_empty() => "no devices".to_owned(),
+        ExecutionResult::Devices(_) => result.to_string(),
+        ExecutionResult::Event(_) => format!("» {}", result),
+        ExecutionResult::Capabilities(_) => result.to_string(),
+        ExecutionResult::Batch(batch) if !batch.is_success() => format!("✘ {}", result),
+        ExecutionResult::Batch(_) => format!("✔ {}", result),
+        ExecutionResult::PowerSocket(_)
+        | ExecutionResult::TimedPowerSocket { .. }
+        | ExecutionResult::Temperature(_)
+        | ExecutionResult::Authenticated(_) => {
+            format!("✔ {}", result)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{PowerSocket, SmartDevice};
+
+    fn helper() -> ShellHelper {
+        let socket = |name: &str| SmartDevice::Socket(PowerSocket::new(name, 0));
+        let helper = ShellHelper::default();
+        *helper.devices.lock().unwrap() = vec![
+            DeviceEntry {
+                room: "hall".into(),
+                device: socket("lamp"),
+                version: 0,
+            },
+            DeviceEntry {
+                room: "kitchen".into(),
+                device: socket("kettle"),
+                version: 0,
+            },
+        ];
+        helper
+    }
+
+    #[test]
+    fn complete_commands_and_targets() {
+        let helper = helper();
+        assert_eq!(helper.candidates("st"), (0, vec!["state".to_owned()]));
+        assert_eq!(
+            helper.candidates("on k"),
+            (3, vec!["kitchen/kettle".to_owned()])
+        );
+        assert_eq!(
+            helper.candidates("watch h"),
+            (6, vec!["hall".to_owned(), "hall/lamp".to_owned()])
+        );
+        assert!(helper.candidates("on hall/lamp ").1.is_empty());
+    }
+
+    #[test]
+    fn parse_commands() {
+        assert_eq!(
+            ShellCommand::parse(" watch Hall/Lamp ").unwrap(),
+            ShellCommand::Watch(Some(("hall".into(), Some("lamp".into()))))
+        );
+        assert!(ShellCommand::parse("on lamp").is_err());
+        assert!(ShellCommand::parse("toggle hall/lamp").is_err());
+    }
+}
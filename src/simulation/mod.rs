@@ -0,0 +1,348 @@
+//! Deterministic simulation of a whole house for testing automations without hardware.
+//!
+//! A `Simulation` owns a regular `SmartHouse` and `SmartDeviceList`, so code under test
+//! talks to the same types it uses in production. Time only moves when the simulation
+//! is stepped; every random decision (command failures, latency, sensor noise) comes
+//! from a seeded generator, so the same seed and the same calls give the same run.
+//!
+//! Each room has a first order thermal model: its temperature approaches the outdoor
+//! temperature with the room's time constant, and sockets marked as heaters add heat
+//! while they are powered. Thermometers report the temperature of their room.
+
+use crate::device_key::{device_key, DeviceKey};
+use crate::{
+    CommandData, CustomError, CustomResult, DeviceFault, ExecutionResult, FaultKind, PowerSocket,
+    Room, SmartDevice, SmartDeviceList, SmartHouse, Temperature, Thermometer, VirtualClock,
+};
+use std::collections::BTreeMap;
+use std::f64::consts::PI;
+use std::time::{Duration, SystemTime};
+
+/// Small seedable generator (xorshift64*), good enough for simulations.
+#[derive(Debug, Clone)]
+pub struct SimRng(u64);
+
+impl SimRng {
+    pub fn new(seed: u64) -> Self {
+        // xorshift must not start at zero; mix the seed so close seeds diverge quickly
+        Self((seed ^ 0x9E37_79B9_7F4A_7C15).wrapping_mul(0xBF58_476D_1CE4_E5B9) | 1)
+    }
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+    /// uniform in `[0, 1)`
+    pub fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+    /// true with probability `p`
+    pub fn chance(&mut self, p: f64) -> bool {
+        self.next_f64() < p
+    }
+    /// uniform in `[min, max]`
+    pub fn duration_between(&mut self, min: Duration, max: Duration) -> Duration {
+        if max <= min {
+            return min;
+        }
+        min + (max - min).mul_f64(self.next_f64())
+    }
+    /// approximately normal with mean 0 and standard deviation 1
+    pub fn normal(&mut self) -> f64 {
+        (0..12).map(|_| self.next_f64()).sum::<f64>() - 6.0
+    }
+}
+
+/// How fast a room exchanges heat with the outside and with its heaters.
+#[derive(Debug, Clone, Copy)]
+pub struct ThermalModel {
+    /// time for the difference to the outdoor temperature to drop by ~63%
+    pub time_constant: Duration,
+    /// warming in celsius per hour for every kilowatt of powered heaters
+    pub heating_per_kw: f64,
+}
+
+impl Default for ThermalModel {
+    /// a moderately insulated room where 2 kW keep it about 20°C above outdoors
+    fn default() -> Self {
+        Self {
+            time_constant: Duration::from_secs(4 * 3600),
+            heating_per_kw: 2.5,
+        }
+    }
+}
+
+/// A socket together with its simulated behaviour.
+#[derive(Debug, Clone)]
+pub struct SimulatedSocket {
+    socket: PowerSocket,
+    heater: bool,
+    failure_rate: f64,
+    latency: (Duration, Duration),
+}
+
+impl SimulatedSocket {
+    /// A socket that is off, drawing `watts` when turned on.
+    pub fn new(name: &str, watts: u16) -> Self {
+        Self {
+            socket: PowerSocket::new(name, watts),
+            heater: false,
+            failure_rate: 0.0,
+            latency: (Duration::ZERO, Duration::ZERO),
+        }
+    }
+    /// Heats its room while powered.
+    pub fn heater(mut self) -> Self {
+        self.heater = true;
+        self
+    }
+    /// Probability of a command failing without reaching the socket.
+    pub fn failure_rate(mut self, probability: f64) -> Self {
+        self.failure_rate = probability.clamp(0.0, 1.0);
+        self
+    }
+    /// Commands take a uniformly distributed time between `min` and `max`.
+    pub fn latency(mut self, min: Duration, max: Duration) -> Self {
+        self.latency = (min, max);
+        self
+    }
+}
+
+#[derive(Debug)]
+struct RoomState {
+    name: String,
+    /// celsius
+    temperature: f64,
+    model: ThermalModel,
+    heaters: Vec<String>,
+    /// thermometer name and noise standard deviation in celsius
+    thermometers: Vec<(String, f64)>,
+}
+
+#[derive(Debug)]
+struct SocketProfile {
+    failure_rate: f64,
+    latency: (Duration, Duration),
+}
+
+pub struct Simulation {
+    clock: VirtualClock,
+    rng: SimRng,
+    house: SmartHouse,
+    devices: SmartDeviceList,
+    /// by lowercase room name, ordered so that runs are reproducible
+    rooms: BTreeMap<String, RoomState>,
+    /// by lowercase room and socket name
+    sockets: BTreeMap<DeviceKey, SocketProfile>,
+    /// celsius
+    outdoor: f64,
+    daily_swing: f64,
+}
+
+impl Simulation {
+    /// The clock starts at the unix epoch, so runs do not depend on the real time.
+    /// Socket timers run on the same clock.
+    pub fn new(seed: u64) -> Self {
+        let clock = VirtualClock::new(SystemTime::UNIX_EPOCH);
+        let devices = SmartDeviceList::new();
+        devices.set_clock(clock.clone());
+        Self {
+            clock,
+            rng: SimRng::new(seed),
+            house: SmartHouse::new(),
+            devices,
+            rooms: BTreeMap::new(),
+            sockets: BTreeMap::new(),
+            outdoor: 10.0,
+            daily_swing: 0.0,
+        }
+    }
+    /// Mean outdoor temperature, 10°C by default.
+    pub fn with_outdoor_temperature(mut self, temperature: Temperature) -> Self {
+        self.outdoor = temperature.as_celsius_f64();
+        self
+    }
+    /// Outdoor temperature varies by `celsius` around the mean over a day,
+    /// coldest at 03:00 and warmest at 15:00 of the virtual clock.
+    pub fn with_daily_swing(mut self, celsius: f64) -> Self {
+        self.daily_swing = celsius;
+        self
+    }
+
+    pub fn add_room(
+        &mut self,
+        name: &str,
+        temperature: Temperature,
+        model: ThermalModel,
+    ) -> CustomResult<()> {
+        self.house.try_add_room(Room::with_name(name))?;
+        self.rooms.insert(
+            name.to_lowercase(),
+            RoomState {
+                name: name.to_owned(),
+                temperature: temperature.as_celsius_f64(),
+                model,
+                heaters: Vec::new(),
+                thermometers: Vec::new(),
+            },
+        );
+        Ok(())
+    }
+    pub fn add_socket(&mut self, room: &str, socket: SimulatedSocket) -> CustomResult<()> {
+        let name = socket.socket.name.clone();
+        self.add_device(room, SmartDevice::Socket(socket.socket))?;
+        if socket.heater {
+            self.room_mut(room)?.heaters.push(name.clone());
+        }
+        self.sockets.insert(
+            device_key(room, &name),
+            SocketProfile {
+                failure_rate: socket.failure_rate,
+                latency: socket.latency,
+            },
+        );
+        Ok(())
+    }
+    /// Readings deviate from the room temperature by normally distributed
+    /// noise with standard deviation `noise` (celsius).
+    pub fn add_thermometer(&mut self, room: &str, name: &str, noise: f64) -> CustomResult<()> {
+        let temperature = self.room_mut(room)?.temperature;
+        self.add_device(
+            room,
+            SmartDevice::Thermo(Thermometer::new(
+                name,
+                Temperature::Celsius(temperature as f32),
+            )),
+        )?;
+        self.room_mut(room)?
+            .thermometers
+            .push((name.to_owned(), noise));
+        Ok(())
+    }
+
+    pub fn clock(&self) -> &VirtualClock {
+        &self.clock
+    }
+    pub fn house(&self) -> &SmartHouse {
+        &self.house
+    }
+    /// The simulated devices; clones share them with the simulation.
+    pub fn devices(&self) -> &SmartDeviceList {
+        &self.devices
+    }
+    /// True temperature of the room, without sensor noise.
+    pub fn room_temperature(&self, room: &str) -> Option<Temperature> {
+        self.rooms
+            .get(&room.to_lowercase())
+            .map(|r| Temperature::Celsius(r.temperature as f32))
+    }
+    pub fn outdoor_temperature(&self) -> Temperature {
+        Temperature::Celsius(self.outdoor_at(self.clock.elapsed()) as f32)
+    }
+
+    /// Executes a command the way a real network would: the simulation runs for
+    /// the socket's latency first, and the command may fail without reaching it.
+    /// Fails with `CustomError::AmbiguousDevice` when several rooms have a
+    /// device of that name, see `execute_in`.
+    pub fn execute(&mut self, command: CommandData) -> ExecutionResult {
+        self.execute_at(None, command)
+    }
+    /// Same as `execute`, for the device in `room`.
+    pub fn execute_in(&mut self, room: &str, command: CommandData) -> ExecutionResult {
+        self.execute_at(Some(room), command)
+    }
+
+    fn execute_at(&mut self, room: Option<&str>, mut command: CommandData) -> ExecutionResult {
+        let entry = match self.devices.resolve(room, &command.device_name) {
+            Ok(entry) => entry,
+            Err(err) => return ExecutionResult::Error(err),
+        };
+        command.device_name = entry.device.get_name();
+        let key = device_key(&entry.room, &command.device_name);
+        if let Some(profile) = self.sockets.get(&key) {
+            let (min, max) = profile.latency;
+            let failure_rate = profile.failure_rate;
+            let latency = self.rng.duration_between(min, max);
+            self.step(latency);
+            if self.rng.chance(failure_rate) {
+                let fault = DeviceFault::new(&command.device_name, FaultKind::Unreachable)
+                    .with_detail("did not respond");
+                return ExecutionResult::Error(fault.into());
+            }
+        }
+        self.devices.execute_command_in(&entry.room, command)
+    }
+
+    /// Advances the clock and updates rooms and thermometers.
+    pub fn step(&mut self, duration: Duration) {
+        if duration.is_zero() {
+            return;
+        }
+        let outdoor = self.outdoor_at(self.clock.elapsed() + duration / 2);
+        for room in self.rooms.values_mut() {
+            let watts: u32 = room
+                .heaters
+                .iter()
+                .filter_map(|heater| {
+                    match self.devices.resolve(Some(&room.name), heater).ok()?.device {
+                        SmartDevice::Socket(s) if s.is_turned_on() => {
+                            Some(s.get_power_consumption() as u32)
+                        }
+                        _ => None,
+                    }
+                })
+                .sum();
+            let tau_hours = room.model.time_constant.as_secs_f64() / 3600.0;
+            // temperature the room settles at with the current heating
+            let settled = outdoor + watts as f64 / 1000.0 * room.model.heating_per_kw * tau_hours;
+            let decay = (-duration.as_secs_f64() / room.model.time_constant.as_secs_f64()).exp();
+            room.temperature = settled + (room.temperature - settled) * decay;
+        }
+        self.clock.advance(duration);
+        self.devices.run_timers();
+        for room in self.rooms.values() {
+            for (thermometer, noise) in &room.thermometers {
+                let reading = room.temperature + self.rng.normal() * noise;
+                self.devices
+                    .with_device_mut(&room.name, thermometer, |device| {
+                        if let SmartDevice::Thermo(t) = device {
+                            t.state = Temperature::Celsius(reading as f32);
+                        }
+                    })
+                    .ok();
+            }
+        }
+    }
+    /// Steps through `duration` in increments of at most `step`,
+    /// calling `tick` after every increment (e.g. to run an automation).
+    pub fn run_for<F: FnMut(&mut Simulation)>(
+        &mut self,
+        duration: Duration,
+        step: Duration,
+        mut tick: F,
+    ) {
+        let end = self.clock.elapsed() + duration;
+        let step = if step.is_zero() { duration } else { step };
+        while self.clock.elapsed() < end {
+            let step = step.min(end - self.clock.elapsed());
+            self.step(step);
+            tick(self);
+        }
+    }
+
+    fn add_device(&mut self, room: &str, device: SmartDevice) -> CustomResult<()> {
+        self.room_mut(room)?;
+        self.house.try_add_device(room, &device.get_name())?;
+        self.devices.add_device(room, device)
+    }
+    fn room_mut(&mut self, room: &str) -> CustomResult<&mut RoomState> {
+        self.rooms
+            .get_mut(&room.to_lowercase())
+            .ok_or(CustomError::RoomNotFound)
+    }
+    fn outdoor_at(&self, elapsed: Duration) -> f64 {
+        let hours = elapsed.as_secs_f64() / 3600.0;
+        self.outdoor + self.daily_swing * (2.0 * PI * (hours - 9.0) / 24.0).sin()
+    }
+}
@@ -0,0 +1,139 @@
+//! What a device can do, described in data so clients can build their UI from it.
+
+use crate::DeviceCommand;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// Type of a command parameter.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ParameterKind {
+    Integer,
+    Number,
+    Duration,
+    Text,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ParameterSpec {
+    pub name: String,
+    pub kind: ParameterKind,
+    #[serde(default)]
+    pub unit: Option<String>,
+    /// bounds of the value, of the length for text
+    #[serde(default)]
+    pub min: Option<f64>,
+    #[serde(default)]
+    pub max: Option<f64>,
+    pub description: String,
+}
+
+impl ParameterSpec {
+    pub fn new(name: &str, kind: ParameterKind, description: &str) -> Self {
+        Self {
+            name: name.to_owned(),
+            kind,
+            unit: None,
+            min: None,
+            max: None,
+            description: description.to_owned(),
+        }
+    }
+    pub fn unit(mut self, unit: &str) -> Self {
+        self.unit = Some(unit.to_owned());
+        self
+    }
+    pub fn range(mut self, min: f64, max: f64) -> Self {
+        self.min = Some(min);
+        self.max = Some(max);
+        self
+    }
+}
+
+/// A command the device accepts.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CommandSpec {
+    /// same as `DeviceCommand::name`
+    pub name: String,
+    pub description: String,
+    #[serde(default)]
+    pub parameters: Vec<ParameterSpec>,
+}
+
+impl CommandSpec {
+    pub fn new(name: &str, description: &str) -> Self {
+        Self {
+            name: name.to_owned(),
+            description: description.to_owned(),
+            parameters: Vec::new(),
+        }
+    }
+    pub fn parameter(mut self, parameter: ParameterSpec) -> Self {
+        self.parameters.push(parameter);
+        self
+    }
+}
+
+/// A value the device reports.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ReadingSpec {
+    pub name: String,
+    #[serde(default)]
+    pub unit: Option<String>,
+    pub description: String,
+}
+
+impl ReadingSpec {
+    pub fn new(name: &str, unit: Option<&str>, description: &str) -> Self {
+        Self {
+            name: name.to_owned(),
+            unit: unit.map(str::to_owned),
+            description: description.to_owned(),
+        }
+    }
+}
+
+/// Commands a device accepts and readings it provides.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Capabilities {
+    /// same as `SmartDevice::get_type`
+    pub kind: String,
+    pub commands: Vec<CommandSpec>,
+    pub readings: Vec<ReadingSpec>,
+}
+
+impl Capabilities {
+    pub fn command(&self, name: &str) -> Option<&CommandSpec> {
+        self.commands.iter().find(|c| c.name == name)
+    }
+    pub fn reading(&self, name: &str) -> Option<&ReadingSpec> {
+        self.readings.iter().find(|r| r.name == name)
+    }
+    pub fn accepts(&self, command: &DeviceCommand) -> bool {
+        self.command(command.name()).is_some()
+    }
+}
+
+impl fmt::Display for Capabilities {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.kind)?;
+        for command in &self.commands {
+            write!(f, "\n  command {}", command.name)?;
+            for p in &command.parameters {
+                write!(f, " <{}", p.name)?;
+                if let Some(unit) = &p.unit {
+                    write!(f, " {}", unit)?;
+                }
+                write!(f, ">")?;
+            }
+            write!(f, ": {}", command.description)?;
+        }
+        for reading in &self.readings {
+            write!(f, "\n  reading {}", reading.name)?;
+            if let Some(unit) = &reading.unit {
+                write!(f, " ({})", unit)?;
+            }
+            write!(f, ": {}", reading.description)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,432 @@
+//! Compact encodings of device commands.
+//!
+//! Every command starts with a two byte code, the device type followed by the command,
+//! and commands with a parameter append it as payload. As a number the code is
+//! `device type << 8 | command`, `0x0101` for `socket.on`.
+//!
+//! | device type | byte   | command          | byte   | payload                | text                         |
+//! |-------------|--------|------------------|--------|------------------------|------------------------------|
+//! | socket      | `0x01` | `TurnOff`        | `0x00` |                        | `socket.off`                 |
+//! |             |        | `TurnOn`         | `0x01` |                        | `socket.on`                  |
+//! |             |        | `GetState`       | `0x02` |                        | `socket.state`               |
+//! |             |        | `SetPowerLimit`  | `0x03` | watts, `u16`           | `socket.limit 2000`          |
+//! |             |        | `TurnOnFor`      | `0x04` | seconds, `u32`         | `socket.on_for 30m`          |
+//! |             |        | `SetDescription` | `0x05` | UTF-8 text             | `socket.description <text>`  |
+//! |             |        | `TurnOffIn`      | `0x06` | seconds, `u32`         | `socket.off_in 10m`          |
+//! |             |        | `TurnOnIn`       | `0x07` | seconds, `u32`         | `socket.on_in 1h`            |
+//! |             |        | `CancelTimer`    | `0x08` |                        | `socket.cancel`              |
+//! | thermometer | `0x02` | `SetCalibration` | `0x00` | degrees celsius, `f32` | `thermometer.calibrate -0.5` |
+//! |             |        | `GetTemperature` | `0x01` |                        | `thermometer.temperature`    |
+//! |             |        | `SetReading`     | `0x02` | unit `u8`, value `f32` | `thermometer.reading 21.5C`  |
+//!
+//! Units of readings are `0x00` celsius, `0x01` fahrenheit and `0x02` kelvin.
+//! Numbers are big endian. Durations in text take an `s`, `m` or `h` suffix, seconds without.
+//! Malformed codes are reported as `CustomError::InvalidCommandCode`,
+//! parameters out of range as `CustomError::InvalidParameter`.
+
+use crate::{
+    CustomError, CustomResult, DeviceCommand, PowerSocketCommand, Temperature, TemperatureUnit,
+    ThermometerCommand, MAX_CALIBRATION, MAX_DESCRIPTION_LEN, MAX_SOCKET_POWER, MAX_TIMER,
+};
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+const SOCKET: u8 = 0x01;
+const THERMOMETER: u8 = 0x02;
+
+fn invalid(msg: String) -> CustomError {
+    CustomError::InvalidCommandCode(msg)
+}
+
+impl DeviceCommand {
+    /// Text form of the command without its parameter, also used in capability descriptors.
+    pub fn name(&self) -> &'static str {
+        match self {
+            DeviceCommand::PowerSocket(command) => match command {
+                PowerSocketCommand::TurnOff => "socket.off",
+                PowerSocketCommand::TurnOn => "socket.on",
+                PowerSocketCommand::GetState => "socket.state",
+                PowerSocketCommand::SetPowerLimit(_) => "socket.limit",
+                PowerSocketCommand::TurnOnFor(_) => "socket.on_for",
+                PowerSocketCommand::SetDescription(_) => "socket.description",
+                PowerSocketCommand::TurnOffIn(_) => "socket.off_in",
+                PowerSocketCommand::TurnOnIn(_) => "socket.on_in",
+                PowerSocketCommand::CancelTimer => "socket.cancel",
+            },
+            DeviceCommand::Thermometer(command) => match command {
+                ThermometerCommand::SetCalibration(_) => "thermometer.calibrate",
+                ThermometerCommand::GetTemperature => "thermometer.temperature",
+                ThermometerCommand::SetReading(_) => "thermometer.reading",
+            },
+        }
+    }
+    /// The two byte code, without the payload.
+    pub fn code(&self) -> u16 {
+        let [device, command] = self.code_bytes();
+        u16::from_be_bytes([device, command])
+    }
+    /// Only for commands without a parameter.
+    pub fn from_code(code: u16) -> CustomResult<Self> {
+        Self::from_bytes(&code.to_be_bytes())
+    }
+    /// Wire form of the command, the code followed by the payload.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = self.code_bytes().to_vec();
+        match self {
+            DeviceCommand::PowerSocket(PowerSocketCommand::SetPowerLimit(watts)) => {
+                bytes.extend(watts.to_be_bytes())
+            }
+            DeviceCommand::PowerSocket(
+                PowerSocketCommand::TurnOnFor(duration)
+                | PowerSocketCommand::TurnOffIn(duration)
+                | PowerSocketCommand::TurnOnIn(duration),
+            ) => {
+                let secs = u32::try_from(duration.as_secs()).unwrap_or(u32::MAX);
+                bytes.extend(secs.to_be_bytes())
+            }
+            DeviceCommand::PowerSocket(PowerSocketCommand::SetDescription(text)) => {
+                bytes.extend(text.as_bytes())
+            }
+            DeviceCommand::Thermometer(ThermometerCommand::SetCalibration(offset)) => {
+                bytes.extend(offset.to_be_bytes())
+            }
+            DeviceCommand::Thermometer(ThermometerCommand::SetReading(temperature)) => {
+                bytes.push(match temperature.unit() {
+                    TemperatureUnit::Celsius => 0x00,
+                    TemperatureUnit::Fahrenheit => 0x01,
+                    TemperatureUnit::Kelvin => 0x02,
+                });
+                bytes.extend(temperature.value().to_be_bytes())
+            }
+            DeviceCommand::PowerSocket(_) | DeviceCommand::Thermometer(_) => {}
+        }
+        bytes
+    }
+    /// Parses and validates the whole of `bytes`, trailing bytes are an error.
+    pub fn from_bytes(bytes: &[u8]) -> CustomResult<Self> {
+        let [device, command, payload @ ..] = bytes else {
+            return Err(invalid(format!(
+                "a command code has 2 bytes, got {}",
+                bytes.len()
+            )));
+        };
+        let parsed = match (*device, *command) {
+            (SOCKET, 0x00) => DeviceCommand::PowerSocket(PowerSocketCommand::TurnOff),
+            (SOCKET, 0x01) => DeviceCommand::PowerSocket(PowerSocketCommand::TurnOn),
+            (SOCKET, 0x02) => DeviceCommand::PowerSocket(PowerSocketCommand::GetState),
+            (SOCKET, 0x03) => DeviceCommand::PowerSocket(PowerSocketCommand::SetPowerLimit(
+                u16::from_be_bytes(fixed("socket.limit", payload)?),
+            )),
+            (SOCKET, 0x04) => DeviceCommand::PowerSocket(PowerSocketCommand::TurnOnFor(seconds(
+                "socket.on_for",
+                payload,
+            )?)),
+            (SOCKET, 0x06) => DeviceCommand::PowerSocket(PowerSocketCommand::TurnOffIn(seconds(
+                "socket.off_in",
+                payload,
+            )?)),
+            (SOCKET, 0x07) => DeviceCommand::PowerSocket(PowerSocketCommand::TurnOnIn(seconds(
+                "socket.on_in",
+                payload,
+            )?)),
+            (SOCKET, 0x08) => DeviceCommand::PowerSocket(PowerSocketCommand::CancelTimer),
+            (SOCKET, 0x05) => {
+                let text = std::str::from_utf8(payload)
+                    .map_err(|_| invalid("socket.description is not UTF-8".into()))?;
+                return DeviceCommand::PowerSocket(PowerSocketCommand::SetDescription(
+                    text.to_owned(),
+                ))
+                .validated();
+            }
+            (SOCKET, _) => return Err(invalid(format!("unknown socket command {:#04x}", command))),
+            (THERMOMETER, 0x00) => DeviceCommand::Thermometer(ThermometerCommand::SetCalibration(
+                f32::from_be_bytes(fixed("thermometer.calibrate", payload)?),
+            )),
+            (THERMOMETER, 0x01) => DeviceCommand::Thermometer(ThermometerCommand::GetTemperature),
+            (THERMOMETER, 0x02) => {
+                let [unit, value @ ..] = fixed::<5>("thermometer.reading", payload)?;
+                let unit = match unit {
+                    0x00 => TemperatureUnit::Celsius,
+                    0x01 => TemperatureUnit::Fahrenheit,
+                    0x02 => TemperatureUnit::Kelvin,
+                    _ => return Err(invalid(format!("unknown temperature unit {:#04x}", unit))),
+                };
+                DeviceCommand::Thermometer(ThermometerCommand::SetReading(Temperature::new(
+                    f32::from_be_bytes(value),
+                    unit,
+                )))
+            }
+            (THERMOMETER, _) => {
+                return Err(invalid(format!(
+                    "unknown thermometer command {:#04x}",
+                    command
+                )))
+            }
+            _ => return Err(invalid(format!("unknown device type {:#04x}", device))),
+        };
+        // payloads of commands with a parameter were checked for their length already
+        if !parsed.has_parameter() && !payload.is_empty() {
+            return Err(invalid(format!(
+                "{} trailing bytes after {}",
+                payload.len(),
+                parsed
+            )));
+        }
+        parsed.validated()
+    }
+    /// Checks the parameter against its range in the device capabilities.
+    pub fn validate(&self) -> CustomResult<()> {
+        let error = |parameter: &str, reason: String| CustomError::InvalidParameter {
+            command: self.name().to_owned(),
+            parameter: parameter.to_owned(),
+            reason,
+        };
+        match self {
+            DeviceCommand::PowerSocket(PowerSocketCommand::SetPowerLimit(watts)) => {
+                if !(1..=MAX_SOCKET_POWER).contains(watts) {
+                    return Err(error(
+                        "watts",
+                        format!("{} is not within 1..={}", watts, MAX_SOCKET_POWER),
+                    ));
+                }
+            }
+            DeviceCommand::PowerSocket(
+                PowerSocketCommand::TurnOnFor(duration)
+                | PowerSocketCommand::TurnOffIn(duration)
+                | PowerSocketCommand::TurnOnIn(duration),
+            ) => {
+                let parameter = match self {
+                    DeviceCommand::PowerSocket(PowerSocketCommand::TurnOnFor(_)) => "duration",
+                    _ => "delay",
+                };
+                if duration.subsec_nanos() != 0 {
+                    return Err(error(parameter, "must be whole seconds".into()));
+                }
+                if duration.is_zero() || *duration > MAX_TIMER {
+                    return Err(error(
+                        parameter,
+                        format!(
+                            "{}s is not within 1s..={}s",
+                            duration.as_secs(),
+                            MAX_TIMER.as_secs()
+                        ),
+                    ));
+                }
+            }
+            DeviceCommand::PowerSocket(PowerSocketCommand::SetDescription(text)) => {
+                if text.chars().count() > MAX_DESCRIPTION_LEN {
+                    return Err(error(
+                        "text",
+                        format!("longer than {} characters", MAX_DESCRIPTION_LEN),
+                    ));
+                }
+                if text.chars().any(char::is_control) {
+                    return Err(error("text", "contains control characters".into()));
+                }
+            }
+            DeviceCommand::Thermometer(ThermometerCommand::SetCalibration(offset)) => {
+                if !(-MAX_CALIBRATION..=MAX_CALIBRATION).contains(offset) {
+                    return Err(error(
+                        "offset",
+                        format!(
+                            "{} is not within -{}..={}",
+                            offset, MAX_CALIBRATION, MAX_CALIBRATION
+                        ),
+                    ));
+                }
+            }
+            DeviceCommand::Thermometer(ThermometerCommand::SetReading(temperature)) => {
+                if !temperature.value().is_finite() {
+                    return Err(error("temperature", "is not a number".into()));
+                }
+                if temperature.is_below_absolute_zero() {
+                    return Err(error(
+                        "temperature",
+                        format!("{} is below absolute zero", temperature),
+                    ));
+                }
+            }
+            DeviceCommand::PowerSocket(_) | DeviceCommand::Thermometer(_) => {}
+        }
+        Ok(())
+    }
+
+    fn validated(self) -> CustomResult<Self> {
+        self.validate()?;
+        Ok(self)
+    }
+    fn has_parameter(&self) -> bool {
+        !matches!(
+            self,
+            DeviceCommand::PowerSocket(
+                PowerSocketCommand::TurnOff
+                    | PowerSocketCommand::TurnOn
+                    | PowerSocketCommand::GetState
+                    | PowerSocketCommand::CancelTimer
+            ) | DeviceCommand::Thermometer(ThermometerCommand::GetTemperature)
+        )
+    }
+    fn code_bytes(&self) -> [u8; 2] {
+        match self {
+            DeviceCommand::PowerSocket(command) => [
+                SOCKET,
+                match command {
+                    PowerSocketCommand::TurnOff => 0x00,
+                    PowerSocketCommand::TurnOn => 0x01,
+                    PowerSocketCommand::GetState => 0x02,
+                    PowerSocketCommand::SetPowerLimit(_) => 0x03,
+                    PowerSocketCommand::TurnOnFor(_) => 0x04,
+                    PowerSocketCommand::SetDescription(_) => 0x05,
+                    PowerSocketCommand::TurnOffIn(_) => 0x06,
+                    PowerSocketCommand::TurnOnIn(_) => 0x07,
+                    PowerSocketCommand::CancelTimer => 0x08,
+                },
+            ],
+            DeviceCommand::Thermometer(command) => [
+                THERMOMETER,
+                match command {
+                    ThermometerCommand::SetCalibration(_) => 0x00,
+                    ThermometerCommand::GetTemperature => 0x01,
+                    ThermometerCommand::SetReading(_) => 0x02,
+                },
+            ],
+        }
+    }
+}
+
+/// Payload of exactly `N` bytes.
+fn fixed<const N: usize>(command: &str, payload: &[u8]) -> CustomResult<[u8; N]> {
+    payload.try_into().map_err(|_| {
+        invalid(format!(
+            "{} needs a {} byte payload, got {}",
+            command,
+            N,
+            payload.len()
+        ))
+    })
+}
+
+/// Whole seconds as a `u32` payload.
+fn seconds(command: &str, payload: &[u8]) -> CustomResult<Duration> {
+    let secs = u32::from_be_bytes(fixed(command, payload)?);
+    Ok(Duration::from_secs(secs.into()))
+}
+
+/// `90`, `90s`, `30m` or `2h`.
+fn parse_duration(text: &str) -> CustomResult<Duration> {
+    let (number, scale) = match text.char_indices().last() {
+        Some((i, 's')) => (&text[..i], 1),
+        Some((i, 'm')) => (&text[..i], 60),
+        Some((i, 'h')) => (&text[..i], 60 * 60),
+        _ => (text, 1),
+    };
+    let number: u64 = number
+        .parse()
+        .map_err(|_| invalid(format!("invalid duration '{}'", text)))?;
+    number
+        .checked_mul(scale)
+        .map(Duration::from_secs)
+        .ok_or_else(|| invalid(format!("invalid duration '{}'", text)))
+}
+
+impl fmt::Display for DeviceCommand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())?;
+        match self {
+            DeviceCommand::PowerSocket(PowerSocketCommand::SetPowerLimit(watts)) => {
+                write!(f, " {}", watts)
+            }
+            DeviceCommand::PowerSocket(
+                PowerSocketCommand::TurnOnFor(duration)
+                | PowerSocketCommand::TurnOffIn(duration)
+                | PowerSocketCommand::TurnOnIn(duration),
+            ) => write!(f, " {}s", duration.as_secs()),
+            DeviceCommand::PowerSocket(PowerSocketCommand::SetDescription(text)) => {
+                write!(f, " {}", text)
+            }
+            DeviceCommand::Thermometer(ThermometerCommand::SetCalibration(offset)) => {
+                write!(f, " {}", offset)
+            }
+            DeviceCommand::Thermometer(ThermometerCommand::SetReading(temperature)) => {
+                write!(f, " {}", temperature)
+            }
+            DeviceCommand::PowerSocket(_) | DeviceCommand::Thermometer(_) => Ok(()),
+        }
+    }
+}
+
+/// Parses and validates the text form, e.g. `socket.on` or `socket.limit 2000`.
+/// Names ignore case, a description is taken as written.
+impl FromStr for DeviceCommand {
+    type Err = CustomError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let (name, argument) = match s.split_once(char::is_whitespace) {
+            Some((name, argument)) => (name, Some(argument.trim())),
+            None => (s, None),
+        };
+        let name = name.to_lowercase();
+        let (device, command) = name
+            .split_once('.')
+            .ok_or_else(|| invalid(format!("expected <device>.<command>, got '{}'", s)))?;
+        let required = |parameter: &str| {
+            argument.ok_or_else(|| invalid(format!("{} needs a {}", name, parameter)))
+        };
+        let parsed = match (device, command) {
+            ("socket", "off") => DeviceCommand::PowerSocket(PowerSocketCommand::TurnOff),
+            ("socket", "on") => DeviceCommand::PowerSocket(PowerSocketCommand::TurnOn),
+            ("socket", "state") => DeviceCommand::PowerSocket(PowerSocketCommand::GetState),
+            ("socket", "limit") => {
+                let text = required("watts")?;
+                let watts = text
+                    .parse()
+                    .map_err(|_| invalid(format!("invalid watts '{}'", text)))?;
+                DeviceCommand::PowerSocket(PowerSocketCommand::SetPowerLimit(watts))
+            }
+            ("socket", "on_for") => DeviceCommand::PowerSocket(PowerSocketCommand::TurnOnFor(
+                parse_duration(required("duration")?)?,
+            )),
+            ("socket", "off_in") => DeviceCommand::PowerSocket(PowerSocketCommand::TurnOffIn(
+                parse_duration(required("delay")?)?,
+            )),
+            ("socket", "on_in") => DeviceCommand::PowerSocket(PowerSocketCommand::TurnOnIn(
+                parse_duration(required("delay")?)?,
+            )),
+            ("socket", "cancel") => DeviceCommand::PowerSocket(PowerSocketCommand::CancelTimer),
+            ("socket", "description") => DeviceCommand::PowerSocket(
+                PowerSocketCommand::SetDescription(argument.unwrap_or_default().to_owned()),
+            ),
+            ("thermometer", "calibrate") => {
+                let text = required("offset")?;
+                let offset = text
+                    .parse()
+                    .map_err(|_| invalid(format!("invalid offset '{}'", text)))?;
+                DeviceCommand::Thermometer(ThermometerCommand::SetCalibration(offset))
+            }
+            ("thermometer", "temperature") => {
+                DeviceCommand::Thermometer(ThermometerCommand::GetTemperature)
+            }
+            ("thermometer", "reading") => {
+                let text = required("temperature")?;
+                let temperature = text
+                    .parse()
+                    .map_err(|_| invalid(format!("invalid temperature '{}'", text)))?;
+                DeviceCommand::Thermometer(ThermometerCommand::SetReading(temperature))
+            }
+            ("socket", _) => return Err(invalid(format!("unknown socket command '{}'", command))),
+            ("thermometer", _) => {
+                return Err(invalid(format!(
+                    "unknown thermometer command '{}'",
+                    command
+                )))
+            }
+            _ => return Err(invalid(format!("unknown device type '{}'", device))),
+        };
+        if argument.is_some() && !parsed.has_parameter() {
+            return Err(invalid(format!("{} takes no parameter", name)));
+        }
+        parsed.validated()
+    }
+}
@@ -1,84 +1,171 @@
 use serde::{Deserialize, Serialize};
 
-use crate::{error::CustomError, house::CustomResult, PowerSocketState};
+use crate::{
+    Batch, BatchResult, Capabilities, Countdown, Credentials, DeviceEntry, DeviceEvent,
+    PowerSocketState, Temperature,
+};
+use std::fmt;
+use std::time::Duration;
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum Command {
     Execute(CommandData),
+    /// runs the command only if the device is still at `version`,
+    /// see `SmartDeviceList::execute_command_if`
+    ExecuteIf {
+        command: CommandData,
+        version: u64,
+    },
+    /// runs the command on the device of that name in `room`,
+    /// optionally only if it is still at `version`
+    ExecuteIn {
+        room: String,
+        command: CommandData,
+        #[serde(default)]
+        version: Option<u64>,
+    },
+    /// answered with `ExecutionResult::Devices`
+    ListDevices,
+    /// server answers with `ExecutionResult::Devices` holding the current state,
+    /// then keeps sending `ExecutionResult::Event` until the connection is closed
+    Watch,
+    /// capabilities of the named device, in `room` if given,
+    /// answered with `ExecutionResult::Capabilities`
+    Describe {
+        device: String,
+        #[serde(default)]
+        room: Option<String>,
+    },
+    /// answered with `ExecutionResult::Batch`
+    Batch(Batch),
+    /// must be the first command when the server requires authentication,
+    /// answered with `ExecutionResult::Authenticated`
+    Authenticate(Credentials),
     Unknown,
 }
-#[derive(Debug, Serialize, Deserialize, Copy, Clone)]
+/// Highest power limit a socket accepts, 16 A at 230 V.
+pub const MAX_SOCKET_POWER: u16 = 3680;
+/// Longest time a socket can be switched on for.
+pub const MAX_TIMER: Duration = Duration::from_secs(24 * 60 * 60);
+pub const MAX_DESCRIPTION_LEN: usize = 256;
+/// Largest calibration offset of a thermometer in either direction, in degrees celsius.
+pub const MAX_CALIBRATION: f32 = 10.;
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 pub enum DeviceCommand {
     PowerSocket(PowerSocketCommand),
+    Thermometer(ThermometerCommand),
 }
-impl DeviceCommand {
-    fn from_u8(n: u8) -> CustomResult<Self> {
-        let s = n.to_string();
-        let mut iter = s.chars().rev();
-        let cmd_code = iter.next().unwrap().to_digit(10).unwrap();
-        let device_code = iter.next().unwrap().to_digit(10).unwrap();
-        match (device_code, cmd_code) {
-            (1, _) => Ok(DeviceCommand::PowerSocket(PowerSocketCommand::from_u8(
-                cmd_code as u8,
-            )?)),
-            _ => Err(CustomError::CommandExecutionFailure(
-                "Unknown device code in command code".into(),
-            )),
-        }
-    }
-
-}
-#[derive(Debug, Serialize, Deserialize, Copy, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
 pub enum PowerSocketCommand {
     TurnOn,
     TurnOff,
     GetState,
+    /// highest load in watts the socket may be switched on with
+    SetPowerLimit(u16),
+    /// switches on, then off again once the duration passed
+    TurnOnFor(Duration),
+    /// switches off once the delay passed
+    TurnOffIn(Duration),
+    /// switches on once the delay passed
+    TurnOnIn(Duration),
+    CancelTimer,
+    SetDescription(String),
+}
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub enum ThermometerCommand {
+    /// degrees celsius added to every reading
+    SetCalibration(f32),
+    GetTemperature,
+    /// raw reading reported for the sensor, e.g. by an MQTT bridge
+    SetReading(Temperature),
 }
 #[derive(Serialize, Deserialize, Debug)]
 pub struct PowerSocketResult {
     pub command: PowerSocketCommand,
-    pub result: Result<PowerSocketState, String>
-    
+    pub result: Result<PowerSocketState, String>,
 }
-impl PowerSocketCommand {
-    fn from_u8(n: u8) -> Result<Self, CustomError> {
-        let cmd: Self = match n {
-            2 => Self::GetState,
-            1 => Self::TurnOn,
-            0 => Self::TurnOff,
-            _ => {
-                return Err(CustomError::CommandExecutionFailure(
-                    "Unknown PowerSocketCommand code".into(),
-                ))
-            }
-        };
-        Ok(cmd)
-    }
-}
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct CommandData {
     pub device_name: String,
     pub data: DeviceCommand,
 }
 
-impl From<(String, u8)> for Command {
-    fn from(key_code: (String, u8)) -> Self {
-        let s = key_code.0;
-        if let Ok(data) = DeviceCommand::from_u8(key_code.1) {
-            Command::Execute(CommandData {
-                device_name: s,
-                data,
-            })
-        } else {
-            Command::Unknown
+/// Device name and wire form of the command, see `DeviceCommand::from_bytes`.
+impl From<(String, Vec<u8>)> for Command {
+    fn from((device_name, bytes): (String, Vec<u8>)) -> Self {
+        match DeviceCommand::from_bytes(&bytes) {
+            Ok(data) => Command::Execute(CommandData { device_name, data }),
+            Err(_) => Command::Unknown,
         }
     }
 }
-#[derive(Debug, Serialize, Deserialize)]
+/// Device name and command code, see `DeviceCommand::from_code`.
+impl From<(String, u16)> for Command {
+    fn from((device_name, code): (String, u16)) -> Self {
+        match DeviceCommand::from_code(code) {
+            Ok(data) => Command::Execute(CommandData { device_name, data }),
+            Err(_) => Command::Unknown,
+        }
+    }
+}
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum ExecutionResult {
     PowerSocket(PowerSocketState),
+    /// state of a socket with a pending timer
+    TimedPowerSocket {
+        state: PowerSocketState,
+        countdown: Countdown,
+    },
+    /// calibrated reading of a thermometer
+    Temperature(Temperature),
+    Devices(Vec<DeviceEntry>),
+    Event(DeviceEvent),
+    /// name of the authenticated principal
+    Authenticated(String),
+    Capabilities(Capabilities),
+    Batch(BatchResult),
     Error(crate::error::CustomError),
 }
+
+impl fmt::Display for ExecutionResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExecutionResult::PowerSocket(PowerSocketState::Powered(watts)) => {
+                write!(f, "on ({} W)", watts)
+            }
+            ExecutionResult::PowerSocket(PowerSocketState::NotPowered) => write!(f, "off"),
+            ExecutionResult::TimedPowerSocket { state, countdown } => {
+                write!(f, "{}, {}", ExecutionResult::PowerSocket(*state), countdown)
+            }
+            ExecutionResult::Temperature(temperature) => write!(f, "{}", temperature),
+            ExecutionResult::Devices(devices) => {
+                let lines: Vec<String> = devices
+                    .iter()
+                    .map(|e| {
+                        format!(
+                            "{}/{}: {}",
+                            e.room,
+                            e.device.get_name(),
+                            e.device.get_state()
+                        )
+                    })
+                    .collect();
+                write!(f, "{}", lines.join("\n"))
+            }
+            ExecutionResult::Event(DeviceEvent::StateChanged {
+                room,
+                device,
+                state,
+            }) => write!(f, "{}/{} -> {}", room, device, state),
+            ExecutionResult::Event(DeviceEvent::Alarm(alarm)) => write!(f, "alarm: {:?}", alarm),
+            ExecutionResult::Authenticated(name) => write!(f, "authenticated as {}", name),
+            ExecutionResult::Capabilities(capabilities) => write!(f, "{}", capabilities),
+            ExecutionResult::Batch(batch) => write!(f, "{}", batch),
+            ExecutionResult::Error(err) => write!(f, "error: {}", err),
+        }
+    }
+}
 pub trait Executable {
     fn execute(&mut self, command: DeviceCommand) -> ExecutionResult;
 }
@@ -1,18 +1,26 @@
+mod capabilities;
+mod code;
 mod command;
 mod power_socket;
 mod thermometer;
 
+use crate::{CustomError, DeviceFault, FaultKind};
+use serde::{Deserialize, Serialize};
 use std::any::Any;
+use std::time::SystemTime;
 
+pub use capabilities::{Capabilities, CommandSpec, ParameterKind, ParameterSpec, ReadingSpec};
 pub use command::{
     Command, CommandData, DeviceCommand, Executable, ExecutionResult, PowerSocketCommand,
+    PowerSocketResult, ThermometerCommand, MAX_CALIBRATION, MAX_DESCRIPTION_LEN, MAX_SOCKET_POWER,
+    MAX_TIMER,
 };
-pub use power_socket::{PowerSocket, PowerSocketState, SocketError};
-pub use thermometer::{Temperature, Thermometer};
-
-use crate::house::CustomResult;
+pub use power_socket::{
+    Countdown, PowerSocket, PowerSocketState, SocketTimer, TimerAction, DEFAULT_SOCKET_POWER,
+};
+pub use thermometer::{Temperature, TemperatureDelta, TemperatureUnit, Thermometer};
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum SmartDevice {
     Thermo(Thermometer),
     Socket(PowerSocket),
@@ -21,9 +29,9 @@ pub enum SmartDevice {
 impl SmartDevice {
     pub fn from(device: Box<dyn Any>) -> Self {
         if device.is::<Thermometer>() {
-            return Self::Thermo(*(device.downcast::<Thermometer>().unwrap()));
+            Self::Thermo(*(device.downcast::<Thermometer>().unwrap()))
         } else if device.is::<PowerSocket>() {
-            return Self::Socket(*(device.downcast::<PowerSocket>().unwrap()));
+            Self::Socket(*(device.downcast::<PowerSocket>().unwrap()))
         } else {
             panic!("unknown device");
         }
@@ -34,6 +42,12 @@ impl SmartDevice {
             SmartDevice::Thermo(t) => t.name.to_owned(),
         }
     }
+    pub fn set_name(&mut self, name: &str) {
+        match self {
+            SmartDevice::Socket(s) => s.name = name.to_owned(),
+            SmartDevice::Thermo(t) => t.name = name.to_owned(),
+        }
+    }
     pub fn get_state(&self) -> String {
         match self {
             SmartDevice::Socket(s) => format!("{:?}", s.get_state()),
@@ -46,9 +60,29 @@ impl SmartDevice {
             SmartDevice::Thermo(_) => "SmartThermometer".to_owned(),
         }
     }
+    pub fn capabilities(&self) -> Capabilities {
+        match self {
+            SmartDevice::Socket(s) => s.capabilities(),
+            SmartDevice::Thermo(t) => t.capabilities(),
+        }
+    }
     pub fn execute_command(&mut self, cmd: DeviceCommand) -> ExecutionResult {
+        self.execute_command_at(cmd, SystemTime::now())
+    }
+    /// Runs the command at `now`, the time socket timers start from.
+    /// Invalid parameters and commands missing from the device's capabilities
+    /// are rejected without running.
+    pub fn execute_command_at(&mut self, cmd: DeviceCommand, now: SystemTime) -> ExecutionResult {
+        if let Err(err) = cmd.validate() {
+            return ExecutionResult::Error(err);
+        }
+        if !self.capabilities().accepts(&cmd) {
+            let fault = DeviceFault::new(&self.get_name(), FaultKind::CommandRejected)
+                .with_detail(&format!("does not support {}", cmd.name()));
+            return ExecutionResult::Error(CustomError::DeviceFault(fault));
+        }
         match self {
-            SmartDevice::Socket(sock) => sock.execute(cmd),
+            SmartDevice::Socket(sock) => sock.execute_at(cmd, now),
             SmartDevice::Thermo(therm) => therm.execute(cmd),
         }
     }
@@ -83,6 +117,7 @@ mod test {
         let thermometer = Thermometer {
             name: "thermometer".to_owned(),
             state: Temperature::Celsius(11.),
+            ..Default::default()
         };
         let device = SmartDevice::Thermo(thermometer);
         assert_eq!(device.get_name(), "thermometer");
@@ -100,6 +135,7 @@ mod test {
             state: PowerSocketState::NotPowered,
             power_consumption: 0,
             description: "smart power socket".to_owned(),
+            ..Default::default()
         };
         let device = SmartDevice::Socket(socket);
         assert_eq!(device.get_name(), "socket");
@@ -1,35 +1,173 @@
-use crate::Executable;
-use crate::{DeviceCommand, PowerSocketCommand};
-use derive_more::Display;
+use crate::{
+    Capabilities, CommandSpec, CustomError, DeviceCommand, DeviceFault, Executable, FaultKind,
+    ParameterKind, ParameterSpec, PowerSocketCommand, ReadingSpec, MAX_DESCRIPTION_LEN,
+    MAX_SOCKET_POWER, MAX_TIMER,
+};
 use serde::{Deserialize, Serialize};
-use std::error::Error;
+use std::fmt;
+use std::time::{Duration, SystemTime};
 
 use super::command::ExecutionResult;
 
-#[derive(Clone, Debug)]
+/// Consumption assumed for sockets with no configured load.
+pub const DEFAULT_SOCKET_POWER: u16 = 220;
+
+/// Fields added after the first four default, so sockets can still be built
+/// with `PowerSocket { name, state, description, power_consumption, ..Default::default() }`.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
 pub struct PowerSocket {
     pub name: String,
     pub state: PowerSocketState,
     pub description: String,
+    /// load drawn while powered, set when the socket is turned on
     pub power_consumption: u16,
+    /// load of the appliance plugged in, `DEFAULT_SOCKET_POWER` when unset
+    #[serde(default)]
+    pub rated_power: Option<u16>,
+    /// highest load the socket may be switched on with
+    #[serde(default)]
+    pub power_limit: Option<u16>,
+    /// pending switch, any manual on or off cancels it
+    #[serde(default)]
+    pub timer: Option<SocketTimer>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TimerAction {
+    TurnOn,
+    TurnOff,
+}
+
+/// A switch scheduled for a point in time.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SocketTimer {
+    pub action: TimerAction,
+    pub at: SystemTime,
+}
+
+/// Time left until a socket timer fires.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Countdown {
+    pub action: TimerAction,
+    pub remaining: Duration,
 }
+
+/// Formats as `off in 44m 59s`.
+impl fmt::Display for Countdown {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let action = match self.action {
+            TimerAction::TurnOn => "on",
+            TimerAction::TurnOff => "off",
+        };
+        let secs = self.remaining.as_secs();
+        let (h, m, s) = (secs / 3600, secs / 60 % 60, secs % 60);
+        match (h, m) {
+            (0, 0) => write!(f, "{} in {}s", action, s),
+            (0, _) => write!(f, "{} in {}m {}s", action, m, s),
+            _ => write!(f, "{} in {}h {}m {}s", action, h, m, s),
+        }
+    }
+}
+
 impl Executable for PowerSocket {
     fn execute(&mut self, command: DeviceCommand) -> ExecutionResult {
-        match command {
-            DeviceCommand::PowerSocket(cmd) => {
-                match cmd {
-                    PowerSocketCommand::TurnOff => self.turn_off(),
-                    PowerSocketCommand::TurnOn => self.turn_on(),
-                    PowerSocketCommand::GetState => {
-                        self.get_state();
-                    }
-                };
-                ExecutionResult::PowerSocket(self.get_state())
-            }
-        }
+        self.execute_at(command, SystemTime::now())
     }
 }
 impl PowerSocket {
+    /// A socket that is off, drawing `watts` when turned on
+    /// (`DEFAULT_SOCKET_POWER` for 0).
+    pub fn new(name: &str, watts: u16) -> Self {
+        Self {
+            name: name.to_owned(),
+            rated_power: (watts != 0).then_some(watts),
+            ..Default::default()
+        }
+    }
+    pub fn with_description(mut self, description: &str) -> Self {
+        self.description = description.to_owned();
+        self
+    }
+    /// Refuses to be switched on with a load over `watts`.
+    pub fn with_power_limit(mut self, watts: u16) -> Self {
+        self.power_limit = Some(watts);
+        self
+    }
+
+    /// Runs the command at `now`, the time timers start from.
+    pub fn execute_at(&mut self, command: DeviceCommand, now: SystemTime) -> ExecutionResult {
+        let cmd = match command {
+            DeviceCommand::PowerSocket(cmd) => cmd,
+            other => return self.reject(&format!("unsupported command {}", other)),
+        };
+        match cmd {
+            PowerSocketCommand::TurnOff => self.turn_off(),
+            PowerSocketCommand::TurnOn | PowerSocketCommand::TurnOnFor(_) => {
+                if let Err(fault) = self.try_turn_on() {
+                    return ExecutionResult::Error(CustomError::DeviceFault(fault));
+                }
+                if let PowerSocketCommand::TurnOnFor(duration) = cmd {
+                    self.schedule(TimerAction::TurnOff, now + duration);
+                }
+            }
+            PowerSocketCommand::TurnOffIn(delay) => {
+                self.schedule(TimerAction::TurnOff, now + delay)
+            }
+            PowerSocketCommand::TurnOnIn(delay) => self.schedule(TimerAction::TurnOn, now + delay),
+            PowerSocketCommand::CancelTimer => self.cancel_timer(),
+            PowerSocketCommand::GetState => {}
+            PowerSocketCommand::SetPowerLimit(watts) => self.set_power_limit(watts),
+            PowerSocketCommand::SetDescription(text) => self.description = text,
+        };
+        match self.countdown(now) {
+            Some(countdown) => ExecutionResult::TimedPowerSocket {
+                state: self.get_state(),
+                countdown,
+            },
+            None => ExecutionResult::PowerSocket(self.get_state()),
+        }
+    }
+    pub fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            kind: "SmartSocket".to_owned(),
+            commands: vec![
+                CommandSpec::new("socket.on", "switch the socket on"),
+                CommandSpec::new("socket.off", "switch the socket off"),
+                CommandSpec::new("socket.state", "report the current state"),
+                CommandSpec::new("socket.limit", "refuse loads above the limit").parameter(
+                    ParameterSpec::new("watts", ParameterKind::Integer, "highest load")
+                        .unit("W")
+                        .range(1., MAX_SOCKET_POWER.into()),
+                ),
+                CommandSpec::new("socket.on_for", "switch on, then off after a while").parameter(
+                    ParameterSpec::new("duration", ParameterKind::Duration, "time to stay on")
+                        .unit("s")
+                        .range(1., MAX_TIMER.as_secs() as f64),
+                ),
+                CommandSpec::new("socket.description", "describe the socket").parameter(
+                    ParameterSpec::new("text", ParameterKind::Text, "new description")
+                        .range(0., MAX_DESCRIPTION_LEN as f64),
+                ),
+                CommandSpec::new("socket.off_in", "switch off after a delay").parameter(
+                    ParameterSpec::new("delay", ParameterKind::Duration, "time until off")
+                        .unit("s")
+                        .range(1., MAX_TIMER.as_secs() as f64),
+                ),
+                CommandSpec::new("socket.on_in", "switch on after a delay").parameter(
+                    ParameterSpec::new("delay", ParameterKind::Duration, "time until on")
+                        .unit("s")
+                        .range(1., MAX_TIMER.as_secs() as f64),
+                ),
+                CommandSpec::new("socket.cancel", "cancel the pending timer"),
+            ],
+            readings: vec![
+                ReadingSpec::new("state", None, "on or off"),
+                ReadingSpec::new("power", Some("W"), "consumption while on"),
+                ReadingSpec::new("countdown", Some("s"), "time until the timer fires"),
+            ],
+        }
+    }
+
     pub fn get_power_consumption(&self) -> u16 {
         self.power_consumption
     }
@@ -38,15 +176,61 @@ impl PowerSocket {
         &self.description
     }
 
+    pub fn get_power_limit(&self) -> Option<u16> {
+        self.power_limit
+    }
+
+    /// Load the socket draws when turned on.
+    pub fn get_rated_power(&self) -> u16 {
+        self.rated_power.unwrap_or(DEFAULT_SOCKET_POWER)
+    }
+
+    /// A powered socket drawing more than `watts` is switched off right away,
+    /// which also cancels its timer.
+    pub fn set_power_limit(&mut self, watts: u16) {
+        self.power_limit = Some(watts);
+        if self.is_turned_on() && self.power_consumption > watts {
+            self.turn_off();
+        }
+    }
+
+    /// Fails when the load is over the socket's `power_limit`.
+    pub fn check_power_limit(&self) -> Result<(), DeviceFault> {
+        match self.power_limit.filter(|l| self.get_rated_power() > *l) {
+            Some(limit) => {
+                let detail = format!(
+                    "load of {} W exceeds the limit of {} W",
+                    self.get_rated_power(),
+                    limit
+                );
+                Err(DeviceFault::new(&self.name, FaultKind::CommandRejected).with_detail(&detail))
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Switches the socket on without checking its `power_limit`.
+    /// Commands go through `try_turn_on` and report an over-limit load
+    /// as an `ExecutionResult::Error`.
     pub fn turn_on(&mut self) {
+        self.timer = None;
         if self.is_turned_on() {
             return;
         }
-        self.power_consumption = 220;
+        self.power_consumption = self.get_rated_power();
         self.state = PowerSocketState::Powered(self.power_consumption);
     }
 
+    /// Refuses a load over the socket's `power_limit`. Circuit limits are
+    /// enforced by `SmartDeviceList`, which knows the other sockets.
+    pub fn try_turn_on(&mut self) -> Result<(), DeviceFault> {
+        self.check_power_limit()?;
+        self.turn_on();
+        Ok(())
+    }
+
     pub fn turn_off(&mut self) {
+        self.timer = None;
         if self.is_turned_on() {
             self.state = PowerSocketState::NotPowered
         }
@@ -56,16 +240,48 @@ impl PowerSocket {
         self.state
     }
 
-    fn is_turned_on(&self) -> bool {
+    pub fn get_timer(&self) -> Option<SocketTimer> {
+        self.timer
+    }
+
+    pub fn cancel_timer(&mut self) {
+        self.timer = None;
+    }
+
+    /// Time left on the pending timer as of `now`.
+    pub fn countdown(&self, now: SystemTime) -> Option<Countdown> {
+        self.timer.map(|timer| Countdown {
+            action: timer.action,
+            remaining: timer.at.duration_since(now).unwrap_or_default(),
+        })
+    }
+    /// Fires the timer when it is due at `now` and returns what it did.
+    /// A timed turn on over the power limit is dropped.
+    pub fn run_timer(&mut self, now: SystemTime) -> Option<TimerAction> {
+        let timer = self.timer.filter(|timer| timer.at <= now)?;
+        self.timer = None;
+        match timer.action {
+            TimerAction::TurnOff => self.turn_off(),
+            TimerAction::TurnOn => self.try_turn_on().ok()?,
+        }
+        Some(timer.action)
+    }
+
+    fn schedule(&mut self, action: TimerAction, at: SystemTime) {
+        self.timer = Some(SocketTimer { action, at });
+    }
+    fn reject(&self, detail: &str) -> ExecutionResult {
+        let fault = DeviceFault::new(&self.name, FaultKind::CommandRejected).with_detail(detail);
+        ExecutionResult::Error(CustomError::DeviceFault(fault))
+    }
+
+    pub fn is_turned_on(&self) -> bool {
         matches!(self.state, PowerSocketState::Powered(_))
     }
 }
-#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
 pub enum PowerSocketState {
     Powered(u16),
+    #[default]
     NotPowered,
 }
-
-#[derive(Debug, Display, Default)]
-pub struct SocketError {}
-impl Error for SocketError {}
@@ -1,36 +1,316 @@
-use crate::{house::CustomResult, Executable};
+use crate::{
+    Capabilities, CommandSpec, CustomError, DeviceCommand, DeviceFault, Executable, FaultKind,
+    ParameterKind, ParameterSpec, ReadingSpec, ThermometerCommand, MAX_CALIBRATION,
+};
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::fmt;
+use std::ops::{Add, Sub};
+use std::str::FromStr;
 
 use super::command::ExecutionResult;
 
-#[derive(Clone, Copy, Debug)]
+const KELVIN_OFFSET: f64 = 273.15;
+/// temperatures closer than this (in kelvin) are equal for `Temperature::approx_eq`
+const EQ_TOLERANCE: f64 = 1e-4;
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
 pub enum Temperature {
     Celsius(f32),
     Fahrenheit(f32),
+    Kelvin(f32),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TemperatureUnit {
+    Celsius,
+    Fahrenheit,
+    Kelvin,
+}
+
+impl TemperatureUnit {
+    pub fn symbol(&self) -> &'static str {
+        match self {
+            TemperatureUnit::Celsius => "°C",
+            TemperatureUnit::Fahrenheit => "°F",
+            TemperatureUnit::Kelvin => "K",
+        }
+    }
+}
+
+/// Difference between two temperatures. Stored in kelvin (same scale as celsius).
+#[derive(Clone, Copy, Debug, PartialEq, PartialOrd, Default)]
+pub struct TemperatureDelta(f64);
+
+impl TemperatureDelta {
+    pub fn from_celsius(degrees: f64) -> Self {
+        Self(degrees)
+    }
+    pub fn from_fahrenheit(degrees: f64) -> Self {
+        Self(degrees / 1.8)
+    }
+    pub fn as_celsius(&self) -> f64 {
+        self.0
+    }
+    pub fn as_fahrenheit(&self) -> f64 {
+        self.0 * 1.8
+    }
+    pub fn abs(&self) -> Self {
+        Self(self.0.abs())
+    }
 }
 
 impl Temperature {
-    pub fn as_celsius(&self) -> i16 {
+    pub fn new(value: f32, unit: TemperatureUnit) -> Self {
+        match unit {
+            TemperatureUnit::Celsius => Temperature::Celsius(value),
+            TemperatureUnit::Fahrenheit => Temperature::Fahrenheit(value),
+            TemperatureUnit::Kelvin => Temperature::Kelvin(value),
+        }
+    }
+    pub fn unit(&self) -> TemperatureUnit {
+        match self {
+            Temperature::Celsius(_) => TemperatureUnit::Celsius,
+            Temperature::Fahrenheit(_) => TemperatureUnit::Fahrenheit,
+            Temperature::Kelvin(_) => TemperatureUnit::Kelvin,
+        }
+    }
+    /// value in the unit the temperature was created with
+    pub fn value(&self) -> f32 {
         match *self {
-            Temperature::Celsius(c) => c.round() as i16,
-            Temperature::Fahrenheit(f) => (((f - 32.0) * 5.0) / 9.0).round() as i16,
+            Temperature::Celsius(v) | Temperature::Fahrenheit(v) | Temperature::Kelvin(v) => v,
         }
     }
 
+    pub fn as_celsius(&self) -> i16 {
+        self.as_celsius_f64().round() as i16
+    }
+
     pub fn as_fahrenheit(&self) -> i16 {
+        self.as_fahrenheit_f64().round() as i16
+    }
+
+    pub fn as_kelvin(&self) -> i16 {
+        self.as_kelvin_f64().round() as i16
+    }
+
+    pub fn as_celsius_f64(&self) -> f64 {
         match *self {
-            Temperature::Fahrenheit(f) => f.round() as i16,
-            Temperature::Celsius(c) => (c * 1.8 + 32.0).round() as i16,
+            Temperature::Celsius(c) => c as f64,
+            Temperature::Fahrenheit(f) => ((f as f64 - 32.0) * 5.0) / 9.0,
+            Temperature::Kelvin(k) => k as f64 - KELVIN_OFFSET,
+        }
+    }
+
+    pub fn as_fahrenheit_f64(&self) -> f64 {
+        match *self {
+            Temperature::Fahrenheit(f) => f as f64,
+            _ => self.as_celsius_f64() * 1.8 + 32.0,
+        }
+    }
+
+    pub fn as_kelvin_f64(&self) -> f64 {
+        match *self {
+            Temperature::Kelvin(k) => k as f64,
+            _ => self.as_celsius_f64() + KELVIN_OFFSET,
+        }
+    }
+
+    pub fn as_celsius_f32(&self) -> f32 {
+        self.as_celsius_f64() as f32
+    }
+
+    pub fn as_fahrenheit_f32(&self) -> f32 {
+        self.as_fahrenheit_f64() as f32
+    }
+
+    pub fn as_kelvin_f32(&self) -> f32 {
+        self.as_kelvin_f64() as f32
+    }
+
+    pub fn in_unit(&self, unit: TemperatureUnit) -> f64 {
+        match unit {
+            TemperatureUnit::Celsius => self.as_celsius_f64(),
+            TemperatureUnit::Fahrenheit => self.as_fahrenheit_f64(),
+            TemperatureUnit::Kelvin => self.as_kelvin_f64(),
+        }
+    }
+
+    /// same temperature expressed in another unit
+    pub fn convert(&self, unit: TemperatureUnit) -> Self {
+        if self.unit() == unit {
+            return *self;
+        }
+        Temperature::new(self.in_unit(unit) as f32, unit)
+    }
+
+    /// Equal up to rounding, e.g. after a conversion between units.
+    pub fn approx_eq(&self, other: &Self) -> bool {
+        (self.as_kelvin_f64() - other.as_kelvin_f64()).abs() < EQ_TOLERANCE
+    }
+
+    /// Below 0 K, beyond what rounding explains.
+    pub fn is_below_absolute_zero(&self) -> bool {
+        self.as_kelvin_f64() < -EQ_TOLERANCE
+    }
+}
+
+/// Compares the exact values in kelvin, use `approx_eq` for converted temperatures.
+impl PartialEq for Temperature {
+    fn eq(&self, other: &Self) -> bool {
+        self.as_kelvin_f64() == other.as_kelvin_f64()
+    }
+}
+
+impl PartialOrd for Temperature {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        self.as_kelvin_f64().partial_cmp(&other.as_kelvin_f64())
+    }
+}
+
+/// Result keeps the unit of the left operand.
+impl Add<TemperatureDelta> for Temperature {
+    type Output = Temperature;
+    fn add(self, delta: TemperatureDelta) -> Temperature {
+        let value = match self.unit() {
+            TemperatureUnit::Fahrenheit => self.value() as f64 + delta.as_fahrenheit(),
+            _ => self.value() as f64 + delta.as_celsius(),
+        };
+        Temperature::new(value as f32, self.unit())
+    }
+}
+
+impl Sub<TemperatureDelta> for Temperature {
+    type Output = Temperature;
+    fn sub(self, delta: TemperatureDelta) -> Temperature {
+        self + TemperatureDelta(-delta.0)
+    }
+}
+
+impl Sub for Temperature {
+    type Output = TemperatureDelta;
+    fn sub(self, other: Temperature) -> TemperatureDelta {
+        TemperatureDelta(self.as_kelvin_f64() - other.as_kelvin_f64())
+    }
+}
+
+/// Formats as `21.5°C`; precision is forwarded to the value (`{:.1}`).
+impl fmt::Display for Temperature {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match f.precision() {
+            Some(p) => write!(f, "{:.*}{}", p, self.value(), self.unit().symbol()),
+            None => write!(f, "{}{}", self.value(), self.unit().symbol()),
         }
     }
 }
 
-#[derive(Clone, Debug)]
+/// Accepts a number followed by a unit: `21.5C`, `70 F`, `-4.5°c`, `300K`.
+impl FromStr for Temperature {
+    type Err = CustomError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let split = s
+            .find(|c: char| c.is_alphabetic() || c == '°')
+            .ok_or_else(|| CustomError::ParseError(format!("no unit in temperature '{}'", s)))?;
+        let (value, unit) = s.split_at(split);
+        let unit = match unit.trim_start_matches('°').to_lowercase().as_str() {
+            "c" => TemperatureUnit::Celsius,
+            "f" => TemperatureUnit::Fahrenheit,
+            "k" => TemperatureUnit::Kelvin,
+            other => {
+                return Err(CustomError::ParseError(format!(
+                    "unknown temperature unit '{}'",
+                    other
+                )))
+            }
+        };
+        let value: f32 = value
+            .trim()
+            .parse()
+            .map_err(|_| CustomError::ParseError(format!("invalid temperature value '{}'", s)))?;
+        if !value.is_finite() {
+            return Err(CustomError::ParseError(format!(
+                "invalid temperature value '{}'",
+                s
+            )));
+        }
+        let temperature = Temperature::new(value, unit);
+        if temperature.is_below_absolute_zero() {
+            return Err(CustomError::ParseError(format!(
+                "'{}' is below absolute zero",
+                s
+            )));
+        }
+        Ok(temperature)
+    }
+}
+
+/// Build with `Thermometer { name, state, ..Default::default() }` to leave it uncalibrated.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct Thermometer {
     pub name: String,
+    /// raw reading of the sensor
     pub state: Temperature,
+    /// degrees celsius added to the raw reading
+    #[serde(default)]
+    pub calibration: f32,
+}
+
+/// An unnamed thermometer reading 0 °C.
+impl Default for Thermometer {
+    fn default() -> Self {
+        Self {
+            name: String::new(),
+            state: Temperature::Celsius(0.),
+            calibration: 0.,
+        }
+    }
 }
 
 impl Thermometer {
+    /// An uncalibrated thermometer reading `state`.
+    pub fn new(name: &str, state: Temperature) -> Self {
+        Self {
+            name: name.to_owned(),
+            state,
+            ..Default::default()
+        }
+    }
+    pub fn with_calibration(mut self, celsius: f32) -> Self {
+        self.calibration = celsius;
+        self
+    }
+
+    pub fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            kind: "SmartThermometer".to_owned(),
+            commands: vec![
+                CommandSpec::new(
+                    "thermometer.calibrate",
+                    "correct the readings of the sensor",
+                )
+                .parameter(
+                    ParameterSpec::new("offset", ParameterKind::Number, "added to every reading")
+                        .unit("°C")
+                        .range(-MAX_CALIBRATION as f64, MAX_CALIBRATION as f64),
+                ),
+                CommandSpec::new("thermometer.temperature", "report the current reading"),
+                CommandSpec::new("thermometer.reading", "report a new raw reading").parameter(
+                    ParameterSpec::new(
+                        "temperature",
+                        ParameterKind::Text,
+                        "number followed by its unit, e.g. 21.5C",
+                    ),
+                ),
+            ],
+            readings: vec![ReadingSpec::new(
+                "temperature",
+                Some(self.state.unit().symbol()),
+                "current temperature",
+            )],
+        }
+    }
+
     pub fn get_celsius(&self) -> i16 {
         self.get_temperature().as_celsius()
     }
@@ -39,19 +319,43 @@ impl Thermometer {
         self.get_temperature().as_fahrenheit()
     }
 
+    /// Degrees celsius added to the raw reading.
+    pub fn get_calibration(&self) -> f32 {
+        self.calibration
+    }
+
+    /// The calibrated reading.
     pub fn get_temperature(&self) -> Temperature {
-        self.state
+        self.state + TemperatureDelta::from_celsius(self.calibration as f64)
     }
 }
 impl Executable for Thermometer {
-    fn execute(&mut self, _command: crate::DeviceCommand) -> ExecutionResult {
-        todo!()
+    /// Only calibration, new raw readings and reads are accepted.
+    fn execute(&mut self, command: DeviceCommand) -> ExecutionResult {
+        match command {
+            DeviceCommand::Thermometer(ThermometerCommand::SetCalibration(offset)) => {
+                self.calibration = offset;
+                ExecutionResult::Temperature(self.get_temperature())
+            }
+            DeviceCommand::Thermometer(ThermometerCommand::GetTemperature) => {
+                ExecutionResult::Temperature(self.get_temperature())
+            }
+            DeviceCommand::Thermometer(ThermometerCommand::SetReading(temperature)) => {
+                self.state = temperature;
+                ExecutionResult::Temperature(self.get_temperature())
+            }
+            other => {
+                let fault = DeviceFault::new(&self.name, FaultKind::CommandRejected)
+                    .with_detail(&format!("unsupported command {}", other));
+                ExecutionResult::Error(CustomError::DeviceFault(fault))
+            }
+        }
     }
 }
 
 #[cfg(test)]
 mod test {
-    use super::Temperature;
+    use super::{Temperature, TemperatureDelta, TemperatureUnit};
     #[test]
     fn temperature_convertion() {
         assert_eq!(Temperature::Celsius(-10.).as_fahrenheit(), 14);
@@ -62,4 +366,81 @@ mod test {
         assert_eq!(Temperature::Fahrenheit(0.).as_celsius(), -18);
         assert_eq!(Temperature::Fahrenheit(32.).as_celsius(), 0);
     }
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!(
+            (actual - expected).abs() < 1e-4,
+            "{} is not close to {}",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn precise_convertion() {
+        assert_close(Temperature::Celsius(21.5).as_fahrenheit_f64(), 70.7);
+        assert_close(Temperature::Kelvin(0.).as_celsius_f64(), -273.15);
+        assert_close(Temperature::Fahrenheit(212.).as_kelvin_f32().into(), 373.15);
+        assert_eq!(Temperature::Kelvin(300.).as_kelvin(), 300);
+        assert!(Temperature::Celsius(100.)
+            .convert(TemperatureUnit::Fahrenheit)
+            .approx_eq(&Temperature::Fahrenheit(212.)));
+    }
+
+    #[test]
+    fn compare_across_units() {
+        assert_eq!(Temperature::Celsius(0.), Temperature::Fahrenheit(32.));
+        assert!(Temperature::Celsius(0.).approx_eq(&Temperature::Kelvin(273.15)));
+        assert!(Temperature::Fahrenheit(70.) > Temperature::Celsius(21.));
+        assert!(Temperature::Kelvin(0.) < Temperature::Fahrenheit(-459.));
+        // equality is exact, so it is transitive and agrees with the ordering
+        let (a, b) = (Temperature::Celsius(20.), Temperature::Celsius(20.00005));
+        assert!(a.approx_eq(&b));
+        assert_ne!(a, b);
+        assert!(a < b);
+    }
+
+    #[test]
+    fn arithmetic() {
+        let delta = Temperature::Fahrenheit(50.) - Temperature::Celsius(0.);
+        assert!((delta.as_celsius() - 10.).abs() < 1e-9);
+        assert_eq!(
+            Temperature::Fahrenheit(32.) + TemperatureDelta::from_celsius(10.),
+            Temperature::Fahrenheit(50.)
+        );
+        assert_eq!(
+            Temperature::Celsius(20.) - TemperatureDelta::from_fahrenheit(9.),
+            Temperature::Celsius(15.)
+        );
+    }
+
+    #[test]
+    fn parse_and_display() {
+        assert_eq!(
+            "21.5C".parse::<Temperature>().unwrap(),
+            Temperature::Celsius(21.5)
+        );
+        assert_eq!(
+            "70 F".parse::<Temperature>().unwrap(),
+            Temperature::Fahrenheit(70.)
+        );
+        assert_eq!(
+            "-4.5°c".parse::<Temperature>().unwrap(),
+            Temperature::Celsius(-4.5)
+        );
+        assert_eq!(
+            "300K".parse::<Temperature>().unwrap(),
+            Temperature::Kelvin(300.)
+        );
+        assert!("21.5".parse::<Temperature>().is_err());
+        assert!("21.5X".parse::<Temperature>().is_err());
+        assert!("C".parse::<Temperature>().is_err());
+        assert!("NaNC".parse::<Temperature>().is_err());
+        assert!("-1K".parse::<Temperature>().is_err());
+        assert!("-300C".parse::<Temperature>().is_err());
+        assert!("-459.67F".parse::<Temperature>().is_ok());
+
+        assert_eq!(Temperature::Celsius(21.5).to_string(), "21.5°C");
+        assert_eq!(format!("{:.1}", Temperature::Fahrenheit(70.66)), "70.7°F");
+    }
 }
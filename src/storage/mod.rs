@@ -0,0 +1,86 @@
+use crate::{
+    ConfigHistory, CustomError, CustomResult, Room, SmartDevice, SmartDeviceList, SmartHouse,
+    TemperatureUnit,
+};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::Path;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RoomFile {
+    pub name: String,
+    pub devices: Vec<SmartDevice>,
+}
+
+/// JSON representation of a house together with its devices.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct HouseFile {
+    pub rooms: Vec<RoomFile>,
+    #[serde(default)]
+    pub temperature_unit: Option<TemperatureUnit>,
+    /// undo/redo history of edits made through the CLI
+    #[serde(default, skip_serializing_if = "ConfigHistory::is_empty")]
+    pub history: ConfigHistory,
+}
+
+impl HouseFile {
+    /// Devices present in the list but not registered in the house are not saved.
+    pub fn from_house(house: &SmartHouse, devices: &SmartDeviceList) -> Self {
+        let snapshot = devices.snapshot();
+        let rooms = house
+            .get_rooms()
+            .into_iter()
+            .map(|room| {
+                let mut names = house.get_devices(room).unwrap_or_default();
+                names.sort_unstable();
+                RoomFile {
+                    name: room.to_owned(),
+                    devices: names
+                        .into_iter()
+                        .filter_map(|name| snapshot.get(room, name).cloned())
+                        .collect(),
+                }
+            })
+            .collect();
+        Self {
+            rooms,
+            temperature_unit: house.temperature_unit(),
+            history: ConfigHistory::default(),
+        }
+    }
+    pub fn with_history(mut self, history: ConfigHistory) -> Self {
+        self.history = history;
+        self
+    }
+
+    pub fn into_house(self) -> CustomResult<(SmartHouse, SmartDeviceList)> {
+        let mut house = SmartHouse::new();
+        let mut devices = SmartDeviceList::new();
+        if let Some(unit) = self.temperature_unit {
+            house.set_temperature_unit(unit);
+        }
+        for room in self.rooms {
+            house.try_add_room(Room::with_name(&room.name))?;
+            for device in room.devices {
+                house.try_add_device(&room.name, &device.get_name())?;
+                devices.add_device(&room.name, device)?;
+            }
+        }
+        Ok((house, devices))
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> CustomResult<Self> {
+        let data = fs::read_to_string(path.as_ref()).map_err(|e| {
+            CustomError::StorageError(format!("cannot read {}: {}", path.as_ref().display(), e))
+        })?;
+        serde_json::from_str(&data).map_err(|e| CustomError::StorageError(e.to_string()))
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> CustomResult<()> {
+        let data = serde_json::to_string_pretty(self)
+            .map_err(|e| CustomError::StorageError(e.to_string()))?;
+        fs::write(path.as_ref(), data).map_err(|e| {
+            CustomError::StorageError(format!("cannot write {}: {}", path.as_ref().display(), e))
+        })
+    }
+}
@@ -0,0 +1,171 @@
+//! TLS settings for the control channel, built on rustls.
+//! Certificates and keys are read from PEM; clients only trust the given CA,
+//! and servers can require client certificates signed by a CA of their own.
+
+use crate::{CustomError, CustomResult};
+use rustls::crypto::{ring, CryptoProvider};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use rustls::server::WebPkiClientVerifier;
+use rustls::StreamOwned;
+use rustls::{ClientConfig, ClientConnection, RootCertStore, ServerConfig, ServerConnection};
+use std::fmt::Display;
+use std::fs;
+use std::net::TcpStream;
+use std::path::Path;
+use std::sync::Arc;
+
+fn tls_error(e: impl Display) -> CustomError {
+    CustomError::TlsError(e.to_string())
+}
+
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(ring::default_provider())
+}
+
+fn read_pem<P: AsRef<Path>>(path: P) -> CustomResult<Vec<u8>> {
+    fs::read(path.as_ref()).map_err(|e| {
+        CustomError::StorageError(format!("cannot read {}: {}", path.as_ref().display(), e))
+    })
+}
+
+fn parse_certs(pem: &[u8]) -> CustomResult<Vec<CertificateDer<'static>>> {
+    let certs = CertificateDer::pem_slice_iter(pem)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(tls_error)?;
+    if certs.is_empty() {
+        return Err(CustomError::TlsError("no certificate found".into()));
+    }
+    Ok(certs)
+}
+
+fn parse_key(pem: &[u8]) -> CustomResult<PrivateKeyDer<'static>> {
+    PrivateKeyDer::from_pem_slice(pem).map_err(tls_error)
+}
+
+fn root_store(ca_pem: &[u8]) -> CustomResult<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    for cert in parse_certs(ca_pem)? {
+        roots.add(cert).map_err(tls_error)?;
+    }
+    Ok(roots)
+}
+
+/// Certificate of a control server, optionally requiring client certificates.
+#[derive(Clone)]
+pub struct TlsServerConfig(Arc<ServerConfig>);
+
+impl TlsServerConfig {
+    /// `cert_chain` starts with the server certificate, followed by intermediates.
+    pub fn from_pem(cert_chain: &[u8], private_key: &[u8]) -> CustomResult<Self> {
+        Self::build(cert_chain, private_key, None)
+    }
+    /// Mutual TLS: only clients with a certificate signed by `client_ca` may connect.
+    pub fn with_client_auth(
+        cert_chain: &[u8],
+        private_key: &[u8],
+        client_ca: &[u8],
+    ) -> CustomResult<Self> {
+        Self::build(cert_chain, private_key, Some(client_ca))
+    }
+    /// Reads PEM files, `client_ca` enables mutual TLS.
+    pub fn load<P: AsRef<Path>>(
+        cert_chain: P,
+        private_key: P,
+        client_ca: Option<P>,
+    ) -> CustomResult<Self> {
+        let client_ca = client_ca.map(read_pem).transpose()?;
+        Self::build(
+            &read_pem(cert_chain)?,
+            &read_pem(private_key)?,
+            client_ca.as_deref(),
+        )
+    }
+
+    fn build(
+        cert_chain: &[u8],
+        private_key: &[u8],
+        client_ca: Option<&[u8]>,
+    ) -> CustomResult<Self> {
+        let builder = ServerConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()
+            .map_err(tls_error)?;
+        let builder = match client_ca {
+            Some(ca) => {
+                let verifier = WebPkiClientVerifier::builder_with_provider(
+                    Arc::new(root_store(ca)?),
+                    provider(),
+                )
+                .build()
+                .map_err(tls_error)?;
+                builder.with_client_cert_verifier(verifier)
+            }
+            None => builder.with_no_client_auth(),
+        };
+        let config = builder
+            .with_single_cert(parse_certs(cert_chain)?, parse_key(private_key)?)
+            .map_err(tls_error)?;
+        Ok(Self(Arc::new(config)))
+    }
+
+    pub(crate) fn accept(
+        &self,
+        stream: TcpStream,
+    ) -> CustomResult<StreamOwned<ServerConnection, TcpStream>> {
+        let connection = ServerConnection::new(Arc::clone(&self.0)).map_err(tls_error)?;
+        Ok(StreamOwned::new(connection, stream))
+    }
+}
+
+/// CA trusted by a client, with an optional client certificate for mutual TLS.
+#[derive(Clone)]
+pub struct TlsClientConfig(Arc<ClientConfig>);
+
+impl TlsClientConfig {
+    pub fn from_pem(ca: &[u8]) -> CustomResult<Self> {
+        Self::build(ca, None)
+    }
+    /// Presents `cert_chain` to servers that require client certificates.
+    pub fn with_identity(ca: &[u8], cert_chain: &[u8], private_key: &[u8]) -> CustomResult<Self> {
+        Self::build(ca, Some((cert_chain, private_key)))
+    }
+    /// Reads PEM files, `identity` holds the client certificate chain and key.
+    pub fn load<P: AsRef<Path>>(ca: P, identity: Option<(P, P)>) -> CustomResult<Self> {
+        let ca = read_pem(ca)?;
+        match identity {
+            Some((cert_chain, key)) => {
+                Self::with_identity(&ca, &read_pem(cert_chain)?, &read_pem(key)?)
+            }
+            None => Self::from_pem(&ca),
+        }
+    }
+
+    fn build(ca: &[u8], identity: Option<(&[u8], &[u8])>) -> CustomResult<Self> {
+        let builder = ClientConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()
+            .map_err(tls_error)?
+            .with_root_certificates(root_store(ca)?);
+        let config = match identity {
+            Some((cert_chain, key)) => builder
+                .with_client_auth_cert(parse_certs(cert_chain)?, parse_key(key)?)
+                .map_err(tls_error)?,
+            None => builder.with_no_client_auth(),
+        };
+        Ok(Self(Arc::new(config)))
+    }
+
+    /// Completes the handshake, so certificate problems surface here.
+    /// `server_name` is a DNS name or IP address the server certificate must be valid for.
+    pub(crate) fn connect(
+        &self,
+        server_name: &str,
+        mut stream: TcpStream,
+    ) -> CustomResult<StreamOwned<ClientConnection, TcpStream>> {
+        let name = ServerName::try_from(server_name.to_owned()).map_err(tls_error)?;
+        let mut connection = ClientConnection::new(Arc::clone(&self.0), name).map_err(tls_error)?;
+        while connection.is_handshaking() {
+            connection.complete_io(&mut stream).map_err(tls_error)?;
+        }
+        Ok(StreamOwned::new(connection, stream))
+    }
+}
@@ -0,0 +1,219 @@
+use smart_house::*;
+use std::time::{Duration, SystemTime};
+
+fn at(secs: u64) -> SystemTime {
+    SystemTime::UNIX_EPOCH + Duration::from_secs(secs)
+}
+
+fn freezer_monitor() -> AlarmMonitor {
+    let mut monitor = AlarmMonitor::new();
+    monitor.configure(
+        "kitchen",
+        "freezer",
+        AlarmConfig::new()
+            .low(-30., Severity::Warning)
+            .high(-15., Severity::Critical)
+            .hysteresis(1.),
+    );
+    monitor
+}
+
+#[test]
+fn threshold_alarm_is_raised_and_cleared() {
+    let mut monitor = freezer_monitor();
+    assert!(monitor
+        .record("kitchen", "freezer", Temperature::Celsius(-18.), at(0))
+        .is_empty());
+
+    let events = monitor.record("kitchen", "Freezer", Temperature::Celsius(-12.), at(60));
+    assert!(
+        matches!(&events[..], [AlarmEvent::Raised(a)] if a.kind == AlarmKind::High
+        && a.severity == Severity::Critical && a.raised_at == at(60))
+    );
+    assert_eq!(monitor.active_alarms().len(), 1);
+
+    // still above the limit: no duplicate alarm
+    assert!(monitor
+        .record("kitchen", "freezer", Temperature::Celsius(-13.), at(120))
+        .is_empty());
+    // within hysteresis band: alarm stays active
+    assert!(monitor
+        .record("kitchen", "freezer", Temperature::Celsius(-15.5), at(180))
+        .is_empty());
+
+    let events = monitor.record("kitchen", "freezer", Temperature::Celsius(-17.), at(240));
+    assert!(matches!(&events[..], [AlarmEvent::Cleared(a)] if a.cleared_at == Some(at(240))));
+    assert!(monitor.active_alarms().is_empty());
+    assert_eq!(monitor.history().len(), 1);
+}
+
+#[test]
+fn fahrenheit_readings_are_compared_in_celsius() {
+    let mut monitor = freezer_monitor();
+    // -40F == -40C, below the low threshold
+    let events = monitor.record("kitchen", "freezer", Temperature::Fahrenheit(-40.), at(0));
+    assert!(matches!(&events[..], [AlarmEvent::Raised(a)] if a.kind == AlarmKind::Low));
+}
+
+#[test]
+fn rate_of_change_alarm() {
+    let mut monitor = AlarmMonitor::new();
+    monitor.configure(
+        "server",
+        "rack",
+        AlarmConfig::new().max_rate(1., Severity::Warning),
+    );
+    monitor.record("server", "rack", Temperature::Celsius(25.), at(0));
+    // 3 degrees in one minute
+    let events = monitor.record("server", "rack", Temperature::Celsius(28.), at(60));
+    assert!(
+        matches!(&events[..], [AlarmEvent::Raised(a)] if a.kind == AlarmKind::RateOfChange
+        && a.value == Some(3.))
+    );
+    let events = monitor.record("server", "rack", Temperature::Celsius(28.5), at(120));
+    assert!(matches!(&events[..], [AlarmEvent::Cleared(_)]));
+}
+
+#[test]
+fn stale_sensor_alarm() {
+    let mut monitor = AlarmMonitor::new();
+    monitor.configure(
+        "server",
+        "rack",
+        AlarmConfig::new().stale_after(Duration::from_secs(300), Severity::Critical),
+    );
+    monitor.record("server", "rack", Temperature::Celsius(25.), at(0));
+    assert!(monitor.check_stale(at(200)).is_empty());
+
+    let events = monitor.check_stale(at(400));
+    assert!(matches!(&events[..], [AlarmEvent::Raised(a)] if a.kind == AlarmKind::SensorStale));
+    assert!(monitor.check_stale(at(500)).is_empty());
+    assert_eq!(monitor.active_alarms_for("server", "rack").len(), 1);
+
+    let events = monitor.record("server", "rack", Temperature::Celsius(25.), at(600));
+    assert!(matches!(&events[..], [AlarmEvent::Cleared(a)] if a.kind == AlarmKind::SensorStale));
+}
+
+#[test]
+fn alarms_are_published_to_event_bus() {
+    let bus = EventBus::new();
+    let events = bus.subscribe();
+    let mut monitor = AlarmMonitor::with_event_bus(bus);
+    monitor.configure(
+        "server",
+        "rack",
+        AlarmConfig::new().high(30., Severity::Critical),
+    );
+
+    let mut devices = SmartDeviceList::new();
+    devices
+        .add_device(
+            "server",
+            SmartDevice::Thermo(Thermometer::new("rack", Temperature::Celsius(35.))),
+        )
+        .unwrap();
+    monitor.scan(&devices, at(0));
+
+    match events.try_recv().unwrap() {
+        DeviceEvent::Alarm(AlarmEvent::Raised(alarm)) => assert_eq!(alarm.device, "rack"),
+        other => panic!("unexpected event {:?}", other),
+    }
+    assert!(events.try_recv().is_err());
+}
+
+#[test]
+fn alarms_belong_to_a_room() {
+    let mut monitor = freezer_monitor();
+    // a freezer elsewhere is not configured
+    assert!(monitor
+        .record("garage", "freezer", Temperature::Celsius(-5.), at(0))
+        .is_empty());
+    let events = monitor.record("Kitchen", "freezer", Temperature::Celsius(-5.), at(0));
+    assert!(matches!(&events[..], [AlarmEvent::Raised(a)] if a.room == "kitchen"));
+    assert!(monitor.active_alarms_for("garage", "freezer").is_empty());
+    assert_eq!(monitor.active_alarms_for("kitchen", "freezer").len(), 1);
+}
+
+#[test]
+fn removing_a_sensor_clears_its_alarms() {
+    let bus = EventBus::new();
+    let events = bus.subscribe();
+    let mut monitor = AlarmMonitor::with_event_bus(bus);
+    monitor.configure(
+        "kitchen",
+        "freezer",
+        AlarmConfig::new().high(-15., Severity::Critical),
+    );
+    monitor.record("kitchen", "freezer", Temperature::Celsius(-5.), at(0));
+    events.try_recv().unwrap();
+
+    assert!(monitor.remove("kitchen", "freezer", at(60)).is_some());
+    match events.try_recv().unwrap() {
+        DeviceEvent::Alarm(AlarmEvent::Cleared(alarm)) => {
+            assert_eq!(alarm.cleared_at, Some(at(60)))
+        }
+        other => panic!("unexpected event {:?}", other),
+    }
+    assert!(monitor.active_alarms().is_empty());
+    assert_eq!(monitor.history().len(), 1);
+    assert!(monitor.remove("kitchen", "freezer", at(60)).is_none());
+}
+
+#[test]
+fn alarms_follow_renamed_sensors() {
+    let mut monitor = freezer_monitor();
+    monitor.record("kitchen", "freezer", Temperature::Celsius(-5.), at(0));
+    monitor.rename_device("kitchen", "freezer", "Chest freezer");
+    let active = monitor.active_alarms_for("kitchen", "chest freezer");
+    assert_eq!(active.len(), 1);
+    assert_eq!(active[0].device, "chest freezer");
+    assert!(monitor.active_alarms_for("kitchen", "freezer").is_empty());
+
+    monitor.rename_room("kitchen", "Cellar");
+    assert_eq!(
+        monitor.active_alarms_for("cellar", "chest freezer").len(),
+        1
+    );
+    // the alarm clears under the new names
+    let events = monitor.record(
+        "cellar",
+        "chest freezer",
+        Temperature::Celsius(-20.),
+        at(60),
+    );
+    assert!(matches!(&events[..], [AlarmEvent::Cleared(a)] if a.room == "cellar"));
+
+    monitor.record(
+        "cellar",
+        "chest freezer",
+        Temperature::Celsius(-5.),
+        at(120),
+    );
+    monitor.remove_room("cellar", at(180));
+    assert!(monitor.active_alarms().is_empty());
+    assert!(monitor
+        .record(
+            "cellar",
+            "chest freezer",
+            Temperature::Celsius(-5.),
+            at(240)
+        )
+        .is_empty());
+}
+
+#[test]
+fn history_keeps_the_last_cleared_alarms() {
+    let mut monitor = freezer_monitor().with_history_limit(2);
+    // a flapping sensor
+    for i in 0..5 {
+        monitor.record("kitchen", "freezer", Temperature::Celsius(-5.), at(i * 120));
+        monitor.record(
+            "kitchen",
+            "freezer",
+            Temperature::Celsius(-20.),
+            at(i * 120 + 60),
+        );
+    }
+    let cleared: Vec<_> = monitor.history().iter().map(|a| a.cleared_at).collect();
+    assert_eq!(cleared, [Some(at(3 * 120 + 60)), Some(at(4 * 120 + 60))]);
+}
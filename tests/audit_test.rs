@@ -0,0 +1,233 @@
+mod common;
+
+use common::{house_with, socket, socket_command};
+use smart_house::*;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+fn devices() -> SmartDeviceList {
+    let layout = [
+        ("server room", socket("heater", 0)),
+        ("hall", socket("lamp", 0)),
+    ];
+    house_with(&[], layout).1
+}
+
+fn log_file(name: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!(
+        "smart_house_audit_{}_{}.log",
+        name,
+        std::process::id()
+    ));
+    remove_log(&path);
+    path
+}
+
+fn remove_log(path: &Path) {
+    std::fs::remove_file(path).ok();
+    for n in 1..5 {
+        std::fs::remove_file(format!("{}.{}", path.display(), n)).ok();
+    }
+}
+
+#[test]
+fn commands_are_recorded() {
+    let devices = devices();
+    let log = AuditLog::in_memory();
+    devices.set_audit_log(log.clone());
+
+    devices.execute_command_by(socket_command("heater", PowerSocketCommand::TurnOff), "bob");
+    devices.execute_command(socket_command("lamp", PowerSocketCommand::TurnOn));
+    devices.execute_command_by(socket_command("fridge", PowerSocketCommand::TurnOn), "bob");
+
+    let records = log.query(&AuditQuery::new()).unwrap();
+    assert_eq!(records.len(), 3);
+    assert_eq!(records[0].principal.as_deref(), Some("bob"));
+    assert_eq!(records[0].room.as_deref(), Some("server room"));
+    assert_eq!(
+        records[0].command,
+        DeviceCommand::PowerSocket(PowerSocketCommand::TurnOff)
+    );
+    assert!(!records[0].is_error());
+    assert_eq!(records[1].principal, None);
+    // failed commands are recorded too
+    assert_eq!(records[2].room, None);
+    assert!(matches!(
+        records[2].result,
+        ExecutionResult::Error(CustomError::DeviceNotFound)
+    ));
+}
+
+#[test]
+fn query_by_device_principal_and_time() {
+    let devices = devices();
+    let log = AuditLog::in_memory();
+    devices.set_audit_log(log.clone());
+    devices.execute_command_by(
+        socket_command("heater", PowerSocketCommand::TurnOn),
+        "alice",
+    );
+    let between = SystemTime::now();
+    std::thread::sleep(Duration::from_millis(10));
+    devices.execute_command_by(socket_command("Heater", PowerSocketCommand::TurnOff), "bob");
+    devices.execute_command_by(socket_command("lamp", PowerSocketCommand::TurnOn), "bob");
+
+    let heater = log.query(&AuditQuery::new().device("HEATER")).unwrap();
+    assert_eq!(heater.len(), 2);
+    let bob = log.query(&AuditQuery::new().principal("bob")).unwrap();
+    assert_eq!(bob.len(), 2);
+    let who = log
+        .query(&AuditQuery::new().device("heater").since(between))
+        .unwrap();
+    assert_eq!(who.len(), 1);
+    assert_eq!(who[0].principal.as_deref(), Some("bob"));
+    assert_eq!(
+        log.query(&AuditQuery::new().until(between)).unwrap().len(),
+        1
+    );
+}
+
+#[test]
+fn file_log_is_append_only() {
+    let path = log_file("append");
+    let devices = devices();
+    devices.set_audit_log(AuditLog::open(&path).unwrap());
+    devices.execute_command_by(
+        socket_command("heater", PowerSocketCommand::TurnOn),
+        "alice",
+    );
+
+    // a restarted server keeps appending
+    let devices = self::devices();
+    devices.set_audit_log(AuditLog::open(&path).unwrap());
+    devices.execute_command_by(socket_command("heater", PowerSocketCommand::TurnOff), "bob");
+
+    let records = AuditLog::open(&path)
+        .unwrap()
+        .query(&AuditQuery::new())
+        .unwrap();
+    let principals: Vec<_> = records
+        .iter()
+        .map(|r| r.principal.clone().unwrap())
+        .collect();
+    assert_eq!(principals, ["alice", "bob"]);
+    assert_eq!(std::fs::read_to_string(&path).unwrap().lines().count(), 2);
+    remove_log(&path);
+}
+
+#[test]
+fn failed_writes_are_counted() {
+    let devices = devices();
+    devices.set_audit_log(AuditLog::open("/dev/full").unwrap());
+    let result = devices.execute_command(socket_command("lamp", PowerSocketCommand::TurnOn));
+    // the command itself still succeeds
+    assert!(matches!(result, ExecutionResult::PowerSocket(_)));
+    assert_eq!(devices.audit_failures(), 1);
+}
+
+#[test]
+fn file_log_rotates() {
+    let path = log_file("rotate");
+    let log = AuditLog::open(&path).unwrap().with_rotation(600, 2);
+    let devices = devices();
+    devices.set_audit_log(log.clone());
+    for n in 0..10 {
+        let principal = format!("user{}", n);
+        devices.execute_command_by(
+            socket_command("lamp", PowerSocketCommand::GetState),
+            &principal,
+        );
+    }
+    assert!(std::fs::metadata(&path).unwrap().len() <= 600);
+    assert!(Path::new(&format!("{}.2", path.display())).exists());
+    assert!(!Path::new(&format!("{}.3", path.display())).exists());
+
+    // the oldest records were dropped with the oldest file, the rest stay in order
+    let records = log.query(&AuditQuery::new()).unwrap();
+    assert!(records.len() < 10);
+    assert_eq!(records.last().unwrap().principal.as_deref(), Some("user9"));
+    assert!(records.windows(2).all(|w| w[0].time <= w[1].time));
+
+    log.rotate().unwrap();
+    assert_eq!(std::fs::metadata(&path).unwrap().len(), 0);
+    remove_log(&path);
+}
+
+#[test]
+fn remote_commands_record_the_principal() {
+    let devices = devices();
+    let log = AuditLog::in_memory();
+    devices.set_audit_log(log.clone());
+    let auth = Authenticator::new().with_token("t0ken", Principal::admin("carol"));
+    let server = ControlServer::bind("127.0.0.1:0", devices.clone())
+        .unwrap()
+        .with_auth(auth);
+    let addr = server.local_addr().unwrap().to_string();
+    server.spawn();
+
+    let options = ConnectOptions::new().with_credentials(Credentials::Token("t0ken".into()));
+    let mut client = ControlClient::connect_with(&addr, &options).unwrap();
+    client
+        .send(&Command::Execute(socket_command(
+            "heater",
+            PowerSocketCommand::TurnOff,
+        )))
+        .unwrap();
+
+    let api = RestApi::new(Arc::new(Mutex::new(SmartHouse::new())), devices);
+    let response = api.handle(&HttpRequest::new(
+        "POST",
+        "/devices/lamp/commands",
+        br#"{"PowerSocket":"TurnOn"}"#,
+    ));
+    assert_eq!(response.status, 200);
+
+    let records = log.query(&AuditQuery::new()).unwrap();
+    let issuers: Vec<_> = records
+        .iter()
+        .map(|r| (r.principal.clone().unwrap(), r.device.clone()))
+        .collect();
+    assert_eq!(
+        issuers,
+        [
+            ("carol".to_owned(), "heater".to_owned()),
+            ("anonymous".to_owned(), "lamp".to_owned())
+        ]
+    );
+}
+
+#[test]
+fn cli_audit_command() {
+    let path = log_file("cli");
+    let devices = devices();
+    devices.set_audit_log(AuditLog::open(&path).unwrap());
+    devices.execute_command_by(
+        socket_command("heater", PowerSocketCommand::TurnOff),
+        "dave",
+    );
+    devices.execute_command_by(socket_command("lamp", PowerSocketCommand::TurnOn), "erin");
+
+    let args = ["audit", path.to_str().unwrap(), "--device", "heater"];
+    let output = Cli::parse(args).unwrap().run().unwrap();
+    assert_eq!(output.lines().count(), 1);
+    assert!(output.contains("dave server room/heater"), "{}", output);
+    let args = [
+        "audit",
+        path.to_str().unwrap(),
+        "--since",
+        "0",
+        "--principal",
+        "nobody",
+    ];
+    assert_eq!(Cli::parse(args).unwrap().run().unwrap(), "");
+    assert!(Cli::parse(["audit", "log", "--since", "yesterday"]).is_err());
+    assert!(Cli::parse(["audit", "missing.log"]).unwrap().run().is_err());
+    assert!(matches!(
+        Cli::parse(["serve", "127.0.0.1:0", "--audit", "audit.log"])
+            .unwrap()
+            .command,
+        CliCommand::Serve { audit: Some(_), .. }
+    ));
+    remove_log(&path);
+}
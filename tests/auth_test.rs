@@ -0,0 +1,378 @@
+mod common;
+
+use common::{house_with, socket, turn_on};
+use smart_house::*;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+fn house() -> (SmartHouse, SmartDeviceList) {
+    house_with(
+        &[],
+        [
+            ("hall", socket("lamp", 0)),
+            ("kitchen", socket("kettle", 0)),
+        ],
+    )
+}
+
+/// guests may read everything, alice may only use the hall
+fn authenticator() -> Authenticator {
+    Authenticator::new()
+        .with_token(
+            "guest-token",
+            Principal::new("guest").allow(ANY_ROOM, &[Action::Read]),
+        )
+        .with_user(
+            "alice",
+            "secret",
+            vec![Permission {
+                room: "Hall".into(),
+                actions: vec![Action::Read, Action::Control],
+            }],
+        )
+}
+
+fn guest() -> Credentials {
+    Credentials::Token("guest-token".into())
+}
+
+fn alice() -> Credentials {
+    Credentials::Password {
+        user: "alice".into(),
+        password: "secret".into(),
+    }
+}
+
+fn start_control_server() -> (String, SmartDeviceList) {
+    let (_, devices) = house();
+    let server = ControlServer::bind("127.0.0.1:0", devices.clone())
+        .unwrap()
+        .with_auth(authenticator());
+    let addr = server.local_addr().unwrap().to_string();
+    server.spawn();
+    (addr, devices)
+}
+
+fn execute(device: &str, command: PowerSocketCommand) -> Command {
+    Command::Execute(CommandData {
+        device_name: device.into(),
+        data: DeviceCommand::PowerSocket(command),
+    })
+}
+
+#[test]
+fn control_requires_authentication() {
+    let (addr, _) = start_control_server();
+    let mut client = ControlClient::connect(addr.as_str()).unwrap();
+    assert!(matches!(
+        client.list_devices(),
+        Err(CustomError::AuthenticationFailed(_))
+    ));
+    assert!(matches!(
+        client.authenticate(&Credentials::Token("wrong".into())),
+        Err(CustomError::AuthenticationFailed(_))
+    ));
+    assert!(matches!(
+        client.authenticate(&Credentials::Password {
+            user: "alice".into(),
+            password: "guess".into()
+        }),
+        Err(CustomError::AuthenticationFailed(_))
+    ));
+    assert_eq!(client.authenticate(&guest()).unwrap(), "guest");
+    assert_eq!(client.list_devices().unwrap().len(), 2);
+}
+
+#[test]
+fn guests_can_read_but_not_control() {
+    let (addr, devices) = start_control_server();
+    let mut client = ControlClient::connect(addr.as_str()).unwrap();
+    client.authenticate(&guest()).unwrap();
+
+    let result = client
+        .send(&execute("lamp", PowerSocketCommand::GetState))
+        .unwrap();
+    assert!(matches!(
+        result,
+        ExecutionResult::PowerSocket(PowerSocketState::NotPowered)
+    ));
+    let result = client
+        .send(&execute("lamp", PowerSocketCommand::TurnOn))
+        .unwrap();
+    assert!(matches!(
+        result,
+        ExecutionResult::Error(CustomError::PermissionDenied(_))
+    ));
+    assert_eq!(devices.query(&DeviceQuery::new().powered()).len(), 0);
+}
+
+#[test]
+fn permissions_are_scoped_per_room() {
+    let (addr, devices) = start_control_server();
+    let mut client = ControlClient::connect(addr.as_str()).unwrap();
+    client.authenticate(&alice()).unwrap();
+
+    let rooms: Vec<String> = client
+        .list_devices()
+        .unwrap()
+        .into_iter()
+        .map(|e| e.room)
+        .collect();
+    assert_eq!(rooms, ["hall"]);
+
+    let mut watcher = ControlClient::connect(addr.as_str()).unwrap();
+    watcher.authenticate(&alice()).unwrap();
+    let mut events = watcher.watch().unwrap();
+
+    let result = client
+        .send(&execute("kettle", PowerSocketCommand::TurnOn))
+        .unwrap();
+    assert!(matches!(
+        result,
+        ExecutionResult::Error(CustomError::PermissionDenied(_))
+    ));
+    // changes made by others in rooms alice cannot read are not shown to her
+    devices.execute_command(turn_on("kettle"));
+    let result = client
+        .send(&execute("lamp", PowerSocketCommand::TurnOn))
+        .unwrap();
+    assert!(matches!(result, ExecutionResult::PowerSocket(_)));
+    match events.next().unwrap().unwrap() {
+        DeviceEvent::StateChanged { device, .. } => assert_eq!(device, "lamp"),
+        other => panic!("unexpected event {:?}", other),
+    }
+}
+
+/// a heater in the hall alice may use, and one in the kitchen she may not
+fn heaters() -> SmartDeviceList {
+    let (_, mut devices) = house();
+    for room in ["hall", "kitchen"] {
+        devices.add_device(room, socket("heater", 0)).unwrap();
+    }
+    devices
+}
+
+fn is_on(devices: &SmartDeviceList, room: &str) -> bool {
+    match devices.resolve(Some(room), "heater").unwrap().device {
+        SmartDevice::Socket(s) => s.is_turned_on(),
+        other => panic!("unexpected {:?}", other),
+    }
+}
+
+#[test]
+fn commands_run_in_the_room_that_was_checked() {
+    let devices = heaters();
+    let server = ControlServer::bind("127.0.0.1:0", devices.clone())
+        .unwrap()
+        .with_auth(authenticator());
+    let addr = server.local_addr().unwrap().to_string();
+    server.spawn();
+    let mut client = ControlClient::connect(addr.as_str()).unwrap();
+    client.authenticate(&alice()).unwrap();
+
+    let result = client
+        .send(&execute("heater", PowerSocketCommand::TurnOn))
+        .unwrap();
+    assert!(matches!(
+        result,
+        ExecutionResult::Error(CustomError::AmbiguousDevice(_))
+    ));
+    let execute_in = |room: &str| Command::ExecuteIn {
+        room: room.into(),
+        command: turn_on("heater"),
+        version: None,
+    };
+    assert!(matches!(
+        client.send(&execute_in("kitchen")).unwrap(),
+        ExecutionResult::Error(CustomError::PermissionDenied(_))
+    ));
+    assert!(matches!(
+        client.send(&execute_in("hall")).unwrap(),
+        ExecutionResult::PowerSocket(_)
+    ));
+    assert!(is_on(&devices, "hall"));
+    assert!(!is_on(&devices, "kitchen"));
+
+    let api = RestApi::new(Arc::new(Mutex::new(SmartHouse::new())), devices.clone())
+        .with_auth(authenticator());
+    let post = |path: &str| {
+        let mut request = HttpRequest::new("POST", path, b"socket.off");
+        request
+            .headers
+            .push(("Authorization".into(), "Basic YWxpY2U6c2VjcmV0".into()));
+        api.handle(&request).status
+    };
+    assert_eq!(post("/devices/heater/commands"), 409);
+    // alice may not read the kitchen, so its heater is not found
+    assert_eq!(post("/rooms/kitchen/devices/heater/commands"), 404);
+    assert_eq!(post("/rooms/hall/devices/heater/commands"), 200);
+    assert!(!is_on(&devices, "hall"));
+}
+
+fn start_http_server() -> String {
+    let (house, devices) = house();
+    let api = RestApi::new(Arc::new(Mutex::new(house)), devices).with_auth(authenticator());
+    let server = HttpServer::bind("127.0.0.1:0", api).unwrap();
+    let addr = server.local_addr().unwrap().to_string();
+    server.spawn();
+    addr
+}
+
+fn request(addr: &str, method: &str, path: &str, auth: Option<&str>, body: &str) -> (u16, String) {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    let auth = auth
+        .map(|auth| format!("Authorization: {}\r\n", auth))
+        .unwrap_or_default();
+    write!(
+        stream,
+        "{} {} HTTP/1.1\r\nHost: {}\r\n{}Content-Length: {}\r\n\r\n{}",
+        method,
+        path,
+        addr,
+        auth,
+        body.len(),
+        body
+    )
+    .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    let status = response[9..12].parse().unwrap();
+    let body = response.split_once("\r\n\r\n").unwrap().1.to_owned();
+    (status, body)
+}
+
+#[test]
+fn http_requires_credentials() {
+    let addr = start_http_server();
+    assert_eq!(request(&addr, "GET", "/rooms", None, "").0, 401);
+    assert_eq!(
+        request(&addr, "GET", "/rooms", Some("Bearer nope"), "").0,
+        401
+    );
+    let (status, body) = request(&addr, "GET", "/rooms", Some("Bearer guest-token"), "");
+    assert_eq!(status, 200);
+    assert_eq!(body, r#"["hall","kitchen"]"#);
+
+    // "alice:secret"
+    let alice = Some("Basic YWxpY2U6c2VjcmV0");
+    let (status, body) = request(&addr, "GET", "/rooms", alice, "");
+    assert_eq!(status, 200);
+    assert_eq!(body, r#"["hall"]"#);
+}
+
+#[test]
+fn http_denials_are_forbidden() {
+    let addr = start_http_server();
+    let guest = Some("Bearer guest-token");
+    let alice = Some("Basic YWxpY2U6c2VjcmV0");
+    let turn_on = r#"{"PowerSocket":"TurnOn"}"#;
+
+    assert_eq!(request(&addr, "GET", "/devices/kettle", guest, "").0, 200);
+    // devices alice may not read look like missing ones
+    for path in [
+        "/devices/kettle",
+        "/devices/kettle/capabilities",
+        "/devices/toaster",
+    ] {
+        assert_eq!(request(&addr, "GET", path, alice, "").0, 404, "{}", path);
+    }
+    let (status, body) = request(&addr, "POST", "/devices/lamp/commands", guest, turn_on);
+    assert_eq!(status, 403);
+    assert!(body.contains("Permission denied"), "{}", body);
+    assert_eq!(
+        request(&addr, "POST", "/devices/lamp/commands", alice, turn_on).0,
+        200
+    );
+    assert_eq!(request(&addr, "DELETE", "/rooms/hall", alice, "").0, 403);
+    assert_eq!(
+        request(&addr, "POST", "/rooms", guest, r#"{"name":"attic"}"#).0,
+        403
+    );
+}
+
+#[test]
+fn websocket_requires_credentials() {
+    let (house, devices) = house();
+    let api = RestApi::new(Arc::new(Mutex::new(house)), devices.clone()).with_auth(authenticator());
+    let server = HttpServer::bind("127.0.0.1:0", api).unwrap();
+    let addr = server.local_addr().unwrap().to_string();
+    server.spawn();
+
+    assert!(WebSocketClient::connect(&addr, "/ws").is_err());
+    let mut client = WebSocketClient::connect_with(&addr, "/ws", &alice()).unwrap();
+    client.set_timeout(Some(Duration::from_secs(5))).unwrap();
+    client
+        .send(&WsClientMessage::Subscribe(Topic::all()))
+        .unwrap();
+    assert_eq!(
+        client.receive().unwrap(),
+        WsServerMessage::Subscribed(Topic::all())
+    );
+    for device in ["kettle", "lamp"] {
+        devices.execute_command(CommandData {
+            device_name: device.into(),
+            data: DeviceCommand::PowerSocket(PowerSocketCommand::TurnOn),
+        });
+    }
+    match client.receive().unwrap() {
+        WsServerMessage::Event(DeviceEvent::StateChanged { device, .. }) => {
+            assert_eq!(device, "lamp")
+        }
+        other => panic!("unexpected message {:?}", other),
+    }
+}
+
+#[test]
+fn cli_credentials() {
+    let cli = Cli::parse(["--token", "abc", "shell"]).unwrap();
+    assert_eq!(cli.credentials, Some(Credentials::Token("abc".into())));
+    // passwords never appear on the command line
+    std::env::remove_var("SMART_HOUSE_PASSWORD");
+    assert!(Cli::parse(["--user", "alice", "shell"]).is_err());
+    std::env::set_var("SMART_HOUSE_PASSWORD", "pa:ss");
+    let cli = Cli::parse(["--user", "alice", "shell"]).unwrap();
+    assert_eq!(
+        cli.credentials,
+        Some(Credentials::Password {
+            user: "alice".into(),
+            password: "pa:ss".into()
+        })
+    );
+    let hash = Cli::parse(["hash-password"]).unwrap().run().unwrap();
+    let auth: Authenticator = serde_json::from_value(serde_json::json!({
+        "users": [{"user": "alice", "password_hash": hash, "permissions": []}]
+    }))
+    .unwrap();
+    assert!(auth
+        .authenticate(&Credentials::Password {
+            user: "alice".into(),
+            password: "pa:ss".into()
+        })
+        .is_ok());
+    assert!(matches!(
+        Cli::parse(["serve", "127.0.0.1:0", "--auth", "auth.json"])
+            .unwrap()
+            .command,
+        CliCommand::Serve { auth: Some(_), .. }
+    ));
+}
+
+#[test]
+fn overlong_lines_close_the_connection() {
+    let (addr, _) = start_control_server();
+    let mut stream = TcpStream::connect(&addr).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    // never ends, the server stops reading long before
+    let line = vec![b'a'; 100 * 1024];
+    stream.write_all(&line).ok();
+    let mut response = String::new();
+    match stream.read_to_string(&mut response) {
+        Ok(_) => assert!(response.contains("longer than"), "{}", response),
+        // closing with unread data may reset the connection
+        Err(e) => assert_eq!(e.kind(), std::io::ErrorKind::ConnectionReset),
+    }
+}
@@ -0,0 +1,287 @@
+mod common;
+
+use common::{house_with, socket, socket_command};
+use smart_house::*;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+fn devices() -> SmartDeviceList {
+    let layout = [
+        ("hall", socket("lamp", 60)),
+        ("hall", socket("heater", 2000)),
+        ("garage", socket("charger", 3000)),
+    ];
+    house_with(&[], layout).1
+}
+
+fn is_on(devices: &SmartDeviceList, name: &str) -> bool {
+    match devices.find(name).unwrap().device {
+        SmartDevice::Socket(socket) => socket.is_turned_on(),
+        other => panic!("unexpected {:?}", other),
+    }
+}
+
+fn is_on_in(devices: &SmartDeviceList, room: &str, name: &str) -> bool {
+    match devices.resolve(Some(room), name).unwrap().device {
+        SmartDevice::Socket(socket) => socket.is_turned_on(),
+        other => panic!("unexpected {:?}", other),
+    }
+}
+
+/// turns on the lamp and the heater, with a command to a missing device in between
+fn failing_batch() -> Batch {
+    Batch::new(vec![
+        socket_command("lamp", PowerSocketCommand::TurnOn),
+        socket_command("fridge", PowerSocketCommand::TurnOn),
+        socket_command("heater", PowerSocketCommand::TurnOn),
+    ])
+}
+
+fn outcomes(result: &BatchResult) -> Vec<&'static str> {
+    result
+        .items
+        .iter()
+        .map(|item| match item.outcome {
+            BatchOutcome::Done(_) => "done",
+            BatchOutcome::Failed(_) => "failed",
+            BatchOutcome::Skipped => "skipped",
+        })
+        .collect()
+}
+
+#[test]
+fn failures_do_not_stop_a_batch_by_default() {
+    let devices = devices();
+    let result = devices.execute_batch(failing_batch());
+    assert_eq!(outcomes(&result), ["done", "failed", "done"]);
+    assert!(!result.is_success());
+    assert!(!result.rolled_back);
+    assert_eq!(result.failures().next().unwrap().device_name, "fridge");
+    assert!(is_on(&devices, "lamp") && is_on(&devices, "heater"));
+    assert_eq!(result.to_string(), "2 done, 1 failed");
+}
+
+#[test]
+fn stop_on_error_skips_the_rest() {
+    let devices = devices();
+    let result = devices.execute_batch(failing_batch().stop_on_error());
+    assert_eq!(outcomes(&result), ["done", "failed", "skipped"]);
+    assert!(is_on(&devices, "lamp"));
+    assert!(!is_on(&devices, "heater"));
+    assert_eq!(result.to_string(), "1 done, 1 failed, 1 skipped");
+}
+
+#[test]
+fn transactions_roll_back() {
+    let devices = devices();
+    devices.execute_command(socket_command("heater", PowerSocketCommand::TurnOn));
+    let batch = Batch::new(vec![
+        socket_command("lamp", PowerSocketCommand::TurnOn),
+        socket_command("lamp", PowerSocketCommand::SetDescription("desk".into())),
+        socket_command("heater", PowerSocketCommand::TurnOff),
+        socket_command("charger", PowerSocketCommand::TurnOn),
+        socket_command("heater", PowerSocketCommand::TurnOn),
+    ])
+    .transactional();
+    devices
+        .faults()
+        .inject("garage", "charger", Fault::new(FaultKind::HardwareFault));
+    let events = devices.subscribe();
+    let result = devices.execute_batch(batch);
+
+    assert_eq!(
+        outcomes(&result),
+        ["done", "done", "done", "failed", "skipped"]
+    );
+    assert!(result.rolled_back && result.is_restored());
+    assert!(!is_on(&devices, "lamp"));
+    assert!(is_on(&devices, "heater"));
+    assert!(!is_on(&devices, "charger"));
+    match devices.find("lamp").unwrap().device {
+        SmartDevice::Socket(lamp) => assert_eq!(lamp.description, ""),
+        other => panic!("unexpected {:?}", other),
+    }
+    // restored in reverse order, the untouched charger needs nothing
+    let compensations: Vec<_> = result
+        .compensations
+        .iter()
+        .map(|c| format!("{} {}", c.device_name, c.command))
+        .collect();
+    assert_eq!(
+        compensations,
+        [
+            "heater socket.on",
+            "lamp socket.description ",
+            "lamp socket.off"
+        ]
+    );
+    assert_eq!(
+        result.to_string(),
+        "3 done, 1 failed, 1 skipped, rolled back"
+    );
+    // every change and its undo is published
+    assert_eq!(events.try_iter().count(), 4);
+}
+
+#[test]
+fn rollback_restores_timers() {
+    let clock = VirtualClock::new(SystemTime::UNIX_EPOCH);
+    let devices = devices();
+    devices.set_clock(clock.clone());
+    devices.execute_command(socket_command(
+        "heater",
+        PowerSocketCommand::TurnOnFor(Duration::from_secs(600)),
+    ));
+    clock.advance(Duration::from_secs(60));
+    let batch = Batch::new(vec![
+        socket_command("heater", PowerSocketCommand::TurnOff),
+        socket_command(
+            "lamp",
+            PowerSocketCommand::TurnOnIn(Duration::from_secs(60)),
+        ),
+        socket_command("fridge", PowerSocketCommand::TurnOn),
+    ])
+    .transactional();
+    let result = devices.execute_batch(batch);
+    assert!(result.rolled_back && result.is_restored());
+
+    let heater = devices.get_device_info("hall", "heater").unwrap();
+    assert!(is_on(&devices, "heater"));
+    assert_eq!(
+        heater.countdown.unwrap().remaining,
+        Duration::from_secs(540)
+    );
+    assert_eq!(
+        devices.get_device_info("hall", "lamp").unwrap().countdown,
+        None
+    );
+}
+
+#[test]
+fn rollback_restores_shed_sockets() {
+    let mut devices = devices();
+    devices
+        .add_device("kitchen", socket("kettle", 2000))
+        .unwrap();
+    devices
+        .add_device("kitchen", socket("heater", 1500))
+        .unwrap();
+    let circuit = Circuit::new("kitchen", 3000)
+        .with_socket("kitchen", "kettle", 10)
+        .with_socket("kitchen", "heater", 1)
+        .shed_on_overload();
+    devices.add_circuit(circuit).unwrap();
+    devices
+        .with_device_mut("kitchen", "heater", |d| match d {
+            SmartDevice::Socket(s) => s.turn_on(),
+            _ => unreachable!(),
+        })
+        .unwrap();
+
+    let batch = Batch::new(vec![
+        socket_command("kettle", PowerSocketCommand::TurnOn),
+        socket_command("fridge", PowerSocketCommand::TurnOn),
+    ])
+    .transactional();
+    let result = devices.execute_batch(batch);
+    assert!(result.rolled_back && result.is_restored());
+    let compensations: Vec<_> = result
+        .compensations
+        .iter()
+        .map(|c| format!("{}/{} {}", c.room, c.device_name, c.command))
+        .collect();
+    assert_eq!(
+        compensations,
+        ["kitchen/kettle socket.off", "kitchen/heater socket.on"]
+    );
+    assert_eq!(devices.circuit_load("kitchen").unwrap(), 1500);
+    // the heater in the hall was never touched
+    assert!(!is_on_in(&devices, "hall", "heater"));
+}
+
+#[test]
+fn parallel_batches() {
+    let devices = devices();
+    for name in ["lamp", "heater"] {
+        devices
+            .faults()
+            .set_latency("hall", name, Duration::from_millis(100));
+    }
+    let batch = Batch::new(vec![
+        socket_command("lamp", PowerSocketCommand::TurnOn),
+        socket_command("heater", PowerSocketCommand::TurnOn),
+    ])
+    .parallel();
+    let started = std::time::Instant::now();
+    let result = devices.execute_batch(batch);
+    // one after the other would take 200 ms
+    assert!(started.elapsed() < Duration::from_millis(200));
+    assert!(result.is_success());
+    assert!(is_on(&devices, "lamp") && is_on(&devices, "heater"));
+
+    devices.faults().clear_all();
+    let result = devices.execute_batch(failing_batch().parallel().transactional());
+    assert_eq!(outcomes(&result), ["done", "failed", "done"]);
+    assert!(result.rolled_back);
+    // both were on before the batch already
+    assert!(result.compensations.is_empty());
+
+    devices.execute_command(socket_command("lamp", PowerSocketCommand::TurnOff));
+    let result = devices.execute_batch(failing_batch().parallel().transactional());
+    assert_eq!(result.compensations.len(), 1);
+    assert!(!is_on(&devices, "lamp"));
+}
+
+#[test]
+fn batches_over_the_control_channel() {
+    let devices = devices();
+    let guest = Authenticator::new().with_token(
+        "guest-token",
+        Principal::new("guest").allow("hall", &[Action::Read, Action::Control]),
+    );
+    let server = ControlServer::bind("127.0.0.1:0", devices.clone())
+        .unwrap()
+        .with_auth(guest);
+    let addr = server.local_addr().unwrap().to_string();
+    server.spawn();
+
+    let options = ConnectOptions::new().with_credentials(Credentials::Token("guest-token".into()));
+    let mut client = ControlClient::connect_with(&addr, &options).unwrap();
+    let result = client
+        .execute_batch(failing_batch().stop_on_error())
+        .unwrap();
+    assert_eq!(outcomes(&result), ["done", "failed", "skipped"]);
+
+    // a single forbidden command refuses the whole batch
+    let batch = Batch::new(vec![
+        socket_command("heater", PowerSocketCommand::TurnOn),
+        socket_command("charger", PowerSocketCommand::TurnOn),
+    ]);
+    assert!(matches!(
+        client.execute_batch(batch),
+        Err(CustomError::PermissionDenied(_))
+    ));
+    assert!(!is_on(&devices, "heater"));
+}
+
+#[test]
+fn batches_over_http() {
+    let devices = devices();
+    let api = RestApi::new(Arc::new(Mutex::new(SmartHouse::new())), devices.clone());
+    let body = serde_json::to_vec(&failing_batch().transactional()).unwrap();
+    let response = api.handle(&HttpRequest::new("POST", "/batch", &body));
+    assert_eq!(response.status, 200);
+    let result: BatchResult = serde_json::from_str(&response.body).unwrap();
+    assert!(result.rolled_back);
+    assert!(!is_on(&devices, "lamp"));
+
+    // mode and error handling are optional
+    let body = br#"{"commands": [{"device_name": "lamp", "data": {"PowerSocket": "TurnOn"}}]}"#;
+    let response = api.handle(&HttpRequest::new("POST", "/batch", body));
+    let result: BatchResult = serde_json::from_str(&response.body).unwrap();
+    assert!(result.is_success());
+    assert!(is_on(&devices, "lamp"));
+
+    let response = api.handle(&HttpRequest::new("GET", "/batch", b""));
+    assert_eq!(response.status, 405);
+}
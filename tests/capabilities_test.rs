@@ -0,0 +1,169 @@
+mod common;
+
+use common::{house_with, socket, thermometer, turn_on};
+use smart_house::*;
+use std::sync::{Arc, Mutex};
+
+fn house() -> (SmartHouse, SmartDeviceList) {
+    let layout = [
+        ("hall", socket("lamp", 60)),
+        (
+            "garage",
+            thermometer("garage_temp", Temperature::Fahrenheit(50.)),
+        ),
+    ];
+    house_with(&[], layout)
+}
+
+#[test]
+fn devices_describe_themselves() {
+    let (_, devices) = house();
+    let lamp = devices.find("lamp").unwrap().device.capabilities();
+    assert_eq!(lamp.kind, "SmartSocket");
+    let names: Vec<_> = lamp.commands.iter().map(|c| c.name.as_str()).collect();
+    assert_eq!(
+        names,
+        [
+            "socket.on",
+            "socket.off",
+            "socket.state",
+            "socket.limit",
+            "socket.on_for",
+            "socket.description",
+            "socket.off_in",
+            "socket.on_in",
+            "socket.cancel"
+        ]
+    );
+    let limit = &lamp.command("socket.limit").unwrap().parameters[0];
+    assert_eq!(limit.unit.as_deref(), Some("W"));
+    assert_eq!(limit.max, Some(MAX_SOCKET_POWER.into()));
+    assert!(lamp.accepts(&DeviceCommand::PowerSocket(PowerSocketCommand::TurnOn)));
+    assert_eq!(lamp.reading("power").unwrap().unit.as_deref(), Some("W"));
+
+    let thermometer = devices.find("garage_temp").unwrap().device.capabilities();
+    assert_eq!(thermometer.kind, "SmartThermometer");
+    let calibrate = thermometer.command("thermometer.calibrate").unwrap();
+    assert_eq!(calibrate.parameters[0].kind, ParameterKind::Number);
+    assert!(!thermometer.accepts(&DeviceCommand::PowerSocket(PowerSocketCommand::TurnOn)));
+    let temperature = thermometer.reading("temperature").unwrap();
+    assert_eq!(temperature.unit.as_deref(), Some("°F"));
+
+    let json = serde_json::to_string(&lamp).unwrap();
+    assert_eq!(serde_json::from_str::<Capabilities>(&json).unwrap(), lamp);
+}
+
+#[test]
+fn unsupported_commands_are_rejected() {
+    let (_, devices) = house();
+    let result = devices.execute_command(turn_on("garage_temp"));
+    match result {
+        ExecutionResult::Error(CustomError::DeviceFault(fault)) => {
+            assert_eq!(fault.kind, FaultKind::CommandRejected);
+            assert!(fault.to_string().contains("does not support socket.on"));
+        }
+        other => panic!("unexpected {:?}", other),
+    }
+}
+
+#[test]
+fn capabilities_over_the_control_channel() {
+    let (_, devices) = house();
+    let guest = Authenticator::new().with_token(
+        "guest-token",
+        Principal::new("guest").allow("hall", &[Action::Read]),
+    );
+    let server = ControlServer::bind("127.0.0.1:0", devices)
+        .unwrap()
+        .with_auth(guest);
+    let addr = server.local_addr().unwrap().to_string();
+    server.spawn();
+
+    let options = ConnectOptions::new().with_credentials(Credentials::Token("guest-token".into()));
+    let mut client = ControlClient::connect_with(&addr, &options).unwrap();
+    let lamp = client.capabilities(None, "LAMP").unwrap();
+    assert!(lamp.accepts(&DeviceCommand::PowerSocket(PowerSocketCommand::TurnOn)));
+    // rooms the principal may not read are hidden
+    assert!(matches!(
+        client.capabilities(None, "garage_temp"),
+        Err(CustomError::DeviceNotFound)
+    ));
+}
+
+#[test]
+fn capabilities_over_http() {
+    let (house, devices) = house();
+    let api = RestApi::new(Arc::new(Mutex::new(house)), devices);
+    let response = api.handle(&HttpRequest::new(
+        "GET",
+        "/devices/garage_temp/capabilities",
+        b"",
+    ));
+    assert_eq!(response.status, 200);
+    let capabilities: Capabilities = serde_json::from_str(&response.body).unwrap();
+    assert_eq!(capabilities.readings.len(), 1);
+
+    let response = api.handle(&HttpRequest::new("POST", "/devices/lamp/capabilities", b""));
+    assert_eq!(response.status, 405);
+    let response = api.handle(&HttpRequest::new(
+        "GET",
+        "/devices/fridge/capabilities",
+        b"",
+    ));
+    assert_eq!(response.status, 404);
+}
+
+#[test]
+fn cli_describes_devices() {
+    let file = std::env::temp_dir().join(format!(
+        "smart_house_capabilities_{}.json",
+        std::process::id()
+    ));
+    std::fs::remove_file(&file).ok();
+    let run = |args: &str| {
+        let mut full = vec!["--file".to_owned(), file.display().to_string()];
+        full.extend(args.split_whitespace().map(String::from));
+        Cli::parse(full)?.run()
+    };
+    run("init").unwrap();
+    run("room add hall").unwrap();
+    run("device add hall socket lamp").unwrap();
+
+    let text = run("device describe hall/lamp").unwrap();
+    assert!(text.starts_with("SmartSocket"), "{}", text);
+    assert!(text.contains("command socket.on"), "{}", text);
+    assert!(text.contains("reading power (W)"), "{}", text);
+    let json = run("device describe hall/lamp --format json").unwrap();
+    let capabilities: Capabilities = serde_json::from_str(&json).unwrap();
+    assert_eq!(capabilities.commands.len(), 9);
+    assert!(run("device describe hall/fridge").is_err());
+    assert!(run("device describe lamp").is_err());
+    std::fs::remove_file(&file).ok();
+}
+
+#[test]
+fn devices_sharing_a_name_are_described_by_room() {
+    let (mut house, mut devices) = house();
+    house.try_add_device("garage", "lamp").unwrap();
+    let thermometer = Thermometer::new("lamp", Temperature::Celsius(10.));
+    devices
+        .add_device("garage", SmartDevice::Thermo(thermometer))
+        .unwrap();
+
+    let server = ControlServer::bind("127.0.0.1:0", devices.clone()).unwrap();
+    let addr = server.local_addr().unwrap().to_string();
+    server.spawn();
+    let mut client = ControlClient::connect(&addr).unwrap();
+    assert!(client.capabilities(None, "lamp").is_err());
+    let lamp = client.capabilities(Some("garage"), "lamp").unwrap();
+    assert_eq!(lamp.kind, "SmartThermometer");
+
+    let api = RestApi::new(Arc::new(Mutex::new(house)), devices);
+    let get = |path: &str| api.handle(&HttpRequest::new("GET", path, b""));
+    assert_eq!(get("/devices/lamp/capabilities").status, 409);
+    let response = get("/rooms/hall/devices/lamp/capabilities");
+    assert_eq!(response.status, 200);
+    let capabilities: Capabilities = serde_json::from_str(&response.body).unwrap();
+    assert_eq!(capabilities.kind, "SmartSocket");
+    assert_eq!(get("/rooms/hall/devices/fridge/capabilities").status, 404);
+}
@@ -0,0 +1,214 @@
+mod common;
+
+use common::{socket, socket_command, turn_on};
+use smart_house::*;
+
+fn create_list(circuit: Circuit) -> SmartDeviceList {
+    let mut list = SmartDeviceList::new();
+    list.add_device("kitchen", socket("kettle", 2000)).unwrap();
+    list.add_device("kitchen", socket("lamp", 0)).unwrap();
+    list.add_device("bedroom", socket("heater", 1500)).unwrap();
+    list.add_circuit(circuit).unwrap();
+    list
+}
+
+#[test]
+fn socket_uses_configured_load() {
+    let list = create_list(Circuit::new("main", 10_000));
+    assert!(matches!(
+        list.execute_command(turn_on("kettle")),
+        ExecutionResult::PowerSocket(PowerSocketState::Powered(2000))
+    ));
+    assert!(matches!(
+        list.execute_command(turn_on("lamp")),
+        ExecutionResult::PowerSocket(PowerSocketState::Powered(DEFAULT_SOCKET_POWER))
+    ));
+}
+
+#[test]
+fn turn_on_is_refused_on_overload() {
+    let circuit = Circuit::new("main", 3000)
+        .with_socket("kitchen", "kettle", 1)
+        .with_socket("bedroom", "heater", 1);
+    let list = create_list(circuit);
+    list.execute_command(turn_on("heater"));
+    assert!(matches!(
+        list.execute_command(turn_on("kettle")),
+        ExecutionResult::Error(CustomError::CircuitOverload {
+            load: 3500,
+            limit: 3000,
+            ..
+        })
+    ));
+    assert_eq!(list.circuit_load("main").unwrap(), 1500);
+
+    // sockets outside the circuit are not limited
+    assert!(matches!(
+        list.execute_command(turn_on("lamp")),
+        ExecutionResult::PowerSocket(PowerSocketState::Powered(_))
+    ));
+}
+
+#[test]
+fn concurrent_turn_ons_stay_under_the_limit() {
+    for _ in 0..20 {
+        let circuit = Circuit::new("main", 3000)
+            .with_socket("kitchen", "kettle", 1)
+            .with_socket("bedroom", "heater", 1);
+        let list = create_list(circuit);
+        let threads: Vec<_> = ["kettle", "heater", "lamp"]
+            .into_iter()
+            .map(|name| {
+                let list = list.clone();
+                std::thread::spawn(move || list.execute_command(turn_on(name)))
+            })
+            .collect();
+        let results: Vec<_> = threads.into_iter().map(|t| t.join().unwrap()).collect();
+        // exactly one of the circuit's sockets fits, the lamp is not on it
+        assert!(list.circuit_load("main").unwrap() <= 3000);
+        assert!(matches!(
+            results[..2],
+            [ExecutionResult::PowerSocket(_), ExecutionResult::Error(_)]
+                | [ExecutionResult::Error(_), ExecutionResult::PowerSocket(_)]
+        ));
+        assert!(matches!(results[2], ExecutionResult::PowerSocket(_)));
+    }
+}
+
+#[test]
+fn low_priority_sockets_are_shed() {
+    let circuit = Circuit::new("main", 3000)
+        .with_socket("kitchen", "kettle", 10)
+        .with_socket("bedroom", "heater", 1)
+        .shed_on_overload();
+    let list = create_list(circuit);
+    list.execute_command(turn_on("heater"));
+    assert!(matches!(
+        list.execute_command(turn_on("kettle")),
+        ExecutionResult::PowerSocket(PowerSocketState::Powered(2000))
+    ));
+    assert_eq!(list.circuit_load("main").unwrap(), 2000);
+
+    // heater has lower priority and cannot shed the kettle
+    assert!(matches!(
+        list.execute_command(turn_on("heater")),
+        ExecutionResult::Error(CustomError::CircuitOverload { .. })
+    ));
+}
+
+#[test]
+fn socket_belongs_to_one_circuit() {
+    let list = create_list(Circuit::new("main", 3000).with_socket("kitchen", "kettle", 1));
+    assert!(list
+        .add_circuit(Circuit::new("second", 3000).with_socket("kitchen", "Kettle", 1))
+        .is_err());
+    assert!(list.add_circuit(Circuit::new("MAIN", 3000)).is_err());
+    list.remove_circuit("main").unwrap();
+    assert!(list.get_circuits().is_empty());
+}
+
+#[test]
+fn sockets_in_other_rooms_are_not_members() {
+    let circuit = Circuit::new("main", 3000)
+        .with_socket("kitchen", "kettle", 10)
+        .with_socket("kitchen", "heater", 1)
+        .shed_on_overload();
+    let mut list = create_list(circuit);
+    list.add_device("kitchen", socket("heater", 1500)).unwrap();
+    let switch_on = |room: &str| {
+        list.with_device_mut(room, "heater", |d| match d {
+            SmartDevice::Socket(s) => s.turn_on(),
+            _ => unreachable!(),
+        })
+        .unwrap()
+    };
+    switch_on("bedroom");
+    assert_eq!(list.circuit_load("main").unwrap(), 0);
+    switch_on("kitchen");
+    assert_eq!(list.circuit_load("main").unwrap(), 1500);
+
+    // only the kitchen heater is shed
+    list.execute_command(turn_on("kettle"));
+    assert_eq!(list.circuit_load("main").unwrap(), 2000);
+    let heaters = list.query(&DeviceQuery::new().name_matches("heater").powered());
+    assert_eq!(heaters.len(), 1);
+    assert_eq!(heaters[0].room, "bedroom");
+}
+
+#[test]
+fn direct_access_respects_the_breaker() {
+    let circuit = Circuit::new("main", 3000)
+        .with_socket("kitchen", "kettle", 1)
+        .with_socket("bedroom", "heater", 1);
+    let list = create_list(circuit);
+    list.execute_command(turn_on("heater"));
+    let result = list.with_device_mut("kitchen", "kettle", |d| match d {
+        SmartDevice::Socket(s) => s.turn_on(),
+        _ => unreachable!(),
+    });
+    assert!(matches!(
+        result,
+        Err(CustomError::CircuitOverload { load: 3500, .. })
+    ));
+    assert_eq!(list.circuit_load("main").unwrap(), 1500);
+    match list.find("kettle").unwrap().device {
+        SmartDevice::Socket(s) => assert!(!s.is_turned_on()),
+        other => panic!("unexpected {:?}", other),
+    }
+}
+
+#[test]
+fn direct_access_does_not_hold_the_circuits() {
+    let list = create_list(Circuit::new("main", 3000).with_socket("kitchen", "kettle", 1));
+    let circuits = list
+        .with_device_mut("kitchen", "kettle", |_| list.get_circuits())
+        .unwrap();
+    assert_eq!(circuits.len(), 1);
+}
+
+#[test]
+fn try_turn_on_refuses_loads_over_the_socket_limit() {
+    let mut socket = PowerSocket::new("kettle", 2000).with_power_limit(1000);
+    let fault = socket.try_turn_on().unwrap_err();
+    assert_eq!(fault.kind, FaultKind::CommandRejected);
+    assert!(!socket.is_turned_on());
+}
+
+#[test]
+fn socket_limit_is_checked_before_shedding() {
+    let circuit = Circuit::new("main", 3000)
+        .with_socket("kitchen", "kettle", 10)
+        .with_socket("bedroom", "heater", 1)
+        .shed_on_overload();
+    let list = create_list(circuit);
+    list.execute_command(turn_on("heater"));
+    list.execute_command(socket_command(
+        "kettle",
+        PowerSocketCommand::SetPowerLimit(1000),
+    ));
+    assert!(matches!(
+        list.execute_command(turn_on("kettle")),
+        ExecutionResult::Error(CustomError::DeviceFault(_))
+    ));
+    // the heater keeps running
+    assert_eq!(list.circuit_load("main").unwrap(), 1500);
+}
+
+#[test]
+fn removed_sockets_leave_their_circuit() {
+    let circuit = Circuit::new("main", 3000)
+        .with_socket("kitchen", "kettle", 1)
+        .with_socket("bedroom", "heater", 1);
+    let mut list = create_list(circuit);
+    list.remove_device("kitchen", "Kettle").unwrap();
+    list.remove_room("bedroom").unwrap();
+    assert!(list.get_circuits()[0].get_members().is_empty());
+
+    // a new kettle is not limited by the old one's circuit
+    list.add_device("kitchen", socket("kettle", 3500)).unwrap();
+    assert!(matches!(
+        list.execute_command(turn_on("kettle")),
+        ExecutionResult::PowerSocket(PowerSocketState::Powered(3500))
+    ));
+    assert_eq!(list.circuit_load("main").unwrap(), 0);
+}
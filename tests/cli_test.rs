@@ -0,0 +1,200 @@
+use smart_house::*;
+use std::path::{Path, PathBuf};
+
+fn house_file(name: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!(
+        "smart_house_cli_{}_{}.json",
+        name,
+        std::process::id()
+    ));
+    std::fs::remove_file(&path).ok();
+    path
+}
+
+fn run(file: &Path, args: &str) -> CustomResult<String> {
+    let mut full = vec!["--file".to_owned(), file.display().to_string()];
+    full.extend(args.split_whitespace().map(String::from));
+    Cli::parse(full)?.run()
+}
+
+fn create_house(file: &Path) {
+    run(file, "init").unwrap();
+    run(file, "room add hall").unwrap();
+    run(file, "room add kitchen").unwrap();
+    run(file, "device add hall socket socket1 --power 100").unwrap();
+    run(
+        file,
+        "device add kitchen thermometer therm1 --temperature 20C",
+    )
+    .unwrap();
+}
+
+#[test]
+fn manage_house_file() {
+    let file = house_file("manage");
+    create_house(&file);
+    assert!(run(&file, "init").is_err());
+    assert!(run(&file, "room add Hall").is_err());
+    assert_eq!(run(&file, "room list").unwrap(), "hall\nkitchen");
+
+    run(&file, "device remove kitchen therm1").unwrap();
+    run(&file, "room remove hall").unwrap();
+    assert_eq!(run(&file, "room list").unwrap(), "kitchen");
+    let report = run(&file, "report").unwrap();
+    assert_eq!(report.lines().count(), 1);
+    std::fs::remove_file(file).ok();
+}
+
+#[test]
+fn report_formats() {
+    let file = house_file("report");
+    create_house(&file);
+
+    let table = run(&file, "report --unit f").unwrap();
+    let lines: Vec<&str> = table.lines().collect();
+    assert_eq!(lines.len(), 3);
+    assert!(lines[0].starts_with("ROOM"));
+    assert!(lines[2].contains("therm1") && lines[2].contains("68.0°F"));
+
+    let json = run(&file, "report --format json").unwrap();
+    let entries: Vec<ReportEntry> = serde_json::from_str(&json).unwrap();
+    assert_eq!(entries.len(), 2);
+    assert_eq!(entries[0].info.as_ref().unwrap().name, "socket1");
+    std::fs::remove_file(file).ok();
+}
+
+#[test]
+fn control_socket_locally() {
+    let file = house_file("local");
+    create_house(&file);
+    assert_eq!(run(&file, "socket on hall/Socket1").unwrap(), "on (100 W)");
+    // state is persisted in the house file
+    assert_eq!(
+        run(&file, "socket state hall/socket1").unwrap(),
+        "on (100 W)"
+    );
+    assert_eq!(run(&file, "socket off hall/socket1").unwrap(), "off");
+    assert!(matches!(
+        run(&file, "socket on kitchen/socket1"),
+        Err(CustomError::DeviceNotFound)
+    ));
+    std::fs::remove_file(file).ok();
+}
+
+#[test]
+fn control_socket_remotely() {
+    let file = house_file("remote");
+    create_house(&file);
+    // same name in another room, the server must not pick it
+    run(&file, "device add kitchen socket socket1 --power 50").unwrap();
+    let (_, devices) = HouseFile::load(&file).unwrap().into_house().unwrap();
+    let server = ControlServer::bind("127.0.0.1:0", devices.clone()).unwrap();
+    let addr = server.local_addr().unwrap().to_string();
+    server.spawn();
+
+    let args = format!("--remote {} socket on hall/socket1", addr);
+    assert_eq!(run(&file, &args).unwrap(), "on (100 W)");
+    let powered = devices.query(&DeviceQuery::new().powered());
+    assert_eq!(powered.len(), 1);
+    assert_eq!(powered[0].room, "hall");
+    std::fs::remove_file(file).ok();
+}
+
+#[test]
+fn invalid_arguments() {
+    let file = house_file("invalid");
+    assert!(run(&file, "room").is_err());
+    assert!(run(&file, "socket toggle hall/socket1").is_err());
+    assert!(run(&file, "socket on socket1").is_err());
+    assert!(run(&file, "device add hall lamp lamp1").is_err());
+    assert!(run(&file, "device add hall socket s1 --power lots").is_err());
+    assert!(run(&file, "report --format xml").is_err());
+    assert!(run(&file, "mqtt localhost:1883 --topic home").is_err());
+    assert!(matches!(
+        Cli::parse(["--file"]),
+        Err(CustomError::ParseError(_))
+    ));
+}
+
+#[test]
+fn parse_mqtt_command() {
+    let cli = Cli::parse(["mqtt", "localhost:1883", "--prefix", "home"]).unwrap();
+    assert!(matches!(
+        cli.command,
+        CliCommand::Mqtt { broker, prefix: Some(prefix), auth: None } if broker == "localhost:1883" && prefix == "home"
+    ));
+    let cli = Cli::parse(["mqtt", "localhost:1883", "--auth", "auth.json"]).unwrap();
+    assert!(matches!(
+        cli.command,
+        CliCommand::Mqtt {
+            prefix: None,
+            auth: Some(_),
+            ..
+        }
+    ));
+}
+
+#[test]
+fn parse_tls_options() {
+    let cli = Cli::parse([
+        "--tls-ca",
+        "ca.pem",
+        "--tls-cert",
+        "cert.pem",
+        "--tls-key",
+        "key.pem",
+        "serve",
+        "0.0.0.0:7000",
+    ])
+    .unwrap();
+    assert_eq!(cli.tls_ca, Some(PathBuf::from("ca.pem")));
+    assert_eq!(cli.tls_cert, Some(PathBuf::from("cert.pem")));
+    assert_eq!(cli.tls_key, Some(PathBuf::from("key.pem")));
+    assert!(Cli::parse(["--tls-cert", "cert.pem", "serve", "0.0.0.0:7000"]).is_err());
+    // missing certificate files only fail the commands using them
+    let file = house_file("tls");
+    assert!(run(&file, "--tls-ca missing.pem init").is_ok());
+    assert!(run(
+        &file,
+        "--remote 127.0.0.1:1 --tls-ca missing.pem socket on hall/socket1"
+    )
+    .is_err());
+    std::fs::remove_file(file).ok();
+}
+
+#[test]
+fn undo_and_redo_edits() {
+    let file = house_file("undo");
+    create_house(&file);
+    assert!(run(&file, "redo").is_err());
+    run(&file, "room remove hall").unwrap();
+    assert_eq!(run(&file, "room list").unwrap(), "kitchen");
+
+    assert_eq!(
+        run(&file, "undo").unwrap(),
+        "undo: add room hall with 1 devices"
+    );
+    assert_eq!(run(&file, "room list").unwrap(), "hall\nkitchen");
+    assert!(run(&file, "report").unwrap().contains("socket1"));
+
+    run(&file, "redo").unwrap();
+    assert_eq!(run(&file, "room list").unwrap(), "kitchen");
+    run(&file, "undo").unwrap();
+
+    run(&file, "device rename hall socket1 lamp").unwrap();
+    run(&file, "room rename kitchen galley").unwrap();
+    assert!(run(&file, "report").unwrap().contains("lamp"));
+    run(&file, "undo").unwrap();
+    run(&file, "undo").unwrap();
+    let report = run(&file, "report").unwrap();
+    assert!(
+        report.contains("socket1") && report.contains("kitchen"),
+        "{}",
+        report
+    );
+    // socket commands are not configuration changes and keep the history
+    run(&file, "socket on hall/socket1").unwrap();
+    run(&file, "redo").unwrap();
+    assert!(run(&file, "report").unwrap().contains("lamp"));
+    std::fs::remove_file(file).ok();
+}
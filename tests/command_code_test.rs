@@ -0,0 +1,99 @@
+use smart_house::*;
+use std::sync::{Arc, Mutex};
+
+const ALL: [DeviceCommand; 3] = [
+    DeviceCommand::PowerSocket(PowerSocketCommand::TurnOff),
+    DeviceCommand::PowerSocket(PowerSocketCommand::TurnOn),
+    DeviceCommand::PowerSocket(PowerSocketCommand::GetState),
+];
+
+fn is_invalid<T: std::fmt::Debug>(result: CustomResult<T>) -> bool {
+    matches!(result, Err(CustomError::InvalidCommandCode(_)))
+}
+
+#[test]
+fn codes_follow_the_table() {
+    let codes: Vec<u16> = ALL.iter().map(DeviceCommand::code).collect();
+    assert_eq!(codes, [0x0100, 0x0101, 0x0102]);
+    let names: Vec<String> = ALL.iter().map(DeviceCommand::to_string).collect();
+    assert_eq!(names, ["socket.off", "socket.on", "socket.state"]);
+    assert_eq!(ALL[1].to_bytes(), [0x01, 0x01]);
+}
+
+#[test]
+fn every_encoding_round_trips() {
+    for command in ALL {
+        assert_eq!(DeviceCommand::from_code(command.code()).unwrap(), command);
+        assert_eq!(
+            DeviceCommand::from_bytes(&command.to_bytes()).unwrap(),
+            command
+        );
+        assert_eq!(command.name().parse::<DeviceCommand>().unwrap(), command);
+    }
+    assert_eq!(
+        " Socket.ON ".parse::<DeviceCommand>().unwrap(),
+        DeviceCommand::PowerSocket(PowerSocketCommand::TurnOn)
+    );
+}
+
+#[test]
+fn malformed_input_is_an_error() {
+    for bytes in [
+        &[][..],
+        &[0x01],
+        &[0x02, 0x00],
+        &[0x01, 0x09],
+        &[0x01, 0x01, 0x00],
+    ] {
+        assert!(is_invalid(DeviceCommand::from_bytes(bytes)), "{:?}", bytes);
+    }
+    // the codes that used to panic or collide in the decimal scheme
+    for code in [0, 5, 10, 11, 255, 0xffff] {
+        assert!(is_invalid(DeviceCommand::from_code(code)), "{}", code);
+    }
+    for text in [
+        "",
+        "on",
+        "socket.",
+        ".on",
+        "socket.dim",
+        "lamp.on",
+        "socket.on.now",
+    ] {
+        assert!(is_invalid(text.parse::<DeviceCommand>()), "{}", text);
+    }
+    assert!(matches!(
+        Command::from(("lamp".to_owned(), 0x0900)),
+        Command::Unknown
+    ));
+    assert!(matches!(
+        Command::from(("lamp".to_owned(), 0x0101)),
+        Command::Execute(CommandData { data, .. }) if data == ALL[1]
+    ));
+}
+
+#[test]
+fn http_accepts_text_commands() {
+    let mut house = SmartHouse::new();
+    house.try_add_room(Room::with_name("hall")).unwrap();
+    house.try_add_device("hall", "lamp").unwrap();
+    let mut devices = SmartDeviceList::new();
+    let socket = SmartDevice::Socket(PowerSocket::new("lamp", 60));
+    devices.add_device("hall", socket).unwrap();
+    let api = RestApi::new(Arc::new(Mutex::new(house)), devices);
+
+    let response = api.handle(&HttpRequest::new(
+        "POST",
+        "/devices/lamp/commands",
+        b"socket.on",
+    ));
+    assert_eq!(response.status, 200);
+    assert_eq!(response.body, r#"{"PowerSocket":{"Powered":60}}"#);
+    let response = api.handle(&HttpRequest::new(
+        "POST",
+        "/devices/lamp/commands",
+        b"socket.dim",
+    ));
+    assert_eq!(response.status, 400);
+    assert!(response.body.contains("unknown socket command"));
+}
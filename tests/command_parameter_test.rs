@@ -0,0 +1,299 @@
+mod common;
+
+use common::{house_with, socket, socket_command, thermometer};
+use smart_house::*;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+fn devices() -> SmartDeviceList {
+    let layout = [
+        ("hall", socket("heater", 2000)),
+        ("hall", thermometer("hall_temp", Temperature::Celsius(20.))),
+    ];
+    house_with(&[], layout).1
+}
+
+fn heater(devices: &SmartDeviceList) -> PowerSocket {
+    match devices.find("heater").unwrap().device {
+        SmartDevice::Socket(socket) => socket,
+        other => panic!("unexpected {:?}", other),
+    }
+}
+
+fn invalid_parameter(result: ExecutionResult) -> String {
+    match result {
+        ExecutionResult::Error(CustomError::InvalidParameter { parameter, .. }) => parameter,
+        other => panic!("unexpected {:?}", other),
+    }
+}
+
+#[test]
+fn power_limit_guards_the_socket() {
+    let devices = devices();
+    devices.execute_command(socket_command("heater", PowerSocketCommand::TurnOn));
+    let result = devices.execute_command(socket_command(
+        "heater",
+        PowerSocketCommand::SetPowerLimit(1500),
+    ));
+    // the running load is over the new limit
+    assert!(matches!(
+        result,
+        ExecutionResult::PowerSocket(PowerSocketState::NotPowered)
+    ));
+    assert_eq!(heater(&devices).get_power_limit(), Some(1500));
+    match devices.execute_command(socket_command("heater", PowerSocketCommand::TurnOn)) {
+        ExecutionResult::Error(CustomError::DeviceFault(fault)) => {
+            assert_eq!(fault.kind, FaultKind::CommandRejected);
+            assert!(fault.detail.contains("2000 W exceeds the limit of 1500 W"));
+        }
+        other => panic!("unexpected {:?}", other),
+    }
+
+    for watts in [0, MAX_SOCKET_POWER + 1] {
+        let result = devices.execute_command(socket_command(
+            "heater",
+            PowerSocketCommand::SetPowerLimit(watts),
+        ));
+        assert_eq!(invalid_parameter(result), "watts");
+    }
+    assert_eq!(heater(&devices).get_power_limit(), Some(1500));
+}
+
+#[test]
+fn lowering_the_limit_switches_the_socket_off() {
+    let devices = devices();
+    let hour = Duration::from_secs(3600);
+    devices.execute_command(socket_command(
+        "heater",
+        PowerSocketCommand::TurnOnFor(hour),
+    ));
+    // a limit over the running load keeps it on
+    devices.execute_command(socket_command(
+        "heater",
+        PowerSocketCommand::SetPowerLimit(2500),
+    ));
+    assert!(heater(&devices).is_turned_on());
+    assert!(heater(&devices).get_timer().is_some());
+
+    devices.execute_command(socket_command(
+        "heater",
+        PowerSocketCommand::SetPowerLimit(1000),
+    ));
+    let heater = heater(&devices);
+    assert_eq!(heater.get_state(), PowerSocketState::NotPowered);
+    assert_eq!(heater.get_timer(), None);
+}
+
+#[test]
+fn direct_turn_on_skips_the_limit() {
+    let mut heater = PowerSocket::new("heater", 2000).with_power_limit(1500);
+    assert_eq!(
+        heater.try_turn_on().unwrap_err().kind,
+        FaultKind::CommandRejected
+    );
+    assert!(!heater.is_turned_on());
+    heater.turn_on();
+    assert_eq!(heater.get_state(), PowerSocketState::Powered(2000));
+
+    // sockets without a rated power draw the default load
+    let mut lamp = PowerSocket {
+        name: "lamp".into(),
+        state: PowerSocketState::NotPowered,
+        description: String::new(),
+        power_consumption: 0,
+        ..Default::default()
+    };
+    lamp.turn_on();
+    assert_eq!(lamp.get_power_consumption(), DEFAULT_SOCKET_POWER);
+}
+
+#[test]
+fn timed_on_sets_a_timer() {
+    let devices = devices();
+    let half_hour = Duration::from_secs(30 * 60);
+    devices.execute_command(socket_command(
+        "heater",
+        PowerSocketCommand::TurnOnFor(half_hour),
+    ));
+    let socket_state = heater(&devices);
+    assert!(socket_state.is_turned_on());
+    let timer = socket_state.get_timer().unwrap();
+    assert_eq!(timer.action, TimerAction::TurnOff);
+    let remaining = timer.at.duration_since(SystemTime::now()).unwrap();
+    assert!(remaining <= half_hour && remaining > half_hour - Duration::from_secs(60));
+    devices.execute_command(socket_command("heater", PowerSocketCommand::TurnOff));
+    assert_eq!(heater(&devices).get_timer(), None);
+
+    for duration in [
+        Duration::ZERO,
+        MAX_TIMER + Duration::from_secs(1),
+        Duration::from_millis(1500),
+    ] {
+        let result = devices.execute_command(socket_command(
+            "heater",
+            PowerSocketCommand::TurnOnFor(duration),
+        ));
+        assert_eq!(invalid_parameter(result), "duration");
+    }
+    assert!(!heater(&devices).is_turned_on());
+}
+
+#[test]
+fn descriptions_are_validated() {
+    let devices = devices();
+    let set = |text: &str| {
+        devices.execute_command(socket_command(
+            "heater",
+            PowerSocketCommand::SetDescription(text.into()),
+        ))
+    };
+    assert!(matches!(
+        set("oil radiator"),
+        ExecutionResult::PowerSocket(_)
+    ));
+    assert_eq!(heater(&devices).description, "oil radiator");
+    assert_eq!(
+        invalid_parameter(set(&"x".repeat(MAX_DESCRIPTION_LEN + 1))),
+        "text"
+    );
+    assert_eq!(invalid_parameter(set("two\nlines")), "text");
+    assert_eq!(heater(&devices).description, "oil radiator");
+}
+
+#[test]
+fn calibration_shifts_readings() {
+    let devices = devices();
+    let calibrate = |offset: f32| {
+        devices.execute_command(CommandData {
+            device_name: "hall_temp".into(),
+            data: DeviceCommand::Thermometer(ThermometerCommand::SetCalibration(offset)),
+        })
+    };
+    match calibrate(-1.5) {
+        ExecutionResult::Temperature(t) => assert_eq!(t, Temperature::Celsius(18.5)),
+        other => panic!("unexpected {:?}", other),
+    }
+    let info = devices.get_device_info("hall", "hall_temp").unwrap();
+    assert_eq!(info.temperature, Some(Temperature::Celsius(18.5)));
+    assert_eq!(invalid_parameter(calibrate(MAX_CALIBRATION + 1.)), "offset");
+    assert_eq!(invalid_parameter(calibrate(f32::NAN)), "offset");
+
+    // sockets do not take calibrations
+    let result = devices.execute_command(CommandData {
+        device_name: "heater".into(),
+        data: DeviceCommand::Thermometer(ThermometerCommand::SetCalibration(1.)),
+    });
+    assert!(matches!(
+        result,
+        ExecutionResult::Error(CustomError::DeviceFault(_))
+    ));
+}
+
+#[test]
+fn parameters_round_trip_on_the_wire() {
+    let commands = [
+        DeviceCommand::PowerSocket(PowerSocketCommand::SetPowerLimit(2000)),
+        DeviceCommand::PowerSocket(PowerSocketCommand::TurnOnFor(Duration::from_secs(1800))),
+        DeviceCommand::PowerSocket(PowerSocketCommand::SetDescription("desk lamp".into())),
+        DeviceCommand::Thermometer(ThermometerCommand::SetCalibration(-0.5)),
+        DeviceCommand::Thermometer(ThermometerCommand::SetReading(Temperature::Kelvin(290.5))),
+    ];
+    for command in &commands {
+        let bytes = command.to_bytes();
+        assert_eq!(&DeviceCommand::from_bytes(&bytes).unwrap(), command);
+        assert_eq!(
+            &command.to_string().parse::<DeviceCommand>().unwrap(),
+            command
+        );
+        let Command::Execute(data) = Command::from(("lamp".to_owned(), bytes)) else {
+            panic!("not a command");
+        };
+        assert_eq!(&data.data, command);
+    }
+    assert_eq!(commands[0].to_bytes(), [0x01, 0x03, 0x07, 0xd0]);
+    assert_eq!(
+        "socket.on_for 30m".parse::<DeviceCommand>().unwrap(),
+        commands[1]
+    );
+    assert_eq!(
+        "Socket.Description desk lamp"
+            .parse::<DeviceCommand>()
+            .unwrap(),
+        commands[2]
+    );
+}
+
+#[test]
+fn malformed_parameters_are_errors() {
+    for bytes in [
+        &[0x01, 0x03][..],
+        &[0x01, 0x03, 0x07],
+        &[0x02, 0x00, 0, 0, 0],
+        &[0x02, 0x02, 0x03, 0, 0, 0, 0],
+    ] {
+        assert!(
+            matches!(
+                DeviceCommand::from_bytes(bytes),
+                Err(CustomError::InvalidCommandCode(_))
+            ),
+            "{:?}",
+            bytes
+        );
+    }
+    assert!(matches!(
+        DeviceCommand::from_bytes(&[0x01, 0x03, 0x00, 0x00]),
+        Err(CustomError::InvalidParameter { .. })
+    ));
+    let below_zero = [&[0x02, 0x02, 0x02][..], &(-1f32).to_be_bytes()].concat();
+    assert!(matches!(
+        DeviceCommand::from_bytes(&below_zero),
+        Err(CustomError::InvalidParameter { .. })
+    ));
+    for text in [
+        "socket.limit",
+        "socket.limit lots",
+        "socket.on 5",
+        "socket.on_for 3d",
+        "thermometer.reading warm",
+        "thermometer.reading -1K",
+    ] {
+        assert!(
+            matches!(
+                text.parse::<DeviceCommand>(),
+                Err(CustomError::InvalidCommandCode(_))
+            ),
+            "{}",
+            text
+        );
+    }
+    assert!(matches!(
+        "socket.on_for 25h".parse::<DeviceCommand>(),
+        Err(CustomError::InvalidParameter { .. })
+    ));
+    assert!(matches!(
+        Command::from(("lamp".to_owned(), vec![0x01, 0x04])),
+        Command::Unknown
+    ));
+}
+
+#[test]
+fn settings_need_configure_permission() {
+    let limit = DeviceCommand::PowerSocket(PowerSocketCommand::SetPowerLimit(100));
+    assert_eq!(Action::of(&limit), Action::Configure);
+    let timer = DeviceCommand::PowerSocket(PowerSocketCommand::TurnOnFor(Duration::from_secs(60)));
+    assert_eq!(Action::of(&timer), Action::Control);
+
+    let api = RestApi::new(Arc::new(Mutex::new(SmartHouse::new())), devices());
+    let response = api.handle(&HttpRequest::new(
+        "POST",
+        "/devices/heater/commands",
+        b"socket.limit 9000",
+    ));
+    assert_eq!(response.status, 422);
+    let response = api.handle(&HttpRequest::new(
+        "POST",
+        "/devices/heater/commands",
+        b"socket.on_for 10m",
+    ));
+    assert_eq!(response.status, 200);
+}
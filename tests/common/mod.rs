@@ -0,0 +1,54 @@
+//! Fixtures shared by the integration tests, each test uses some of them.
+#![allow(dead_code)]
+
+use smart_house::*;
+
+pub fn socket(name: &str, watts: u16) -> SmartDevice {
+    SmartDevice::Socket(PowerSocket::new(name, watts))
+}
+
+pub fn thermometer(name: &str, temperature: Temperature) -> SmartDevice {
+    SmartDevice::Thermo(Thermometer::new(name, temperature))
+}
+
+pub fn socket_command(device: &str, command: PowerSocketCommand) -> CommandData {
+    CommandData {
+        device_name: device.into(),
+        data: DeviceCommand::PowerSocket(command),
+    }
+}
+
+pub fn turn_on(device: &str) -> CommandData {
+    socket_command(device, PowerSocketCommand::TurnOn)
+}
+
+/// A house with the empty `rooms` followed by the rooms of `devices`, each
+/// device is added to the house and to the device list.
+pub fn house_with<'a>(
+    rooms: &[&str],
+    devices: impl IntoIterator<Item = (&'a str, SmartDevice)>,
+) -> (SmartHouse, SmartDeviceList) {
+    let mut house = SmartHouse::new();
+    for room in rooms {
+        house.try_add_room(Room::with_name(room)).unwrap();
+    }
+    let mut list = SmartDeviceList::new();
+    for (room, device) in devices {
+        house.try_add_room(Room::with_name(room)).ok();
+        house.try_add_device(room, &device.get_name()).unwrap();
+        list.add_device(room, device).unwrap();
+    }
+    (house, list)
+}
+
+/// A hall with the 500 W sockets `heater` and `lamp` and the thermometer `hall_temp`.
+pub fn house() -> (SmartHouse, SmartDeviceList) {
+    house_with(
+        &[],
+        [
+            ("hall", socket("heater", 500)),
+            ("hall", socket("lamp", 500)),
+            ("hall", thermometer("hall_temp", Temperature::Celsius(20.))),
+        ],
+    )
+}
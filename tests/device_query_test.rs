@@ -0,0 +1,150 @@
+use smart_house::*;
+
+fn socket(name: &str, powered: bool) -> SmartDevice {
+    let mut socket = PowerSocket::new(name, 0).with_description("no desc");
+    if powered {
+        socket.turn_on();
+    }
+    SmartDevice::Socket(socket)
+}
+
+fn thermometer(name: &str, celsius: f32) -> SmartDevice {
+    SmartDevice::Thermo(Thermometer::new(name, Temperature::Celsius(celsius)))
+}
+
+fn create_list() -> SmartDeviceList {
+    let mut list = SmartDeviceList::new();
+    list.add_device("hall", socket("hall_socket1", true))
+        .unwrap();
+    list.add_device("hall", socket("hall_socket2", false))
+        .unwrap();
+    list.add_device("hall", thermometer("hall_therm", 21.))
+        .unwrap();
+    list.add_device("kitchen", socket("kettle", true)).unwrap();
+    list.add_device("kitchen", thermometer("fridge", 4.))
+        .unwrap();
+    list.add_device("server", thermometer("rack", 35.)).unwrap();
+    list
+}
+
+fn names(entries: &[DeviceEntry]) -> Vec<String> {
+    entries.iter().map(|e| e.device.get_name()).collect()
+}
+
+#[test]
+fn empty_query_returns_all_devices() {
+    let list = create_list();
+    assert_eq!(list.query(&DeviceQuery::new()).len(), 6);
+    assert_eq!(list.count(&DeviceQuery::new()), 6);
+}
+
+#[test]
+fn find_powered_sockets() {
+    let list = create_list();
+    let found = list.query(&DeviceQuery::new().powered().sort_by(SortKey::Name));
+    assert_eq!(names(&found), ["hall_socket1", "kettle"]);
+
+    let found = list.query(&DeviceQuery::new().not_powered());
+    assert_eq!(names(&found), ["hall_socket2"]);
+}
+
+#[test]
+fn find_thermometers_by_threshold() {
+    let list = create_list();
+    let found = list.query(
+        &DeviceQuery::new()
+            .temperature_above(20.)
+            .sort_by(SortKey::Reading),
+    );
+    assert_eq!(names(&found), ["hall_therm", "rack"]);
+
+    let found = list.query(&DeviceQuery::new().temperature_below(5.));
+    assert_eq!(names(&found), ["fridge"]);
+}
+
+#[test]
+fn find_by_name_pattern_and_rooms() {
+    let list = create_list();
+    let found = list.query(
+        &DeviceQuery::new()
+            .name_matches("HALL_*")
+            .sort_by(SortKey::Name),
+    );
+    assert_eq!(
+        names(&found),
+        ["hall_socket1", "hall_socket2", "hall_therm"]
+    );
+
+    let query = DeviceQuery::new()
+        .in_rooms(&["Kitchen", "server"])
+        .kind(DeviceKind::Thermometer)
+        .sort_by_desc(SortKey::Reading);
+    let found = list.query(&query);
+    assert_eq!(names(&found), ["rack", "fridge"]);
+    assert_eq!(found[0].room, "server");
+}
+
+#[test]
+fn pagination() {
+    let list = create_list();
+    let query = DeviceQuery::new().sort_by(SortKey::Name);
+    let first = list.query(&query.clone().page(0, 4));
+    let second = list.query(&query.clone().page(4, 4));
+    assert_eq!(
+        names(&first),
+        ["fridge", "hall_socket1", "hall_socket2", "hall_therm"]
+    );
+    assert_eq!(names(&second), ["kettle", "rack"]);
+    assert_eq!(list.count(&query.page(4, 4)), 6);
+}
+
+#[test]
+fn snapshot_is_detached_from_list() {
+    let list = create_list();
+    let snapshot = list.snapshot();
+    assert_eq!(snapshot.len(), 6);
+    assert_eq!(snapshot.rooms(), ["hall", "kitchen", "server"]);
+
+    list.with_device_mut("kitchen", "Kettle", |d| {
+        if let SmartDevice::Socket(s) = d {
+            s.turn_off();
+        }
+    })
+    .unwrap();
+
+    // the snapshot keeps the old state, the list has the new one
+    let state = snapshot.get("kitchen", "kettle").unwrap().get_state();
+    assert_eq!(state, format!("{:?}", PowerSocketState::Powered(220)));
+    let state = list
+        .snapshot()
+        .get("kitchen", "kettle")
+        .unwrap()
+        .get_state();
+    assert_eq!(state, format!("{:?}", PowerSocketState::NotPowered));
+}
+
+#[test]
+fn visit_devices() {
+    let list = create_list();
+    let mut count = 0;
+    list.for_each(|_, _| count += 1);
+    assert_eq!(count, 6);
+
+    let mut kinds = list.map(|room, d| format!("{}/{}", room, d.get_type()));
+    kinds.sort();
+    kinds.dedup();
+    assert_eq!(kinds.len(), 5);
+}
+
+#[test]
+fn scoped_access_to_missing_device() {
+    let list = create_list();
+    assert!(matches!(
+        list.with_device_mut("attic", "kettle", |_| ()),
+        Err(CustomError::RoomNotFound)
+    ));
+    assert!(matches!(
+        list.with_device_mut("kitchen", "toaster", |_| ()),
+        Err(CustomError::DeviceNotFound)
+    ));
+}
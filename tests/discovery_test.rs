@@ -0,0 +1,135 @@
+mod common;
+
+use common::socket;
+use smart_house::*;
+use std::net::{SocketAddr, UdpSocket};
+use std::time::{Duration, SystemTime};
+
+const WAIT: Duration = Duration::from_secs(5);
+
+fn thermometer(name: &str) -> SmartDevice {
+    SmartDevice::Thermo(Thermometer::new(name, Temperature::Celsius(20.)))
+}
+
+fn announcer(device: &SmartDevice, port: u16, target: SocketAddr) -> AnnouncerHandle {
+    let address: SocketAddr = ([127, 0, 0, 1], port).into();
+    DeviceAnnouncer::new(Announcement::new(device, address), target)
+        .unwrap()
+        .with_interval(Duration::from_millis(50))
+        .spawn()
+}
+
+#[test]
+fn devices_are_discovered_on_loopback() {
+    let discovery = Discovery::bind("127.0.0.1:0").unwrap().spawn();
+    let target = discovery.local_addr();
+    let _kettle = announcer(&socket("Kettle", 0), 7001, target);
+    let _sensor = announcer(&thermometer("hall_temp"), 7002, target);
+
+    let kettle = discovery
+        .wait_for("kettle", WAIT)
+        .expect("kettle not heard");
+    assert_eq!(kettle.announcement.name, "Kettle");
+    assert_eq!(kettle.announcement.kind, "SmartSocket");
+    assert_eq!(kettle.announcement.address.port(), 7001);
+    let capabilities = kettle.announcement.capabilities.unwrap();
+    assert!(capabilities.accepts(&DeviceCommand::PowerSocket(PowerSocketCommand::TurnOn)));
+    discovery
+        .wait_for("hall_temp", WAIT)
+        .expect("thermometer not heard");
+
+    let names: Vec<_> = discovery
+        .discovered()
+        .into_iter()
+        .map(|d| d.announcement.name)
+        .collect();
+    assert_eq!(names, ["hall_temp", "Kettle"]);
+}
+
+#[test]
+fn adopting_adds_the_device_to_a_room() {
+    let discovery = Discovery::bind("127.0.0.1:0").unwrap().spawn();
+    let _kettle = announcer(&socket("kettle", 0), 7003, discovery.local_addr());
+    discovery.wait_for("kettle", WAIT).unwrap();
+
+    let mut house = SmartHouse::new();
+    house.try_add_room(Room::with_name("kitchen")).unwrap();
+    let mut devices = SmartDeviceList::new();
+    assert_eq!(discovery.new_devices(&devices).len(), 1);
+
+    let device = discovery
+        .adopt("kettle", "kitchen", &mut house, &mut devices)
+        .unwrap();
+    assert!(matches!(&device, SmartDevice::Socket(s) if s.description.contains("127.0.0.1:7003")));
+    assert_eq!(house.get_devices("kitchen").unwrap(), ["kettle"]);
+    assert_eq!(devices.find("kettle").unwrap().room, "kitchen");
+    assert!(discovery.new_devices(&devices).is_empty());
+
+    // adopting twice or an unheard device fails
+    assert!(discovery
+        .adopt("kettle", "kitchen", &mut house, &mut devices)
+        .is_err());
+    assert!(matches!(
+        discovery.adopt("toaster", "kitchen", &mut house, &mut devices),
+        Err(CustomError::DeviceNotFound)
+    ));
+}
+
+#[test]
+fn announcements_are_heartbeats() {
+    let mut devices = SmartDeviceList::new();
+    devices
+        .add_device("hall", thermometer("hall_temp"))
+        .unwrap();
+    let discovery = Discovery::bind("127.0.0.1:0")
+        .unwrap()
+        .with_heartbeats(devices.clone());
+    let target = discovery.local_addr();
+    let device = thermometer("hall_temp");
+    DeviceAnnouncer::new(Announcement::new(&device, target), target)
+        .unwrap()
+        .announce()
+        .unwrap();
+
+    let heard = discovery.receive(WAIT).unwrap().unwrap();
+    assert_eq!(heard.name, "hall_temp");
+    let health = devices
+        .device_health("hall", "hall_temp", SystemTime::now())
+        .unwrap();
+    assert_eq!(health.status, HealthStatus::Healthy);
+}
+
+#[test]
+fn stray_datagrams_are_ignored() {
+    let discovery = Discovery::bind("127.0.0.1:0").unwrap();
+    let sender = UdpSocket::bind("127.0.0.1:0").unwrap();
+    sender
+        .send_to(b"not an announcement", discovery.local_addr())
+        .unwrap();
+    sender
+        .send_to(
+            br#"{"Announce":{"kind":"Toaster","name":"t","address":"127.0.0.1:1"}}"#,
+            discovery.local_addr(),
+        )
+        .unwrap();
+    assert_eq!(discovery.receive(WAIT).unwrap(), None);
+    // unknown kinds are listed but cannot be adopted
+    let toaster = discovery.receive(WAIT).unwrap().unwrap();
+    assert!(toaster.to_device().is_err());
+    assert_eq!(discovery.receive(Duration::from_millis(20)).unwrap(), None);
+}
+
+#[test]
+fn stopped_announcers_go_quiet() {
+    let discovery = Discovery::bind("127.0.0.1:0").unwrap();
+    let handle = announcer(&socket("lamp", 0), 7004, discovery.local_addr());
+    assert!(discovery.receive(WAIT).unwrap().is_some());
+    handle.stop();
+    // drain what was sent before the stop
+    while discovery
+        .receive(Duration::from_millis(200))
+        .unwrap()
+        .is_some()
+    {}
+    assert_eq!(discovery.receive(Duration::from_millis(200)).unwrap(), None);
+}
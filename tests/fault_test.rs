@@ -0,0 +1,239 @@
+mod common;
+
+use common::{house, socket, turn_on};
+use smart_house::*;
+use std::sync::{Arc, Mutex};
+
+fn fault(result: ExecutionResult) -> DeviceFault {
+    match result {
+        ExecutionResult::Error(CustomError::DeviceFault(fault)) => fault,
+        other => panic!("expected a device failure, got {:?}", other),
+    }
+}
+
+fn powered(devices: &SmartDeviceList) -> Vec<String> {
+    devices
+        .query(&DeviceQuery::new().powered())
+        .into_iter()
+        .map(|e| e.device.get_name())
+        .collect()
+}
+
+/// sends the command until it succeeds or fails permanently, returns the attempts made
+fn with_retries(devices: &SmartDeviceList, device: &str, attempts: u32) -> (u32, ExecutionResult) {
+    let mut tries = 0;
+    loop {
+        tries += 1;
+        let result = devices.execute_command(turn_on(device));
+        match &result {
+            ExecutionResult::Error(CustomError::DeviceFault(fault))
+                if fault.kind.is_transient() && tries < attempts => {}
+            _ => return (tries, result),
+        }
+    }
+}
+
+#[test]
+fn unreachable_devices_are_not_changed() {
+    let (_, devices) = house();
+    devices.faults().inject(
+        "hall",
+        "heater",
+        Fault::new(FaultKind::Unreachable).detail("no route to host"),
+    );
+    let fault = fault(devices.execute_command(turn_on("heater")));
+    assert_eq!(fault.device, "heater");
+    assert_eq!(fault.kind, FaultKind::Unreachable);
+    assert_eq!(
+        CustomError::DeviceFault(fault).to_string(),
+        "Error in device: heater unreachable: no route to host"
+    );
+    assert!(powered(&devices).is_empty());
+
+    // other devices keep working
+    assert!(matches!(
+        devices.execute_command(turn_on("lamp")),
+        ExecutionResult::PowerSocket(_)
+    ));
+    devices.faults().clear("hall", "heater");
+    devices.execute_command(turn_on("heater"));
+    assert_eq!(powered(&devices).len(), 2);
+    assert_eq!(devices.faults().triggered("hall", "heater"), 1);
+}
+
+#[test]
+fn timeouts_leave_the_outcome_unknown() {
+    let (_, devices) = house();
+    let events = devices.subscribe();
+    devices
+        .faults()
+        .inject("hall", "heater", Fault::new(FaultKind::Timeout).times(1));
+    let fault = fault(devices.execute_command(turn_on("heater")));
+    assert_eq!(fault.kind, FaultKind::Timeout);
+    assert!(fault.kind.is_transient());
+    // the socket got the command even though the caller saw an error
+    assert_eq!(powered(&devices), ["heater"]);
+    assert!(matches!(
+        events.try_recv(),
+        Ok(DeviceEvent::StateChanged { .. })
+    ));
+    assert!(!devices.faults().is_faulty("hall", "heater"));
+}
+
+#[test]
+fn transient_faults_recover_after_retries() {
+    let (_, devices) = house();
+    devices.faults().inject(
+        "hall",
+        "heater",
+        Fault::new(FaultKind::Unreachable).times(2),
+    );
+    let (tries, result) = with_retries(&devices, "heater", 5);
+    assert_eq!(tries, 3);
+    assert!(matches!(result, ExecutionResult::PowerSocket(_)));
+
+    // permanent faults are not retried
+    devices
+        .faults()
+        .inject("hall", "lamp", Fault::new(FaultKind::HardwareFault));
+    let (tries, result) = with_retries(&devices, "lamp", 5);
+    assert_eq!(tries, 1);
+    assert!(!fault(result).kind.is_transient());
+    assert_eq!(devices.faults().triggered("hall", "heater"), 2);
+}
+
+#[test]
+fn probabilistic_faults_are_reproducible() {
+    fn outcomes(seed: u64) -> Vec<bool> {
+        let (_, devices) = house();
+        devices.faults().seed(seed);
+        devices.faults().inject(
+            "hall",
+            "lamp",
+            Fault::new(FaultKind::Unreachable).probability(0.5),
+        );
+        (0..32)
+            .map(|_| {
+                matches!(
+                    devices.execute_command(turn_on("lamp")),
+                    ExecutionResult::Error(_)
+                )
+            })
+            .collect()
+    }
+    let run = outcomes(9);
+    assert_eq!(run, outcomes(9));
+    assert_ne!(run, outcomes(10));
+    let failed = run.iter().filter(|f| **f).count();
+    assert!((4..28).contains(&failed), "{}", failed);
+}
+
+#[test]
+fn thermometers_reject_socket_commands() {
+    let (_, devices) = house();
+    let fault = fault(devices.execute_command(turn_on("hall_temp")));
+    assert_eq!(fault.kind, FaultKind::CommandRejected);
+    assert!(!fault.kind.is_transient());
+    assert!(fault.detail.contains("socket.on"), "{}", fault.detail);
+}
+
+#[test]
+fn faults_reach_audit_and_http_clients() {
+    let (_, devices) = house();
+    let log = AuditLog::in_memory();
+    devices.set_audit_log(log.clone());
+    devices
+        .faults()
+        .inject("hall", "heater", Fault::new(FaultKind::Unreachable));
+    devices
+        .faults()
+        .inject("hall", "lamp", Fault::new(FaultKind::Timeout));
+
+    let api = RestApi::new(Arc::new(Mutex::new(SmartHouse::new())), devices.clone());
+    let send = |device: &str| {
+        api.handle(&HttpRequest::new(
+            "POST",
+            &format!("/devices/{}/commands", device),
+            br#"{"PowerSocket":"TurnOn"}"#,
+        ))
+        .status
+    };
+    assert_eq!(send("heater"), 503);
+    assert_eq!(send("lamp"), 504);
+    assert_eq!(send("hall_temp"), 422);
+
+    let records = log.query(&AuditQuery::new()).unwrap();
+    assert_eq!(records.len(), 3);
+    assert!(records.iter().all(|r| r.is_error()));
+    // the fault survives the trip through the log
+    assert_eq!(
+        fault(records[0].result.clone()).kind,
+        FaultKind::Unreachable
+    );
+}
+
+#[test]
+fn faults_are_kept_per_room() {
+    let (_, mut devices) = house();
+    let lamp = SmartDevice::Socket(PowerSocket::new("lamp", 500));
+    devices.add_device("kitchen", lamp).unwrap();
+    devices
+        .faults()
+        .inject("kitchen", "lamp", Fault::new(FaultKind::HardwareFault));
+    assert!(!devices.faults().is_faulty("hall", "lamp"));
+
+    let result = devices.execute_command_in("hall", turn_on("lamp"));
+    assert!(matches!(result, ExecutionResult::PowerSocket(_)));
+    let result = devices.execute_command_in("kitchen", turn_on("lamp"));
+    assert_eq!(fault(result).kind, FaultKind::HardwareFault);
+    assert_eq!(devices.faults().triggered("kitchen", "lamp"), 1);
+    assert_eq!(devices.faults().triggered("hall", "lamp"), 0);
+
+    devices.rename_room("kitchen", "pantry").unwrap();
+    assert!(devices.faults().is_faulty("pantry", "lamp"));
+    assert_eq!(devices.faults().triggered("pantry", "lamp"), 1);
+}
+
+#[test]
+fn faults_follow_renames_and_removals() {
+    let (_, mut devices) = house();
+    devices
+        .faults()
+        .inject("hall", "lamp", Fault::new(FaultKind::HardwareFault));
+    devices.rename_device("hall", "lamp", "desk lamp").unwrap();
+    assert!(devices.faults().is_faulty("hall", "desk lamp"));
+    assert!(!devices.faults().is_faulty("hall", "lamp"));
+    let result = devices.execute_command_in("hall", turn_on("desk lamp"));
+    assert_eq!(fault(result).kind, FaultKind::HardwareFault);
+
+    devices.remove_device("hall", "desk lamp").unwrap();
+    assert!(!devices.faults().is_faulty("hall", "desk lamp"));
+    assert_eq!(devices.faults().triggered("hall", "desk lamp"), 0);
+    let lamp = SmartDevice::Socket(PowerSocket::new("desk lamp", 60));
+    devices.add_device("hall", lamp).unwrap();
+    let result = devices.execute_command_in("hall", turn_on("desk lamp"));
+    assert!(matches!(result, ExecutionResult::PowerSocket(_)));
+
+    devices
+        .faults()
+        .inject("hall", "desk lamp", Fault::new(FaultKind::Unreachable));
+    devices.remove_room("hall").unwrap();
+    assert!(!devices.faults().is_faulty("hall", "desk lamp"));
+}
+
+#[test]
+fn removing_a_room_matches_its_name_like_lookups_do() {
+    let (_, mut devices) = house();
+    devices.add_device("Küche", socket("kettle", 500)).unwrap();
+    devices
+        .faults()
+        .inject("küche", "kettle", Fault::new(FaultKind::HardwareFault));
+    devices.set_policy("küche", "kettle", CommandPolicy::new().retries(3));
+
+    devices.remove_room("KÜCHE").unwrap();
+    devices.add_device("küche", socket("kettle", 500)).unwrap();
+    assert!(!devices.faults().is_faulty("küche", "kettle"));
+    assert_eq!(devices.policy("küche", "kettle").get_retries(), 0);
+    let result = devices.execute_command_in("Küche", turn_on("kettle"));
+    assert!(matches!(result, ExecutionResult::PowerSocket(_)));
+}
@@ -0,0 +1,218 @@
+mod common;
+
+use common::house;
+use smart_house::*;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+const MINUTE: Duration = Duration::from_secs(60);
+
+fn get_state(device: &str) -> CommandData {
+    CommandData {
+        device_name: device.into(),
+        data: DeviceCommand::PowerSocket(PowerSocketCommand::GetState),
+    }
+}
+
+#[test]
+fn devices_are_unknown_until_seen() {
+    let (_, devices) = house();
+    let health = devices
+        .device_health("hall", "lamp", SystemTime::now())
+        .unwrap();
+    assert_eq!(health.status, HealthStatus::Unknown);
+    assert_eq!(health.last_seen, None);
+    assert_eq!(health.heartbeat_interval, DEFAULT_HEARTBEAT_INTERVAL);
+
+    devices.execute_command(get_state("lamp"));
+    let now = SystemTime::now();
+    let health = devices.device_health("hall", "lamp", now).unwrap();
+    assert_eq!(health.status, HealthStatus::Healthy);
+    assert_eq!(health.commands, 1);
+    assert!(health.last_seen.unwrap() <= now);
+    assert!(health.uptime.is_some());
+    assert!(devices.device_health("hall", "fridge", now).is_err());
+}
+
+#[test]
+fn missed_heartbeats_make_devices_unresponsive() {
+    let (_, devices) = house();
+    devices.set_heartbeat_interval("hall", "hall_temp", 5 * MINUTE);
+    devices.heartbeat("hall", "hall_temp");
+    let seen = devices
+        .device_health("hall", "hall_temp", SystemTime::now())
+        .unwrap()
+        .last_seen
+        .unwrap();
+
+    let health = devices
+        .device_health("hall", "hall_temp", seen + 14 * MINUTE)
+        .unwrap();
+    assert_eq!(health.status, HealthStatus::Healthy);
+    assert_eq!(health.uptime, Some(14 * MINUTE));
+    // three intervals without a heartbeat
+    let health = devices
+        .device_health("hall", "hall_temp", seen + 16 * MINUTE)
+        .unwrap();
+    assert_eq!(health.status, HealthStatus::Unresponsive);
+    assert_eq!(health.uptime, None);
+    assert_eq!(health.last_seen, Some(seen));
+
+    // other devices use the default interval
+    devices.heartbeat("hall", "lamp");
+    let health = devices
+        .device_health("hall", "lamp", seen + 4 * MINUTE)
+        .unwrap();
+    assert_eq!(health.status, HealthStatus::Unresponsive);
+    devices.set_default_heartbeat_interval(10 * MINUTE);
+    let health = devices
+        .device_health("hall", "lamp", seen + 4 * MINUTE)
+        .unwrap();
+    assert_eq!(health.status, HealthStatus::Healthy);
+}
+
+#[test]
+fn errors_are_counted() {
+    let (_, devices) = house();
+    devices.execute_command(get_state("heater"));
+    devices.faults().inject(
+        "hall",
+        "heater",
+        Fault::new(FaultKind::HardwareFault).times(2),
+    );
+    devices.execute_command(get_state("heater"));
+    devices.execute_command(get_state("heater"));
+
+    let health = devices
+        .device_health("hall", "heater", SystemTime::now())
+        .unwrap();
+    assert_eq!(health.status, HealthStatus::Degraded);
+    assert_eq!((health.errors, health.commands), (2, 3));
+    assert_eq!(health.consecutive_errors, 2);
+    assert!(health.last_error.unwrap().contains("hardware fault"));
+    assert_eq!(health.uptime, None);
+
+    // answering again restarts the uptime, the totals stay
+    devices.execute_command(get_state("heater"));
+    let health = devices
+        .device_health("hall", "heater", SystemTime::now())
+        .unwrap();
+    assert_eq!(health.status, HealthStatus::Healthy);
+    assert_eq!((health.errors, health.commands), (2, 4));
+    assert_eq!(health.consecutive_errors, 0);
+
+    // a rejected command still proves the device is alive
+    devices.execute_command(get_state("hall_temp"));
+    let health = devices
+        .device_health("hall", "hall_temp", SystemTime::now())
+        .unwrap();
+    assert_eq!(health.status, HealthStatus::Healthy);
+    assert_eq!(health.errors, 0);
+}
+
+#[test]
+fn offline_devices_are_unresponsive() {
+    let (_, devices) = house();
+    devices.set_policy("hall", "lamp", CommandPolicy::new().breaker(1, 10 * MINUTE));
+    devices.heartbeat("hall", "lamp");
+    devices
+        .faults()
+        .inject("hall", "lamp", Fault::new(FaultKind::Unreachable));
+    devices.execute_command(get_state("lamp"));
+    let health = devices
+        .device_health("hall", "lamp", SystemTime::now())
+        .unwrap();
+    assert_eq!(health.status, HealthStatus::Unresponsive);
+}
+
+#[test]
+fn health_is_kept_per_room() {
+    let (mut house, mut devices) = house();
+    house.try_add_room(Room::with_name("kitchen")).unwrap();
+    house.try_add_device("kitchen", "lamp").unwrap();
+    let lamp = devices.resolve(Some("hall"), "lamp").unwrap().device;
+    devices.add_device("kitchen", lamp).unwrap();
+
+    devices.execute_command_in("kitchen", get_state("lamp"));
+    let now = SystemTime::now();
+    let status = |room: &str| devices.device_health(room, "lamp", now).unwrap().status;
+    assert_eq!(status("kitchen"), HealthStatus::Healthy);
+    assert_eq!(status("hall"), HealthStatus::Unknown);
+    let report = house.get_health_report(&devices, now);
+    assert!(report.contains("room: kitchen, device: lamp, status: healthy"));
+    assert!(report.contains("room: hall, device: lamp, status: unknown"));
+
+    devices.rename_room("kitchen", "pantry").unwrap();
+    let health = devices.device_health("pantry", "lamp", now).unwrap();
+    assert_eq!(health.status, HealthStatus::Healthy);
+}
+
+#[test]
+fn health_appears_in_reports() {
+    let (house, devices) = house();
+    devices.execute_command(get_state("heater"));
+    devices.execute_command(get_state("lamp"));
+    devices
+        .faults()
+        .inject("hall", "lamp", Fault::new(FaultKind::Timeout));
+    devices.execute_command(get_state("lamp"));
+    let now = SystemTime::now();
+
+    let report = house.get_report(&devices);
+    assert!(report.contains("health: Healthy"), "{}", report);
+    assert!(report.contains("health: Degraded"), "{}", report);
+    assert!(report.contains("health: Unknown"), "{}", report);
+
+    let report = house.get_health_report(&devices, now);
+    let lines: Vec<&str> = report.lines().collect();
+    assert_eq!(lines.len(), 3);
+    assert!(
+        lines[0].starts_with("room: hall, device: hall_temp, status: unknown, last seen: never")
+    );
+    assert!(lines[1].starts_with("room: hall, device: heater, status: healthy, last seen: 0s ago"));
+    assert!(lines[2].ends_with("errors: 1/2"), "{}", lines[2]);
+
+    let report = devices.health_report(now);
+    let names: Vec<_> = report.iter().map(|h| h.device.as_str()).collect();
+    assert_eq!(names, ["hall_temp", "heater", "lamp"]);
+}
+
+#[test]
+fn health_over_http() {
+    let (house, devices) = house();
+    devices.execute_command(get_state("lamp"));
+    let api = RestApi::new(Arc::new(Mutex::new(house)), devices);
+    let response = api.handle(&HttpRequest::new("GET", "/health", b""));
+    assert_eq!(response.status, 200);
+    let report: Vec<DeviceHealth> = serde_json::from_str(&response.body).unwrap();
+    assert_eq!(report.len(), 3);
+    assert_eq!(report[2].status, HealthStatus::Healthy);
+
+    let response = api.handle(&HttpRequest::new("GET", "/devices/lamp", b""));
+    let info: DeviceInfo = serde_json::from_str(&response.body).unwrap();
+    assert_eq!(info.health, HealthStatus::Healthy);
+    assert!(info.last_seen.is_some());
+}
+
+#[test]
+fn health_follows_renames_and_removals() {
+    let (_, mut devices) = house();
+    devices.execute_command(get_state("lamp"));
+    let now = SystemTime::now();
+    devices.rename_device("hall", "lamp", "Desk lamp").unwrap();
+    let health = devices.device_health("hall", "desk lamp", now).unwrap();
+    assert_eq!(health.status, HealthStatus::Healthy);
+
+    devices.remove_device("hall", "desk lamp").unwrap();
+    let lamp = SmartDevice::Socket(PowerSocket::new("desk lamp", 60));
+    devices.add_device("hall", lamp).unwrap();
+    let health = devices.device_health("hall", "desk lamp", now).unwrap();
+    assert_eq!((health.status, health.commands), (HealthStatus::Unknown, 0));
+
+    devices.execute_command(get_state("heater"));
+    devices.remove_room("hall").unwrap();
+    let heater = SmartDevice::Socket(PowerSocket::new("heater", 500));
+    devices.add_device("hall", heater).unwrap();
+    let health = devices.device_health("hall", "heater", now).unwrap();
+    assert_eq!(health.status, HealthStatus::Unknown);
+}
@@ -0,0 +1,153 @@
+mod common;
+
+use common::{house_with, socket, turn_on};
+use smart_house::*;
+
+/// Adding the toaster is the only recorded change.
+fn house() -> (SmartHouse, SmartDeviceList, ConfigHistory) {
+    let (mut house, mut devices) = house_with(
+        &["hall", "kitchen", "office"],
+        [("kitchen", socket("kettle", 2000))],
+    );
+    let mut history = ConfigHistory::default();
+    let change = ConfigChange::AddDevice {
+        room: "kitchen".into(),
+        device: socket("toaster", 800),
+    };
+    history.apply(change, &mut house, &mut devices).unwrap();
+    (house, devices, history)
+}
+
+fn device_names(house: &SmartHouse, room: &str) -> Vec<String> {
+    let mut names: Vec<String> = house
+        .get_devices(room)
+        .unwrap()
+        .into_iter()
+        .map(String::from)
+        .collect();
+    names.sort();
+    names
+}
+
+#[test]
+fn undo_restores_removed_room_with_devices() {
+    let (mut house, mut devices, mut history) = house();
+    devices.execute_command(turn_on("kettle"));
+    let remove = ConfigChange::RemoveRoom {
+        room: "kitchen".into(),
+    };
+    history.apply(remove, &mut house, &mut devices).unwrap();
+    assert_eq!(house.get_rooms(), ["hall", "office"]);
+    assert!(devices.find("kettle").is_none());
+
+    let undone = history.undo(&mut house, &mut devices).unwrap();
+    assert_eq!(undone.to_string(), "add room kitchen with 2 devices");
+    assert_eq!(house.get_rooms(), ["hall", "kitchen", "office"]);
+    assert_eq!(device_names(&house, "kitchen"), ["kettle", "toaster"]);
+    // device state is restored as well
+    assert_eq!(devices.query(&DeviceQuery::new().powered()).len(), 1);
+
+    let redone = history.redo(&mut house, &mut devices).unwrap();
+    assert_eq!(redone.to_string(), "remove room kitchen");
+    assert_eq!(house.get_rooms(), ["hall", "office"]);
+    assert!(!history.can_redo());
+}
+
+#[test]
+fn undo_and_redo_renames() {
+    let (mut house, mut devices, mut history) = house();
+    let rename = ConfigChange::RenameRoom {
+        room: "kitchen".into(),
+        to: "Galley".into(),
+    };
+    history.apply(rename, &mut house, &mut devices).unwrap();
+    let rename = ConfigChange::RenameDevice {
+        room: "Galley".into(),
+        device: "kettle".into(),
+        to: "boiler".into(),
+    };
+    history.apply(rename, &mut house, &mut devices).unwrap();
+    assert_eq!(house.get_rooms(), ["hall", "Galley", "office"]);
+    assert_eq!(device_names(&house, "Galley"), ["boiler", "toaster"]);
+    assert_eq!(devices.find("boiler").unwrap().room, "galley");
+
+    history.undo(&mut house, &mut devices).unwrap();
+    history.undo(&mut house, &mut devices).unwrap();
+    assert_eq!(house.get_rooms(), ["hall", "kitchen", "office"]);
+    assert_eq!(device_names(&house, "kitchen"), ["kettle", "toaster"]);
+    assert_eq!(devices.find("kettle").unwrap().room, "kitchen");
+
+    history.redo(&mut house, &mut devices).unwrap();
+    assert_eq!(house.get_rooms(), ["hall", "Galley", "office"]);
+}
+
+#[test]
+fn invalid_changes_are_not_recorded() {
+    let (mut house, mut devices, mut history) = house();
+    let duplicate = ConfigChange::AddDevice {
+        room: "kitchen".into(),
+        device: socket("Kettle", 100),
+    };
+    assert!(history.apply(duplicate, &mut house, &mut devices).is_err());
+    let rename = ConfigChange::RenameRoom {
+        room: "hall".into(),
+        to: "Kitchen".into(),
+    };
+    assert!(history.apply(rename, &mut house, &mut devices).is_err());
+    assert_eq!(house.get_rooms(), ["hall", "kitchen", "office"]);
+
+    // the last recorded change is still adding the toaster
+    history.undo(&mut house, &mut devices).unwrap();
+    assert_eq!(device_names(&house, "kitchen"), ["kettle"]);
+    assert!(devices.find("toaster").is_none());
+}
+
+#[test]
+fn new_changes_clear_redo() {
+    let (mut house, mut devices, mut history) = house();
+    history.undo(&mut house, &mut devices).unwrap();
+    assert!(history.can_redo());
+    history
+        .apply(ConfigChange::add_room("attic"), &mut house, &mut devices)
+        .unwrap();
+    assert!(!history.can_redo());
+    assert!(matches!(
+        history.redo(&mut house, &mut devices),
+        Err(CustomError::HistoryError(_))
+    ));
+}
+
+#[test]
+fn history_is_bounded() {
+    let mut house = SmartHouse::new();
+    let mut devices = SmartDeviceList::new();
+    let mut history = ConfigHistory::new(2);
+    for room in ["a", "b", "c"] {
+        history
+            .apply(ConfigChange::add_room(room), &mut house, &mut devices)
+            .unwrap();
+    }
+    history.undo(&mut house, &mut devices).unwrap();
+    history.undo(&mut house, &mut devices).unwrap();
+    assert!(history.undo(&mut house, &mut devices).is_err());
+    assert_eq!(house.get_rooms(), ["a"]);
+}
+
+#[test]
+fn failed_undo_keeps_the_change() {
+    let (mut house, mut devices, mut history) = house();
+    // changed behind the history's back
+    house.try_remove_device("kitchen", "toaster").unwrap();
+    assert!(history.undo(&mut house, &mut devices).is_err());
+    assert!(history.can_undo());
+    house.try_add_device("kitchen", "toaster").unwrap();
+    history.undo(&mut house, &mut devices).unwrap();
+    assert_eq!(device_names(&house, "kitchen"), ["kettle"]);
+}
+
+#[test]
+fn removing_rooms_keeps_order() {
+    let (mut house, _, _) = house();
+    house.try_remove_room("hall").unwrap();
+    assert_eq!(house.get_rooms(), ["kitchen", "office"]);
+}
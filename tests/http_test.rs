@@ -0,0 +1,119 @@
+use smart_house::*;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::sync::{Arc, Mutex};
+
+fn start_server() -> (String, SmartDeviceList) {
+    let mut house = SmartHouse::new();
+    house.try_add_room(Room::with_name("hall")).unwrap();
+    house.try_add_device("hall", "socket1").unwrap();
+    house.try_add_device("hall", "therm1").unwrap();
+    let mut devices = SmartDeviceList::new();
+    devices
+        .add_device("hall", SmartDevice::Socket(PowerSocket::new("socket1", 0)))
+        .unwrap();
+    devices
+        .add_device(
+            "hall",
+            SmartDevice::Thermo(Thermometer::new("therm1", Temperature::Celsius(21.5))),
+        )
+        .unwrap();
+    let api = RestApi::new(Arc::new(Mutex::new(house)), devices.clone());
+    let server = HttpServer::bind("127.0.0.1:0", api).unwrap();
+    let addr = server.local_addr().unwrap().to_string();
+    server.spawn();
+    (addr, devices)
+}
+
+fn request(addr: &str, method: &str, path: &str, body: &str) -> (u16, String) {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    write!(
+        stream,
+        "{} {} HTTP/1.1\r\nHost: {}\r\nContent-Length: {}\r\n\r\n{}",
+        method,
+        path,
+        addr,
+        body.len(),
+        body
+    )
+    .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    let status = response[9..12].parse().unwrap();
+    let body = response.split_once("\r\n\r\n").unwrap().1.to_owned();
+    (status, body)
+}
+
+#[test]
+fn inspect_house() {
+    let (addr, _) = start_server();
+    assert_eq!(
+        request(&addr, "GET", "/rooms", ""),
+        (200, r#"["hall"]"#.into())
+    );
+
+    let (status, body) = request(&addr, "GET", "/rooms/Hall/devices", "");
+    assert_eq!(status, 200);
+    let infos: Vec<DeviceInfo> = serde_json::from_str(&body).unwrap();
+    assert_eq!(infos.len(), 2);
+    assert_eq!(infos[1].temperature, Some(Temperature::Celsius(21.5)));
+
+    let (status, body) = request(&addr, "GET", "/devices/socket1", "");
+    assert_eq!(status, 200);
+    let info: DeviceInfo = serde_json::from_str(&body).unwrap();
+    assert_eq!(info.kind, "SmartSocket");
+
+    assert_eq!(request(&addr, "GET", "/devices/socket9", "").0, 404);
+    assert_eq!(request(&addr, "GET", "/rooms/attic/devices", "").0, 404);
+    assert_eq!(request(&addr, "GET", "/nothing", "").0, 404);
+    assert_eq!(request(&addr, "PUT", "/rooms", "").0, 405);
+}
+
+#[test]
+fn send_commands() {
+    let (addr, devices) = start_server();
+    let (status, body) = request(
+        &addr,
+        "POST",
+        "/devices/socket1/commands",
+        r#"{"PowerSocket":"TurnOn"}"#,
+    );
+    assert_eq!(status, 200);
+    assert_eq!(body, r#"{"PowerSocket":{"Powered":220}}"#);
+    assert_eq!(devices.query(&DeviceQuery::new().powered()).len(), 1);
+
+    let (status, _) = request(&addr, "POST", "/devices/socket1/commands", "on");
+    assert_eq!(status, 400);
+}
+
+#[test]
+fn create_and_delete() {
+    let (addr, devices) = start_server();
+    assert_eq!(
+        request(&addr, "POST", "/rooms", r#"{"name":"living room"}"#).0,
+        201
+    );
+    assert_eq!(
+        request(&addr, "POST", "/rooms", r#"{"name":"Hall"}"#).0,
+        409
+    );
+
+    let lamp = r#"{"Socket":{"name":"lamp","state":"NotPowered","description":"","power_consumption":60}}"#;
+    let (status, body) = request(&addr, "POST", "/rooms/living%20room/devices", lamp);
+    assert_eq!(status, 201);
+    assert!(body.contains("\"name\":\"lamp\""));
+    assert_eq!(
+        request(&addr, "POST", "/rooms/living%20room/devices", lamp).0,
+        409
+    );
+    assert!(devices.find("lamp").is_some());
+
+    assert_eq!(
+        request(&addr, "DELETE", "/rooms/living%20room/devices/lamp", "").0,
+        204
+    );
+    assert!(devices.find("lamp").is_none());
+    assert_eq!(request(&addr, "DELETE", "/rooms/hall", "").0, 204);
+    assert_eq!(request(&addr, "GET", "/rooms", "").1, r#"["living room"]"#);
+    assert!(devices.find("socket1").is_none());
+}
@@ -0,0 +1,329 @@
+mod common;
+
+use common::{house_with, socket, thermometer, turn_on};
+use smart_house::*;
+use std::time::Duration;
+
+fn devices() -> SmartDeviceList {
+    let layout = [
+        ("Hall", socket("lamp", 60)),
+        ("kitchen", thermometer("fridge", Temperature::Celsius(4.5))),
+    ];
+    house_with(&[], layout).1
+}
+
+fn start(
+    bridge: impl FnOnce(SmartDeviceList) -> MqttBridge,
+) -> (MqttBrokerHandle, SmartDeviceList) {
+    let broker = MqttBroker::bind("127.0.0.1:0").unwrap().spawn().unwrap();
+    let devices = devices();
+    bridge(devices.clone()).spawn(broker.local_addr()).unwrap();
+    (broker, devices)
+}
+
+fn client(broker: &MqttBrokerHandle, filter: &str) -> MqttClient {
+    let mut client = MqttClient::connect(broker.local_addr(), "test").unwrap();
+    client.set_timeout(Some(Duration::from_secs(5))).unwrap();
+    client.subscribe(filter).unwrap();
+    client
+}
+
+fn retained(broker: &MqttBrokerHandle, topic: &str) -> Option<String> {
+    broker
+        .retained(topic)
+        .map(|payload| String::from_utf8(payload).unwrap())
+}
+
+fn next(client: &mut MqttClient) -> (String, String) {
+    let message = client.receive().unwrap();
+    let payload = message.payload_str();
+    (message.topic, payload)
+}
+
+#[test]
+fn publishes_current_states_as_retained() {
+    let (broker, _) = start(MqttBridge::new);
+    assert_eq!(retained(&broker, "house/hall/lamp/state").unwrap(), "off");
+    assert_eq!(
+        retained(&broker, "house/kitchen/fridge/state").unwrap(),
+        "4.5°C"
+    );
+
+    // late subscribers get the retained states
+    let mut client = client(&broker, "house/+/+/state");
+    assert_eq!(
+        next(&mut client),
+        ("house/hall/lamp/state".into(), "off".into())
+    );
+    assert_eq!(
+        next(&mut client),
+        ("house/kitchen/fridge/state".into(), "4.5°C".into())
+    );
+}
+
+#[test]
+fn set_topics_control_sockets() {
+    let (broker, devices) = start(MqttBridge::new);
+    let mut client = client(&broker, "house/hall/lamp/state");
+    assert_eq!(next(&mut client).1, "off");
+
+    client.publish("house/hall/lamp/set", b"ON", false).unwrap();
+    assert_eq!(
+        next(&mut client),
+        ("house/hall/lamp/state".into(), "on".into())
+    );
+    let lamp = devices.find("lamp").unwrap().device;
+    assert!(matches!(
+        lamp,
+        SmartDevice::Socket(PowerSocket {
+            state: PowerSocketState::Powered(60),
+            ..
+        })
+    ));
+
+    client
+        .publish(
+            "house/hall/lamp/set",
+            br#"{"PowerSocket":"TurnOff"}"#,
+            false,
+        )
+        .unwrap();
+    assert_eq!(next(&mut client).1, "off");
+
+    // asking for the state republishes it
+    client
+        .publish("house/hall/lamp/set", b"state", false)
+        .unwrap();
+    assert_eq!(next(&mut client).1, "off");
+}
+
+#[test]
+fn set_topics_pick_the_device_of_their_room() {
+    let (broker, mut devices) = start(MqttBridge::new);
+    let lamp = devices.find("lamp").unwrap().device;
+    devices.add_device("kitchen", lamp).unwrap();
+    let mut client = client(&broker, "house/kitchen/lamp/state");
+
+    client
+        .publish("house/kitchen/lamp/set", b"on", false)
+        .unwrap();
+    assert_eq!(
+        next(&mut client),
+        ("house/kitchen/lamp/state".into(), "on".into())
+    );
+    let powered = devices.query(&DeviceQuery::new().powered());
+    assert_eq!(powered.len(), 1);
+    assert_eq!(powered[0].room, "kitchen");
+}
+
+#[test]
+fn sensor_readings_update_thermometers() {
+    let path =
+        std::env::temp_dir().join(format!("smart_house_readings_{}.log", std::process::id()));
+    std::fs::remove_file(&path).ok();
+    let (broker, devices) = start(MqttBridge::new);
+    devices.set_audit_log(AuditLog::open(&path).unwrap());
+    let mut client = client(&broker, "house/kitchen/fridge/state");
+    assert_eq!(next(&mut client).1, "4.5°C");
+
+    client
+        .publish("house/kitchen/fridge/set", b"40.1 F", false)
+        .unwrap();
+    assert_eq!(next(&mut client).1, "40.1°F");
+    match devices.find("fridge").unwrap().device {
+        SmartDevice::Thermo(t) => assert_eq!(t.get_temperature(), Temperature::Fahrenheit(40.1)),
+        other => panic!("unexpected device {:?}", other),
+    }
+    // readings are commands like any other, and audited as such
+    let records = AuditLog::open(&path)
+        .unwrap()
+        .query(&AuditQuery::new().device("fridge"))
+        .unwrap();
+    assert_eq!(
+        records[0].command,
+        DeviceCommand::Thermometer(ThermometerCommand::SetReading(Temperature::Fahrenheit(
+            40.1
+        )))
+    );
+    std::fs::remove_file(&path).ok();
+}
+
+#[test]
+fn local_changes_are_published() {
+    let (broker, devices) = start(MqttBridge::new);
+    let mut client = client(&broker, "house/hall/+/state");
+    assert_eq!(next(&mut client).1, "off");
+
+    devices.execute_command(turn_on("lamp"));
+    assert_eq!(next(&mut client).1, "on");
+    assert_eq!(retained(&broker, "house/hall/lamp/state").unwrap(), "on");
+}
+
+#[test]
+fn failures_are_reported_on_error_topics() {
+    let (broker, _) = start(MqttBridge::new);
+    let mut client = client(&broker, "house/+/+/error");
+
+    client
+        .publish("house/hall/lamp/set", b"blink", false)
+        .unwrap();
+    let (topic, payload) = next(&mut client);
+    assert_eq!(topic, "house/hall/lamp/error");
+    assert!(payload.contains("blink"), "{}", payload);
+
+    client
+        .publish("house/kitchen/fridge/set", b"cold", false)
+        .unwrap();
+    assert_eq!(next(&mut client).0, "house/kitchen/fridge/error");
+
+    // the device exists, but in another room
+    client
+        .publish("house/hall/fridge/set", b"5C", false)
+        .unwrap();
+    assert_eq!(
+        next(&mut client),
+        (
+            "house/hall/fridge/error".into(),
+            CustomError::DeviceNotFound.to_string()
+        )
+    );
+}
+
+#[test]
+fn custom_prefix() {
+    let (broker, _) = start(|devices| MqttBridge::new(devices).with_prefix("home/"));
+    assert_eq!(retained(&broker, "home/hall/lamp/state").unwrap(), "off");
+    assert_eq!(retained(&broker, "house/hall/lamp/state"), None);
+
+    let mut client = client(&broker, "home/hall/lamp/state");
+    assert_eq!(next(&mut client).1, "off");
+    client.publish("home/hall/lamp/set", b"on", false).unwrap();
+    assert_eq!(next(&mut client).1, "on");
+}
+
+/// alice may control the hall only
+/// alice may use the hall, guests may only read
+fn authenticator() -> Authenticator {
+    Authenticator::new()
+        .with_user(
+            "alice",
+            "secret",
+            vec![Permission {
+                room: "hall".into(),
+                actions: vec![Action::Read, Action::Control],
+            }],
+        )
+        .with_user(
+            "guest",
+            "guest",
+            vec![Permission {
+                room: ANY_ROOM.into(),
+                actions: vec![Action::Read],
+            }],
+        )
+        .with_user(
+            "root",
+            "root",
+            vec![Permission {
+                room: ANY_ROOM.into(),
+                actions: vec![Action::Read, Action::Control, Action::Configure],
+            }],
+        )
+}
+
+#[test]
+fn broker_requires_credentials() {
+    let broker = MqttBroker::bind("127.0.0.1:0")
+        .unwrap()
+        .with_auth(authenticator())
+        .spawn()
+        .unwrap();
+    let addr = broker.local_addr();
+    assert!(MqttClient::connect(addr, "anonymous").is_err());
+    assert!(MqttClient::connect_as(addr, "mallory", "alice", "guess").is_err());
+    assert!(MqttClient::connect_as(addr, "test", "alice", "secret").is_ok());
+
+    assert!(MqttBridge::new(devices()).spawn(addr).is_err());
+    MqttBridge::new(devices())
+        .with_credentials("alice", "secret")
+        .spawn(addr)
+        .unwrap();
+    assert_eq!(retained(&broker, "house/hall/lamp/state").unwrap(), "off");
+}
+
+#[test]
+fn bridge_commands_run_as_its_user() {
+    let path = std::env::temp_dir().join(format!("smart_house_mqtt_{}.log", std::process::id()));
+    std::fs::remove_file(&path).ok();
+    let broker = MqttBroker::bind("127.0.0.1:0").unwrap().spawn().unwrap();
+    let devices = devices();
+    devices.set_audit_log(AuditLog::open(&path).unwrap());
+    let bridge = MqttBridge::new(devices.clone()).with_auth(authenticator());
+    assert!(matches!(
+        bridge.spawn(broker.local_addr()),
+        Err(CustomError::AuthenticationFailed(_))
+    ));
+    MqttBridge::new(devices.clone())
+        .with_auth(authenticator())
+        .with_credentials("alice", "secret")
+        .spawn(broker.local_addr())
+        .unwrap();
+
+    let mut client = client(&broker, "house/+/+/+");
+    client.publish("house/hall/lamp/set", b"on", false).unwrap();
+    client
+        .publish("house/kitchen/fridge/set", b"6C", false)
+        .unwrap();
+    let mut seen = Vec::new();
+    while seen.len() < 2 {
+        let (topic, payload) = next(&mut client);
+        if topic == "house/hall/lamp/state" && payload == "on"
+            || topic == "house/kitchen/fridge/error"
+        {
+            seen.push(topic);
+        }
+    }
+    let records = AuditLog::open(&path)
+        .unwrap()
+        .query(&AuditQuery::new().principal("alice"))
+        .unwrap();
+    assert_eq!(records.len(), 1);
+    assert_eq!(records[0].device, "lamp");
+    std::fs::remove_file(&path).ok();
+}
+
+#[test]
+fn broker_drops_commands_the_publisher_may_not_send() {
+    let broker = MqttBroker::bind("127.0.0.1:0")
+        .unwrap()
+        .with_auth(authenticator())
+        .spawn()
+        .unwrap();
+    let devices = devices();
+    MqttBridge::new(devices.clone())
+        .with_credentials("alice", "secret")
+        .spawn(broker.local_addr())
+        .unwrap();
+    let mut guest = MqttClient::connect_as(broker.local_addr(), "guest", "guest", "guest").unwrap();
+    guest.set_timeout(Some(Duration::from_secs(5))).unwrap();
+    guest.subscribe("house/hall/lamp/state").unwrap();
+    assert_eq!(next(&mut guest).1, "off");
+
+    guest.publish("house/hall/lamp/set", b"on", false).unwrap();
+    // reading is allowed, and answered after the refused command
+    guest
+        .publish("house/hall/lamp/set", b"state", false)
+        .unwrap();
+    assert_eq!(next(&mut guest).1, "off");
+    assert!(devices.query(&DeviceQuery::new().powered()).is_empty());
+}
+
+#[test]
+fn bridge_refuses_users_of_every_room() {
+    let broker = MqttBroker::bind("127.0.0.1:0").unwrap().spawn().unwrap();
+    let result = MqttBridge::new(devices())
+        .with_auth(authenticator())
+        .with_credentials("root", "root")
+        .spawn(broker.local_addr());
+    assert!(matches!(result, Err(CustomError::PermissionDenied(_))));
+}
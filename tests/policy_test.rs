@@ -0,0 +1,328 @@
+mod common;
+
+use common::{house, turn_on};
+use smart_house::*;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+const MS: Duration = Duration::from_millis(1);
+
+fn fault_kind(result: &ExecutionResult) -> Option<FaultKind> {
+    match result {
+        ExecutionResult::Error(CustomError::DeviceFault(fault)) => Some(fault.kind),
+        _ => None,
+    }
+}
+
+#[test]
+fn backoff_grows_exponentially_up_to_the_limit() {
+    let policy = CommandPolicy::new().backoff(100 * MS, 500 * MS);
+    let delays: Vec<_> = (0..5).map(|n| policy.backoff_delay(n)).collect();
+    assert_eq!(delays, [100 * MS, 200 * MS, 400 * MS, 500 * MS, 500 * MS]);
+    assert_eq!(policy.backoff_delay(u32::MAX), 500 * MS);
+}
+
+#[test]
+fn transient_failures_are_retried() {
+    let (_, devices) = house();
+    devices.set_policy(
+        "hall",
+        "heater",
+        CommandPolicy::new().retries(3).backoff(10 * MS, 100 * MS),
+    );
+    devices.faults().inject(
+        "hall",
+        "heater",
+        Fault::new(FaultKind::Unreachable).times(2),
+    );
+
+    let started = Instant::now();
+    let result = devices.execute_command(turn_on("heater"));
+    assert!(matches!(result, ExecutionResult::PowerSocket(_)));
+    // 10 ms and 20 ms between the attempts
+    assert!(started.elapsed() >= 30 * MS);
+    assert_eq!(devices.faults().triggered("hall", "heater"), 2);
+    // it worked, but not at the first attempt
+    assert_eq!(
+        devices.availability("hall", "heater"),
+        Availability::Degraded
+    );
+    devices.execute_command(turn_on("heater"));
+    assert_eq!(devices.availability("hall", "heater"), Availability::Online);
+
+    // no retries without a policy, none for permanent faults
+    devices
+        .faults()
+        .inject("hall", "lamp", Fault::new(FaultKind::Unreachable).times(1));
+    assert!(fault_kind(&devices.execute_command(turn_on("lamp"))).is_some());
+    devices.set_default_policy(CommandPolicy::new().retries(5).backoff(MS, MS));
+    devices
+        .faults()
+        .inject("hall", "lamp", Fault::new(FaultKind::HardwareFault));
+    assert_eq!(
+        fault_kind(&devices.execute_command(turn_on("lamp"))),
+        Some(FaultKind::HardwareFault)
+    );
+    assert_eq!(devices.faults().triggered("hall", "lamp"), 2);
+}
+
+#[test]
+fn timeouts_are_only_retried_when_idempotent() {
+    let (_, devices) = house();
+    devices.set_default_policy(CommandPolicy::new().retries(3).backoff(MS, MS));
+    devices
+        .faults()
+        .inject("hall", "heater", Fault::new(FaultKind::Timeout).times(2));
+    // the command took effect, sending it again would apply it twice
+    let result = devices.execute_command(turn_on("heater"));
+    assert_eq!(fault_kind(&result), Some(FaultKind::Timeout));
+    assert_eq!(devices.faults().triggered("hall", "heater"), 1);
+    assert_eq!(devices.query(&DeviceQuery::new().powered()).len(), 1);
+
+    devices.set_policy(
+        "hall",
+        "heater",
+        CommandPolicy::new().retries(3).backoff(MS, MS).idempotent(),
+    );
+    let result = devices.execute_command(turn_on("heater"));
+    assert!(matches!(result, ExecutionResult::PowerSocket(_)));
+    assert_eq!(devices.faults().triggered("hall", "heater"), 2);
+}
+
+#[test]
+fn slow_devices_time_out() {
+    let (_, devices) = house();
+    devices.set_policy("hall", "heater", CommandPolicy::new().timeout(20 * MS));
+    devices.faults().set_latency("hall", "heater", 200 * MS);
+
+    let started = Instant::now();
+    let result = devices.execute_command(turn_on("heater"));
+    assert_eq!(fault_kind(&result), Some(FaultKind::Timeout));
+    assert!(started.elapsed() < 150 * MS);
+    assert_eq!(
+        devices.availability("hall", "heater"),
+        Availability::Degraded
+    );
+
+    // the late command still arrives
+    thread::sleep(300 * MS);
+    assert_eq!(devices.query(&DeviceQuery::new().powered()).len(), 1);
+
+    devices.faults().clear("hall", "heater");
+    assert!(matches!(
+        devices.execute_command(turn_on("heater")),
+        ExecutionResult::PowerSocket(_)
+    ));
+}
+
+#[test]
+fn timed_out_attempts_do_not_overlap_retries() {
+    let (_, devices) = house();
+    let policy = CommandPolicy::new()
+        .timeout(20 * MS)
+        .retries(3)
+        .backoff(MS, MS)
+        .idempotent();
+    devices.set_policy("hall", "heater", policy);
+    devices.faults().set_latency("hall", "heater", 200 * MS);
+
+    let started = Instant::now();
+    let result = devices.execute_command(turn_on("heater"));
+    assert_eq!(fault_kind(&result), Some(FaultKind::Timeout));
+    assert!(started.elapsed() < 150 * MS);
+    // the first attempt was still running, so it was not sent again
+    let health = devices
+        .device_health("hall", "heater", SystemTime::now())
+        .unwrap();
+    assert_eq!(health.commands, 1);
+    thread::sleep(300 * MS);
+    assert_eq!(devices.query(&DeviceQuery::new().powered()).len(), 1);
+}
+
+#[test]
+fn breaker_takes_failing_devices_offline() {
+    let (_, devices) = house();
+    devices.set_policy("hall", "heater", CommandPolicy::new().breaker(2, 50 * MS));
+    devices
+        .faults()
+        .inject("hall", "heater", Fault::new(FaultKind::Unreachable));
+
+    devices.execute_command(turn_on("heater"));
+    assert_eq!(
+        devices.availability("hall", "heater"),
+        Availability::Degraded
+    );
+    devices.execute_command(turn_on("heater"));
+    assert_eq!(
+        devices.availability("hall", "heater"),
+        Availability::Offline
+    );
+
+    // refused without reaching the device
+    let result = devices.execute_command(turn_on("heater"));
+    assert_eq!(fault_kind(&result), Some(FaultKind::Unreachable));
+    assert_eq!(devices.faults().triggered("hall", "heater"), 2);
+    assert!(devices.probe_offline_devices().is_empty());
+
+    // a failing probe keeps it offline
+    thread::sleep(60 * MS);
+    assert!(devices.probe_offline_devices().is_empty());
+    assert_eq!(devices.faults().triggered("hall", "heater"), 3);
+    assert_eq!(
+        devices.availability("hall", "heater"),
+        Availability::Offline
+    );
+
+    devices.faults().clear("hall", "heater");
+    thread::sleep(60 * MS);
+    assert_eq!(
+        devices.probe_offline_devices(),
+        [("hall".to_owned(), "heater".to_owned())]
+    );
+    assert_eq!(devices.availability("hall", "heater"), Availability::Online);
+    assert!(matches!(
+        devices.execute_command(turn_on("heater")),
+        ExecutionResult::PowerSocket(_)
+    ));
+}
+
+#[test]
+fn next_command_after_the_interval_is_a_probe() {
+    let (_, devices) = house();
+    devices.set_policy(
+        "hall",
+        "lamp",
+        CommandPolicy::new()
+            .retries(3)
+            .backoff(MS, MS)
+            .breaker(1, 30 * MS)
+            .idempotent(),
+    );
+    devices
+        .faults()
+        .inject("hall", "lamp", Fault::new(FaultKind::Timeout).times(5));
+    devices.execute_command(turn_on("lamp"));
+    assert_eq!(devices.availability("hall", "lamp"), Availability::Offline);
+    assert_eq!(devices.faults().triggered("hall", "lamp"), 4);
+
+    thread::sleep(40 * MS);
+    // probes are sent once, without retries
+    assert!(fault_kind(&devices.execute_command(turn_on("lamp"))).is_some());
+    assert_eq!(devices.faults().triggered("hall", "lamp"), 5);
+    thread::sleep(40 * MS);
+    assert!(matches!(
+        devices.execute_command(turn_on("lamp")),
+        ExecutionResult::PowerSocket(_)
+    ));
+    assert_eq!(devices.availability("hall", "lamp"), Availability::Online);
+}
+
+#[test]
+fn availability_is_reported() {
+    let (house, devices) = house();
+    devices.set_policy(
+        "hall",
+        "heater",
+        CommandPolicy::new().breaker(1, Duration::from_secs(60)),
+    );
+    devices
+        .faults()
+        .inject("hall", "heater", Fault::new(FaultKind::HardwareFault));
+    devices.execute_command(turn_on("heater"));
+
+    let info = devices.get_device_info("hall", "heater").unwrap();
+    assert_eq!(info.availability, Availability::Offline);
+    let entries = house.get_report_entries(&devices);
+    let availability: Vec<_> = entries
+        .iter()
+        .map(|e| e.info.as_ref().unwrap().availability)
+        .collect();
+    // hall_temp, heater and lamp
+    assert_eq!(
+        availability,
+        [
+            Availability::Online,
+            Availability::Offline,
+            Availability::Online
+        ]
+    );
+    assert!(house.get_report(&devices).contains("Offline"));
+}
+
+#[test]
+fn policies_are_kept_per_room() {
+    let (_, mut devices) = house();
+    let socket = SmartDevice::Socket(PowerSocket::new("heater", 500));
+    devices.add_device("kitchen", socket).unwrap();
+    let thermometer = SmartDevice::Thermo(Thermometer::new("sensor", Temperature::Celsius(20.)));
+    devices.add_device("kitchen", thermometer).unwrap();
+    for device in ["heater", "sensor"] {
+        devices.set_policy("kitchen", device, CommandPolicy::new().breaker(1, 10 * MS));
+        devices.faults().inject(
+            "kitchen",
+            device,
+            Fault::new(FaultKind::Unreachable).times(1),
+        );
+    }
+    devices.execute_command_in("kitchen", turn_on("heater"));
+    devices.execute_command_in(
+        "kitchen",
+        CommandData {
+            device_name: "sensor".into(),
+            data: DeviceCommand::Thermometer(ThermometerCommand::SetCalibration(0.5)),
+        },
+    );
+    assert_eq!(
+        devices.availability("kitchen", "heater"),
+        Availability::Offline
+    );
+    assert_eq!(devices.availability("hall", "heater"), Availability::Online);
+    assert_eq!(devices.policy("hall", "heater"), CommandPolicy::new());
+
+    devices.rename_room("kitchen", "pantry").unwrap();
+    assert_eq!(
+        devices.availability("pantry", "sensor"),
+        Availability::Offline
+    );
+    // thermometers are probed with a read
+    thread::sleep(20 * MS);
+    let mut recovered = devices.probe_offline_devices();
+    recovered.sort();
+    assert_eq!(
+        recovered,
+        [
+            ("pantry".to_owned(), "heater".to_owned()),
+            ("pantry".to_owned(), "sensor".to_owned())
+        ]
+    );
+}
+
+#[test]
+fn policies_follow_renames_and_removals() {
+    let (_, mut devices) = house();
+    let policy = CommandPolicy::new().breaker(1, Duration::from_secs(60));
+    devices.set_policy("hall", "lamp", policy.clone());
+    devices
+        .faults()
+        .inject("hall", "lamp", Fault::new(FaultKind::Unreachable).times(1));
+    devices.execute_command(turn_on("lamp"));
+    devices.rename_device("hall", "lamp", "desk lamp").unwrap();
+    assert_eq!(devices.policy("hall", "desk lamp"), policy);
+    assert_eq!(
+        devices.availability("hall", "desk lamp"),
+        Availability::Offline
+    );
+
+    devices.remove_device("hall", "desk lamp").unwrap();
+    let lamp = SmartDevice::Socket(PowerSocket::new("desk lamp", 60));
+    devices.add_device("hall", lamp).unwrap();
+    assert_eq!(devices.policy("hall", "desk lamp"), CommandPolicy::new());
+    assert_eq!(
+        devices.availability("hall", "desk lamp"),
+        Availability::Online
+    );
+
+    devices.set_policy("hall", "heater", policy.clone());
+    devices.remove_room("hall").unwrap();
+    assert_eq!(devices.policy("hall", "heater"), CommandPolicy::new());
+}
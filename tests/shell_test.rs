@@ -0,0 +1,95 @@
+use smart_house::*;
+use std::sync::mpsc::channel;
+use std::time::Duration;
+
+fn start_server() -> (String, SmartDeviceList) {
+    let mut devices = SmartDeviceList::new();
+    for (room, name) in [("hall", "Lamp"), ("kitchen", "kettle")] {
+        devices
+            .add_device(room, SmartDevice::Socket(PowerSocket::new(name, 0)))
+            .unwrap();
+    }
+    let server = ControlServer::bind("127.0.0.1:0", devices.clone()).unwrap();
+    let addr = server.local_addr().unwrap().to_string();
+    server.spawn();
+    (addr, devices)
+}
+
+#[test]
+fn shell_controls_sockets() {
+    let (addr, _devices) = start_server();
+    let mut shell = Shell::connect(&addr).unwrap();
+
+    assert_eq!(shell.execute("rooms").unwrap().unwrap(), "hall\nkitchen");
+    let output = shell.execute("on hall/lamp").unwrap().unwrap();
+    assert_eq!(output, "hall/Lamp: ✔ on (220 W)");
+    let output = shell.execute("devices hall").unwrap().unwrap();
+    assert_eq!(output, "hall/Lamp: Powered(220)");
+    assert!(matches!(
+        shell.execute("on hall/toaster"),
+        Err(CustomError::DeviceNotFound)
+    ));
+    assert!(shell.execute("quit").unwrap().is_none());
+}
+
+#[test]
+fn shell_completes_device_names() {
+    let (addr, _devices) = start_server();
+    let shell = Shell::connect(&addr).unwrap();
+    let (_, candidates) = shell.helper().candidates("off kitchen/");
+    assert_eq!(candidates, ["kitchen/kettle"]);
+}
+
+#[test]
+fn shell_watches_state_changes() {
+    let (addr, devices) = start_server();
+    let mut shell = Shell::connect(&addr).unwrap();
+    let (tx, rx) = channel();
+    shell.set_output(move |line| tx.send(line).unwrap());
+    shell.execute("watch kitchen").unwrap();
+
+    let turn_on = |name: &str| {
+        devices.execute_command(CommandData {
+            device_name: name.into(),
+            data: DeviceCommand::PowerSocket(PowerSocketCommand::TurnOn),
+        })
+    };
+    turn_on("Lamp");
+    turn_on("kettle");
+    let line = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert_eq!(line, "» kitchen/kettle -> Powered(220)");
+    assert!(rx.recv_timeout(Duration::from_millis(100)).is_err());
+
+    assert_eq!(
+        shell.execute("unwatch").unwrap().unwrap(),
+        "stopped watching"
+    );
+}
+
+#[test]
+fn list_devices_and_watch_over_control_channel() {
+    let (addr, devices) = start_server();
+    let mut client = ControlClient::connect(addr.as_str()).unwrap();
+    assert_eq!(client.list_devices().unwrap().len(), 2);
+
+    let mut events = ControlClient::connect(addr.as_str())
+        .unwrap()
+        .watch()
+        .unwrap();
+    std::thread::sleep(Duration::from_millis(100));
+    devices
+        .with_device_mut("hall", "lamp", |d| {
+            if let SmartDevice::Socket(s) = d {
+                s.turn_on();
+            }
+        })
+        .unwrap();
+    assert_eq!(
+        events.next().unwrap().unwrap(),
+        DeviceEvent::StateChanged {
+            room: "hall".into(),
+            device: "Lamp".into(),
+            state: "Powered(220)".into()
+        }
+    );
+}
@@ -0,0 +1,189 @@
+use smart_house::*;
+use std::time::Duration;
+
+const MINUTE: Duration = Duration::from_secs(60);
+const HOUR: Duration = Duration::from_secs(3600);
+
+fn turn(device: &str, command: PowerSocketCommand) -> CommandData {
+    CommandData {
+        device_name: device.into(),
+        data: DeviceCommand::PowerSocket(command),
+    }
+}
+
+fn reading(sim: &Simulation, thermometer: &str) -> f64 {
+    match sim.devices().find(thermometer).unwrap().device {
+        SmartDevice::Thermo(t) => t.get_temperature().as_celsius_f64(),
+        other => panic!("not a thermometer: {:?}", other),
+    }
+}
+
+/// living room with a heater, an unheated garage, both with a thermometer
+fn house(seed: u64) -> Simulation {
+    let mut sim = Simulation::new(seed).with_outdoor_temperature(Temperature::Celsius(0.));
+    sim.add_room("living", Temperature::Celsius(15.), ThermalModel::default())
+        .unwrap();
+    sim.add_room("garage", Temperature::Celsius(15.), ThermalModel::default())
+        .unwrap();
+    sim.add_socket("living", SimulatedSocket::new("heater", 2000).heater())
+        .unwrap();
+    sim.add_socket("living", SimulatedSocket::new("tv", 150))
+        .unwrap();
+    sim.add_thermometer("living", "living_temp", 0.1).unwrap();
+    sim.add_thermometer("garage", "garage_temp", 0.1).unwrap();
+    sim
+}
+
+#[test]
+fn rooms_cool_down_to_outdoor_temperature() {
+    let mut sim = house(1);
+    sim.run_for(24 * HOUR, 10 * MINUTE, |_| {});
+    let garage = sim.room_temperature("garage").unwrap().as_celsius_f64();
+    assert!(garage.abs() < 0.1, "{}", garage);
+    assert!((reading(&sim, "garage_temp") - garage).abs() < 1.0);
+    assert_eq!(sim.clock().elapsed(), 24 * HOUR);
+}
+
+#[test]
+fn heaters_warm_their_room_only() {
+    let mut sim = house(2);
+    sim.execute(turn("heater", PowerSocketCommand::TurnOn));
+    // the tv draws power but is no heater
+    sim.execute(turn("tv", PowerSocketCommand::TurnOn));
+    sim.run_for(24 * HOUR, 10 * MINUTE, |_| {});
+    // 2 kW * 2.5 °C/h per kW * 4 h time constant
+    let living = sim.room_temperature("living").unwrap().as_celsius_f64();
+    assert!((living - 20.0).abs() < 0.1, "{}", living);
+    assert!(sim.room_temperature("garage").unwrap().as_celsius_f64() < 0.1);
+}
+
+#[test]
+fn heaters_are_told_apart_by_room() {
+    let mut sim = house(3);
+    sim.add_socket("garage", SimulatedSocket::new("heater", 1000).heater())
+        .unwrap();
+    assert!(matches!(
+        sim.execute(turn("heater", PowerSocketCommand::TurnOn)),
+        ExecutionResult::Error(CustomError::AmbiguousDevice(_))
+    ));
+    sim.execute_in("garage", turn("heater", PowerSocketCommand::TurnOn));
+    sim.run_for(24 * HOUR, 10 * MINUTE, |_| {});
+    // 1 kW * 2.5 °C/h per kW * 4 h time constant
+    let garage = sim.room_temperature("garage").unwrap().as_celsius_f64();
+    assert!((garage - 10.0).abs() < 0.1, "{}", garage);
+    assert!(sim.room_temperature("living").unwrap().as_celsius_f64() < 0.1);
+}
+
+#[test]
+fn thermostat_automation_holds_temperature() {
+    let mut sim = house(3);
+    let mut switches = 0;
+    sim.run_for(12 * HOUR, 5 * MINUTE, |sim| {
+        let command = match reading(sim, "living_temp") {
+            t if t < 17.5 => PowerSocketCommand::TurnOn,
+            t if t > 18.5 => PowerSocketCommand::TurnOff,
+            _ => return,
+        };
+        if let ExecutionResult::PowerSocket(_) = sim.execute(turn("heater", command)) {
+            switches += 1;
+        }
+    });
+    let living = sim.room_temperature("living").unwrap().as_celsius_f64();
+    assert!((16.5..19.5).contains(&living), "{}", living);
+    assert!(switches > 2);
+}
+
+#[test]
+fn same_seed_same_run() {
+    fn trace(seed: u64) -> Vec<String> {
+        let mut sim = house(seed);
+        sim.add_socket(
+            "garage",
+            SimulatedSocket::new("flaky", 500)
+                .failure_rate(0.3)
+                .latency(Duration::from_millis(50), Duration::from_secs(2)),
+        )
+        .unwrap();
+        let mut trace = Vec::new();
+        for _ in 0..20 {
+            let result = sim.execute(turn("flaky", PowerSocketCommand::GetState));
+            sim.step(MINUTE);
+            trace.push(format!(
+                "{:?} {:?} {:.3}",
+                sim.clock().elapsed(),
+                matches!(result, ExecutionResult::Error(_)),
+                reading(&sim, "living_temp")
+            ));
+        }
+        trace
+    }
+    assert_eq!(trace(42), trace(42));
+    assert_ne!(trace(42), trace(43));
+}
+
+#[test]
+fn failures_and_latency() {
+    let mut sim = Simulation::new(7);
+    sim.add_room("lab", Temperature::Celsius(20.), ThermalModel::default())
+        .unwrap();
+    sim.add_socket("lab", SimulatedSocket::new("dead", 100).failure_rate(1.0))
+        .unwrap();
+    sim.add_socket(
+        "lab",
+        SimulatedSocket::new("slow", 100).latency(Duration::from_secs(1), Duration::from_secs(3)),
+    )
+    .unwrap();
+
+    let result = sim.execute(turn("dead", PowerSocketCommand::TurnOn));
+    assert!(matches!(
+        result,
+        ExecutionResult::Error(CustomError::DeviceFault(_))
+    ));
+    assert_eq!(sim.devices().query(&DeviceQuery::new().powered()).len(), 0);
+
+    let before = sim.clock().now();
+    let result = sim.execute(turn("slow", PowerSocketCommand::TurnOn));
+    assert!(matches!(result, ExecutionResult::PowerSocket(_)));
+    let took = sim.clock().now().duration_since(before).unwrap();
+    assert!((Duration::from_secs(1)..=Duration::from_secs(3)).contains(&took));
+
+    assert!(matches!(
+        sim.execute(turn("missing", PowerSocketCommand::TurnOn)),
+        ExecutionResult::Error(CustomError::DeviceNotFound)
+    ));
+    assert!(sim
+        .add_socket("attic", SimulatedSocket::new("fan", 40))
+        .is_err());
+}
+
+#[test]
+fn daily_outdoor_cycle() {
+    let mut sim = Simulation::new(0)
+        .with_outdoor_temperature(Temperature::Celsius(10.))
+        .with_daily_swing(5.);
+    sim.step(3 * HOUR);
+    assert!((sim.outdoor_temperature().as_celsius_f64() - 5.0).abs() < 0.01);
+    sim.step(12 * HOUR);
+    assert!((sim.outdoor_temperature().as_celsius_f64() - 15.0).abs() < 0.01);
+}
+
+#[test]
+fn alarms_on_virtual_time() {
+    let mut sim = house(5);
+    let mut monitor = AlarmMonitor::new();
+    monitor.configure(
+        "garage",
+        "garage_temp",
+        AlarmConfig::new().low(5., Severity::Warning),
+    );
+    let mut raised = None;
+    sim.run_for(12 * HOUR, 10 * MINUTE, |sim| {
+        let events = monitor.scan(sim.devices(), sim.clock().now());
+        if raised.is_none() && !events.is_empty() {
+            raised = Some(sim.clock().elapsed());
+        }
+    });
+    // 15°C falls to 5°C after about 4h24m with a 4h time constant
+    let raised = raised.expect("no alarm raised");
+    assert!(raised > 4 * HOUR && raised < 5 * HOUR, "{:?}", raised);
+}
@@ -24,6 +24,7 @@ fn create_thermometer(name: &str) -> Thermometer {
     Thermometer {
         name: name.to_string(),
         state: Temperature::Celsius(0.),
+        ..Default::default()
     }
 }
 
@@ -33,6 +34,7 @@ fn create_powersocket(name: &str) -> PowerSocket {
         state: PowerSocketState::NotPowered,
         description: "no desc".into(),
         power_consumption: 0,
+        ..Default::default()
     }
 }
 fn create_devices_storage() -> impl DeviceInfoProvider {
@@ -51,12 +53,9 @@ fn create_devices_storage() -> impl DeviceInfoProvider {
         Box::new(therm2),
     ];
 
-    collection
-        .into_iter()
-        .map(|d| create_device(d))
-        .for_each(|dev| {
-            storage.add_device("hall", dev).ok();
-        });
+    collection.into_iter().map(create_device).for_each(|dev| {
+        storage.add_device("hall", dev).ok();
+    });
     storage
 }
 
@@ -121,7 +120,7 @@ fn report_contains_error_if_no_device() {
     let storage = create_devices_storage();
     let report = house.get_report(&storage);
     println!("{}", report);
-    assert!(report.contains("device not found"));
+    assert!(report.to_lowercase().contains("device not found"));
 }
 
 #[test]
@@ -168,6 +167,21 @@ fn count_devices(house: &mut SmartHouse) -> usize {
     house
         .get_rooms()
         .iter()
-        .flat_map(|room| house.get_devices(room))
-        .count()
+        .map(|room| house.get_devices(room).unwrap().len())
+        .sum()
+}
+
+#[test]
+fn report_uses_preferred_temperature_unit() {
+    let mut house = create_house();
+    house.try_add_device("hall", "therm1").unwrap();
+    let storage = create_devices_storage();
+
+    // the same format with and without a preference
+    let report = house.get_report(&storage);
+    assert!(report.contains("state: \"0.0°C\""));
+
+    house.set_temperature_unit(TemperatureUnit::Fahrenheit);
+    let report = house.get_report(&storage);
+    assert!(report.contains("state: \"32.0°F\""));
 }
@@ -0,0 +1,184 @@
+mod common;
+
+use common::{house_with, socket, socket_command, turn_on};
+use smart_house::*;
+use std::time::{Duration, SystemTime};
+
+const MINUTE: Duration = Duration::from_secs(60);
+
+fn devices() -> (SmartDeviceList, VirtualClock) {
+    let clock = VirtualClock::new(SystemTime::UNIX_EPOCH);
+    let (_, devices) = house_with(&[], [("hall", socket("heater", 2000))]);
+    devices.set_clock(clock.clone());
+    (devices, clock)
+}
+
+fn is_on(devices: &SmartDeviceList) -> bool {
+    match devices.find("heater").unwrap().device {
+        SmartDevice::Socket(socket) => socket.is_turned_on(),
+        other => panic!("unexpected {:?}", other),
+    }
+}
+
+fn countdown(devices: &SmartDeviceList) -> Option<Countdown> {
+    devices.get_device_info("hall", "heater").unwrap().countdown
+}
+
+#[test]
+fn timed_on_switches_off_when_due() {
+    let (devices, clock) = devices();
+    let events = devices.subscribe();
+    match devices.execute_command(socket_command(
+        "heater",
+        PowerSocketCommand::TurnOnFor(45 * MINUTE),
+    )) {
+        ExecutionResult::TimedPowerSocket { state, countdown } => {
+            assert!(matches!(state, PowerSocketState::Powered(2000)));
+            assert_eq!(countdown.action, TimerAction::TurnOff);
+            assert_eq!(countdown.remaining, 45 * MINUTE);
+        }
+        other => panic!("unexpected {:?}", other),
+    }
+    events.try_recv().unwrap();
+
+    clock.advance(30 * MINUTE);
+    assert!(devices.run_timers().is_empty());
+    assert!(is_on(&devices));
+    let left = countdown(&devices).unwrap();
+    assert_eq!(left.remaining, 15 * MINUTE);
+    assert_eq!(left.to_string(), "off in 15m 0s");
+
+    clock.advance(15 * MINUTE);
+    assert_eq!(devices.run_timers(), ["heater"]);
+    assert!(!is_on(&devices));
+    assert_eq!(countdown(&devices), None);
+    assert!(matches!(
+        events.try_recv().unwrap(),
+        DeviceEvent::StateChanged { .. }
+    ));
+    // fired timers are gone
+    assert!(devices.run_timers().is_empty());
+}
+
+#[test]
+fn delayed_switches() {
+    let (devices, clock) = devices();
+    devices.execute_command(socket_command(
+        "heater",
+        PowerSocketCommand::TurnOnIn(MINUTE),
+    ));
+    assert!(!is_on(&devices));
+    clock.advance(MINUTE);
+    devices.run_timers();
+    assert!(is_on(&devices));
+
+    let result = devices.execute_command(socket_command(
+        "heater",
+        PowerSocketCommand::TurnOffIn(10 * MINUTE),
+    ));
+    assert_eq!(result.to_string(), "on (2000 W), off in 10m 0s");
+    clock.advance(10 * MINUTE + Duration::from_secs(5));
+    devices.run_timers();
+    assert!(!is_on(&devices));
+}
+
+#[test]
+fn manual_commands_cancel_timers() {
+    let (devices, clock) = devices();
+    devices.execute_command(socket_command(
+        "heater",
+        PowerSocketCommand::TurnOffIn(10 * MINUTE),
+    ));
+    devices.execute_command(socket_command("heater", PowerSocketCommand::TurnOn));
+    assert_eq!(countdown(&devices), None);
+
+    devices.execute_command(socket_command(
+        "heater",
+        PowerSocketCommand::TurnOnFor(MINUTE),
+    ));
+    devices.execute_command(socket_command("heater", PowerSocketCommand::CancelTimer));
+    clock.advance(2 * MINUTE);
+    assert!(devices.run_timers().is_empty());
+    assert!(is_on(&devices));
+
+    // reading the state leaves the timer alone
+    devices.execute_command(socket_command(
+        "heater",
+        PowerSocketCommand::TurnOffIn(MINUTE),
+    ));
+    devices.execute_command(socket_command("heater", PowerSocketCommand::GetState));
+    assert!(countdown(&devices).is_some());
+}
+
+#[test]
+fn timed_on_respects_circuits() {
+    let (mut devices, clock) = devices();
+    let kettle = SmartDevice::Socket(PowerSocket::new("kettle", 2000));
+    devices.add_device("hall", kettle).unwrap();
+    devices
+        .add_circuit(
+            Circuit::new("hall", 3000)
+                .with_socket("hall", "heater", 1)
+                .with_socket("hall", "kettle", 1),
+        )
+        .unwrap();
+    devices.execute_command(socket_command(
+        "heater",
+        PowerSocketCommand::TurnOnIn(MINUTE),
+    ));
+    devices.execute_command(turn_on("kettle"));
+    clock.advance(MINUTE);
+    assert!(devices.run_timers().is_empty());
+    assert!(!is_on(&devices));
+    assert_eq!(countdown(&devices), None);
+}
+
+#[test]
+fn reports_show_the_countdown() {
+    let (devices, _) = devices();
+    let mut house = SmartHouse::new();
+    house.try_add_room(Room::with_name("hall")).unwrap();
+    house.try_add_device("hall", "heater").unwrap();
+    devices.execute_command(socket_command(
+        "heater",
+        PowerSocketCommand::TurnOnFor(90 * MINUTE),
+    ));
+    let report = house.get_report(&devices);
+    assert!(report.contains("remaining: 5400s"), "{}", report);
+    let json = serde_json::to_string(&house.get_report_entries(&devices)).unwrap();
+    assert!(
+        json.contains(r#""countdown":{"action":"TurnOff""#),
+        "{}",
+        json
+    );
+}
+
+#[test]
+fn timer_commands_on_the_wire() {
+    let commands = [
+        DeviceCommand::PowerSocket(PowerSocketCommand::TurnOffIn(10 * MINUTE)),
+        DeviceCommand::PowerSocket(PowerSocketCommand::TurnOnIn(Duration::from_secs(3600))),
+        DeviceCommand::PowerSocket(PowerSocketCommand::CancelTimer),
+    ];
+    for command in &commands {
+        assert_eq!(
+            &DeviceCommand::from_bytes(&command.to_bytes()).unwrap(),
+            command
+        );
+        assert_eq!(
+            &command.to_string().parse::<DeviceCommand>().unwrap(),
+            command
+        );
+        assert_eq!(Action::of(command), Action::Control);
+    }
+    assert_eq!(commands[0].to_bytes(), [0x01, 0x06, 0, 0, 0x02, 0x58]);
+    assert_eq!(commands[2].code(), 0x0108);
+    assert_eq!(
+        "socket.on_in 1h".parse::<DeviceCommand>().unwrap(),
+        commands[1]
+    );
+    assert!(matches!(
+        "socket.off_in 0".parse::<DeviceCommand>(),
+        Err(CustomError::InvalidParameter { parameter, .. }) if parameter == "delay"
+    ));
+}
@@ -0,0 +1,194 @@
+mod common;
+
+use common::{socket, socket_command, turn_on};
+use rcgen::{
+    BasicConstraints, CertificateParams, CertifiedIssuer, DnType, ExtendedKeyUsagePurpose, IsCa,
+    KeyPair,
+};
+use smart_house::*;
+
+/// certificate and key in PEM
+struct Identity {
+    cert: String,
+    key: String,
+}
+
+/// A locally generated CA that signs server and client certificates.
+struct TestCa {
+    issuer: CertifiedIssuer<'static, KeyPair>,
+}
+
+impl TestCa {
+    fn new(name: &str) -> Self {
+        let mut params = CertificateParams::new(Vec::new()).unwrap();
+        params.distinguished_name.push(DnType::CommonName, name);
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let issuer = CertifiedIssuer::self_signed(params, KeyPair::generate().unwrap()).unwrap();
+        Self { issuer }
+    }
+    fn pem(&self) -> String {
+        self.issuer.pem()
+    }
+    fn sign(&self, names: &[&str], usage: ExtendedKeyUsagePurpose) -> Identity {
+        let names: Vec<String> = names.iter().map(|n| n.to_string()).collect();
+        let mut params = CertificateParams::new(names).unwrap();
+        params.extended_key_usages = vec![usage];
+        let key = KeyPair::generate().unwrap();
+        let cert = params.signed_by(&key, &self.issuer).unwrap();
+        Identity {
+            cert: cert.pem(),
+            key: key.serialize_pem(),
+        }
+    }
+    fn server(&self) -> Identity {
+        self.sign(
+            &["localhost", "127.0.0.1"],
+            ExtendedKeyUsagePurpose::ServerAuth,
+        )
+    }
+    fn client(&self) -> Identity {
+        self.sign(&["sensor-1"], ExtendedKeyUsagePurpose::ClientAuth)
+    }
+}
+
+fn start_server(tls: TlsServerConfig) -> (u16, SmartDeviceList) {
+    let mut devices = SmartDeviceList::new();
+    devices.add_device("hall", socket("lamp", 0)).unwrap();
+    let server = ControlServer::bind("127.0.0.1:0", devices.clone())
+        .unwrap()
+        .with_tls(tls);
+    let port = server.local_addr().unwrap().port();
+    server.spawn();
+    (port, devices)
+}
+
+fn turn_on_lamp(client: &mut ControlClient) -> CustomResult<ExecutionResult> {
+    client.send(&Command::Execute(turn_on("lamp")))
+}
+
+#[test]
+fn commands_over_tls() {
+    let ca = TestCa::new("house ca");
+    let server = ca.server();
+    let tls = TlsServerConfig::from_pem(server.cert.as_bytes(), server.key.as_bytes()).unwrap();
+    let (port, devices) = start_server(tls);
+
+    let options =
+        ConnectOptions::new().with_tls(TlsClientConfig::from_pem(ca.pem().as_bytes()).unwrap());
+    for host in ["localhost", "127.0.0.1"] {
+        let addr = format!("{}:{}", host, port);
+        let mut client = ControlClient::connect_with(&addr, &options).unwrap();
+        assert_eq!(client.list_devices().unwrap().len(), 1);
+    }
+    let mut client = ControlClient::connect_with(&format!("localhost:{}", port), &options).unwrap();
+    assert!(matches!(
+        turn_on_lamp(&mut client).unwrap(),
+        ExecutionResult::PowerSocket(PowerSocketState::Powered(_))
+    ));
+    assert_eq!(devices.query(&DeviceQuery::new().powered()).len(), 1);
+
+    let mut events = client.watch().unwrap();
+    devices.execute_command(socket_command("lamp", PowerSocketCommand::TurnOff));
+    assert!(matches!(
+        events.next().unwrap().unwrap(),
+        DeviceEvent::StateChanged { .. }
+    ));
+}
+
+#[test]
+fn untrusted_servers_are_rejected() {
+    let server = TestCa::new("house ca").server();
+    let tls = TlsServerConfig::from_pem(server.cert.as_bytes(), server.key.as_bytes()).unwrap();
+    let (port, _) = start_server(tls);
+
+    let other = TlsClientConfig::from_pem(TestCa::new("other ca").pem().as_bytes()).unwrap();
+    let options = ConnectOptions::new().with_tls(other);
+    assert!(matches!(
+        ControlClient::connect_with(&format!("127.0.0.1:{}", port), &options),
+        Err(CustomError::TlsError(_))
+    ));
+}
+
+#[test]
+fn plain_clients_cannot_talk_to_tls_servers() {
+    let server = TestCa::new("house ca").server();
+    let tls = TlsServerConfig::from_pem(server.cert.as_bytes(), server.key.as_bytes()).unwrap();
+    let (port, devices) = start_server(tls);
+
+    let mut client = ControlClient::connect(("127.0.0.1", port)).unwrap();
+    assert!(turn_on_lamp(&mut client).is_err());
+    assert_eq!(devices.query(&DeviceQuery::new().powered()).len(), 0);
+}
+
+#[test]
+fn mutual_tls_requires_client_certificates() {
+    let ca = TestCa::new("house ca");
+    let devices_ca = TestCa::new("devices ca");
+    let server = ca.server();
+    let tls = TlsServerConfig::with_client_auth(
+        server.cert.as_bytes(),
+        server.key.as_bytes(),
+        devices_ca.pem().as_bytes(),
+    )
+    .unwrap();
+    let (port, _) = start_server(tls);
+    let addr = format!("localhost:{}", port);
+
+    // the server only learns about a missing certificate after the handshake
+    let anonymous = TlsClientConfig::from_pem(ca.pem().as_bytes()).unwrap();
+    let result = ControlClient::connect_with(&addr, &ConnectOptions::new().with_tls(anonymous))
+        .and_then(|mut client| client.list_devices());
+    assert!(result.is_err());
+
+    // signed by a CA the server does not accept for clients
+    let stranger = ca.client();
+    let stranger = TlsClientConfig::with_identity(
+        ca.pem().as_bytes(),
+        stranger.cert.as_bytes(),
+        stranger.key.as_bytes(),
+    )
+    .unwrap();
+    let result = ControlClient::connect_with(&addr, &ConnectOptions::new().with_tls(stranger))
+        .and_then(|mut client| client.list_devices());
+    assert!(result.is_err());
+
+    let device = devices_ca.client();
+    let device = TlsClientConfig::with_identity(
+        ca.pem().as_bytes(),
+        device.cert.as_bytes(),
+        device.key.as_bytes(),
+    )
+    .unwrap();
+    let mut client =
+        ControlClient::connect_with(&addr, &ConnectOptions::new().with_tls(device)).unwrap();
+    assert_eq!(client.list_devices().unwrap().len(), 1);
+}
+
+#[test]
+fn tls_with_authentication() {
+    let ca = TestCa::new("house ca");
+    let server = ca.server();
+    let tls = TlsServerConfig::from_pem(server.cert.as_bytes(), server.key.as_bytes()).unwrap();
+    let auth = Authenticator::new().with_token("t0ken", Principal::admin("admin"));
+    let server = ControlServer::bind("127.0.0.1:0", SmartDeviceList::new())
+        .unwrap()
+        .with_tls(tls)
+        .with_auth(auth);
+    let addr = format!("localhost:{}", server.local_addr().unwrap().port());
+    server.spawn();
+
+    let options = ConnectOptions::new()
+        .with_tls(TlsClientConfig::from_pem(ca.pem().as_bytes()).unwrap())
+        .with_credentials(Credentials::Token("t0ken".into()));
+    let mut client = ControlClient::connect_with(&addr, &options).unwrap();
+    assert_eq!(client.list_devices().unwrap().len(), 0);
+}
+
+#[test]
+fn invalid_pem_is_reported() {
+    assert!(matches!(
+        TlsClientConfig::from_pem(b"not a certificate"),
+        Err(CustomError::TlsError(_))
+    ));
+    assert!(TlsServerConfig::load("missing.pem", "missing.key", None).is_err());
+}
@@ -0,0 +1,241 @@
+mod common;
+
+use common::{house_with, socket, socket_command, turn_on};
+use smart_house::*;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+fn devices() -> SmartDeviceList {
+    house_with(&[], [("hall", socket("lamp", 1000))]).1
+}
+
+fn is_on(devices: &SmartDeviceList) -> bool {
+    match devices.find("lamp").unwrap().device {
+        SmartDevice::Socket(socket) => socket.is_turned_on(),
+        other => panic!("unexpected {:?}", other),
+    }
+}
+
+#[test]
+fn changes_increase_the_version() {
+    let devices = devices();
+    assert_eq!(devices.version("hall", "LAMP").unwrap(), 0);
+    devices.execute_command(socket_command("lamp", PowerSocketCommand::TurnOn));
+    devices.execute_command(socket_command(
+        "lamp",
+        PowerSocketCommand::SetDescription("desk".into()),
+    ));
+    assert_eq!(devices.version("hall", "lamp").unwrap(), 2);
+
+    // reads and failed commands change nothing
+    devices.execute_command(socket_command("lamp", PowerSocketCommand::GetState));
+    devices.execute_command(socket_command("lamp", PowerSocketCommand::SetPowerLimit(0)));
+    assert_eq!(devices.version("hall", "lamp").unwrap(), 2);
+
+    assert_eq!(devices.get_device_info("hall", "lamp").unwrap().version, 2);
+    assert_eq!(devices.query(&DeviceQuery::new())[0].version, 2);
+    assert!(matches!(
+        devices.version("hall", "fridge"),
+        Err(CustomError::DeviceNotFound)
+    ));
+}
+
+#[test]
+fn direct_access_counts_only_changes() {
+    let devices = devices();
+    devices.with_device_mut("hall", "lamp", |_| ()).unwrap();
+    assert_eq!(devices.version("hall", "lamp").unwrap(), 0);
+    devices
+        .with_device_mut("hall", "lamp", |d| match d {
+            SmartDevice::Socket(s) => s.turn_on(),
+            _ => unreachable!(),
+        })
+        .unwrap();
+    assert_eq!(devices.version("hall", "lamp").unwrap(), 1);
+}
+
+#[test]
+fn stale_versions_conflict() {
+    let devices = devices();
+    let result = devices.execute_command_if(socket_command("lamp", PowerSocketCommand::TurnOn), 0);
+    assert!(matches!(result, ExecutionResult::PowerSocket(_)));
+
+    // a second dashboard still at version 0
+    match devices.execute_command_if(socket_command("lamp", PowerSocketCommand::TurnOff), 0) {
+        ExecutionResult::Error(CustomError::VersionConflict {
+            device,
+            expected,
+            actual,
+        }) => {
+            assert_eq!(device, "lamp");
+            assert_eq!((expected, actual), (0, 1));
+        }
+        other => panic!("unexpected {:?}", other),
+    }
+    assert!(is_on(&devices));
+    assert_eq!(devices.version("hall", "lamp").unwrap(), 1);
+
+    let result = devices.execute_command_if(socket_command("lamp", PowerSocketCommand::TurnOff), 1);
+    assert!(matches!(result, ExecutionResult::PowerSocket(_)));
+    assert!(!is_on(&devices));
+    // missing devices are not conflicts
+    let result = devices.execute_command_if(turn_on("fridge"), 0);
+    assert!(matches!(
+        result,
+        ExecutionResult::Error(CustomError::DeviceNotFound)
+    ));
+}
+
+#[test]
+fn only_one_writer_wins() {
+    let devices = devices();
+    let handles: Vec<_> = (0..8)
+        .map(|i| {
+            let devices = devices.clone();
+            thread::spawn(move || {
+                let description = format!("client {}", i);
+                devices.execute_command_if(
+                    socket_command("lamp", PowerSocketCommand::SetDescription(description)),
+                    0,
+                )
+            })
+        })
+        .collect();
+    let results: Vec<_> = handles.into_iter().map(|h| h.join().unwrap()).collect();
+    let won = results
+        .iter()
+        .filter(|r| matches!(r, ExecutionResult::PowerSocket(_)))
+        .count();
+    let conflicts = results
+        .iter()
+        .filter(|r| {
+            matches!(
+                r,
+                ExecutionResult::Error(CustomError::VersionConflict { .. })
+            )
+        })
+        .count();
+    assert_eq!((won, conflicts), (1, 7));
+    assert_eq!(devices.version("hall", "lamp").unwrap(), 1);
+}
+
+#[test]
+fn timers_and_renames_count_as_changes() {
+    let clock = VirtualClock::new(SystemTime::UNIX_EPOCH);
+    let mut devices = devices();
+    devices.set_clock(clock.clone());
+    devices.execute_command(socket_command(
+        "lamp",
+        PowerSocketCommand::TurnOnFor(Duration::from_secs(60)),
+    ));
+    clock.advance(Duration::from_secs(60));
+    devices.run_timers();
+    assert_eq!(devices.version("hall", "lamp").unwrap(), 2);
+    // nothing due, nothing changed
+    devices.run_timers();
+    assert_eq!(devices.version("hall", "lamp").unwrap(), 2);
+
+    devices.rename_device("hall", "lamp", "desk_lamp").unwrap();
+    assert_eq!(devices.version("hall", "desk_lamp").unwrap(), 3);
+
+    // a device added again under an old name does not reuse versions
+    devices.remove_device("hall", "desk_lamp").unwrap();
+    devices
+        .add_device("hall", socket("desk_lamp", 1000))
+        .unwrap();
+    assert_eq!(devices.version("hall", "desk_lamp").unwrap(), 3);
+}
+
+#[test]
+fn versions_are_kept_per_room() {
+    let mut devices = devices();
+    devices.add_device("kitchen", socket("lamp", 1000)).unwrap();
+    devices.execute_command_in(
+        "kitchen",
+        socket_command("lamp", PowerSocketCommand::TurnOn),
+    );
+    assert_eq!(devices.version("kitchen", "lamp").unwrap(), 1);
+    assert_eq!(devices.version("hall", "lamp").unwrap(), 0);
+    assert!(matches!(
+        devices.version("garage", "lamp"),
+        Err(CustomError::RoomNotFound)
+    ));
+
+    devices.rename_room("kitchen", "pantry").unwrap();
+    assert_eq!(devices.version("pantry", "lamp").unwrap(), 1);
+}
+
+#[test]
+fn timed_out_compare_and_set_is_not_retried() {
+    let devices = devices();
+    devices.set_policy(
+        "hall",
+        "lamp",
+        CommandPolicy::new()
+            .retries(3)
+            .backoff(Duration::from_millis(1), Duration::from_millis(1))
+            .idempotent(),
+    );
+    devices
+        .faults()
+        .inject("hall", "lamp", Fault::new(FaultKind::Timeout).times(1));
+    let result = devices.execute_command_if(socket_command("lamp", PowerSocketCommand::TurnOn), 0);
+    // the command took effect, a retry at version 0 would only conflict
+    assert!(matches!(
+        result,
+        ExecutionResult::Error(CustomError::DeviceFault(_))
+    ));
+    assert_eq!(devices.faults().triggered("hall", "lamp"), 1);
+    assert!(is_on(&devices));
+    assert_eq!(devices.version("hall", "lamp").unwrap(), 1);
+}
+
+#[test]
+fn conditional_commands_over_http() {
+    let devices = devices();
+    let api = RestApi::new(Arc::new(Mutex::new(SmartHouse::new())), devices.clone());
+    let post = |version: &str| {
+        let mut request = HttpRequest::new("POST", "/devices/lamp/commands", b"socket.on");
+        request.headers.push(("If-Match".into(), version.into()));
+        api.handle(&request)
+    };
+    assert_eq!(post("\"0\"").status, 200);
+    let response = post("0");
+    assert_eq!(response.status, 409);
+    assert!(
+        response.body.contains("expected 0, found 1"),
+        "{}",
+        response.body
+    );
+    assert_eq!(post("soon").status, 400);
+
+    let response = api.handle(&HttpRequest::new("GET", "/devices/lamp", b""));
+    let info: DeviceInfo = serde_json::from_str(&response.body).unwrap();
+    assert_eq!(info.version, 1);
+}
+
+#[test]
+fn conditional_commands_over_the_control_channel() {
+    let devices = devices();
+    let server = ControlServer::bind("127.0.0.1:0", devices.clone()).unwrap();
+    let addr = server.local_addr().unwrap().to_string();
+    server.spawn();
+
+    let mut client = ControlClient::connect(&addr).unwrap();
+    let version = client.list_devices().unwrap()[0].version;
+    let execute_if = |client: &mut ControlClient, version| {
+        client.send(&Command::ExecuteIf {
+            command: socket_command("lamp", PowerSocketCommand::TurnOn),
+            version,
+        })
+    };
+    assert!(matches!(
+        execute_if(&mut client, version).unwrap(),
+        ExecutionResult::PowerSocket(_)
+    ));
+    assert!(matches!(
+        execute_if(&mut client, version).unwrap(),
+        ExecutionResult::Error(CustomError::VersionConflict { .. })
+    ));
+}
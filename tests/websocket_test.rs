@@ -0,0 +1,170 @@
+mod common;
+
+use common::socket;
+use smart_house::*;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+fn start_server() -> (String, SmartDeviceList) {
+    let mut devices = SmartDeviceList::new();
+    devices.add_device("hall", socket("lamp", 0)).unwrap();
+    devices.add_device("kitchen", socket("kettle", 0)).unwrap();
+    devices
+        .add_device(
+            "kitchen",
+            SmartDevice::Thermo(Thermometer::new("fridge", Temperature::Celsius(9.))),
+        )
+        .unwrap();
+    let api = RestApi::new(Arc::new(Mutex::new(SmartHouse::new())), devices.clone());
+    let server = HttpServer::bind("127.0.0.1:0", api).unwrap();
+    let addr = server.local_addr().unwrap().to_string();
+    server.spawn();
+    (addr, devices)
+}
+
+fn connect(addr: &str) -> WebSocketClient {
+    let client = WebSocketClient::connect(addr, "/ws").unwrap();
+    client.set_timeout(Some(Duration::from_secs(5))).unwrap();
+    client
+}
+
+fn subscribe(client: &mut WebSocketClient, topic: Topic) {
+    client
+        .send(&WsClientMessage::Subscribe(topic.clone()))
+        .unwrap();
+    assert_eq!(
+        client.receive().unwrap(),
+        WsServerMessage::Subscribed(topic)
+    );
+}
+
+fn switch(devices: &SmartDeviceList, device: &str, command: PowerSocketCommand) {
+    let result = devices.execute_command(CommandData {
+        device_name: device.into(),
+        data: DeviceCommand::PowerSocket(command),
+    });
+    assert!(!matches!(result, ExecutionResult::Error(_)), "{}", result);
+}
+
+fn changed_device(message: WsServerMessage) -> String {
+    match message {
+        WsServerMessage::Event(DeviceEvent::StateChanged { device, .. }) => device,
+        other => panic!("unexpected message {:?}", other),
+    }
+}
+
+#[test]
+fn pushes_only_subscribed_rooms() {
+    let (addr, devices) = start_server();
+    let mut client = connect(&addr);
+    subscribe(&mut client, Topic::room("kitchen"));
+
+    switch(&devices, "lamp", PowerSocketCommand::TurnOn);
+    switch(&devices, "kettle", PowerSocketCommand::TurnOn);
+    assert_eq!(changed_device(client.receive().unwrap()), "kettle");
+
+    client
+        .send(&WsClientMessage::Unsubscribe(Topic::room("kitchen")))
+        .unwrap();
+    assert_eq!(
+        client.receive().unwrap(),
+        WsServerMessage::Unsubscribed(Topic::room("kitchen"))
+    );
+    subscribe(&mut client, Topic::device("LAMP"));
+    switch(&devices, "kettle", PowerSocketCommand::TurnOff);
+    switch(&devices, "lamp", PowerSocketCommand::TurnOff);
+    assert_eq!(changed_device(client.receive().unwrap()), "lamp");
+    client.close().unwrap();
+}
+
+#[test]
+fn clients_have_independent_subscriptions() {
+    let (addr, devices) = start_server();
+    let mut everything = connect(&addr);
+    let mut hall = connect(&addr);
+    subscribe(&mut everything, Topic::all());
+    subscribe(&mut hall, Topic::room("hall"));
+
+    switch(&devices, "kettle", PowerSocketCommand::TurnOn);
+    switch(&devices, "lamp", PowerSocketCommand::TurnOn);
+    assert_eq!(changed_device(everything.receive().unwrap()), "kettle");
+    assert_eq!(changed_device(everything.receive().unwrap()), "lamp");
+    assert_eq!(changed_device(hall.receive().unwrap()), "lamp");
+}
+
+#[test]
+fn room_and_device_topics_match_together() {
+    let (addr, devices) = start_server();
+    let mut client = connect(&addr);
+    // the kettle is in the kitchen, so nothing matches
+    subscribe(
+        &mut client,
+        Topic {
+            room: Some("hall".into()),
+            device: Some("kettle".into()),
+        },
+    );
+    switch(&devices, "lamp", PowerSocketCommand::TurnOn);
+    switch(&devices, "kettle", PowerSocketCommand::TurnOn);
+
+    subscribe(&mut client, Topic::room("kitchen"));
+    switch(&devices, "kettle", PowerSocketCommand::TurnOff);
+    assert_eq!(changed_device(client.receive().unwrap()), "kettle");
+}
+
+#[test]
+fn pushes_alarms() {
+    let (addr, devices) = start_server();
+    let mut monitor = AlarmMonitor::with_event_bus(devices.events().clone());
+    monitor.configure(
+        "kitchen",
+        "fridge",
+        AlarmConfig::new().high(8., Severity::Warning),
+    );
+    let mut client = connect(&addr);
+    subscribe(&mut client, Topic::room("kitchen"));
+
+    monitor.scan(&devices, SystemTime::now());
+    match client.receive().unwrap() {
+        WsServerMessage::Event(DeviceEvent::Alarm(AlarmEvent::Raised(alarm))) => {
+            assert_eq!(alarm.device, "fridge")
+        }
+        other => panic!("unexpected message {:?}", other),
+    }
+}
+
+#[test]
+fn invalid_messages_are_reported() {
+    let (addr, _) = start_server();
+    let mut client = connect(&addr);
+    client.send_text(r#"{"action":"shout"}"#).unwrap();
+    assert!(matches!(
+        client.receive().unwrap(),
+        WsServerMessage::Error(_)
+    ));
+    // the connection stays usable
+    subscribe(&mut client, Topic::all());
+}
+
+#[test]
+fn plain_requests_still_work() {
+    let (addr, _) = start_server();
+    assert!(WebSocketClient::connect(&addr, "/rooms").is_err());
+}
+
+#[test]
+fn other_protocol_versions_are_refused() {
+    use std::io::{Read, Write};
+    let (addr, _) = start_server();
+    let mut stream = std::net::TcpStream::connect(&addr).unwrap();
+    stream
+        .write_all(
+            b"GET /ws HTTP/1.1\r\nHost: smart-house\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+              Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 8\r\n\r\n",
+        )
+        .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    assert!(response.starts_with("HTTP/1.1 426"));
+    assert!(response.contains("Sec-WebSocket-Version: 13"));
+}